@@ -0,0 +1,71 @@
+#![allow(clippy::needless_return)]
+
+// how many frames a second the emulator runs a ROM at, from power on with no buttons pressed
+//
+// cargo run --release --example fps -- [--frames N] [--blocks] ROM
+
+use gameboy_emulator::{Cartridge, GameBoy, Model};
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Instant;
+
+const USAGE: &str = "Usage: fps [--frames N] [--blocks] ROM";
+const FRAMES: usize = 60 * 60; // a minute of play
+const FULL_SPEED: f64 = 59.73; // frames a second on the real hardware
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut frames = FRAMES;
+    let mut blocks = false;
+    let mut rom_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => frames = n,
+                None => exit(USAGE),
+            },
+            "--blocks" => blocks = true,
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => exit(USAGE),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| exit(USAGE));
+
+    let rom = fs::read(rom_path).unwrap_or_else(|e| exit(&format!("{}: {}", rom_path, e)));
+    let cartridge = Cartridge::new(rom).unwrap_or_else(|e| exit(&e.to_string()));
+    let mut gb = GameBoy::new(Model::for_cartridge(&cartridge), cartridge);
+    if blocks {
+        use_blocks(&mut gb);
+    }
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        if let Err(e) = gb.run_frame() {
+            exit(&e.to_string());
+        }
+    }
+    let per_second = frames as f64 / start.elapsed().as_secs_f64();
+    println!(
+        "{} frames: {:.0} frames/s, {:.1}x full speed",
+        frames,
+        per_second,
+        per_second / FULL_SPEED
+    );
+}
+
+#[cfg(feature = "blocks")]
+fn use_blocks(gb: &mut GameBoy) {
+    gb.use_blocks(true);
+}
+
+#[cfg(not(feature = "blocks"))]
+fn use_blocks(_: &mut GameBoy) {
+    exit("--blocks needs the blocks feature");
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,31 @@
+#![no_main]
+
+// parses any file as a cartridge, then pokes its memory bank controller and reads back through
+// whatever banks that selected
+
+use gameboy_emulator::Cartridge;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (Vec<u8>, Vec<(u16, u8)>)| {
+    let (rom, writes) = input;
+    let mut cartridge = match Cartridge::new(rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return,
+    };
+    cartridge.supports_cgb();
+    cartridge.supports_sgb();
+    cartridge.checksum();
+
+    for (address, value) in writes {
+        match address {
+            0x0000..=0x7fff => cartridge.write_rom(address, value),
+            0xa000..=0xbfff => cartridge.write_ram(address, value),
+            _ => continue,
+        }
+        for bank_start in [0x0000, 0x3fff, 0x4000, 0x7fff] {
+            cartridge.read_rom(bank_start);
+        }
+        cartridge.read_ram(0xa000);
+        cartridge.read_ram(0xbfff);
+    }
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+// runs the bytes as a ROM for a few frames, which must not panic however the game misbehaves
+
+use gameboy_emulator::{Cartridge, GameBoy, Model};
+use libfuzzer_sys::fuzz_target;
+
+const ROM_SIZE: usize = 0x8000; // the smallest real ROM, so short inputs still have a header
+const FRAMES: usize = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rom = data.to_vec();
+    rom.resize(rom.len().max(ROM_SIZE), 0);
+    let cartridge = match Cartridge::new(rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return,
+    };
+
+    let mut gb = GameBoy::new(Model::for_cartridge(&cartridge), cartridge);
+    for _ in 0..FRAMES {
+        // a lock up is an error the emulator reports, not a bug
+        if gb.run_frame().is_err() {
+            return;
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+// decodes any bytes as instructions, including illegal opcodes and ones cut off by the end
+
+use gameboy_emulator::disassembler;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&high, bytes)) = data.split_first() {
+        disassembler::listing(bytes, (high as u16) << 8);
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+// parses any bytes as a movie, which must either be rejected or write back the same bytes and play
+// back to a result without crashing
+
+use gameboy_emulator::movie::Movie;
+use gameboy_emulator::{Cartridge, GameBoy};
+use libfuzzer_sys::fuzz_target;
+
+const MAX_FRAMES: usize = 4; // longer movies are only parsed, to keep each run quick
+
+// the ROM movies are played on, a loop for the CPU to spin in while the PPU draws
+fn cartridge() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]); // JR -2
+    return Cartridge::new(rom).unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let movie = match Movie::from_bytes(data) {
+        Ok(movie) => movie,
+        Err(_) => return,
+    };
+    assert!(movie.to_bytes() == data);
+    if movie.frames() <= MAX_FRAMES {
+        let mut gb = GameBoy::new(movie.model(), cartridge());
+        let _ = movie.play(&mut gb);
+    }
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+// loads any bytes as a save state, which must either be rejected or leave a machine that runs
+
+use gameboy_emulator::{Cartridge, GameBoy, Model};
+use libfuzzer_sys::fuzz_target;
+
+const FRAMES: usize = 2;
+
+// the ROM states are checked against, a loop for the CPU to spin in while the PPU draws
+fn cartridge() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]); // JR -2
+    return Cartridge::new(rom).unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    for model in [Model::DMG, Model::CGB, Model::SGB] {
+        let mut gb = GameBoy::new(model, cartridge());
+        if gb.load_state(data).is_err() {
+            continue;
+        }
+        for _ in 0..FRAMES {
+            if gb.run_frame().is_err() {
+                break;
+            }
+        }
+        gb.save_state();
+    }
+});
@@ -0,0 +1,653 @@
+use crate::cpu::special_registers::*;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const SAMPLE_RATE: u32 = 48000; // stereo samples per second
+const CLOCK_RATE: u32 = 4194304; // clock cycles per second, which the channels count in
+const MAX_SAMPLES: usize = SAMPLE_RATE as usize * 2; // a second's worth, the rest are dropped
+const SAMPLE_SCALE: i16 = 64; // from the mixer's -480 to 480 to most of the i16 range
+
+const WAVE_RAM_START: u16 = 0xff30;
+const WAVE_RAM_SIZE: usize = 0x10;
+const REGISTER_COUNT: usize = (NR_52 - NR_10 + 1) as usize;
+
+// bits which always read as 1, from NR10 to NR52
+const READ_MASKS: [u8; REGISTER_COUNT] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // unused, NR21-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // unused, NR41-NR44
+    0x00, 0x00, 0x70, //             NR50-NR52
+];
+
+// square wave patterns, played from the top bit
+const DUTY_CYCLES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// volume which changes on the frame sequencer's last step, set through NRx2
+#[derive(Clone, Copy, Default)]
+struct Envelope {
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.volume = state.u8()? & 0x0f;
+        self.timer = state.u8()?;
+        return Ok(());
+    }
+
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.timer = nrx2 & 0x07;
+    }
+
+    fn step(&mut self, nrx2: u8) {
+        let period = nrx2 & 0x07;
+        if period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+        if nrx2 & 0x08 != 0 && self.volume < 15 {
+            self.volume += 1;
+        } else if nrx2 & 0x08 == 0 && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+// what every channel has: whether it is playing, when it stops and when its waveform advances
+#[derive(Clone, Copy, Default)]
+struct Channel {
+    enabled: bool,
+    length: u16, // steps left until the channel stops, when length is enabled
+    timer: u32,  // clock cycles until the waveform advances
+}
+
+impl Channel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.length);
+        state.u32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.length = state.u16()?;
+        self.timer = state.u32()?.max(1);
+        return Ok(());
+    }
+
+    // NRx4 bit 6 enables the length
+    fn step_length(&mut self, nrx4: u8) {
+        if nrx4 & 0x40 != 0 && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // how many times the waveform advances in the given clock cycles
+    fn clock(&mut self, cycles: u32, period: u32) -> u32 {
+        let mut steps = 0;
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = period;
+            steps += 1;
+        }
+        self.timer -= cycles;
+        return steps;
+    }
+}
+
+// the sound controller's four channels, mixed into stereo samples
+// https://gbdev.io/pandocs/Audio.html
+#[derive(Clone)]
+pub struct APU {
+    registers: [u8; REGISTER_COUNT], // NR10-NR52 as last written
+    wave_ram: [u8; WAVE_RAM_SIZE],
+    channels: [Channel; 4],
+    envelopes: [Envelope; 4], // the wave channel has none
+    duty_steps: [u8; 2],      // of the square channels
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    sweep_frequency: u16, // shadow of channel 1's frequency which the sweep works from
+    wave_position: u8,    // of the next 4 bit sample in wave RAM
+    lfsr: u16,            // the noise channel's linear feedback shift register
+    sequencer_step: u8,   // of the frame sequencer, clocked at 512 Hz by DIV
+    sample_clock: u32,    // clock cycles since the last sample, in units of 1 / SAMPLE_RATE
+    samples: Vec<i16>,    // interleaved left and right, not saved in states
+}
+
+impl APU {
+    pub fn new() -> APU {
+        return APU {
+            registers: [0; REGISTER_COUNT],
+            wave_ram: [0; WAVE_RAM_SIZE],
+            channels: [Channel {
+                enabled: false,
+                length: 0,
+                timer: 1,
+            }; 4],
+            envelopes: [Envelope::default(); 4],
+            duty_steps: [0; 2],
+            sweep_enabled: false,
+            sweep_timer: 0,
+            sweep_frequency: 0,
+            wave_position: 0,
+            lfsr: 0x7fff,
+            sequencer_step: 0,
+            sample_clock: 0,
+            samples: vec![],
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.bytes(&self.wave_ram);
+        for channel in self.channels.iter() {
+            channel.save_state(state);
+        }
+        for envelope in self.envelopes.iter() {
+            envelope.save_state(state);
+        }
+        state.bytes(&self.duty_steps);
+        state.bool(self.sweep_enabled);
+        state.u8(self.sweep_timer);
+        state.u16(self.sweep_frequency);
+        state.u8(self.wave_position);
+        state.u16(self.lfsr);
+        state.u8(self.sequencer_step);
+        state.u32(self.sample_clock);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes(&mut self.registers)?;
+        state.bytes(&mut self.wave_ram)?;
+        for channel in self.channels.iter_mut() {
+            channel.load_state(state)?;
+        }
+        for envelope in self.envelopes.iter_mut() {
+            envelope.load_state(state)?;
+        }
+        state.bytes(&mut self.duty_steps)?;
+        self.duty_steps[0] &= 0x07;
+        self.duty_steps[1] &= 0x07;
+        self.sweep_enabled = state.bool()?;
+        self.sweep_timer = state.u8()?;
+        self.sweep_frequency = state.u16()? & 0x7ff;
+        self.wave_position = state.u8()? & 0x1f;
+        self.lfsr = state.u16()? & 0x7fff;
+        self.sequencer_step = state.u8()? & 0x07;
+        self.sample_clock = state.u32()? % CLOCK_RATE;
+        return Ok(());
+    }
+
+    // samples mixed since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        return core::mem::take(&mut self.samples);
+    }
+
+    // the boot ROM's chime on channel 1 has faded out by the time it hands over to the game
+    pub fn end_boot_sound(&mut self) {
+        self.envelopes[0].volume = 0;
+    }
+
+    fn register(&self, address: u16) -> u8 {
+        return self.registers[(address - NR_10) as usize];
+    }
+
+    fn powered(&self) -> bool {
+        return self.register(NR_52) & 0x80 != 0;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            NR_52 => {
+                let mut value = self.register(NR_52) & 0x80 | READ_MASKS[REGISTER_COUNT - 1];
+                for (i, channel) in self.channels.iter().enumerate() {
+                    value |= (channel.enabled as u8) << i;
+                }
+                value
+            }
+            NR_10..=NR_51 => self.register(address) | READ_MASKS[(address - NR_10) as usize],
+            0xff30..=0xff3f => self.wave_ram[(address - WAVE_RAM_START) as usize],
+            _ => 0xff,
+        };
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xff30..=0xff3f => self.wave_ram[(address - WAVE_RAM_START) as usize] = value,
+            NR_52 if value & 0x80 == 0 => {
+                // powering off clears every register and stops every channel
+                self.registers = [0; REGISTER_COUNT];
+                self.channels.iter_mut().for_each(|c| c.enabled = false);
+            }
+            NR_52 => {
+                if !self.powered() {
+                    self.sequencer_step = 0;
+                }
+                self.registers[REGISTER_COUNT - 1] = 0x80;
+            }
+            _ if !self.powered() => return,
+            NR_10..=NR_51 => {
+                self.registers[(address - NR_10) as usize] = value;
+                self.write_channel(address, value);
+            }
+            _ => return,
+        }
+    }
+
+    fn write_channel(&mut self, address: u16, value: u8) {
+        match address {
+            NR_11 => self.channels[0].length = 64 - (value & 0x3f) as u16,
+            NR_21 => self.channels[1].length = 64 - (value & 0x3f) as u16,
+            NR_31 => self.channels[2].length = 256 - value as u16,
+            NR_41 => self.channels[3].length = 64 - (value & 0x3f) as u16,
+            NR_12 | NR_22 | NR_30 | NR_42 if !self.dac_on(channel_of(address)) => {
+                self.channels[channel_of(address)].enabled = false;
+            }
+            NR_14 | NR_24 | NR_34 | NR_44 if value & 0x80 != 0 => {
+                self.trigger(channel_of(address));
+            }
+            _ => return,
+        }
+    }
+
+    // the DAC is off when the upper 5 bits of NRx2 are clear, or NR30 bit 7 for the wave channel
+    fn dac_on(&self, channel: usize) -> bool {
+        return match channel {
+            0 => self.register(NR_12) & 0xf8 != 0,
+            1 => self.register(NR_22) & 0xf8 != 0,
+            2 => self.register(NR_30) & 0x80 != 0,
+            _ => self.register(NR_42) & 0xf8 != 0,
+        };
+    }
+
+    fn frequency(&self, channel: usize) -> u16 {
+        let (low, high) = match channel {
+            0 => (NR_13, NR_14),
+            1 => (NR_23, NR_24),
+            _ => (NR_33, NR_34),
+        };
+        return (self.register(high) as u16 & 0x07) << 8 | self.register(low) as u16;
+    }
+
+    // clock cycles between steps of the channel's waveform
+    fn period(&self, channel: usize) -> u32 {
+        return match channel {
+            0 | 1 => (2048 - self.frequency(channel) as u32) * 4,
+            2 => (2048 - self.frequency(channel) as u32) * 2,
+            _ => {
+                let nr43 = self.register(NR_43);
+                NOISE_DIVISORS[(nr43 & 0x07) as usize] << (nr43 >> 4)
+            }
+        };
+    }
+
+    fn trigger(&mut self, channel: usize) {
+        let max_length = if channel == 2 { 256 } else { 64 };
+        let period = self.period(channel);
+        let dac_on = self.dac_on(channel);
+        let c = &mut self.channels[channel];
+        c.enabled = dac_on;
+        if c.length == 0 {
+            c.length = max_length;
+        }
+        c.timer = period;
+
+        match channel {
+            0 => {
+                self.envelopes[0].trigger(self.register(NR_12));
+                let nr10 = self.register(NR_10);
+                self.sweep_frequency = self.frequency(0);
+                self.sweep_timer = sweep_period(nr10);
+                self.sweep_enabled = nr10 & 0x77 != 0;
+                if nr10 & 0x07 != 0 {
+                    self.sweep_next();
+                }
+            }
+            1 => self.envelopes[1].trigger(self.register(NR_22)),
+            2 => self.wave_position = 0,
+            _ => {
+                self.envelopes[3].trigger(self.register(NR_42));
+                self.lfsr = 0x7fff;
+            }
+        }
+    }
+
+    // the frequency the sweep moves channel 1 to next, which stops the channel if out of range
+    fn sweep_next(&mut self) -> u16 {
+        let nr10 = self.register(NR_10);
+        let change = self.sweep_frequency >> (nr10 & 0x07);
+        let frequency = if nr10 & 0x08 != 0 {
+            self.sweep_frequency - change
+        } else {
+            self.sweep_frequency + change
+        };
+        if frequency > 0x7ff {
+            self.channels[0].enabled = false;
+        }
+        return frequency;
+    }
+
+    fn step_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        let nr10 = self.register(NR_10);
+        self.sweep_timer = sweep_period(nr10);
+        if !self.sweep_enabled || nr10 & 0x70 == 0 {
+            return;
+        }
+
+        let frequency = self.sweep_next();
+        if frequency <= 0x7ff && nr10 & 0x07 != 0 {
+            self.sweep_frequency = frequency;
+            self.registers[(NR_13 - NR_10) as usize] = frequency as u8;
+            let nr14 = &mut self.registers[(NR_14 - NR_10) as usize];
+            *nr14 = (*nr14 & 0xf8) | (frequency >> 8) as u8;
+            self.sweep_next();
+        }
+    }
+
+    // called at 512 Hz as a bit of DIV falls: lengths on even steps, the sweep on steps 2 and 6,
+    // envelopes on step 7
+    pub fn step_sequencer(&mut self) {
+        if !self.powered() {
+            return;
+        }
+        let step = self.sequencer_step;
+        self.sequencer_step = (step + 1) % 8;
+
+        if step.is_multiple_of(2) {
+            for (i, &nrx4) in [NR_14, NR_24, NR_34, NR_44].iter().enumerate() {
+                let nrx4 = self.register(nrx4);
+                self.channels[i].step_length(nrx4);
+            }
+        }
+        if step == 2 || step == 6 {
+            self.step_sweep();
+        }
+        if step == 7 {
+            // the wave channel has no envelope
+            for &(i, nrx2) in [(0, NR_12), (1, NR_22), (3, NR_42)].iter() {
+                let nrx2 = self.register(nrx2);
+                self.envelopes[i].step(nrx2);
+            }
+        }
+    }
+
+    // advances by the given clock cycles, mixing a sample at each point one is due and still
+    // mixing silence while powered off
+    pub fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        loop {
+            let until_sample = (CLOCK_RATE - self.sample_clock).div_ceil(SAMPLE_RATE);
+            if cycles < until_sample {
+                self.clock_channels(cycles);
+                self.sample_clock += cycles * SAMPLE_RATE;
+                return;
+            }
+
+            self.clock_channels(until_sample);
+            self.sample_clock = self.sample_clock + until_sample * SAMPLE_RATE - CLOCK_RATE;
+            cycles -= until_sample;
+            if self.samples.len() < MAX_SAMPLES {
+                let (left, right) = self.mix();
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    fn clock_channels(&mut self, cycles: u32) {
+        if !self.powered() {
+            return;
+        }
+        for channel in 0..self.channels.len() {
+            let period = self.period(channel);
+            let steps = self.channels[channel].clock(cycles, period);
+            match channel {
+                0 | 1 => {
+                    self.duty_steps[channel] =
+                        ((self.duty_steps[channel] as u32 + steps) % 8) as u8;
+                }
+                2 => self.wave_position = ((self.wave_position as u32 + steps) % 32) as u8,
+                _ => {
+                    let width_7 = self.register(NR_43) & 0x08 != 0;
+                    for _ in 0..steps {
+                        self.lfsr = step_lfsr(self.lfsr, width_7);
+                    }
+                }
+            }
+        }
+    }
+
+    // the channel's output from 0 to 15
+    fn output(&self, channel: usize) -> u8 {
+        if !self.channels[channel].enabled {
+            return 0;
+        }
+        return match channel {
+            0 | 1 => {
+                let nrx1 = self.register(if channel == 0 { NR_11 } else { NR_21 });
+                let duty = DUTY_CYCLES[(nrx1 >> 6) as usize];
+                let high = duty >> (7 - self.duty_steps[channel]) & 1;
+                high * self.envelopes[channel].volume
+            }
+            2 => {
+                let byte = self.wave_ram[(self.wave_position / 2) as usize];
+                let sample = if self.wave_position.is_multiple_of(2) {
+                    byte >> 4
+                } else {
+                    byte & 0x0f
+                };
+                // volume code 0 mutes, then 100%, 50% and 25%
+                match (self.register(NR_32) >> 5) & 0x03 {
+                    0 => 0,
+                    code => sample >> (code - 1),
+                }
+            }
+            _ => (!self.lfsr & 1) as u8 * self.envelopes[3].volume,
+        };
+    }
+
+    // NR51 pans each channel left and right, and NR50 sets each side's volume
+    fn mix(&self) -> (i16, i16) {
+        let nr50 = self.register(NR_50);
+        let nr51 = self.register(NR_51);
+        let mut left = 0;
+        let mut right = 0;
+        for channel in 0..self.channels.len() {
+            if !self.dac_on(channel) {
+                continue;
+            }
+            let analog = self.output(channel) as i16 * 2 - 15;
+            if nr51 & (0x10 << channel) != 0 {
+                left += analog;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                right += analog;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0x07) as i16 + 1;
+        let right_volume = (nr50 & 0x07) as i16 + 1;
+        return (
+            left * left_volume * SAMPLE_SCALE,
+            right * right_volume * SAMPLE_SCALE,
+        );
+    }
+}
+
+// channels are numbered from 0 in the order of their registers
+fn channel_of(address: u16) -> usize {
+    return ((address - NR_10) / 5) as usize;
+}
+
+// a period of 0 clocks the sweep timer as if it were 8
+fn sweep_period(nr10: u8) -> u8 {
+    return match (nr10 >> 4) & 0x07 {
+        0 => 8,
+        period => period,
+    };
+}
+
+// shifts right, feeding back the XOR of the two low bits into bit 14, and bit 6 too in 7 bit mode
+fn step_lfsr(lfsr: u16, width_7: bool) -> u16 {
+    let feedback = (lfsr ^ (lfsr >> 1)) & 1;
+    let mut lfsr = lfsr >> 1 | feedback << 14;
+    if width_7 {
+        lfsr = (lfsr & !0x40) | feedback << 6;
+    }
+    return lfsr;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> APU {
+        let mut apu = APU::new();
+        apu.write(NR_52, 0x80);
+        apu.write(NR_50, 0x77);
+        apu.write(NR_51, 0xff);
+        return apu;
+    }
+
+    // runs for the given number of frame sequencer steps
+    fn run_steps(apu: &mut APU, steps: usize) {
+        for _ in 0..steps {
+            apu.step_sequencer();
+        }
+    }
+
+    #[test]
+    fn test_registers_read_back_with_unused_bits_set() {
+        let mut apu = powered();
+        apu.write(NR_11, 0x12);
+        apu.write(NR_30, 0x00);
+
+        assert_eq!(apu.read(NR_11), 0x3f | 0x12);
+        assert_eq!(apu.read(NR_13), 0xff); // write only
+        assert_eq!(apu.read(NR_30), 0x7f);
+        assert_eq!(apu.read(NR_52), 0xf0);
+        assert_eq!(apu.read(0xff27), 0xff);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers_and_ignores_writes() {
+        let mut apu = powered();
+        apu.write(NR_30, 0x80);
+        apu.write(0xff30, 0x12);
+
+        apu.write(NR_52, 0x00);
+        apu.write(NR_50, 0x77);
+
+        assert_eq!(apu.read(NR_30), 0x7f);
+        assert_eq!(apu.read(NR_50), 0x00);
+        assert_eq!(apu.read(NR_52), 0x70);
+        assert_eq!(apu.read(0xff30), 0x12);
+    }
+
+    #[test]
+    fn test_trigger_enables_a_channel_only_with_its_dac_on() {
+        let mut apu = powered();
+        apu.write(NR_24, 0x80);
+        assert_eq!(apu.read(NR_52) & 0x02, 0);
+
+        apu.write(NR_22, 0xf0);
+        apu.write(NR_24, 0x80);
+        assert_eq!(apu.read(NR_52) & 0x02, 0x02);
+
+        apu.write(NR_22, 0x00);
+        assert_eq!(apu.read(NR_52) & 0x02, 0);
+    }
+
+    #[test]
+    fn test_length_stops_the_channel() {
+        let mut apu = powered();
+        apu.write(NR_12, 0xf0);
+        apu.write(NR_11, 0x3e); // 2 steps
+        apu.write(NR_14, 0xc0); // trigger with length enabled
+
+        run_steps(&mut apu, 2); // one length step
+        assert_eq!(apu.read(NR_52) & 0x01, 0x01);
+        run_steps(&mut apu, 1); // and the second
+        assert_eq!(apu.read(NR_52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_envelope_fades_volume() {
+        let mut apu = powered();
+        apu.write(NR_42, 0x31); // volume 3, down every step
+        apu.write(NR_44, 0x80);
+
+        run_steps(&mut apu, 8);
+        assert_eq!(apu.envelopes[3].volume, 2);
+        run_steps(&mut apu, 16);
+        assert_eq!(apu.envelopes[3].volume, 0);
+    }
+
+    #[test]
+    fn test_sweep_overflow_stops_channel_1() {
+        let mut apu = powered();
+        apu.write(NR_12, 0xf0);
+        apu.write(NR_10, 0x11); // every step, up by a half
+        apu.write(NR_13, 0x00);
+        apu.write(NR_14, 0x86); // trigger at 0x600, which would go to 0x900
+
+        assert_eq!(apu.read(NR_52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_mixes_samples_at_the_sample_rate() {
+        let mut apu = powered();
+        apu.write(NR_22, 0xf0);
+        apu.write(NR_21, 0x80); // 50% duty
+        apu.write(NR_24, 0x87);
+
+        let mut at_once = apu.clone();
+
+        for _ in 0..CLOCK_RATE / 4 / 64 {
+            apu.advance(4);
+        }
+        at_once.advance(CLOCK_RATE / 64);
+
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), SAMPLE_RATE as usize / 64 * 2);
+        assert!(samples.iter().any(|&s| s > 0));
+        assert!(samples.iter().any(|&s| s < 0));
+        assert!(apu.take_samples().is_empty());
+        assert_eq!(at_once.take_samples(), samples);
+    }
+
+    #[test]
+    fn test_lfsr_in_7_bit_mode_repeats_every_127_steps() {
+        let mut lfsr = 0x7fff;
+        for _ in 0..127 {
+            lfsr = step_lfsr(lfsr, true);
+        }
+        let start = lfsr;
+        for _ in 0..127 {
+            lfsr = step_lfsr(lfsr, true);
+        }
+        assert_eq!(lfsr & 0x7f, start & 0x7f);
+    }
+}
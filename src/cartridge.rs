@@ -0,0 +1,422 @@
+use crate::error::EmuError;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const CGB_FLAG: usize = 0x143;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const OLD_LICENSEE_CODE: usize = 0x14b;
+const HEADER_CHECKSUM: usize = 0x14d;
+const GLOBAL_CHECKSUM: usize = 0x14e;
+const HEADER_END: usize = 0x150;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const RAM_START: u16 = 0xa000;
+
+// memory bank controller and its banking registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MBC {
+    None,
+    MBC1 {
+        ram_enabled: bool,
+        bank1: u8,           // lower 5 bits of the ROM bank
+        bank2: u8,           // upper 2 bits of the ROM bank, or the RAM bank
+        advanced_mode: bool, // bank2 also applies to 0x0000-0x3fff and RAM
+    },
+    MBC3 {
+        ram_enabled: bool,
+        rom_bank: u8,
+        ram_bank: u8,
+    },
+    MBC5 {
+        ram_enabled: bool,
+        rom_bank: u16,
+        ram_bank: u8,
+    },
+}
+
+#[derive(Clone)]
+pub struct Cartridge {
+    rom: Vec<u8>,
+    rom_bank_count: usize, // banks are numbered modulo this, as the unused upper bits are ignored
+    ram: Vec<u8>,
+    mbc: MBC,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, EmuError> {
+        if rom.len() < HEADER_END {
+            return Err(EmuError::BadCartridge(format!(
+                "ROM is too small to hold a header ({} bytes)",
+                rom.len()
+            )));
+        }
+
+        let mbc = match rom[CARTRIDGE_TYPE] {
+            0x00 | 0x08 | 0x09 => MBC::None,
+            0x01..=0x03 => MBC::MBC1 {
+                ram_enabled: false,
+                bank1: 1,
+                bank2: 0,
+                advanced_mode: false,
+            },
+            0x0f..=0x13 => MBC::MBC3 {
+                ram_enabled: false,
+                rom_bank: 1,
+                ram_bank: 0,
+            },
+            0x19..=0x1e => MBC::MBC5 {
+                ram_enabled: false,
+                rom_bank: 1,
+                ram_bank: 0,
+            },
+            t => {
+                return Err(EmuError::BadCartridge(format!(
+                    "Unsupported cartridge type {:#04x}",
+                    t
+                )))
+            }
+        };
+
+        let ram_size = match rom[RAM_SIZE] {
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => 0,
+        };
+
+        // the declared size is 32KiB << n, but trust the actual data if it disagrees
+        let declared = 2 << rom[ROM_SIZE].min(8);
+        let rom_bank_count = declared.min(rom.len().div_ceil(ROM_BANK_SIZE)).max(1);
+
+        return Ok(Cartridge {
+            rom,
+            rom_bank_count,
+            ram: vec![0; ram_size],
+            mbc,
+        });
+    }
+
+    // bit 7 of the CGB flag is set by games with CGB enhancements, 0xc0 marks CGB only games
+    pub fn supports_cgb(&self) -> bool {
+        return self.rom[CGB_FLAG] & 0x80 != 0;
+    }
+
+    // the SGB only accepts commands from games with the SGB flag and the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        return self.rom[SGB_FLAG] == 0x03 && self.rom[OLD_LICENSEE_CODE] == 0x33;
+    }
+
+    // header and global checksums, which identify the game
+    pub fn checksum(&self) -> [u8; 3] {
+        return [
+            self.rom[HEADER_CHECKSUM],
+            self.rom[GLOBAL_CHECKSUM],
+            self.rom[GLOBAL_CHECKSUM + 1],
+        ];
+    }
+
+    // the ROM itself is not saved, only what a running game can change
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.vec(&self.ram);
+        match self.mbc {
+            MBC::None => state.u8(0),
+            MBC::MBC1 {
+                ram_enabled,
+                bank1,
+                bank2,
+                advanced_mode,
+            } => {
+                state.u8(1);
+                state.bool(ram_enabled);
+                state.u8(bank1);
+                state.u8(bank2);
+                state.bool(advanced_mode);
+            }
+            MBC::MBC3 {
+                ram_enabled,
+                rom_bank,
+                ram_bank,
+            } => {
+                state.u8(3);
+                state.bool(ram_enabled);
+                state.u8(rom_bank);
+                state.u8(ram_bank);
+            }
+            MBC::MBC5 {
+                ram_enabled,
+                rom_bank,
+                ram_bank,
+            } => {
+                state.u8(5);
+                state.bool(ram_enabled);
+                state.u16(rom_bank);
+                state.u8(ram_bank);
+            }
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.vec_into(&mut self.ram)?;
+        let mbc = match state.u8()? {
+            0 => MBC::None,
+            1 => MBC::MBC1 {
+                ram_enabled: state.bool()?,
+                bank1: state.u8()?,
+                bank2: state.u8()?,
+                advanced_mode: state.bool()?,
+            },
+            3 => MBC::MBC3 {
+                ram_enabled: state.bool()?,
+                rom_bank: state.u8()?,
+                ram_bank: state.u8()?,
+            },
+            5 => MBC::MBC5 {
+                ram_enabled: state.bool()?,
+                rom_bank: state.u16()?,
+                ram_bank: state.u8()?,
+            },
+            tag => return Err(format!("Unknown memory bank controller {} in state", tag)),
+        };
+        if core::mem::discriminant(&mbc) != core::mem::discriminant(&self.mbc) {
+            return Err("State is for a different memory bank controller".to_string());
+        }
+        self.mbc = mbc;
+        return Ok(());
+    }
+
+    fn read_rom_bank(&self, bank: usize, address: u16) -> u8 {
+        let bank = bank % self.rom_bank_count;
+        let index = bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        return self.rom.get(index).copied().unwrap_or(0xff);
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        return self.read_rom_bank(self.rom_bank(address), address);
+    }
+
+    // the bank mapped at an address in the ROM area
+    pub(crate) fn rom_bank(&self, address: u16) -> usize {
+        let (low_bank, high_bank) = match self.mbc {
+            MBC::None => (0, 1),
+            MBC::MBC1 {
+                bank1,
+                bank2,
+                advanced_mode,
+                ..
+            } => {
+                let upper = (bank2 as usize) << 5;
+                let low_bank = if advanced_mode { upper } else { 0 };
+                (low_bank, upper | bank1 as usize)
+            }
+            MBC::MBC3 { rom_bank, .. } => (0, rom_bank as usize),
+            MBC::MBC5 { rom_bank, .. } => (0, rom_bank as usize),
+        };
+
+        let bank = if address < ROM_BANK_SIZE as u16 {
+            low_bank
+        } else {
+            high_bank
+        };
+        return bank % self.rom_bank_count;
+    }
+
+    // writes to the ROM area control the memory bank controller
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match &mut self.mbc {
+            MBC::None => return,
+            MBC::MBC1 {
+                ram_enabled,
+                bank1,
+                bank2,
+                advanced_mode,
+            } => match address {
+                0x0000..=0x1fff => *ram_enabled = value & 0xf == 0xa,
+                0x2000..=0x3fff => *bank1 = (value & 0x1f).max(1),
+                0x4000..=0x5fff => *bank2 = value & 0b11,
+                _ => *advanced_mode = value & 1 == 1,
+            },
+            MBC::MBC3 {
+                ram_enabled,
+                rom_bank,
+                ram_bank,
+            } => match address {
+                0x0000..=0x1fff => *ram_enabled = value & 0xf == 0xa,
+                0x2000..=0x3fff => *rom_bank = (value & 0x7f).max(1),
+                0x4000..=0x5fff => *ram_bank = value,
+                // the real time clock is not emulated, so there is nothing to latch
+                _ => return,
+            },
+            MBC::MBC5 {
+                ram_enabled,
+                rom_bank,
+                ram_bank,
+            } => match address {
+                0x0000..=0x1fff => *ram_enabled = value & 0xf == 0xa,
+                0x2000..=0x2fff => *rom_bank = (*rom_bank & 0x100) | value as u16,
+                0x3000..=0x3fff => *rom_bank = (*rom_bank & 0xff) | ((value as u16 & 1) << 8),
+                0x4000..=0x5fff => *ram_bank = value & 0xf,
+                _ => return,
+            },
+        }
+    }
+
+    // index into external RAM, or None if it is disabled or not present
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let (enabled, bank) = match self.mbc {
+            MBC::None => (true, 0),
+            MBC::MBC1 {
+                ram_enabled,
+                bank2,
+                advanced_mode,
+                ..
+            } => (ram_enabled, if advanced_mode { bank2 } else { 0 }),
+            // MBC3 banks 0x08-0x0c select real time clock registers, which are not emulated and so
+            // read as open bus
+            MBC::MBC3 {
+                ram_enabled,
+                ram_bank,
+                ..
+            } => (ram_enabled && ram_bank < 0x08, ram_bank),
+            MBC::MBC5 {
+                ram_enabled,
+                ram_bank,
+                ..
+            } => (ram_enabled, ram_bank),
+        };
+
+        if !enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = bank as usize * RAM_BANK_SIZE + (address - RAM_START) as usize;
+        return Some(offset % self.ram.len());
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        return match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xff,
+        };
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(index) = self.ram_index(address) {
+            self.ram[index] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ROM where every byte holds the number of the bank it is in
+    fn banked_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let bank_count = 2 << rom_size;
+        let mut rom: Vec<u8> = (0..bank_count)
+            .flat_map(|bank| vec![bank as u8; ROM_BANK_SIZE])
+            .collect();
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = rom_size;
+        rom[RAM_SIZE] = ram_size;
+        return rom;
+    }
+
+    #[test]
+    fn test_rejects_rom_without_header() {
+        assert!(Cartridge::new(vec![0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn test_reads_cgb_flag_from_header() {
+        let mut rom = vec![0; 0x8000];
+        assert!(!Cartridge::new(rom.clone()).unwrap().supports_cgb());
+
+        rom[CGB_FLAG] = 0x80;
+        assert!(Cartridge::new(rom.clone()).unwrap().supports_cgb());
+
+        rom[CGB_FLAG] = 0xc0;
+        assert!(Cartridge::new(rom).unwrap().supports_cgb());
+    }
+
+    #[test]
+    fn test_reads_sgb_flag_from_header() {
+        let mut rom = vec![0; 0x8000];
+        rom[SGB_FLAG] = 0x03;
+        assert!(!Cartridge::new(rom.clone()).unwrap().supports_sgb());
+
+        rom[OLD_LICENSEE_CODE] = 0x33;
+        assert!(Cartridge::new(rom).unwrap().supports_sgb());
+    }
+
+    #[test]
+    fn test_mbc1_switches_rom_bank() {
+        let mut cartridge = Cartridge::new(banked_rom(0x01, 0x04, 0x00)).unwrap();
+
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(cartridge.read_rom(0x4000), 5);
+        cartridge.write_rom(0x2000, 0x00); // bank 0 is mapped as bank 1
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn test_mbc1_uses_upper_bank_bits() {
+        let mut cartridge = Cartridge::new(banked_rom(0x01, 0x06, 0x00)).unwrap();
+
+        cartridge.write_rom(0x2000, 0x02);
+        cartridge.write_rom(0x4000, 0x01);
+
+        assert_eq!(cartridge.read_rom(0x4000), 0x22);
+    }
+
+    #[test]
+    fn test_mbc5_switches_rom_bank_zero() {
+        let mut cartridge = Cartridge::new(banked_rom(0x19, 0x04, 0x00)).unwrap();
+
+        cartridge.write_rom(0x2000, 0x00);
+
+        assert_eq!(cartridge.read_rom(0x4000), 0);
+    }
+
+    #[test]
+    fn test_mbc3_real_time_clock_reads_open_bus() {
+        let mut cartridge = Cartridge::new(banked_rom(0x10, 0x01, 0x03)).unwrap();
+        cartridge.write_rom(0x0000, 0x0a);
+        cartridge.write_ram(0xa000, 0x42);
+
+        cartridge.write_rom(0x4000, 0x08); // seconds
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        cartridge.write_ram(0xa000, 0x12);
+        assert_eq!(cartridge.read_ram(0xa000), 0xff);
+
+        cartridge.write_rom(0x4000, 0x00);
+        assert_eq!(cartridge.read_ram(0xa000), 0x42);
+    }
+
+    #[test]
+    fn test_ram_must_be_enabled() {
+        let mut cartridge = Cartridge::new(banked_rom(0x1b, 0x01, 0x03)).unwrap();
+
+        cartridge.write_ram(0xa000, 0x42);
+        assert_eq!(cartridge.read_ram(0xa000), 0xff);
+
+        cartridge.write_rom(0x0000, 0x0a);
+        cartridge.write_rom(0x4000, 0x02);
+        cartridge.write_ram(0xa000, 0x42);
+        assert_eq!(cartridge.read_ram(0xa000), 0x42);
+
+        cartridge.write_rom(0x4000, 0x01);
+        assert_eq!(cartridge.read_ram(0xa000), 0x00);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::cpu::instr::instr::{Instr, Instr::*};
+use crate::cpu::instr::operand::{Op16, Op8};
+use crate::cpu::instr::{try_decode_prefixed, try_decode_unprefixed, PREFIX};
+use crate::cpu::{CPUState, Reg16, Reg8, CPU, MAX_INSTR_CYCLES};
+use crate::memory::Memory;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MAX_BLOCK_LENGTH: usize = 64;
+const BLOCK_AREA_SIZE: u16 = 0x1000; // blocks never cross into another area, which may be banked
+
+// an instruction decoded ahead of time
+#[derive(Clone, Copy)]
+pub struct Step {
+    pc: u16,
+    instr: Instr,
+    prefixed: bool,
+}
+
+// a key is the address a block starts at and the bank mapped there when it was decoded
+type Key = (u16, usize);
+
+// straight line runs of instructions up to a jump, call, return, HALT or STOP, decoded once and then
+// run a whole instruction at a time with the rest of the machine caught up afterwards, wherever
+// nothing could tell that apart from running cycle by cycle, told apart by ROM bank and all
+// forgotten when RAM one was decoded from is written
+pub struct BlockCache {
+    blocks: Vec<Vec<Step>>,
+    starts: BTreeMap<Key, usize>, // index in blocks of the block starting at each key
+    generation: u64,              // of the code in memory the blocks were decoded from
+    next: Option<(usize, usize, usize)>, // bank, block and step expected to run next
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        return BlockCache {
+            blocks: vec![],
+            starts: BTreeMap::new(),
+            generation: 0,
+            next: None,
+        };
+    }
+
+    // runs instructions whole, calling before_each ahead of each, for as long as nothing else in
+    // the machine would do anything and no more than budget machine cycles, then catches the rest
+    // of the machine up, returning the cycles run, 0 when the interpreter has to run the next one
+    pub fn run(
+        &mut self,
+        cpu: &mut CPU,
+        memory: &mut Memory,
+        budget: u128,
+        mut before_each: impl FnMut(&CPU, &Memory),
+    ) -> u128 {
+        if cpu.state != CPUState::Fetch || cpu.halt_bug {
+            return 0;
+        }
+        let quiet = budget.min(memory.quiet_cycles());
+        if quiet < MAX_INSTR_CYCLES {
+            return 0;
+        }
+
+        // nothing can request an interrupt while the rest of the machine is quiet, only the
+        // instructions run can enable them
+        let interrupt_pending = cpu.pending_interrupts(memory) != 0;
+        let mut cycles = 0;
+        while quiet - cycles >= MAX_INSTR_CYCLES {
+            if interrupt_pending && cpu.interrupt_master_enable {
+                break;
+            }
+            let step = match self.step_at(memory, cpu.read_reg16(Reg16::PC)) {
+                Some(step) if accesses_are_plain(cpu, memory, &step) => step,
+                _ => {
+                    self.next = None;
+                    break;
+                }
+            };
+            before_each(cpu, memory);
+            cycles += cpu.run_whole(memory, step.instr, step.prefixed);
+        }
+        memory.skip_cycles(cycles);
+        return cycles;
+    }
+
+    // the decoded instruction at PC, decoding the block starting there if it is new
+    fn step_at(&mut self, memory: &mut Memory, pc: u16) -> Option<Step> {
+        if memory.code_generation() != self.generation {
+            self.generation = memory.code_generation();
+            self.blocks.clear();
+            self.starts.clear();
+            self.next = None;
+        }
+        let bank = memory.code_bank(pc)?;
+
+        // following on from the last instruction saves looking the block up
+        if let Some((next_bank, block, index)) = self.next {
+            if next_bank == bank {
+                if let Some(&step) = self.blocks[block].get(index).filter(|s| s.pc == pc) {
+                    self.next = Some((bank, block, index + 1));
+                    return Some(step);
+                }
+            }
+        }
+
+        let blocks = &mut self.blocks;
+        let block = *self.starts.entry((pc, bank)).or_insert_with(|| {
+            blocks.push(decode_block(memory, pc, bank));
+            return blocks.len() - 1;
+        });
+        let step = *self.blocks[block].first()?;
+        self.next = Some((bank, block, 1));
+        return Some(step);
+    }
+}
+
+fn decode_block(memory: &mut Memory, start: u16, bank: usize) -> Vec<Step> {
+    let mut steps = vec![];
+    let mut pc = start;
+    while steps.len() < MAX_BLOCK_LENGTH {
+        let prefixed = memory.peek(pc) == PREFIX;
+        let opcode_end = if prefixed { pc.wrapping_add(1) } else { pc };
+        let same_area = |address: u16| {
+            return address / BLOCK_AREA_SIZE == start / BLOCK_AREA_SIZE
+                && memory.code_bank(address) == Some(bank);
+        };
+        if !same_area(pc) || !same_area(opcode_end) {
+            break;
+        }
+
+        let instr = if prefixed {
+            try_decode_prefixed(memory.peek(opcode_end))
+        } else {
+            try_decode_unprefixed(memory.peek(pc))
+        };
+        let instr = match instr {
+            // the CPU locks up on illegal opcodes, and waits in HALT and STOP
+            None | Some(HALT) | Some(STOP) => break,
+            Some(instr) => instr,
+        };
+
+        // the interpreter runs instructions whose immediates spill out of the area, which may be
+        // somewhere other than plain memory
+        let length = instr.info().length as u16;
+        if !same_area(pc.wrapping_add(length - 1)) {
+            break;
+        }
+
+        // immediates are read as the instruction runs, so only the opcodes need watching
+        memory.mark_code(pc);
+        memory.mark_code(opcode_end);
+        steps.push(Step {
+            pc,
+            instr,
+            prefixed,
+        });
+        pc = pc.wrapping_add(length);
+        if matches!(instr, JP(..) | JR(..) | CALL(..) | RET(_) | RETI | RST(_)) {
+            break;
+        }
+    }
+    return steps;
+}
+
+// whether every byte the instruction will read or write is plain memory, so that nothing else in
+// the machine can see which of its cycles it happens in
+fn accesses_are_plain(cpu: &CPU, memory: &Memory, step: &Step) -> bool {
+    let pc = step.pc;
+    let immediate8 = || memory.peek(pc.wrapping_add(1));
+    let immediate16 = || {
+        return u16::from_le_bytes([
+            memory.peek(pc.wrapping_add(1)),
+            memory.peek(pc.wrapping_add(2)),
+        ]);
+    };
+    let address8 = |op: Op8| -> Option<u16> {
+        return match op {
+            Op8::Reg(_) | Op8::N => None,
+            Op8::AddrN => Some(0xff00 | immediate8() as u16),
+            Op8::AddrC => Some(0xff00 | cpu.read_reg8(Reg8::C) as u16),
+            Op8::AddrNN => Some(immediate16()),
+            Op8::AddrBC => Some(cpu.read_reg16(Reg16::BC)),
+            Op8::AddrDE => Some(cpu.read_reg16(Reg16::DE)),
+            Op8::AddrHL | Op8::AddrHLInc | Op8::AddrHLDec => Some(cpu.read_reg16(Reg16::HL)),
+        };
+    };
+    let op8 = |op: Op8, write: bool| -> bool {
+        return address8(op).is_none_or(|address| memory.plain(address, write));
+    };
+    let sp = cpu.read_reg16(Reg16::SP);
+    let stack = |write: bool| -> bool {
+        let (low, high) = if write {
+            (sp.wrapping_sub(2), sp.wrapping_sub(1))
+        } else {
+            (sp, sp.wrapping_add(1))
+        };
+        return memory.plain(low, write) && memory.plain(high, write);
+    };
+
+    // the opcodes and immediates are in the plain memory of the block, so only the operands matter
+    return match step.instr {
+        LD(to, from) | LDH(to, from) => op8(to, true) && op8(from, false),
+        LD16(Op16::AddrNN, _) => {
+            let address = immediate16();
+            memory.plain(address, true) && memory.plain(address.wrapping_add(1), true)
+        }
+        ADD(_, op) | ADC(_, op) | SUB(op) | SBC(op) | AND(op) | OR(op) | XOR(op) | CP(op) => {
+            op8(op, false)
+        }
+        BIT(_, op) => op8(op, false),
+        INC(op)
+        | DEC(op)
+        | RLC(op)
+        | RL(op)
+        | RRC(op)
+        | RR(op)
+        | SLA(op)
+        | SRA(op)
+        | SRL(op)
+        | SWAP(op)
+        | SET(_, op)
+        | RES(_, op) => op8(op, true),
+        PUSH(_) | CALL(..) | RST(_) => stack(true),
+        POP(_) | RET(_) | RETI => stack(false),
+        _ => true,
+    };
+}
+
+impl CPU {
+    // the same as cycling through the instruction from its opcode fetch, for an instruction
+    // starting with no interrupt to dispatch, returning the machine cycles it took
+    fn run_whole(&mut self, memory: &mut Memory, instr: Instr, prefixed: bool) -> u128 {
+        let start = self.cycle;
+        if self.interrupt_enable_scheduled {
+            self.interrupt_enable_scheduled = false;
+            self.interrupt_master_enable = true;
+        }
+
+        let pc = self.read_reg16(Reg16::PC);
+        if prefixed {
+            self.write_reg16(Reg16::PC, pc.wrapping_add(2));
+            self.increment_cycle();
+        } else {
+            self.write_reg16(Reg16::PC, pc.wrapping_add(1));
+        }
+        self.begin_execute(memory, instr);
+        self.increment_cycle();
+        while let CPUState::Excute(instr, start_cycle) = self.state {
+            self.execute(memory, instr, start_cycle);
+            self.increment_cycle();
+        }
+        return self.cycle - start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::model::Model;
+    use crate::test_util::rom_with;
+    #[cfg(feature = "std")]
+    use crate::test_util::SharedWriter;
+    #[cfg(feature = "std")]
+    use crate::trace::Tracer;
+
+    // an MBC1 game with code in two switchable banks, in high RAM and rewritten in work RAM, a
+    // timer interrupt handler and a loop polling LY
+    fn gameboy() -> GameBoy {
+        let handler = [
+            0xf5, //             PUSH AF
+            0xfa, 0x00, 0xc1, // LD A, (0xc100)
+            0x3c, //             INC A
+            0xea, 0x00, 0xc1, // LD (0xc100), A
+            0xf1, //             POP AF
+            0xd9, //             RETI
+        ];
+
+        let program = [
+            0xf3, //             DI
+            0x31, 0xfe, 0xff, // LD SP, 0xfffe
+            0x21, 0x80, 0xff, // LD HL, 0xff80
+            0x11, 0x00, 0x02, // LD DE, 0x0200
+            0x06, 0x08, //       LD B, 8
+            0x1a, //             copy: LD A, (DE)
+            0x22, //             LD (HL+), A
+            0x13, //             INC DE
+            0x05, //             DEC B
+            0x20, 0xfa, //       JR NZ, copy
+            0x3e, 0x05, //       LD A, 0x05
+            0xe0, 0x07, //       LDH (TAC), A
+            0x3e, 0x04, //       LD A, 0x04
+            0xe0, 0xff, //       LDH (IE), A
+            0xfb, //             EI
+            0x3e, 0x02, //       main: LD A, 2
+            0xea, 0x00, 0x20, // LD (0x2000), A
+            0xcd, 0x00, 0x40, // CALL 0x4000
+            0x3e, 0x03, //       LD A, 3
+            0xea, 0x00, 0x20, // LD (0x2000), A
+            0xcd, 0x00, 0x40, // CALL 0x4000
+            0xcd, 0x80, 0xff, // CALL 0xff80
+            0x7b, //             LD A, E
+            0xe6, 0x01, //       AND 1
+            0xc6, 0x1c, //       ADD A, 0x1c
+            0xea, 0x00, 0xd0, // LD (0xd000), A, which is INC E or DEC E
+            0x3e, 0xc9, //       LD A, 0xc9
+            0xea, 0x01, 0xd0, // LD (0xd001), A, which is RET
+            0xcd, 0x00, 0xd0, // CALL 0xd000
+            0xf0, 0x44, //       LDH A, (LY)
+            0x57, //             LD D, A
+            0xc3, 0x6b, 0x01, // JP main
+        ];
+
+        let high_ram_routine = [
+            0xfa, 0x00, 0xc0, // LD A, (0xc000)
+            0x81, //             ADD A, C
+            0xea, 0x02, 0xc0, // LD (0xc002), A
+            0xc9, //             RET
+        ];
+
+        let bank_2 = [
+            0x0c, //             INC C
+            0xcb, 0x31, //       SWAP C
+            0x21, 0x00, 0xc0, // LD HL, 0xc000
+            0x71, //             LD (HL), C
+            0xc9, //             RET
+        ];
+        let bank_3 = [
+            0x0d, //             DEC C
+            0xcb, 0x11, //       RL C
+            0x21, 0x01, 0xc0, // LD HL, 0xc001
+            0x71, //             LD (HL), C
+            0xc9, //             RET
+        ];
+
+        return rom_with(
+            Model::DMG,
+            &[
+                (0x50, &handler),
+                (0x100, &[0xc3, 0x50, 0x01]), // JP 0x0150
+                (0x147, &[0x01, 0x01]),       // MBC1 with 4 banks
+                (0x150, &program),
+                (0x200, &high_ram_routine),
+                (0x8000, &bank_2),
+                (0xc000, &bank_3),
+            ],
+        );
+    }
+
+    // runs the next instruction through the cache if it can, returning the cycles it took
+    fn run(cache: &mut BlockCache, gb: &mut GameBoy) -> Option<u128> {
+        let cycles = cache.run(&mut gb.cpu, &mut gb.memory, MAX_INSTR_CYCLES, |_, _| {});
+        return (cycles > 0).then_some(cycles);
+    }
+
+    // steps the interpreter up to the address, leaving an instruction about to start there
+    fn run_to(gb: &mut GameBoy, pc: u16) {
+        while gb.cpu.read_reg16(Reg16::PC) != pc || gb.cpu.state != CPUState::Fetch {
+            gb.step().unwrap();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_traces_match_the_interpreter() {
+        let interpreted = SharedWriter::new();
+        let mut gb = gameboy();
+        gb.trace(Tracer::to_writer(Box::new(interpreted.clone())));
+        let cached = SharedWriter::new();
+        let mut blocks_gb = gameboy();
+        blocks_gb.trace(Tracer::to_writer(Box::new(cached.clone())));
+        blocks_gb.use_blocks(true);
+
+        for _ in 0..20 {
+            gb.run_frame().unwrap();
+            blocks_gb.run_frame().unwrap();
+            assert!(blocks_gb.save_state() == gb.save_state());
+        }
+
+        let interpreted = interpreted.text();
+        assert!(interpreted.len() > 100000);
+        assert!(cached.text() == interpreted);
+        assert!(blocks_gb.memory.peek(0xc100) > 0); // interrupts were taken
+    }
+
+    #[test]
+    fn test_runs_straight_line_code_whole() {
+        let mut gb = gameboy();
+        let mut cache = BlockCache::new();
+        run_to(&mut gb, 0x150);
+
+        assert_eq!(run(&mut cache, &mut gb), Some(1)); // DI
+        assert_eq!(run(&mut cache, &mut gb), Some(3)); // LD SP, 0xfffe
+        assert_eq!(gb.cpu.read_reg16(Reg16::SP), 0xfffe);
+        assert_eq!(gb.cpu.read_reg16(Reg16::PC), 0x154);
+    }
+
+    #[test]
+    fn test_leaves_io_to_the_interpreter() {
+        let mut gb = gameboy();
+        let mut cache = BlockCache::new();
+        run_to(&mut gb, 0x164); // LDH (TAC), A
+
+        assert_eq!(run(&mut cache, &mut gb), None);
+        gb.step().unwrap();
+        assert_eq!(run(&mut cache, &mut gb), Some(2)); // LD A, 0x04
+    }
+
+    #[test]
+    fn test_tells_rom_banks_apart() {
+        let mut gb = gameboy();
+        let mut cache = BlockCache::new();
+        run_to(&mut gb, 0x4000);
+        gb.cpu.write_reg8(Reg8::C, 0x10);
+
+        run(&mut cache, &mut gb); // INC C in bank 2
+        gb.memory.write(0x2000, 3);
+        gb.cpu.write_reg16(Reg16::PC, 0x4000);
+        run(&mut cache, &mut gb); // DEC C in bank 3
+
+        assert_eq!(gb.cpu.read_reg8(Reg8::C), 0x10);
+        assert_eq!(cache.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_forgets_blocks_when_their_ram_is_written() {
+        let mut gb = gameboy();
+        let mut cache = BlockCache::new();
+        run_to(&mut gb, 0x16b);
+        gb.memory.write(0xd000, 0x1c); // INC E
+        gb.cpu.write_reg16(Reg16::PC, 0xd000);
+        gb.cpu.write_reg8(Reg8::E, 0x10);
+        run(&mut cache, &mut gb);
+
+        gb.memory.write(0xd000, 0x1d); // DEC E
+        gb.cpu.write_reg16(Reg16::PC, 0xd000);
+        run(&mut cache, &mut gb);
+
+        assert_eq!(gb.cpu.read_reg8(Reg8::E), 0x10);
+    }
+}
@@ -2,7 +2,7 @@ use crate::cpu::instr::operand::{operand16_from_index, operand8_from_index, Op16
 use crate::cpu::instr::{Instr, Instr::*};
 use crate::cpu::{Reg16::*, Reg8::*};
 
-pub(crate) fn decode_unprefixed(opcode: u8) -> Option<Instr> {
+pub(crate) const fn decode_unprefixed(opcode: u8) -> Option<Instr> {
     let op8_to = operand8_from_index((opcode >> 3) & 0b111);
     let op8_from = operand8_from_index(opcode & 0b111);
     let op16 = operand16_from_index((opcode >> 4) & 0b11);
@@ -11,15 +11,16 @@ pub(crate) fn decode_unprefixed(opcode: u8) -> Option<Instr> {
         0xc6 => Some(ADD(Op8::Reg(A), Op8::N)),
         0xce => Some(ADC(Op8::Reg(A), Op8::N)),
         0xd6 => Some(SUB(Op8::N)),
+        0xde => Some(SBC(Op8::N)),
         0xe6 => Some(AND(Op8::N)),
         0xf6 => Some(OR(Op8::N)),
         0xee => Some(XOR(Op8::N)),
         0xfe => Some(CP(Op8::N)),
         0xe8 => Some(ADD16(Op16::Reg(SP), Op16::N)),
-        0x07 => Some(RLC(Op8::Reg(A))),
-        0x17 => Some(RL(Op8::Reg(A))),
-        0x0f => Some(RRC(Op8::Reg(A))),
-        0x1f => Some(RR(Op8::Reg(A))),
+        0x07 => Some(RLCA),
+        0x17 => Some(RLA),
+        0x0f => Some(RRCA),
+        0x1f => Some(RRA),
         o if o & 0b11111000 == 0x80 => Some(ADD(Op8::Reg(A), op8_from)),
         o if o & 0b11111000 == 0x88 => Some(ADC(Op8::Reg(A), op8_from)),
         o if o & 0b11111000 == 0x90 => Some(SUB(op8_from)),
@@ -37,7 +38,7 @@ pub(crate) fn decode_unprefixed(opcode: u8) -> Option<Instr> {
     };
 }
 
-pub(crate) fn decode_prefixed(opcode: u8) -> Option<Instr> {
+pub(crate) const fn decode_prefixed(opcode: u8) -> Option<Instr> {
     let bit_index = (opcode >> 3) & 0b111;
     let op8 = operand8_from_index(opcode & 0b111);
 
@@ -48,6 +49,7 @@ pub(crate) fn decode_prefixed(opcode: u8) -> Option<Instr> {
         o if o & 0b11111000 == 0x18 => Some(RR(op8)),
         o if o & 0b11111000 == 0x20 => Some(SLA(op8)),
         o if o & 0b11111000 == 0x28 => Some(SRA(op8)),
+        o if o & 0b11111000 == 0x30 => Some(SWAP(op8)),
         o if o & 0b11111000 == 0x38 => Some(SRL(op8)),
         o if o & 0b11000000 == 0x40 => Some(BIT(bit_index, op8)),
         o if o & 0b11000000 == 0xc0 => Some(SET(bit_index, op8)),
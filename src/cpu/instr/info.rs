@@ -0,0 +1,381 @@
+use crate::cpu::instr::instr::{Instr, Instr::*};
+use crate::cpu::instr::operand::{Cond, Op16, Op8};
+use crate::cpu::instr::{try_decode_prefixed, try_decode_unprefixed};
+use crate::cpu::Reg16;
+use core::fmt;
+
+// how an instruction leaves one of the flags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlagEffect {
+    Unchanged,
+    Changed, // depends on the result
+    Reset,
+    Set,
+}
+
+// static properties of an instruction, as listed in opcode tables
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstrInfo {
+    pub length: u8,                // bytes including the prefix and immediates
+    pub cycles: u8,                // machine cycles, when a conditional branch is not taken
+    pub branch_cycles: Option<u8>, // machine cycles when a conditional branch is taken
+    pub flags: [FlagEffect; 4],    // Z, N, H and C
+}
+
+#[allow(dead_code)] // for tools working from opcodes rather than decoded instructions
+impl InstrInfo {
+    pub fn unprefixed(opcode: u8) -> Option<InstrInfo> {
+        return try_decode_unprefixed(opcode).map(|instr| instr.info());
+    }
+
+    pub fn prefixed(opcode: u8) -> Option<InstrInfo> {
+        return try_decode_prefixed(opcode).map(|instr| instr.info());
+    }
+}
+
+// the flags in the usual opcode table notation, like Z0H- for INC
+impl fmt::Display for InstrInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (effect, name) in self.flags.iter().zip(["Z", "N", "H", "C"].iter()) {
+            let symbol = match effect {
+                FlagEffect::Unchanged => "-",
+                FlagEffect::Changed => name,
+                FlagEffect::Reset => "0",
+                FlagEffect::Set => "1",
+            };
+            write!(f, "{}", symbol)?;
+        }
+        return Ok(());
+    }
+}
+
+// bytes read from after the opcode
+fn immediates8(op: Op8) -> u8 {
+    return match op {
+        Op8::N | Op8::AddrN => 1,
+        Op8::AddrNN => 2,
+        _ => 0,
+    };
+}
+
+fn immediates16(op: Op16) -> u8 {
+    return match op {
+        Op16::N | Op16::AddrN => 1,
+        Op16::NN | Op16::AddrNN => 2,
+        _ => 0,
+    };
+}
+
+// machine cycles spent accessing memory other than through immediates
+fn accesses8(op: Op8) -> u8 {
+    return match op {
+        Op8::Reg(_) | Op8::N => 0,
+        _ => 1,
+    };
+}
+
+impl Instr {
+    pub fn info(&self) -> InstrInfo {
+        use FlagEffect::{Changed as C, Reset as R, Set as S, Unchanged as U};
+
+        // opcode fetch, immediates and memory operands each take a cycle
+        let op8 = |op: Op8| -> u8 { immediates8(op) + accesses8(op) };
+        let conditional = |cond: Cond, cycles: u8, taken: u8| -> (u8, Option<u8>) {
+            return match cond {
+                Cond::Always => (taken, None),
+                _ => (cycles, Some(taken)),
+            };
+        };
+
+        let immediates = match *self {
+            LD(a, b) | LDH(a, b) | ADD(a, b) | ADC(a, b) => immediates8(a) + immediates8(b),
+            LD16(a, b) | ADD16(a, b) => immediates16(a) + immediates16(b),
+            SUB(a) | SBC(a) | AND(a) | OR(a) | XOR(a) | CP(a) => immediates8(a),
+            JR(_, a) => immediates8(a),
+            JP(_, a) | CALL(_, a) => immediates16(a),
+            LDHL | STOP => 1,
+            _ => 0,
+        };
+        let prefixed = matches!(
+            *self,
+            RLC(_)
+                | RL(_)
+                | RRC(_)
+                | RR(_)
+                | SLA(_)
+                | SRA(_)
+                | SRL(_)
+                | SWAP(_)
+                | BIT(_, _)
+                | SET(_, _)
+                | RES(_, _)
+        );
+
+        let (cycles, branch_cycles) = match *self {
+            LD(a, b) | LDH(a, b) => (1 + op8(a) + op8(b), None),
+            // writes SP a byte at a time
+            LD16(Op16::AddrNN, _) => (5, None),
+            // LD SP, HL spends a cycle moving the value
+            LD16(_, Op16::Reg(_)) => (2, None),
+            LD16(_, _) => (3, None),
+            LDHL => (3, None),
+            PUSH(_) => (4, None),
+            POP(_) => (3, None),
+            ADD(_, b) | ADC(_, b) => (1 + op8(b), None),
+            SUB(a) | SBC(a) | AND(a) | OR(a) | XOR(a) | CP(a) => (1 + op8(a), None),
+            ADD16(Op16::Reg(Reg16::SP), _) => (4, None),
+            ADD16(_, _) | INC16(_) | DEC16(_) => (2, None),
+            // read and write back
+            INC(a) | DEC(a) => (1 + 2 * accesses8(a), None),
+            BIT(_, a) => (2 + accesses8(a), None),
+            RLC(a)
+            | RL(a)
+            | RRC(a)
+            | RR(a)
+            | SLA(a)
+            | SRA(a)
+            | SRL(a)
+            | SWAP(a)
+            | SET(_, a)
+            | RES(_, a) => (2 + 2 * accesses8(a), None),
+            JP(_, Op16::Reg(_)) => (1, None),
+            JP(cond, _) => conditional(cond, 3, 4),
+            JR(cond, _) => conditional(cond, 2, 3),
+            CALL(cond, _) => conditional(cond, 3, 6),
+            RET(Cond::Always) | RETI | RST(_) => (4, None),
+            RET(cond) => conditional(cond, 2, 5),
+            _ => (1, None),
+        };
+
+        let flags = match *self {
+            ADD(_, _) | ADC(_, _) => [C, R, C, C],
+            SUB(_) | SBC(_) | CP(_) => [C, S, C, C],
+            AND(_) => [C, R, S, R],
+            OR(_) | XOR(_) | SWAP(_) => [C, R, R, R],
+            INC(_) => [C, R, C, U],
+            DEC(_) => [C, S, C, U],
+            ADD16(Op16::Reg(Reg16::SP), _) | LDHL => [R, R, C, C],
+            ADD16(_, _) => [U, R, C, C],
+            RLCA | RLA | RRCA | RRA => [R, R, R, C],
+            RLC(_) | RL(_) | RRC(_) | RR(_) | SLA(_) | SRA(_) | SRL(_) => [C, R, R, C],
+            BIT(_, _) => [C, R, S, U],
+            DAA => [C, U, R, C],
+            CPL => [U, S, S, U],
+            SCF => [U, R, R, S],
+            CCF => [U, R, R, C],
+            POP(Op16::Reg(Reg16::AF)) => [C, C, C, C],
+            _ => [U, U, U, U],
+        };
+
+        return InstrInfo {
+            length: 1 + prefixed as u8 + immediates,
+            cycles,
+            branch_cycles,
+            flags,
+        };
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::cpu::{CPUState, Reg16::*, Reg8::*, CPU};
+    use crate::memory::Memory;
+
+    // lengths, cycles and flags from the usual opcode tables, 0 and empty for illegal opcodes and
+    // the prefix
+    #[rustfmt::skip]
+    const LENGTHS: [u8; 0x100] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 0, 3, 3, 2, 1,
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+    ];
+
+    // when a conditional branch is not taken
+    #[rustfmt::skip]
+    const CYCLES: [u8; 0x100] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    const BRANCH_CYCLES: [(u8, u8); 16] = [
+        (0x20, 3),
+        (0x28, 3),
+        (0x30, 3),
+        (0x38, 3), // JR cc
+        (0xc2, 4),
+        (0xca, 4),
+        (0xd2, 4),
+        (0xda, 4), // JP cc
+        (0xc4, 6),
+        (0xcc, 6),
+        (0xd4, 6),
+        (0xdc, 6), // CALL cc
+        (0xc0, 5),
+        (0xc8, 5),
+        (0xd0, 5),
+        (0xd8, 5), // RET cc
+    ];
+
+    fn reference_flags() -> Vec<&'static str> {
+        let mut flags = vec![];
+        let inc_dec = ["Z0H-", "Z1H-"];
+        for row in 0..4 {
+            flags.extend_from_slice(&["----"; 4]);
+            flags.extend_from_slice(&inc_dec);
+            flags.push("----");
+            flags.push(["000C", "000C", "Z-0C", "-001"][row]);
+            flags.extend_from_slice(&["----", "-0HC", "----", "----"]);
+            flags.extend_from_slice(&inc_dec);
+            flags.push("----");
+            flags.push(["000C", "000C", "-11-", "-00C"][row]);
+        }
+        flags.extend_from_slice(&["----"; 0x40]);
+        flags.extend_from_slice(&["Z0HC"; 0x10]);
+        flags.extend_from_slice(&["Z1HC"; 0x10]);
+        flags.extend_from_slice(&["Z010"; 0x08]);
+        flags.extend_from_slice(&["Z000"; 0x10]);
+        flags.extend_from_slice(&["Z1HC"; 0x08]);
+        #[rustfmt::skip]
+        flags.extend_from_slice(&[
+            "----", "----", "----", "----", "----", "----", "Z0HC", "----",
+            "----", "----", "----", "",     "----", "----", "Z0HC", "----",
+            "----", "----", "----", "",     "----", "----", "Z1HC", "----",
+            "----", "----", "----", "",     "----", "",     "Z1HC", "----",
+            "----", "----", "----", "",     "",     "----", "Z010", "----",
+            "00HC", "----", "----", "",     "",     "",     "Z000", "----",
+            "----", "ZNHC", "----", "----", "",     "----", "Z000", "----",
+            "00HC", "----", "----", "----", "",     "",     "Z1HC", "----",
+        ]);
+        return flags;
+    }
+
+    #[test]
+    fn match_reference_opcode_table() {
+        let flags = reference_flags();
+        for opcode in 0..=0xff {
+            let info = InstrInfo::unprefixed(opcode);
+            let branch_cycles = BRANCH_CYCLES
+                .iter()
+                .find(|&&(branch, _)| branch == opcode)
+                .map(|&(_, cycles)| cycles);
+
+            let message = format!("opcode {:#04x}", opcode);
+            assert_eq!(
+                info.map_or(0, |i| i.length),
+                LENGTHS[opcode as usize],
+                "{}",
+                message
+            );
+            assert_eq!(
+                info.map_or(0, |i| i.cycles),
+                CYCLES[opcode as usize],
+                "{}",
+                message
+            );
+            assert_eq!(
+                info.and_then(|i| i.branch_cycles),
+                branch_cycles,
+                "{}",
+                message
+            );
+            let text = info.map_or(String::new(), |i| i.to_string());
+            assert_eq!(text, flags[opcode as usize], "{}", message);
+        }
+    }
+
+    #[test]
+    fn match_reference_prefixed_opcode_table() {
+        for opcode in 0..=0xff {
+            let info = InstrInfo::prefixed(opcode).unwrap();
+            let memory_operand = opcode & 0b111 == 6;
+            let (cycles, flags) = match opcode {
+                0x30..=0x37 => (if memory_operand { 4 } else { 2 }, "Z000"), // SWAP
+                0x00..=0x3f => (if memory_operand { 4 } else { 2 }, "Z00C"),
+                0x40..=0x7f => (if memory_operand { 3 } else { 2 }, "Z01-"), // BIT
+                _ => (if memory_operand { 4 } else { 2 }, "----"),
+            };
+
+            let message = format!("prefixed opcode {:#04x}", opcode);
+            assert_eq!(info.length, 2, "{}", message);
+            assert_eq!(info.cycles, cycles, "{}", message);
+            assert_eq!(info.branch_cycles, None, "{}", message);
+            assert_eq!(info.to_string(), flags, "{}", message);
+        }
+    }
+
+    // machine cycles the CPU takes to run the instruction at the start of memory
+    fn measure(program: &[u8], flags: u8) -> u8 {
+        let mut memory = Memory::with_program(program);
+        let mut cpu = CPU::new();
+        cpu.write_reg16(SP, 0xdffe);
+        cpu.write_reg16(HL, 0xc000);
+        cpu.write_reg8(A, 0x99); // valid BCD, for DAA
+        cpu.write_reg8(F, flags);
+        let mut cycles = 0;
+        loop {
+            cpu.cycle(&mut memory).unwrap();
+            cycles += 1;
+            if cpu.state == CPUState::Fetch {
+                return cycles;
+            }
+        }
+    }
+
+    #[test]
+    fn match_the_cycles_the_cpu_takes() {
+        for opcode in 0..=0xff {
+            let info = match InstrInfo::unprefixed(opcode) {
+                Some(info) => info,
+                None => continue,
+            };
+            // both never finish without an interrupt or button press
+            if opcode == 0x76 || opcode == 0x10 {
+                continue;
+            }
+
+            // all conditions are either true with no flags set, or with all of them set
+            let program = [opcode, 0x00, 0xc0];
+            let mut cycles = vec![measure(&program, 0x00), measure(&program, 0xf0)];
+            cycles.sort();
+            let expected = match info.branch_cycles {
+                Some(taken) => vec![info.cycles, taken],
+                None => vec![info.cycles, info.cycles],
+            };
+            assert_eq!(cycles, expected, "opcode {:#04x}", opcode);
+        }
+
+        for opcode in 0..=0xff {
+            let info = InstrInfo::prefixed(opcode).unwrap();
+            let cycles = measure(&[0xcb, opcode], 0x00);
+            assert_eq!(cycles, info.cycles, "prefixed opcode {:#04x}", opcode);
+        }
+    }
+}
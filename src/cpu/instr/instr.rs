@@ -1,4 +1,7 @@
-use crate::cpu::instr::operand::{Op16, Op8};
+use crate::cpu::instr::operand::{placeholder, Cond, Immediate, Op16, Op8};
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instr {
@@ -14,6 +17,7 @@ pub enum Instr {
     LD(Op8, Op8),      // load instruction
     LD16(Op16, Op16),  // 16 bit load instruction
     LDH(Op8, Op8),     // half* load instruction (*half of 16 bit reg)
+    LDHL,              // load SP plus signed following byte into HL
     PUSH(Op16),        // bit push instruction
     POP(Op16),         // 16 bit pop instruction
     ADD(Op8, Op8),     // add instruction
@@ -29,6 +33,10 @@ pub enum Instr {
     INC16(Op16),       // 16 bit increment instruction
     DEC(Op8),          // decrement instruction
     DEC16(Op16),       // 16 bit decrement instruction
+    RLCA,              // rotate register A left with carry (Z flag always reset)
+    RLA,               // rotate register A left (Z flag always reset)
+    RRCA,              // rotate register A right with carry (Z flag always reset)
+    RRA,               // rotate register A right (Z flag always reset)
     RLC(Op8),          // rotate left with carry
     RL(Op8),           // rotate left
     RRC(Op8),          // rotate right with carry
@@ -36,7 +44,96 @@ pub enum Instr {
     SLA(Op8),          // shift left into carry (LSB = 0)
     SRA(Op8),          // shift right into carry (MSB constant)
     SRL(Op8),          // shift left into carry (MSB = 0)
+    SWAP(Op8),         // swap upper and lower nibbles
     BIT(u8, Op8),      // test bit in register
     SET(u8, Op8),      // set bit in register
     RES(u8, Op8),      // reset bit in register
+    JP(Cond, Op16),    // jump to address
+    JR(Cond, Op8),     // jump relative to PC by signed offset
+    CALL(Cond, Op16),  // push PC and jump to address
+    RET(Cond),         // pop PC
+    RETI,              // pop PC and enable interrupts
+    RST(u8),           // push PC and jump to fixed address
+}
+
+impl Instr {
+    // RGBDS syntax, with immediate operands written by the given function
+    pub fn format(&self, immediate: &dyn Fn(Immediate) -> String) -> String {
+        use Instr::*;
+
+        let op8 = |op: Op8| op.format(immediate);
+        let op16 = |op: Op16| op.format(immediate);
+        let conditional = |name: &str, cond: Cond, operand: String| -> String {
+            return match cond {
+                Cond::Always => format!("{} {}", name, operand),
+                cond => format!("{} {}, {}", name, cond, operand),
+            };
+        };
+
+        return match *self {
+            NOP => "nop".to_string(),
+            DAA => "daa".to_string(),
+            CPL => "cpl".to_string(),
+            CCF => "ccf".to_string(),
+            SCF => "scf".to_string(),
+            HALT => "halt".to_string(),
+            STOP => "stop".to_string(),
+            DI => "di".to_string(),
+            EI => "ei".to_string(),
+            LD(a, b) => format!("ld {}, {}", op8(a), op8(b)),
+            LD16(a, b) => format!("ld {}, {}", op16(a), op16(b)),
+            LDH(a, b) => format!("ldh {}, {}", op8(a), op8(b)),
+            LDHL => {
+                let offset = immediate(Immediate::Offset);
+                match offset.strip_prefix('-') {
+                    Some(magnitude) => format!("ld hl, sp-{}", magnitude),
+                    None => format!("ld hl, sp+{}", offset),
+                }
+            }
+            PUSH(a) => format!("push {}", op16(a)),
+            POP(a) => format!("pop {}", op16(a)),
+            ADD(a, b) => format!("add {}, {}", op8(a), op8(b)),
+            ADD16(a, b) => format!("add {}, {}", op16(a), op16(b)),
+            ADC(a, b) => format!("adc {}, {}", op8(a), op8(b)),
+            SUB(a) => format!("sub a, {}", op8(a)),
+            SBC(a) => format!("sbc a, {}", op8(a)),
+            AND(a) => format!("and a, {}", op8(a)),
+            OR(a) => format!("or a, {}", op8(a)),
+            XOR(a) => format!("xor a, {}", op8(a)),
+            CP(a) => format!("cp a, {}", op8(a)),
+            INC(a) => format!("inc {}", op8(a)),
+            INC16(a) => format!("inc {}", op16(a)),
+            DEC(a) => format!("dec {}", op8(a)),
+            DEC16(a) => format!("dec {}", op16(a)),
+            RLCA => "rlca".to_string(),
+            RLA => "rla".to_string(),
+            RRCA => "rrca".to_string(),
+            RRA => "rra".to_string(),
+            RLC(a) => format!("rlc {}", op8(a)),
+            RL(a) => format!("rl {}", op8(a)),
+            RRC(a) => format!("rrc {}", op8(a)),
+            RR(a) => format!("rr {}", op8(a)),
+            SLA(a) => format!("sla {}", op8(a)),
+            SRA(a) => format!("sra {}", op8(a)),
+            SRL(a) => format!("srl {}", op8(a)),
+            SWAP(a) => format!("swap {}", op8(a)),
+            BIT(bit, a) => format!("bit {}, {}", bit, op8(a)),
+            SET(bit, a) => format!("set {}, {}", bit, op8(a)),
+            RES(bit, a) => format!("res {}, {}", bit, op8(a)),
+            JP(cond, Op16::NN) => conditional("jp", cond, immediate(Immediate::Address)),
+            JP(cond, a) => conditional("jp", cond, op16(a)),
+            JR(cond, _) => conditional("jr", cond, immediate(Immediate::Target)),
+            CALL(cond, _) => conditional("call", cond, immediate(Immediate::Address)),
+            RET(Cond::Always) => "ret".to_string(),
+            RET(cond) => format!("ret {}", cond),
+            RETI => "reti".to_string(),
+            RST(address) => format!("rst ${:02x}", address),
+        };
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.format(&placeholder));
+    }
 }
@@ -0,0 +1,22 @@
+use crate::cpu::instr::operand::{cond_from_index, Cond, Op16, Op8};
+use crate::cpu::instr::{Instr, Instr::*};
+use crate::cpu::Reg16::*;
+
+pub(crate) const fn decode_unprefixed(opcode: u8) -> Option<Instr> {
+    let cond = cond_from_index((opcode >> 3) & 0b11);
+
+    return match opcode {
+        0xc3 => Some(JP(Cond::Always, Op16::NN)),
+        0xe9 => Some(JP(Cond::Always, Op16::Reg(HL))),
+        0x18 => Some(JR(Cond::Always, Op8::N)),
+        0xcd => Some(CALL(Cond::Always, Op16::NN)),
+        0xc9 => Some(RET(Cond::Always)),
+        0xd9 => Some(RETI),
+        o if o & 0b11100111 == 0xc2 => Some(JP(cond, Op16::NN)),
+        o if o & 0b11100111 == 0x20 => Some(JR(cond, Op8::N)),
+        o if o & 0b11100111 == 0xc4 => Some(CALL(cond, Op16::NN)),
+        o if o & 0b11100111 == 0xc0 => Some(RET(cond)),
+        o if o & 0b11000111 == 0xc7 => Some(RST(o & 0b00111000)),
+        _ => None,
+    };
+}
@@ -1,5 +1,7 @@
 mod alu;
+pub mod info;
 pub mod instr;
+mod jump;
 mod load;
 mod misc;
 pub mod operand;
@@ -9,33 +11,132 @@ use instr::Instr;
 #[allow(dead_code)]
 pub const PREFIX: u8 = 0xcb;
 
-#[allow(dead_code)]
+// every opcode decoded at compile time, as matching the patterns on every fetch is slow
+static UNPREFIXED: [Option<Instr>; 0x100] = decode_table(false);
+static PREFIXED: [Option<Instr>; 0x100] = decode_table(true);
+
+pub fn try_decode_unprefixed(opcode: u8) -> Option<Instr> {
+    return UNPREFIXED[opcode as usize];
+}
+
+pub fn try_decode_prefixed(opcode: u8) -> Option<Instr> {
+    return PREFIXED[opcode as usize];
+}
+
+const fn decode_table(prefixed: bool) -> [Option<Instr>; 0x100] {
+    let mut table = [None; 0x100];
+    let mut opcode = 0;
+    while opcode < table.len() {
+        table[opcode] = if prefixed {
+            alu::decode_prefixed(opcode as u8)
+        } else {
+            match_unprefixed(opcode as u8)
+        };
+        opcode += 1;
+    }
+    return table;
+}
+
+const fn match_unprefixed(opcode: u8) -> Option<Instr> {
+    if let Some(instr) = misc::decode_unprefixed(opcode) {
+        return Some(instr);
+    }
+    if let Some(instr) = load::decode_unprefixed(opcode) {
+        return Some(instr);
+    }
+    if let Some(instr) = alu::decode_unprefixed(opcode) {
+        return Some(instr);
+    }
+    return jump::decode_unprefixed(opcode);
+}
+
+// for tests, where an illegal opcode is a mistake in the test; the CPU locks up on them instead
+#[cfg(test)]
 pub fn decode_unprefixed(opcode: u8) -> Instr {
-    let maybe_instr = misc::decode_unprefixed(opcode)
-        .or_else(|| load::decode_unprefixed(opcode))
-        .or_else(|| alu::decode_unprefixed(opcode));
-    match maybe_instr {
+    match try_decode_unprefixed(opcode) {
         Some(i) => return i,
         None => panic!("Illegal opcode {:#02x}", opcode),
     }
 }
 
-#[allow(dead_code)]
+#[cfg(test)]
 pub fn decode_prefixed(opcode: u8) -> Instr {
-    let maybe_instr = alu::decode_prefixed(opcode);
-    match maybe_instr {
+    match try_decode_prefixed(opcode) {
         Some(i) => return i,
         None => panic!("Illegal prefixed opcode {:#02x}", opcode),
     }
 }
 
+// the opcode an instruction is decoded from, and whether it follows the prefix
+pub fn encode(instr: Instr) -> (bool, u8) {
+    for opcode in 0..=0xff {
+        if try_decode_unprefixed(opcode) == Some(instr) {
+            return (false, opcode);
+        }
+        if try_decode_prefixed(opcode) == Some(instr) {
+            return (true, opcode);
+        }
+    }
+    unreachable!("{:?} has no opcode", instr);
+}
+
 #[cfg(test)]
 mod should {
     use super::*;
-    use crate::cpu::instr::operand::{Op16, Op8};
+    use crate::cpu::instr::operand::{Cond, Op16, Op8};
     use crate::cpu::instr::Instr::*;
     use crate::cpu::{Reg16::*, Reg8::*};
 
+    #[test]
+    fn encode_every_instruction_back_to_its_opcode() {
+        for opcode in 0..=0xff {
+            if let Some(instr) = try_decode_unprefixed(opcode) {
+                assert_eq!(encode(instr), (false, opcode));
+            }
+            assert_eq!(encode(decode_prefixed(opcode)), (true, opcode));
+        }
+    }
+
+    #[test]
+    fn decode_the_same_from_the_tables_as_from_the_patterns() {
+        for opcode in 0..=0xff {
+            assert_eq!(try_decode_unprefixed(opcode), match_unprefixed(opcode));
+            assert_eq!(try_decode_prefixed(opcode), alu::decode_prefixed(opcode));
+        }
+    }
+
+    // compares decoding from the tables with matching the patterns on every fetch as the CPU used
+    // to, which is only worth the tables while it is faster:
+    // cargo test --release decode_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn decode_throughput() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        const ROUNDS: usize = 100_000;
+        let time = |decode: &dyn Fn(u8) -> Option<Instr>| {
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                for opcode in 0..=0xff {
+                    black_box(decode(black_box(opcode)));
+                }
+            }
+            return start.elapsed();
+        };
+
+        let patterns = time(&match_unprefixed);
+        let tables = time(&try_decode_unprefixed);
+        let per_second = |elapsed: std::time::Duration| {
+            return (ROUNDS * 0x100) as f64 / elapsed.as_secs_f64() / 1e6;
+        };
+        println!(
+            "patterns: {:.0}M decodes/s, tables: {:.0}M decodes/s",
+            per_second(patterns),
+            per_second(tables)
+        );
+    }
+
     #[test]
     fn decode_nop() {
         assert_eq!(decode_unprefixed(0x00), NOP);
@@ -137,13 +238,24 @@ mod should {
         // LD SP, HL
     }
 
+    #[test]
+    fn decode_loads_to_hl_from_sp_plus_n() {
+        assert_eq!(decode_unprefixed(0xf8), LDHL); // LDHL SP, n
+    }
+
+    #[test]
+    fn decode_loads_between_register_and_register_l() {
+        assert_eq!(decode_unprefixed(0x45), LD(Op8::Reg(B), Op8::Reg(L))); // LD B, L
+        assert_eq!(decode_unprefixed(0x7d), LD(Op8::Reg(A), Op8::Reg(L))); // LD A, L
+    }
+
     #[test]
     fn decode_pushing_16_bit_register() {
         // PUSH rr
         assert_eq!(decode_unprefixed(0xc5), PUSH(Op16::Reg(BC)));
         assert_eq!(decode_unprefixed(0xd5), PUSH(Op16::Reg(DE)));
         assert_eq!(decode_unprefixed(0xe5), PUSH(Op16::Reg(HL)));
-        assert_eq!(decode_unprefixed(0xf5), PUSH(Op16::Reg(SP)));
+        assert_eq!(decode_unprefixed(0xf5), PUSH(Op16::Reg(AF)));
     }
 
     #[test]
@@ -152,7 +264,7 @@ mod should {
         assert_eq!(decode_unprefixed(0xc1), POP(Op16::Reg(BC)));
         assert_eq!(decode_unprefixed(0xd1), POP(Op16::Reg(DE)));
         assert_eq!(decode_unprefixed(0xe1), POP(Op16::Reg(HL)));
-        assert_eq!(decode_unprefixed(0xf1), POP(Op16::Reg(SP)));
+        assert_eq!(decode_unprefixed(0xf1), POP(Op16::Reg(AF)));
     }
 
     #[test]
@@ -360,7 +472,7 @@ mod should {
 
     #[test]
     fn decode_rotating_left_carry() {
-        assert_eq!(decode_unprefixed(0x07), RLC(Op8::Reg(A)));
+        assert_eq!(decode_unprefixed(0x07), RLCA);
 
         assert_eq!(decode_prefixed(0x05), RLC(Op8::Reg(L)));
         assert_eq!(decode_prefixed(0x06), RLC(Op8::AddrHL));
@@ -368,7 +480,7 @@ mod should {
 
     #[test]
     fn decode_rotating_left() {
-        assert_eq!(decode_unprefixed(0x17), RL(Op8::Reg(A)));
+        assert_eq!(decode_unprefixed(0x17), RLA);
 
         assert_eq!(decode_prefixed(0x12), RL(Op8::Reg(D)));
         assert_eq!(decode_prefixed(0x16), RL(Op8::AddrHL));
@@ -376,7 +488,7 @@ mod should {
 
     #[test]
     fn decode_rotating_right_carry() {
-        assert_eq!(decode_unprefixed(0x0f), RRC(Op8::Reg(A)));
+        assert_eq!(decode_unprefixed(0x0f), RRCA);
 
         assert_eq!(decode_prefixed(0x09), RRC(Op8::Reg(C)));
         assert_eq!(decode_prefixed(0x0e), RRC(Op8::AddrHL));
@@ -384,7 +496,7 @@ mod should {
 
     #[test]
     fn decode_rotating_right() {
-        assert_eq!(decode_unprefixed(0x1f), RR(Op8::Reg(A)));
+        assert_eq!(decode_unprefixed(0x1f), RRA);
 
         assert_eq!(decode_prefixed(0x1a), RR(Op8::Reg(D)));
         assert_eq!(decode_prefixed(0x1e), RR(Op8::AddrHL));
@@ -411,6 +523,13 @@ mod should {
         assert_eq!(decode_prefixed(0x3e), SRL(Op8::AddrHL));
     }
 
+    #[test]
+    fn decode_swapping_nibbles() {
+        assert_eq!(decode_prefixed(0x37), SWAP(Op8::Reg(A)));
+        assert_eq!(decode_prefixed(0x30), SWAP(Op8::Reg(B)));
+        assert_eq!(decode_prefixed(0x36), SWAP(Op8::AddrHL));
+    }
+
     #[test]
     fn decode_bit_testing() {
         assert_eq!(decode_prefixed(0x72), BIT(6, Op8::Reg(D)));
@@ -431,4 +550,41 @@ mod should {
         assert_eq!(decode_prefixed(0xa2), RES(4, Op8::Reg(D)));
         assert_eq!(decode_prefixed(0xbe), RES(7, Op8::AddrHL));
     }
+
+    #[test]
+    fn decode_jumps() {
+        assert_eq!(decode_unprefixed(0xc3), JP(Cond::Always, Op16::NN)); // JP nn
+        assert_eq!(decode_unprefixed(0xc2), JP(Cond::NZ, Op16::NN)); // JP NZ, nn
+        assert_eq!(decode_unprefixed(0xda), JP(Cond::C, Op16::NN)); // JP C, nn
+        assert_eq!(decode_unprefixed(0xe9), JP(Cond::Always, Op16::Reg(HL))); // JP (HL)
+    }
+
+    #[test]
+    fn decode_relative_jumps() {
+        assert_eq!(decode_unprefixed(0x18), JR(Cond::Always, Op8::N)); // JR n
+        assert_eq!(decode_unprefixed(0x28), JR(Cond::Z, Op8::N)); // JR Z, n
+        assert_eq!(decode_unprefixed(0x30), JR(Cond::NC, Op8::N)); // JR NC, n
+    }
+
+    #[test]
+    fn decode_calls() {
+        assert_eq!(decode_unprefixed(0xcd), CALL(Cond::Always, Op16::NN)); // CALL nn
+        assert_eq!(decode_unprefixed(0xcc), CALL(Cond::Z, Op16::NN)); // CALL Z, nn
+        assert_eq!(decode_unprefixed(0xd4), CALL(Cond::NC, Op16::NN)); // CALL NC, nn
+    }
+
+    #[test]
+    fn decode_restarts() {
+        assert_eq!(decode_unprefixed(0xc7), RST(0x00));
+        assert_eq!(decode_unprefixed(0xdf), RST(0x18));
+        assert_eq!(decode_unprefixed(0xff), RST(0x38));
+    }
+
+    #[test]
+    fn decode_returns() {
+        assert_eq!(decode_unprefixed(0xc9), RET(Cond::Always)); // RET
+        assert_eq!(decode_unprefixed(0xc0), RET(Cond::NZ)); // RET NZ
+        assert_eq!(decode_unprefixed(0xd8), RET(Cond::C)); // RET C
+        assert_eq!(decode_unprefixed(0xd9), RETI);
+    }
 }
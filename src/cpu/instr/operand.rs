@@ -1,4 +1,7 @@
 use crate::cpu::{Reg16, Reg8};
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Op8 {
@@ -14,6 +17,7 @@ pub enum Op8 {
     N,         // following byte
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Op16 {
     Reg(Reg16),
@@ -29,13 +33,135 @@ pub enum Op16 {
     NN,        // following 2 bytes
 }
 
-pub(crate) fn operand8_from_index(i: u8) -> Op8 {
-    if i == 6 {
-        return Op8::AddrHL;
+// condition checked by jumps, calls and returns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cond {
+    Always,
+    NZ, // zero flag reset
+    Z,  // zero flag set
+    NC, // carry flag reset
+    C,  // carry flag set
+}
+
+// an operand read from the bytes following the opcode, whose value the instruction alone does not
+// give, so is written by a caller supplied function when formatting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Immediate {
+    N8,          // byte
+    N16,         // little endian word
+    HighAddress, // byte offset from 0xff00, used by LDH
+    Address,     // word used as an address, by loads, jumps and calls
+    Offset,      // signed byte added to SP
+    Target,      // signed byte added to the address of the next instruction by JR
+}
+
+impl Immediate {
+    // how RGBDS documentation writes the operand
+    pub fn placeholder(&self) -> &'static str {
+        return match self {
+            Immediate::N8 => "n8",
+            Immediate::N16 => "n16",
+            Immediate::HighAddress => "a8",
+            Immediate::Address => "a16",
+            Immediate::Offset | Immediate::Target => "e8",
+        };
+    }
+}
+
+pub(crate) fn placeholder(immediate: Immediate) -> String {
+    return immediate.placeholder().to_string();
+}
+
+// memory operands which do not depend on the operand size
+fn indirect(address: &str) -> String {
+    return format!("[{}]", address);
+}
+
+impl Op8 {
+    pub fn format(&self, immediate: &dyn Fn(Immediate) -> String) -> String {
+        return match *self {
+            Op8::Reg(r) => r.to_string(),
+            Op8::AddrN => indirect(&immediate(Immediate::HighAddress)),
+            Op8::AddrC => indirect("c"),
+            Op8::AddrNN => indirect(&immediate(Immediate::Address)),
+            Op8::AddrBC => indirect("bc"),
+            Op8::AddrDE => indirect("de"),
+            Op8::AddrHL => indirect("hl"),
+            Op8::AddrHLInc => indirect("hl+"),
+            Op8::AddrHLDec => indirect("hl-"),
+            Op8::N => immediate(Immediate::N8),
+        };
+    }
+}
+
+impl fmt::Display for Op8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.format(&placeholder));
     }
-    return Op8::Reg(Reg8::from_index(i));
 }
 
-pub(crate) fn operand16_from_index(i: u8) -> Op16 {
+impl Op16 {
+    pub fn format(&self, immediate: &dyn Fn(Immediate) -> String) -> String {
+        return match *self {
+            Op16::Reg(r) => r.to_string(),
+            Op16::AddrN => indirect(&immediate(Immediate::HighAddress)),
+            Op16::AddrC => indirect("c"),
+            Op16::AddrNN => indirect(&immediate(Immediate::Address)),
+            Op16::AddrBC => indirect("bc"),
+            Op16::AddrDE => indirect("de"),
+            Op16::AddrHL => indirect("hl"),
+            Op16::AddrHLInc => indirect("hl+"),
+            Op16::AddrHLDec => indirect("hl-"),
+            Op16::N => immediate(Immediate::Offset),
+            Op16::NN => immediate(Immediate::N16),
+        };
+    }
+}
+
+impl fmt::Display for Op16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.format(&placeholder));
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Cond::Always => "",
+            Cond::NZ => "nz",
+            Cond::Z => "z",
+            Cond::NC => "nc",
+            Cond::C => "c",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+pub(crate) const fn operand8_from_index(i: u8) -> Op8 {
+    return match Reg8::from_index(i) {
+        Some(r) => Op8::Reg(r),
+        None => Op8::AddrHL,
+    };
+}
+
+pub(crate) const fn operand16_from_index(i: u8) -> Op16 {
     return Op16::Reg(Reg16::from_index(i));
 }
+
+// PUSH and POP use index 3 for AF rather than SP
+pub(crate) const fn operand16_stack_from_index(i: u8) -> Op16 {
+    if i == 3 {
+        return Op16::Reg(Reg16::AF);
+    }
+    return operand16_from_index(i);
+}
+
+pub(crate) const fn cond_from_index(i: u8) -> Cond {
+    // i is 2 bits long
+    return match i & 0b11 {
+        0b00 => Cond::NZ,
+        0b01 => Cond::Z,
+        0b10 => Cond::NC,
+        _ => Cond::C,
+    };
+}
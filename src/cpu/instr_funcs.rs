@@ -1,132 +1,855 @@
-use crate::cpu::instr::operand::{Op16, Op8};
-use crate::cpu::{CPUState, Flag, Reg8, CPU};
+use crate::cpu::instr::operand::{Cond, Op16, Op8};
+use crate::cpu::{Bus, CPUState, Flag, Reg16, Reg8, CPU};
 
+/*
+ * every function below is called once per machine cycle of its instruction with the cycle relative
+ * to the opcode fetch, instructions finish by returning the CPU to the fetch state
+ */
 impl CPU {
     pub fn nop(&mut self) {
-        return;
+        self.finish_instr();
     }
 
     // correcting calculation result between two BCD numbers back to BCD
     // https://ehaskins.com/2018-01-30%20Z80%20DAA/
-    // if adding: add 6 to each digit greater than 9, or if it carried
-    // if subtracting: subtract 6 from each digit greater than 9, or if it carried
+    // if adding: add 6 to the lower digit if it is over 9 or half carried, and 0x60 if the whole
+    // byte is over 0x99 or carried, which carries again
+    // if subtracting: take 6 or 0x60 away only for a half carry or carry
     pub fn daa(&mut self) {
         let value: u8 = self.read_reg8(Reg8::A);
         let lower_digit: u8 = value & 0xf;
-        let upper_digit: u8 = (value >> 4) & 0xf;
 
         let mut adjustment: u8 = 0x00;
 
         // flags
         let subtraction: bool = self.test_flag(Flag::N);
-        let carry: bool = self.test_flag(Flag::C);
+        let mut carry: bool = self.test_flag(Flag::C);
         let half_carry: bool = self.test_flag(Flag::H);
 
         // adjustment for least significant digit
         if half_carry || (!subtraction && lower_digit > 9) {
-            adjustment += 0x06;
+            adjustment |= 0x06;
         }
 
         // adjustment for most significant digit
-        if carry || (!subtraction && upper_digit > 9) {
-            adjustment += 0x60;
+        if carry || (!subtraction && value > 0x99) {
+            adjustment |= 0x60;
+            carry = true;
         }
 
         // apply adjustment
         let new_value = if subtraction {
-            value - adjustment
+            value.wrapping_sub(adjustment)
         } else {
-            value + adjustment
+            value.wrapping_add(adjustment)
         };
 
         self.write_reg8(Reg8::A, new_value);
+        self.set_flag_to(Flag::Z, new_value == 0);
+        self.set_flag_to(Flag::H, false);
+        self.set_flag_to(Flag::C, carry);
+        self.finish_instr();
     }
 
     pub fn cpl(&mut self) {
         self.write_reg8(Reg8::A, !self.read_reg8(Reg8::A));
+        self.set_flag_to(Flag::N, true);
+        self.set_flag_to(Flag::H, true);
+        self.finish_instr();
     }
 
     pub fn ccf(&mut self) {
         self.set_flag_to(Flag::N, false);
         self.set_flag_to(Flag::H, false);
         self.set_flag_to(Flag::C, !self.test_flag(Flag::C));
+        self.finish_instr();
     }
 
     pub fn scf(&mut self) {
         self.set_flag_to(Flag::N, false);
         self.set_flag_to(Flag::H, false);
         self.set_flag_to(Flag::C, true);
+        self.finish_instr();
     }
 
-    pub fn halt(&mut self) {
-        self.state = CPUState::Halted;
+    pub fn halt(&mut self, memory: &impl Bus) {
+        self.enter_halt(memory);
     }
 
-    pub fn stop(&mut self) {
-        self.state = CPUState::Stopped;
+    // on a CGB, STOP performs a prepared speed switch instead of stopping, and either way it skips
+    // the byte after it
+    pub fn stop(&mut self, memory: &mut impl Bus) {
+        self.pc_read_next(memory);
+        if memory.speed_switch_armed() {
+            memory.switch_speed();
+            self.finish_instr();
+        } else {
+            self.state = CPUState::Stopped;
+        }
     }
 
     pub fn di(&mut self) {
         self.interrupt_master_enable = false;
+        self.finish_instr();
     }
 
     pub fn ei(&mut self) {
-        self.interrupt_master_enable = true;
+        self.schedule_interrupt_enable();
+        self.finish_instr();
+    }
+
+    pub fn ld(&mut self, memory: &mut impl Bus, cycle: u8, op1: Op8, op2: Op8) {
+        match (op1, op2) {
+            (Op8::Reg(r), _) => {
+                if let Some(value) = self.read_op8(memory, cycle, op2) {
+                    self.write_reg8(r, value);
+                    self.finish_instr();
+                }
+            }
+            (_, Op8::Reg(r)) => {
+                let value = self.read_reg8(r);
+                if self.write_op8(memory, cycle, op1, value) {
+                    self.finish_instr();
+                }
+            }
+            (_, Op8::N) => match cycle {
+                1 => self.z = self.pc_read_next(memory),
+                2 => {
+                    self.write_op8(memory, 1, op1, self.z);
+                    self.finish_instr();
+                }
+                _ => return,
+            },
+            _ => panic!("Illegal load {:?} <- {:?}", op1, op2),
+        }
+    }
+
+    pub fn ld16(&mut self, memory: &mut impl Bus, cycle: u8, op1: Op16, op2: Op16) {
+        match (op1, op2, cycle) {
+            (Op16::Reg(_), Op16::NN, 1) => self.z = self.pc_read_next(memory),
+            (Op16::Reg(r), Op16::NN, 2) => {
+                self.w = self.pc_read_next(memory);
+                self.write_reg16(r, self.wz());
+                self.finish_instr();
+            }
+            (Op16::Reg(to), Op16::Reg(from), 1) => {
+                self.write_reg16(to, self.read_reg16(from));
+                self.finish_instr();
+            }
+            (Op16::AddrNN, Op16::Reg(_), 1) => self.z = self.pc_read_next(memory),
+            (Op16::AddrNN, Op16::Reg(_), 2) => self.w = self.pc_read_next(memory),
+            (Op16::AddrNN, Op16::Reg(r), 3) => memory.write(self.wz(), self.read_reg16(r) as u8),
+            (Op16::AddrNN, Op16::Reg(r), 4) => {
+                let address = self.wz().wrapping_add(1);
+                memory.write(address, (self.read_reg16(r) >> 8) as u8);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn ldh(&mut self, memory: &mut impl Bus, cycle: u8, op1: Op8, op2: Op8) {
+        self.ld(memory, cycle, op1, op2);
+    }
+
+    pub fn ldhl(&mut self, memory: &impl Bus, cycle: u8) {
+        match cycle {
+            1 => self.z = self.pc_read_next(memory),
+            2 => {
+                let value = self.add_sp_offset(self.z);
+                self.write_reg16(Reg16::HL, value);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn push(&mut self, memory: &mut impl Bus, cycle: u8, op: Op16) {
+        let value = self.read_op16_reg(op);
+        match cycle {
+            1 => self.decrement_sp(),
+            2 => {
+                memory.write(self.read_reg16(Reg16::SP), (value >> 8) as u8);
+                self.decrement_sp();
+            }
+            3 => {
+                memory.write(self.read_reg16(Reg16::SP), value as u8);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn pop(&mut self, memory: &impl Bus, cycle: u8, op: Op16) {
+        match cycle {
+            1 => self.z = self.pop_byte(memory),
+            2 => {
+                self.w = self.pop_byte(memory);
+                if let Op16::Reg(r) = op {
+                    self.write_reg16(r, self.wz());
+                }
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn add(&mut self, memory: &mut impl Bus, cycle: u8, _op1: Op8, op2: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op2) {
+            let result = self.add_to_a(value, false);
+            self.write_reg8(Reg8::A, result);
+            self.finish_instr();
+        }
+    }
+
+    pub fn add16(&mut self, memory: &impl Bus, cycle: u8, op1: Op16, op2: Op16) {
+        match (op1, op2, cycle) {
+            (Op16::Reg(Reg16::SP), Op16::N, 1) => self.z = self.pc_read_next(memory),
+            (Op16::Reg(Reg16::SP), Op16::N, 3) => {
+                let value = self.add_sp_offset(self.z);
+                self.write_reg16(Reg16::SP, value);
+                self.finish_instr();
+            }
+            (Op16::Reg(r1), Op16::Reg(r2), 1) => {
+                let value1 = self.read_reg16(r1);
+                let value2 = self.read_reg16(r2);
+                let (result, carry) = value1.overflowing_add(value2);
+                self.set_flag_to(Flag::N, false);
+                self.set_flag_to(Flag::H, (value1 & 0xfff) + (value2 & 0xfff) > 0xfff);
+                self.set_flag_to(Flag::C, carry);
+                self.write_reg16(r1, result);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn adc(&mut self, memory: &mut impl Bus, cycle: u8, _op1: Op8, op2: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op2) {
+            let carry = self.test_flag(Flag::C);
+            let result = self.add_to_a(value, carry);
+            self.write_reg8(Reg8::A, result);
+            self.finish_instr();
+        }
+    }
+
+    pub fn sub(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            let result = self.subtract_from_a(value, false);
+            self.write_reg8(Reg8::A, result);
+            self.finish_instr();
+        }
+    }
+
+    pub fn sbc(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            let carry = self.test_flag(Flag::C);
+            let result = self.subtract_from_a(value, carry);
+            self.write_reg8(Reg8::A, result);
+            self.finish_instr();
+        }
+    }
+
+    pub fn and(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            let result = self.read_reg8(Reg8::A) & value;
+            self.write_reg8(Reg8::A, result);
+            self.set_flags(result == 0, false, true, false);
+            self.finish_instr();
+        }
+    }
+
+    pub fn or(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            let result = self.read_reg8(Reg8::A) | value;
+            self.write_reg8(Reg8::A, result);
+            self.set_flags(result == 0, false, false, false);
+            self.finish_instr();
+        }
+    }
+
+    pub fn xor(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            let result = self.read_reg8(Reg8::A) ^ value;
+            self.write_reg8(Reg8::A, result);
+            self.set_flags(result == 0, false, false, false);
+            self.finish_instr();
+        }
+    }
+
+    // subtraction without storing the result
+    pub fn cp(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            self.subtract_from_a(value, false);
+            self.finish_instr();
+        }
+    }
+
+    pub fn inc(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            let result = value.wrapping_add(1);
+            cpu.set_flag_to(Flag::Z, result == 0);
+            cpu.set_flag_to(Flag::N, false);
+            cpu.set_flag_to(Flag::H, value & 0xf == 0xf);
+            return result;
+        });
+    }
+
+    pub fn inc16(&mut self, cycle: u8, op: Op16) {
+        if let (1, Op16::Reg(r)) = (cycle, op) {
+            self.write_reg16(r, self.read_reg16(r).wrapping_add(1));
+            self.finish_instr();
+        }
+    }
+
+    pub fn dec(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            let result = value.wrapping_sub(1);
+            cpu.set_flag_to(Flag::Z, result == 0);
+            cpu.set_flag_to(Flag::N, true);
+            cpu.set_flag_to(Flag::H, value & 0xf == 0);
+            return result;
+        });
+    }
+
+    pub fn dec16(&mut self, cycle: u8, op: Op16) {
+        if let (1, Op16::Reg(r)) = (cycle, op) {
+            self.write_reg16(r, self.read_reg16(r).wrapping_sub(1));
+            self.finish_instr();
+        }
+    }
+
+    pub fn rlca(&mut self) {
+        let value = self.read_reg8(Reg8::A);
+        let result = self.rotate_left(value, value >> 7);
+        self.write_reg8(Reg8::A, result);
+        self.set_flag_to(Flag::Z, false);
+        self.finish_instr();
+    }
+
+    pub fn rla(&mut self) {
+        let value = self.read_reg8(Reg8::A);
+        let result = self.rotate_left(value, self.test_flag(Flag::C) as u8);
+        self.write_reg8(Reg8::A, result);
+        self.set_flag_to(Flag::Z, false);
+        self.finish_instr();
+    }
+
+    pub fn rrca(&mut self) {
+        let value = self.read_reg8(Reg8::A);
+        let result = self.rotate_right(value, value & 1);
+        self.write_reg8(Reg8::A, result);
+        self.set_flag_to(Flag::Z, false);
+        self.finish_instr();
+    }
+
+    pub fn rra(&mut self) {
+        let value = self.read_reg8(Reg8::A);
+        let result = self.rotate_right(value, self.test_flag(Flag::C) as u8);
+        self.write_reg8(Reg8::A, result);
+        self.set_flag_to(Flag::Z, false);
+        self.finish_instr();
+    }
+
+    pub fn rlc(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            cpu.rotate_left(value, value >> 7)
+        });
+    }
+
+    pub fn rl(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            cpu.rotate_left(value, cpu.test_flag(Flag::C) as u8)
+        });
+    }
+
+    pub fn rrc(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            cpu.rotate_right(value, value & 1)
+        });
+    }
+
+    pub fn rr(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            cpu.rotate_right(value, cpu.test_flag(Flag::C) as u8)
+        });
+    }
+
+    pub fn sla(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| cpu.rotate_left(value, 0));
+    }
+
+    pub fn sra(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            cpu.rotate_right(value, value >> 7)
+        });
+    }
+
+    pub fn srl(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| cpu.rotate_right(value, 0));
+    }
+
+    pub fn swap(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |cpu, value| {
+            let result = value.rotate_left(4);
+            cpu.set_flags(result == 0, false, false, false);
+            return result;
+        });
+    }
+
+    pub fn bit(&mut self, memory: &mut impl Bus, cycle: u8, index: u8, op: Op8) {
+        if let Some(value) = self.read_op8(memory, cycle, op) {
+            self.set_flag_to(Flag::Z, (value >> index) & 1 == 0);
+            self.set_flag_to(Flag::N, false);
+            self.set_flag_to(Flag::H, true);
+            self.finish_instr();
+        }
     }
 
-    pub fn ld(&mut self, cycle: u8, op1: Op8, op2: Op8) {}
+    pub fn set(&mut self, memory: &mut impl Bus, cycle: u8, index: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |_, value| value | (1 << index));
+    }
+
+    pub fn res(&mut self, memory: &mut impl Bus, cycle: u8, index: u8, op: Op8) {
+        self.modify_op8(memory, cycle, op, |_, value| value & !(1 << index));
+    }
+
+    pub fn jp(&mut self, memory: &impl Bus, cycle: u8, cond: Cond, op: Op16) {
+        match (op, cycle) {
+            (Op16::Reg(r), 0) => {
+                self.write_reg16(Reg16::PC, self.read_reg16(r));
+                self.finish_instr();
+            }
+            (Op16::NN, 1) => self.z = self.pc_read_next(memory),
+            (Op16::NN, 2) => {
+                self.w = self.pc_read_next(memory);
+                if !self.test_cond(cond) {
+                    self.finish_instr();
+                }
+            }
+            (Op16::NN, 3) => {
+                self.write_reg16(Reg16::PC, self.wz());
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn jr(&mut self, memory: &impl Bus, cycle: u8, cond: Cond, _op: Op8) {
+        match cycle {
+            1 => {
+                self.z = self.pc_read_next(memory);
+                if !self.test_cond(cond) {
+                    self.finish_instr();
+                }
+            }
+            2 => {
+                let pc = self.read_reg16(Reg16::PC);
+                self.write_reg16(Reg16::PC, pc.wrapping_add(self.z as i8 as u16));
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    pub fn call(&mut self, memory: &mut impl Bus, cycle: u8, cond: Cond, _op: Op16) {
+        match cycle {
+            1 => self.z = self.pc_read_next(memory),
+            2 => {
+                self.w = self.pc_read_next(memory);
+                if !self.test_cond(cond) {
+                    self.finish_instr();
+                }
+            }
+            3..=5 => self.call_address(memory, cycle - 2, self.wz()),
+            _ => return,
+        }
+    }
+
+    pub fn ret(&mut self, memory: &impl Bus, cycle: u8, cond: Cond) {
+        if cond == Cond::Always {
+            self.return_from_call(memory, cycle);
+        } else if cycle == 1 {
+            // checking the condition takes an extra cycle
+            if !self.test_cond(cond) {
+                self.finish_instr();
+            }
+        } else if cycle > 1 {
+            self.return_from_call(memory, cycle - 1);
+        }
+    }
+
+    pub fn reti(&mut self, memory: &impl Bus, cycle: u8) {
+        self.return_from_call(memory, cycle);
+        if self.state == CPUState::Fetch {
+            self.interrupt_master_enable = true;
+        }
+    }
+
+    pub fn rst(&mut self, memory: &mut impl Bus, cycle: u8, address: u8) {
+        self.call_address(memory, cycle, address as u16);
+    }
+
+    // pushes PC in cycles 2 and 3 before jumping to address
+    fn call_address(&mut self, memory: &mut impl Bus, cycle: u8, address: u16) {
+        let [pc_high, pc_low] = self.read_reg16(Reg16::PC).to_be_bytes();
+        match cycle {
+            1 => self.decrement_sp(),
+            2 => {
+                memory.write(self.read_reg16(Reg16::SP), pc_high);
+                self.decrement_sp();
+            }
+            3 => {
+                memory.write(self.read_reg16(Reg16::SP), pc_low);
+                self.write_reg16(Reg16::PC, address);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    // pops PC in cycles 1 and 2 then jumps in cycle 3
+    fn return_from_call(&mut self, memory: &impl Bus, cycle: u8) {
+        match cycle {
+            1 => self.z = self.pop_byte(memory),
+            2 => self.w = self.pop_byte(memory),
+            3 => {
+                self.write_reg16(Reg16::PC, self.wz());
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    // reads an 8 bit operand, returning None while it is still being fetched
+    fn read_op8(&mut self, memory: &impl Bus, cycle: u8, op: Op8) -> Option<u8> {
+        return match (op, cycle) {
+            (Op8::Reg(r), 0) => Some(self.read_reg8(r)),
+            (Op8::N, 1) => Some(self.pc_read_next(memory)),
+            (Op8::AddrN, 1) | (Op8::AddrNN, 1) => {
+                self.z = self.pc_read_next(memory);
+                None
+            }
+            (Op8::AddrNN, 2) => {
+                self.w = self.pc_read_next(memory);
+                None
+            }
+            (Op8::AddrN, 2) | (Op8::AddrNN, 3) => Some(memory.read(self.address_of(op))),
+            (Op8::Reg(_), _) | (Op8::N, _) | (Op8::AddrN, _) | (Op8::AddrNN, _) => None,
+            (_, 1) => {
+                let value = memory.read(self.address_of(op));
+                self.step_hl(op);
+                Some(value)
+            }
+            _ => None,
+        };
+    }
+
+    // writes an 8 bit operand, returning whether the write has happened
+    fn write_op8(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8, value: u8) -> bool {
+        match (op, cycle) {
+            (Op8::Reg(r), 0) => self.write_reg8(r, value),
+            (Op8::AddrN, 1) | (Op8::AddrNN, 1) => {
+                self.z = self.pc_read_next(memory);
+                return false;
+            }
+            (Op8::AddrNN, 2) => {
+                self.w = self.pc_read_next(memory);
+                return false;
+            }
+            (Op8::AddrN, 2) | (Op8::AddrNN, 3) => memory.write(self.address_of(op), value),
+            (Op8::Reg(_), _) | (Op8::N, _) | (Op8::AddrN, _) | (Op8::AddrNN, _) => return false,
+            (_, 1) => {
+                memory.write(self.address_of(op), value);
+                self.step_hl(op);
+            }
+            _ => return false,
+        }
+        return true;
+    }
+
+    // read-modify-write of a register or (HL), taking 2 extra cycles for (HL)
+    fn modify_op8<F>(&mut self, memory: &mut impl Bus, cycle: u8, op: Op8, f: F)
+    where
+        F: FnOnce(&mut CPU, u8) -> u8,
+    {
+        match (op, cycle) {
+            (Op8::Reg(r), 0) => {
+                let result = f(self, self.read_reg8(r));
+                self.write_reg8(r, result);
+                self.finish_instr();
+            }
+            (Op8::AddrHL, 1) => self.z = memory.read(self.read_reg16(Reg16::HL)),
+            (Op8::AddrHL, 2) => {
+                let result = f(self, self.z);
+                memory.write(self.read_reg16(Reg16::HL), result);
+                self.finish_instr();
+            }
+            _ => return,
+        }
+    }
+
+    fn address_of(&self, op: Op8) -> u16 {
+        return match op {
+            Op8::AddrN => 0xff00 | self.z as u16,
+            Op8::AddrC => 0xff00 | self.read_reg8(Reg8::C) as u16,
+            Op8::AddrNN => self.wz(),
+            Op8::AddrBC => self.read_reg16(Reg16::BC),
+            Op8::AddrDE => self.read_reg16(Reg16::DE),
+            Op8::AddrHL | Op8::AddrHLInc | Op8::AddrHLDec => self.read_reg16(Reg16::HL),
+            _ => panic!("Operand {:?} is not an address", op),
+        };
+    }
+
+    fn step_hl(&mut self, op: Op8) {
+        let hl = self.read_reg16(Reg16::HL);
+        match op {
+            Op8::AddrHLInc => self.write_reg16(Reg16::HL, hl.wrapping_add(1)),
+            Op8::AddrHLDec => self.write_reg16(Reg16::HL, hl.wrapping_sub(1)),
+            _ => return,
+        }
+    }
+
+    fn read_op16_reg(&self, op: Op16) -> u16 {
+        return match op {
+            Op16::Reg(r) => self.read_reg16(r),
+            _ => panic!("Operand {:?} is not a register", op),
+        };
+    }
+
+    fn wz(&self) -> u16 {
+        return u16::from_be_bytes([self.w, self.z]);
+    }
+
+    fn decrement_sp(&mut self) {
+        let sp = self.read_reg16(Reg16::SP);
+        self.write_reg16(Reg16::SP, sp.wrapping_sub(1));
+    }
+
+    fn pop_byte(&mut self, memory: &impl Bus) -> u8 {
+        let sp = self.read_reg16(Reg16::SP);
+        self.write_reg16(Reg16::SP, sp.wrapping_add(1));
+        return memory.read(sp);
+    }
+
+    fn set_flags(&mut self, z: bool, n: bool, h: bool, c: bool) {
+        self.set_flag_to(Flag::Z, z);
+        self.set_flag_to(Flag::N, n);
+        self.set_flag_to(Flag::H, h);
+        self.set_flag_to(Flag::C, c);
+    }
+
+    fn add_to_a(&mut self, value: u8, carry: bool) -> u8 {
+        let a = self.read_reg8(Reg8::A);
+        let result = a as u16 + value as u16 + carry as u16;
+        let half_carry = (a & 0xf) + (value & 0xf) + carry as u8 > 0xf;
+        self.set_flags(result as u8 == 0, false, half_carry, result > 0xff);
+        return result as u8;
+    }
+
+    fn subtract_from_a(&mut self, value: u8, carry: bool) -> u8 {
+        let a = self.read_reg8(Reg8::A);
+        let result = a as i16 - value as i16 - carry as i16;
+        let half_carry = ((a & 0xf) as i16) - ((value & 0xf) as i16) - (carry as i16) < 0;
+        self.set_flags(result as u8 == 0, true, half_carry, result < 0);
+        return result as u8;
+    }
+
+    // SP plus a signed byte, with flags from the unsigned addition of the lower byte
+    fn add_sp_offset(&mut self, offset: u8) -> u16 {
+        let sp = self.read_reg16(Reg16::SP);
+        let half_carry = (sp & 0xf) + (offset as u16 & 0xf) > 0xf;
+        let carry = (sp & 0xff) + offset as u16 > 0xff;
+        self.set_flags(false, false, half_carry, carry);
+        return sp.wrapping_add(offset as i8 as u16);
+    }
+
+    // shifts left, filling bit 0 with the given bit and moving bit 7 into the carry flag
+    fn rotate_left(&mut self, value: u8, fill: u8) -> u8 {
+        let result = (value << 1) | fill;
+        self.set_flags(result == 0, false, false, value >> 7 == 1);
+        return result;
+    }
+
+    // shifts right, filling bit 7 with the given bit and moving bit 0 into the carry flag
+    fn rotate_right(&mut self, value: u8, fill: u8) -> u8 {
+        let result = (value >> 1) | (fill << 7);
+        self.set_flags(result == 0, false, false, value & 1 == 1);
+        return result;
+    }
+}
 
-    pub fn ld16(&mut self, cycle: u8, op1: Op16, op2: Op16) {}
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{CPUState, Flag, Reg16, Reg16::*, Reg8::*, CPU};
+    use crate::memory::Memory;
+
+    // runs a program placed at the start of memory until the given number of cycles have passed
+    fn run(program: &[u8], cycles: usize) -> (CPU, Memory) {
+        let mut memory = Memory::with_program(program);
+        let mut cpu = CPU::new();
+        cpu.write_reg16(SP, 0xfffe);
+        for _ in 0..cycles {
+            cpu.cycle(&mut memory).unwrap();
+        }
+        return (cpu, memory);
+    }
 
-    pub fn ldh(&mut self, cycle: u8, op1: Op8, op2: Op8) {}
+    #[test]
+    fn test_load_immediate_takes_two_cycles() {
+        let (cpu, _) = run(&[0x06, 0x42], 1); // LD B, n
+        assert_eq!(cpu.read_reg8(B), 0x00);
 
-    pub fn push(&mut self, cycle: u8, op: Op16) {}
+        let (cpu, _) = run(&[0x06, 0x42], 2);
+        assert_eq!(cpu.read_reg8(B), 0x42);
+        assert_eq!(cpu.state, CPUState::Fetch);
+    }
 
-    pub fn pop(&mut self, cycle: u8, op: Op16) {}
+    #[test]
+    fn test_load_through_incremented_hl() {
+        // LD HL, 0xc000; LD A, n; LD (HL+), A
+        let (cpu, memory) = run(&[0x21, 0x00, 0xc0, 0x3e, 0x99, 0x22], 7);
 
-    pub fn add(&mut self, cycle: u8, op1: Op8, op2: Op8) {}
+        assert_eq!(memory.read(0xc000), 0x99);
+        assert_eq!(cpu.read_reg16(HL), 0xc001);
+    }
 
-    pub fn add16(&mut self, cycle: u8, op1: Op16, op2: Op16) {}
+    #[test]
+    fn test_ldh_writes_to_high_memory() {
+        // LD A, n; LDH (n), A
+        let (_, memory) = run(&[0x3e, 0x12, 0xe0, 0x80], 5);
 
-    pub fn adc(&mut self, cycle: u8, op1: Op8, op2: Op8) {}
+        assert_eq!(memory.read(0xff80), 0x12);
+    }
 
-    pub fn sub(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_add_sets_half_carry_and_carry() {
+        // LD A, 0xf8; ADD A, n
+        let (cpu, _) = run(&[0x3e, 0xf8, 0xc6, 0x08], 4);
 
-    pub fn sbc(&mut self, cycle: u8, op: Op8) {}
+        assert_eq!(cpu.read_reg8(A), 0x00);
+        assert!(cpu.test_flag(Flag::Z));
+        assert!(!cpu.test_flag(Flag::N));
+        assert!(cpu.test_flag(Flag::H));
+        assert!(cpu.test_flag(Flag::C));
+    }
 
-    pub fn and(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_compare_leaves_register_a_unchanged() {
+        // LD A, 0x10; CP n
+        let (cpu, _) = run(&[0x3e, 0x10, 0xfe, 0x20], 4);
 
-    pub fn or(&mut self, cycle: u8, op: Op8) {}
+        assert_eq!(cpu.read_reg8(A), 0x10);
+        assert!(cpu.test_flag(Flag::N));
+        assert!(cpu.test_flag(Flag::C));
+    }
 
-    pub fn xor(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_increment_address_hl_takes_three_cycles() {
+        // LD HL, 0xc000; INC (HL)
+        let (_, memory) = run(&[0x21, 0x00, 0xc0, 0x34], 5);
+        assert_eq!(memory.read(0xc000), 0x00);
 
-    pub fn cp(&mut self, cycle: u8, op: Op8) {}
+        let (_, memory) = run(&[0x21, 0x00, 0xc0, 0x34], 6);
+        assert_eq!(memory.read(0xc000), 0x01);
+    }
 
-    pub fn inc(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_rotate_register_a_always_resets_zero_flag() {
+        let (cpu, _) = run(&[0x07], 1); // RLCA
+        assert!(!cpu.test_flag(Flag::Z));
 
-    pub fn inc16(&mut self, cycle: u8, op: Op16) {}
+        let (cpu, _) = run(&[0xcb, 0x07], 2); // RLC A
+        assert!(cpu.test_flag(Flag::Z));
+    }
 
-    pub fn dec(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_swap_nibbles() {
+        // LD A, 0xab; SWAP A
+        let (cpu, _) = run(&[0x3e, 0xab, 0xcb, 0x37], 4);
 
-    pub fn dec16(&mut self, cycle: u8, op: Op16) {}
+        assert_eq!(cpu.read_reg8(A), 0xba);
+    }
 
-    pub fn rlc(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_bit_tests_address_hl() {
+        // LD HL, 0x0000; BIT 0, (HL)
+        let (cpu, _) = run(&[0x21, 0x00, 0x00, 0xcb, 0x46], 6);
 
-    pub fn rl(&mut self, cycle: u8, op: Op8) {}
+        assert!(!cpu.test_flag(Flag::Z)); // bit 0 of 0x21 is set
+        assert!(cpu.test_flag(Flag::H));
+    }
 
-    pub fn rrc(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_push_and_pop_af_masks_flags() {
+        // LD BC, 0x12ff; PUSH BC; POP AF
+        let (cpu, _) = run(&[0x01, 0xff, 0x12, 0xc5, 0xf1], 10);
 
-    pub fn rr(&mut self, cycle: u8, op: Op8) {}
+        assert_eq!(cpu.read_reg16(AF), 0x12f0);
+        assert_eq!(cpu.read_reg16(SP), 0xfffe);
+    }
+
+    #[test]
+    fn test_conditional_relative_jump_timing() {
+        // XOR A; JR Z, -3
+        let (cpu, _) = run(&[0xaf, 0x28, 0xfd], 4);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0000);
+
+        // XOR A; JR NZ, -3
+        let (cpu, _) = run(&[0xaf, 0x20, 0xfd], 3);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0003);
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        // CALL 0x0010; at 0x0010: RET
+        let mut program = [0; 0x11];
+        program[..3].copy_from_slice(&[0xcd, 0x10, 0x00]);
+        program[0x10] = 0xc9;
+
+        let (cpu, memory) = run(&program, 6);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0010);
+        assert_eq!(cpu.read_reg16(SP), 0xfffc);
+        assert_eq!(memory.read(0xfffc), 0x03);
+
+        let (cpu, _) = run(&program, 10);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0003);
+        assert_eq!(cpu.read_reg16(SP), 0xfffe);
+    }
 
-    pub fn sla(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_interrupts_enabled_after_instruction_following_ei() {
+        let (cpu, _) = run(&[0xfb, 0x00], 1); // EI; NOP
+        assert!(!cpu.interrupt_master_enable);
 
-    pub fn sra(&mut self, cycle: u8, op: Op8) {}
+        let (cpu, _) = run(&[0xfb, 0x00], 2);
+        assert!(cpu.interrupt_master_enable);
+    }
 
-    pub fn srl(&mut self, cycle: u8, op: Op8) {}
+    #[test]
+    fn test_stop_switches_speed_when_armed() {
+        use crate::cartridge::Cartridge;
+        use crate::cpu::special_registers::KEY1;
+        use crate::model::Model;
 
-    pub fn bit(&mut self, cycle: u8, index: u8, op: Op8) {}
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80; // CGB game
+        rom[0..2].copy_from_slice(&[0x10, 0x00]); // STOP
+        let mut memory = Memory::new(Model::CGB, Cartridge::new(rom).unwrap());
+        let mut cpu = CPU::new();
+        memory.write(KEY1, 0x01);
 
-    pub fn set(&mut self, cycle: u8, index: u8, op: Op8) {}
+        cpu.cycle(&mut memory).unwrap();
 
-    pub fn res(&mut self, cycle: u8, index: u8, op: Op8) {}
+        assert_eq!(cpu.state, CPUState::Fetch);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0002);
+        assert_eq!(memory.read(KEY1), 0xfe);
+    }
+
+    #[test]
+    fn test_stop_without_speed_switch_stops() {
+        let (cpu, _) = run(&[0x10, 0x00], 1);
+        assert_eq!(cpu.state, CPUState::Stopped);
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0002);
+    }
 }
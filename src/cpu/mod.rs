@@ -1,14 +1,39 @@
-mod instr;
+#[cfg(feature = "blocks")]
+pub mod blocks;
+pub(crate) mod instr;
 mod instr_funcs;
+#[cfg(test)]
+mod reference_alu;
+#[cfg(test)]
+mod single_step;
+pub(crate) mod special_registers;
 
-extern crate maplit;
 use crate::cpu::instr::instr::Instr;
-use crate::gameboy::MEMORY_SIZE;
+use crate::cpu::instr::operand::Cond;
+use crate::cpu::special_registers::{IE, IF, P1};
+use crate::error::EmuError;
+use crate::model::Model;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
 
 const INITIAL_PC: u16 = 0x100;
-const INITIAL_SP: u16 = 0xfff;
+const INITIAL_SP: u16 = 0xfffe;
 const REG_8_COUNT: usize = 8;
 const REG_16_COUNT: usize = 2; // SP and PC only
+const INTERRUPT_COUNT: u8 = 5;
+const MAX_INSTR_CYCLES: u128 = 6; // CALL, the longest instruction, takes 6 machine cycles
+
+// the rest of the machine as the CPU sees it, which tests can replace with plain RAM
+pub trait Bus {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // reads without the side effects of the CPU reading, like triggering watchpoints
+    fn peek(&self, address: u16) -> u8;
+    fn speed_switch_armed(&self) -> bool;
+    fn switch_speed(&mut self);
+}
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Reg8 {
@@ -23,19 +48,27 @@ pub enum Reg8 {
 }
 
 impl Reg8 {
-    pub(crate) fn from_index(i: u8) -> Reg8 {
+    // None for index 6, which is (HL) in place of a register
+    pub(crate) const fn from_index(i: u8) -> Option<Reg8> {
         use Reg8::*;
         // i is 3 bits long
-        match i {
-            0b000 => B, // 0
-            0b001 => C, // 1
-            0b010 => D, // 2
-            0b011 => E, // 3
-            0b100 => H, // 4
-            0b101 => L, // 5
-            0b111 => A, // 7
-            _ => panic!("Illegal 8-bit register index {}", i),
-        }
+        return match i & 0b111 {
+            0b000 => Some(B), // 0
+            0b001 => Some(C), // 1
+            0b010 => Some(D), // 2
+            0b011 => Some(E), // 3
+            0b100 => Some(H), // 4
+            0b101 => Some(L), // 5
+            0b111 => Some(A), // 7
+            _ => None,
+        };
+    }
+}
+
+// lower case, as written in assembly
+impl fmt::Display for Reg8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", format!("{:?}", self).to_lowercase());
     }
 }
 
@@ -46,22 +79,26 @@ pub enum Reg16 {
     BC,
     DE,
     HL,
+    AF,
 }
 
 impl Reg16 {
-    pub(crate) fn from_index(i: u8) -> Reg16 {
+    pub(crate) const fn from_index(i: u8) -> Reg16 {
         use Reg16::*;
         // i is 2 bits long
-        match i {
+        return match i & 0b11 {
             0b00 => BC,
             0b01 => DE,
             0b10 => HL,
-            0b11 => SP,
-            _ => panic!("Illegal 16-bit register index {}", i),
-        }
+            _ => SP,
+        };
     }
+}
 
-    pub(crate) fn t() {}
+impl fmt::Display for Reg16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", format!("{:?}", self).to_lowercase());
+    }
 }
 
 // flag bits for the flag register F
@@ -85,33 +122,165 @@ impl Flag {
     }
 }
 
+// interrupt sources, in order of priority
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    // bit in the IE and IF registers
+    pub fn bit(&self) -> u8 {
+        return *self as u8;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CPUState {
     Fetch,
     FetchPrefixed,
     Excute(Instr, u128), // instruction and start cycle
+    Interrupt(u128),     // dispatching to an interrupt handler and start cycle
     Halted,
     Stopped,
+    LockedUp, // after an illegal opcode, until reset
 }
 
+#[derive(Clone)]
 pub struct CPU {
     registers8: [u8; REG_8_COUNT],
     registers16: [u16; REG_16_COUNT],
     pub state: CPUState,
     pub interrupt_master_enable: bool,
+    interrupt_enable_scheduled: bool, // EI takes effect after the following instruction
+    halt_bug: bool,                   // next opcode is read without incrementing PC
+    z: u8,                            // temporary registers used by multi cycle instructions
+    w: u8,
     cycle: u128, // machine cycles
 }
 
 impl CPU {
+    // power-on state, PC starts at the beginning of the boot ROM
     pub fn new() -> CPU {
         return CPU {
             registers8: [0; REG_8_COUNT],
-            registers16: [INITIAL_SP, INITIAL_PC],
+            registers16: [0, 0],
             state: CPUState::Fetch,
-            interrupt_master_enable: true,
+            interrupt_master_enable: false,
+            interrupt_enable_scheduled: false,
+            halt_bug: false,
+            z: 0,
+            w: 0,
             cycle: 0,
         };
     }
 
+    // register state left behind by each model's boot ROM, games check A to detect a CGB
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+    pub fn after_boot(model: Model) -> CPU {
+        use Reg16::*;
+
+        let (af, bc, de, hl) = match model {
+            Model::DMG => (0x01b0, 0x0013, 0x00d8, 0x014d),
+            Model::MGB => (0xffb0, 0x0013, 0x00d8, 0x014d),
+            Model::CGB => (0x1180, 0x0000, 0xff56, 0x000d),
+            Model::SGB => (0x0100, 0x0014, 0x0000, 0xc060),
+        };
+
+        let mut cpu = CPU::new();
+        cpu.write_reg16(AF, af);
+        cpu.write_reg16(BC, bc);
+        cpu.write_reg16(DE, de);
+        cpu.write_reg16(HL, hl);
+        cpu.write_reg16(SP, INITIAL_SP);
+        cpu.write_reg16(PC, INITIAL_PC);
+        return cpu;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        use crate::cpu::CPUState::*;
+
+        state.bytes(&self.registers8);
+        state.u16s(&self.registers16);
+        match self.state {
+            Fetch => state.u8(0),
+            FetchPrefixed => state.u8(1),
+            Excute(instr, start_cycle) => {
+                let (prefixed, opcode) = instr::encode(instr);
+                state.u8(2);
+                state.bool(prefixed);
+                state.u8(opcode);
+                state.u128(start_cycle);
+            }
+            Interrupt(start_cycle) => {
+                state.u8(3);
+                state.u128(start_cycle);
+            }
+            Halted => state.u8(4),
+            Stopped => state.u8(5),
+            LockedUp => state.u8(6),
+        }
+        state.bool(self.interrupt_master_enable);
+        state.bool(self.interrupt_enable_scheduled);
+        state.bool(self.halt_bug);
+        state.u8(self.z);
+        state.u8(self.w);
+        state.u128(self.cycle);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        use crate::cpu::CPUState::*;
+
+        state.bytes(&mut self.registers8)?;
+        state.u16s(&mut self.registers16)?;
+        self.state = match state.u8()? {
+            0 => Fetch,
+            1 => FetchPrefixed,
+            2 => {
+                let prefixed = state.bool()?;
+                let opcode = state.u8()?;
+                let instr = if prefixed {
+                    instr::try_decode_prefixed(opcode)
+                } else {
+                    instr::try_decode_unprefixed(opcode)
+                };
+                let instr = instr.ok_or(format!("Illegal opcode {:#04x} in state", opcode))?;
+                Excute(instr, state.u128()?)
+            }
+            3 => Interrupt(state.u128()?),
+            4 => Halted,
+            5 => Stopped,
+            6 => LockedUp,
+            tag => return Err(format!("Unknown CPU state {} in state", tag)),
+        };
+        self.interrupt_master_enable = state.bool()?;
+        self.interrupt_enable_scheduled = state.bool()?;
+        self.halt_bug = state.bool()?;
+        self.z = state.u8()?;
+        self.w = state.u8()?;
+        self.cycle = state.u128()?;
+
+        // an instruction or interrupt dispatch in progress must have started in its last few cycles
+        if let Excute(_, start_cycle) | Interrupt(start_cycle) = self.state {
+            if start_cycle > self.cycle || self.cycle - start_cycle >= MAX_INSTR_CYCLES {
+                return Err(format!(
+                    "Instruction started at cycle {} in state at cycle {}",
+                    start_cycle, self.cycle
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    // machine cycles run since power on, not counting those VRAM DMA paused the CPU for
+    pub fn cycles(&self) -> u128 {
+        return self.cycle;
+    }
+
     pub fn increment_cycle(&mut self) {
         self.cycle += 1;
     }
@@ -125,11 +294,34 @@ impl CPU {
     }
 
     pub(crate) fn read_reg16(&self, r: Reg16) -> u16 {
-        return self.registers16[r as usize];
+        use Reg16::*;
+
+        let (high, low) = match r {
+            SP | PC => return self.registers16[r as usize],
+            BC => (Reg8::B, Reg8::C),
+            DE => (Reg8::D, Reg8::E),
+            HL => (Reg8::H, Reg8::L),
+            AF => (Reg8::A, Reg8::F),
+        };
+        return u16::from_be_bytes([self.read_reg8(high), self.read_reg8(low)]);
     }
 
     pub(crate) fn write_reg16(&mut self, r: Reg16, value: u16) {
-        self.registers16[r as usize] = value;
+        use Reg16::*;
+
+        let [high, low] = value.to_be_bytes();
+        match r {
+            SP | PC => self.registers16[r as usize] = value,
+            BC => self.write_reg8s(Reg8::B, high, Reg8::C, low),
+            DE => self.write_reg8s(Reg8::D, high, Reg8::E, low),
+            HL => self.write_reg8s(Reg8::H, high, Reg8::L, low),
+            AF => self.write_reg8s(Reg8::A, high, Reg8::F, low & 0xf0), // lower bits of F are always 0
+        }
+    }
+
+    fn write_reg8s(&mut self, r1: Reg8, value1: u8, r2: Reg8, value2: u8) {
+        self.write_reg8(r1, value1);
+        self.write_reg8(r2, value2);
     }
 
     pub(crate) fn set_flag_to(&mut self, flag: Flag, value: bool) {
@@ -148,111 +340,267 @@ impl CPU {
         return (self.read_reg8(Reg8::F) >> index) & 1 == 1;
     }
 
-    pub fn pc_read_next(&mut self, memory: &[u8; MEMORY_SIZE]) -> u8 {
+    pub(crate) fn test_cond(&self, cond: Cond) -> bool {
+        return match cond {
+            Cond::Always => true,
+            Cond::NZ => !self.test_flag(Flag::Z),
+            Cond::Z => self.test_flag(Flag::Z),
+            Cond::NC => !self.test_flag(Flag::C),
+            Cond::C => self.test_flag(Flag::C),
+        };
+    }
+
+    // interrupts which are both requested and enabled, lowest bit has highest priority, which the
+    // CPU sees directly rather than by reading them over the bus
+    fn pending_interrupts(&self, memory: &impl Bus) -> u8 {
+        return memory.peek(IE) & memory.peek(IF) & ((1 << INTERRUPT_COUNT) - 1);
+    }
+
+    // whether the next cycle begins the instruction at PC, rather than continuing one, dispatching
+    // an interrupt or waiting, checked without triggering watchpoints
+    #[cfg(feature = "std")]
+    pub(crate) fn at_instruction_start(&self, memory: &impl Bus) -> bool {
+        if self.state != CPUState::Fetch {
+            return false;
+        }
+        return !(self.interrupt_master_enable && self.pending_interrupts(memory) != 0);
+    }
+
+    pub fn pc_read_next(&mut self, memory: &impl Bus) -> u8 {
         let pc: u16 = self.read_reg16(Reg16::PC);
-        let byte: u8 = memory[pc as usize];
-        self.write_reg16(Reg16::PC, pc + 1);
+        let byte: u8 = memory.read(pc);
+        self.write_reg16(Reg16::PC, pc.wrapping_add(1));
         return byte;
     }
 
-    fn read_next_opcode(&mut self, memory: &[u8; MEMORY_SIZE]) -> u8 {
+    fn read_next_opcode(&mut self, memory: &impl Bus) -> u8 {
         let pc = self.read_reg16(Reg16::PC);
-        let opcode: u8 = memory[pc as usize];
-        self.write_reg16(Reg16::PC, pc + 1);
-        self.increment_cycle();
+        let opcode: u8 = memory.read(pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.write_reg16(Reg16::PC, pc.wrapping_add(1));
+        }
         return opcode;
     }
 
-    // a single machine cycle (4 clock cycles)
-    pub fn cycle(&mut self, memory: &[u8; MEMORY_SIZE]) {
+    // a single machine cycle (4 clock cycles), failing on the cycle an illegal opcode is fetched
+    pub fn cycle(&mut self, memory: &mut impl Bus) -> Result<(), EmuError> {
         use crate::cpu::CPUState::*;
 
-        match self.state {
+        let result = match self.state {
             Fetch => self.fetch(memory),
             FetchPrefixed => self.fetch_prefixed(memory),
-            Excute(instr, start_cycle) => self.execute(instr, start_cycle),
-            Halted => return,
-            Stopped => return, // TODO continue on button press
-        }
+            Excute(instr, start_cycle) => {
+                self.execute(memory, instr, start_cycle);
+                Ok(())
+            }
+            Interrupt(start_cycle) => {
+                self.interrupt(memory, start_cycle);
+                Ok(())
+            }
+            Halted => {
+                self.halted(memory);
+                Ok(())
+            }
+            Stopped => {
+                self.stopped(memory);
+                Ok(())
+            }
+            // like the hardware, nothing more runs and interrupts are ignored
+            LockedUp => Ok(()),
+        };
 
-        /*
-         * TODO if doing last execution cycle, pre-fetch next opcode:
-         * - maybe don't call increment_cycles to emulate this? this would violate the contract that
-         *   this function is a single machine cycle
-         * - make a call to fetch()? probably more accurate to what is actually happening in
-         *   hardware
-         */
         self.increment_cycle();
+        return result;
     }
 
-    fn fetch(&mut self, memory: &[u8; MEMORY_SIZE]) {
-        use crate::cpu::instr::{decode_unprefixed, PREFIX};
+    /*
+     * the opcode fetch is the first machine cycle of every instruction, instructions which need no
+     * further cycles complete within it and so are executed straight away with a relative cycle of 0
+     */
+    fn fetch(&mut self, memory: &mut impl Bus) -> Result<(), EmuError> {
+        use crate::cpu::instr::{try_decode_unprefixed, PREFIX};
+
+        if self.interrupt_master_enable && self.pending_interrupts(memory) != 0 {
+            self.state = CPUState::Interrupt(self.cycle);
+            self.interrupt(memory, self.cycle);
+            return Ok(());
+        }
+
+        if self.interrupt_enable_scheduled {
+            self.interrupt_enable_scheduled = false;
+            self.interrupt_master_enable = true;
+        }
+
+        let pc = self.read_reg16(Reg16::PC);
         let opcode: u8 = self.read_next_opcode(memory);
 
         if opcode == PREFIX {
             self.state = CPUState::FetchPrefixed;
-        } else {
-            self.state = CPUState::Excute(decode_unprefixed(opcode), self.cycle);
+            return Ok(());
         }
+        return match try_decode_unprefixed(opcode) {
+            Some(instr) => {
+                self.begin_execute(memory, instr);
+                Ok(())
+            }
+            None => Err(self.lock_up(pc, opcode)),
+        };
     }
 
-    fn fetch_prefixed(&mut self, memory: &[u8; MEMORY_SIZE]) {
-        use crate::cpu::instr::decode_prefixed;
+    fn fetch_prefixed(&mut self, memory: &mut impl Bus) -> Result<(), EmuError> {
+        use crate::cpu::instr::try_decode_prefixed;
+
+        let pc = self.read_reg16(Reg16::PC);
         let opcode: u8 = self.read_next_opcode(memory);
-        self.state = CPUState::Excute(decode_prefixed(opcode), self.cycle);
+        return match try_decode_prefixed(opcode) {
+            Some(instr) => {
+                self.begin_execute(memory, instr);
+                Ok(())
+            }
+            None => Err(self.lock_up(pc, opcode)),
+        };
+    }
+
+    // the opcodes without instructions (d3, db, dd, e3, e4, eb, ec, ed, f4, fc and fd) hang the
+    // CPU until the Game Boy is reset, while the PPU and the rest of the machine carry on
+    fn lock_up(&mut self, pc: u16, opcode: u8) -> EmuError {
+        self.state = CPUState::LockedUp;
+        return EmuError::IllegalOpcode { pc, opcode };
+    }
+
+    fn begin_execute(&mut self, memory: &mut impl Bus, instr: Instr) {
+        self.state = CPUState::Excute(instr, self.cycle);
+        self.execute(memory, instr, self.cycle);
     }
 
-    fn execute(&mut self, instr: Instr, start_cycle: u128) {
+    pub(crate) fn finish_instr(&mut self) {
+        self.state = CPUState::Fetch;
+    }
+
+    fn execute(&mut self, memory: &mut impl Bus, instr: Instr, start_cycle: u128) {
         use crate::cpu::instr::instr::Instr::*;
 
         let cycle: u8 = (self.cycle - start_cycle) as u8;
 
-        // functions defined in cpu_funcs.rs
+        // functions defined in instr_funcs.rs
         match instr {
             NOP => self.nop(),
             DAA => self.daa(),
             CPL => self.cpl(),
             CCF => self.ccf(),
             SCF => self.scf(),
-            HALT => self.halt(),
-            STOP => self.stop(),
+            HALT => self.halt(memory),
+            STOP => self.stop(memory),
             DI => self.di(),
             EI => self.ei(),
-            LD(op1, op2) => self.ld(cycle, op1, op2),
-            LD16(op1, op2) => self.ld16(cycle, op1, op2),
-            LDH(op1, op2) => self.ldh(cycle, op1, op2),
-            PUSH(op) => self.push(cycle, op),
-            POP(op) => self.pop(cycle, op),
-            ADD(op1, op2) => self.add(cycle, op1, op2),
-            ADD16(op1, op2) => self.add16(cycle, op1, op2),
-            ADC(op1, op2) => self.adc(cycle, op1, op2),
-            SUB(op) => self.sub(cycle, op),
-            SBC(op) => self.sbc(cycle, op),
-            AND(op) => self.and(cycle, op),
-            OR(op) => self.or(cycle, op),
-            XOR(op) => self.xor(cycle, op),
-            CP(op) => self.cp(cycle, op),
-            INC(op) => self.inc(cycle, op),
+            LD(op1, op2) => self.ld(memory, cycle, op1, op2),
+            LD16(op1, op2) => self.ld16(memory, cycle, op1, op2),
+            LDH(op1, op2) => self.ldh(memory, cycle, op1, op2),
+            LDHL => self.ldhl(memory, cycle),
+            PUSH(op) => self.push(memory, cycle, op),
+            POP(op) => self.pop(memory, cycle, op),
+            ADD(op1, op2) => self.add(memory, cycle, op1, op2),
+            ADD16(op1, op2) => self.add16(memory, cycle, op1, op2),
+            ADC(op1, op2) => self.adc(memory, cycle, op1, op2),
+            SUB(op) => self.sub(memory, cycle, op),
+            SBC(op) => self.sbc(memory, cycle, op),
+            AND(op) => self.and(memory, cycle, op),
+            OR(op) => self.or(memory, cycle, op),
+            XOR(op) => self.xor(memory, cycle, op),
+            CP(op) => self.cp(memory, cycle, op),
+            INC(op) => self.inc(memory, cycle, op),
             INC16(op) => self.inc16(cycle, op),
-            DEC(op) => self.dec(cycle, op),
+            DEC(op) => self.dec(memory, cycle, op),
             DEC16(op) => self.dec16(cycle, op),
-            RLC(op) => self.rlc(cycle, op),
-            RL(op) => self.rl(cycle, op),
-            RRC(op) => self.rrc(cycle, op),
-            RR(op) => self.rr(cycle, op),
-            SLA(op) => self.sla(cycle, op),
-            SRA(op) => self.sra(cycle, op),
-            SRL(op) => self.srl(cycle, op),
-            BIT(index, op) => self.bit(cycle, index, op),
-            SET(index, op) => self.set(cycle, index, op),
-            RES(index, op) => self.res(cycle, index, op),
+            RLCA => self.rlca(),
+            RLA => self.rla(),
+            RRCA => self.rrca(),
+            RRA => self.rra(),
+            RLC(op) => self.rlc(memory, cycle, op),
+            RL(op) => self.rl(memory, cycle, op),
+            RRC(op) => self.rrc(memory, cycle, op),
+            RR(op) => self.rr(memory, cycle, op),
+            SLA(op) => self.sla(memory, cycle, op),
+            SRA(op) => self.sra(memory, cycle, op),
+            SRL(op) => self.srl(memory, cycle, op),
+            SWAP(op) => self.swap(memory, cycle, op),
+            BIT(index, op) => self.bit(memory, cycle, index, op),
+            SET(index, op) => self.set(memory, cycle, index, op),
+            RES(index, op) => self.res(memory, cycle, index, op),
+            JP(cond, op) => self.jp(memory, cycle, cond, op),
+            JR(cond, op) => self.jr(memory, cycle, cond, op),
+            CALL(cond, op) => self.call(memory, cycle, cond, op),
+            RET(cond) => self.ret(memory, cycle, cond),
+            RETI => self.reti(memory, cycle),
+            RST(address) => self.rst(memory, cycle, address),
+        }
+    }
+
+    // pushes PC and jumps to the handler of the highest priority interrupt, taking 5 machine cycles
+    fn interrupt(&mut self, memory: &mut impl Bus, start_cycle: u128) {
+        let cycle: u8 = (self.cycle - start_cycle) as u8;
+        let sp = self.read_reg16(Reg16::SP);
+        let [pc_high, pc_low] = self.read_reg16(Reg16::PC).to_be_bytes();
+
+        match cycle {
+            0 => self.interrupt_master_enable = false,
+            1 => self.write_reg16(Reg16::SP, sp.wrapping_sub(1)),
+            2 => {
+                memory.write(sp, pc_high);
+                self.write_reg16(Reg16::SP, sp.wrapping_sub(1));
+                // the interrupt is chosen right after pushing the upper byte of PC, which can
+                // overwrite IE and so cancel dispatch, while pushing the lower byte is too late to
+                let pending = self.pending_interrupts(memory);
+                self.z = if pending == 0 {
+                    0x00
+                } else {
+                    let index = pending.trailing_zeros() as u8;
+                    memory.write(IF, memory.peek(IF) & !(1 << index));
+                    0x40 + 8 * index
+                };
+            }
+            3 => {
+                memory.write(sp, pc_low);
+                self.write_reg16(Reg16::PC, self.z as u16);
+            }
+            _ => self.finish_instr(),
+        }
+    }
+
+    // pressing a selected button ends STOP
+    fn stopped(&mut self, memory: &impl Bus) {
+        if memory.read(P1) & 0x0f != 0x0f {
+            self.state = CPUState::Fetch;
         }
     }
+
+    fn halted(&mut self, memory: &impl Bus) {
+        if self.pending_interrupts(memory) != 0 {
+            self.state = CPUState::Fetch;
+        }
+    }
+
+    pub(crate) fn enter_halt(&mut self, memory: &impl Bus) {
+        if !self.interrupt_master_enable && self.pending_interrupts(memory) != 0 {
+            // HALT is skipped and the following byte is read twice
+            self.halt_bug = true;
+            self.finish_instr();
+        } else {
+            self.state = CPUState::Halted;
+        }
+    }
+
+    pub(crate) fn schedule_interrupt_enable(&mut self) {
+        self.interrupt_enable_scheduled = true;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory::Memory;
 
     #[test]
     fn test_read_and_write_8_bit_reg8() {
@@ -286,4 +634,137 @@ mod tests {
         assert_eq!(cpu.read_reg16(PC), 0xabcd);
         assert_eq!(cpu.read_reg16(SP), 0xef01);
     }
+
+    #[test]
+    fn test_read_and_write_register_pairs() {
+        use Reg16::*;
+
+        let mut cpu = CPU::new();
+
+        cpu.write_reg16(BC, 0x1234);
+        cpu.write_reg16(AF, 0x56ff);
+
+        assert_eq!(cpu.read_reg8(Reg8::B), 0x12);
+        assert_eq!(cpu.read_reg8(Reg8::C), 0x34);
+        assert_eq!(cpu.read_reg16(BC), 0x1234);
+        assert_eq!(cpu.read_reg16(AF), 0x56f0); // lower bits of F are always 0
+    }
+
+    #[test]
+    fn test_power_on_state_starts_at_boot_rom() {
+        let cpu = CPU::new();
+
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0000);
+        assert_eq!(cpu.read_reg16(Reg16::SP), 0x0000);
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn test_post_boot_state_for_dmg() {
+        use Reg16::*;
+
+        let cpu = CPU::after_boot(Model::DMG);
+
+        assert_eq!(cpu.read_reg16(AF), 0x01b0);
+        assert_eq!(cpu.read_reg16(BC), 0x0013);
+        assert_eq!(cpu.read_reg16(DE), 0x00d8);
+        assert_eq!(cpu.read_reg16(HL), 0x014d);
+        assert_eq!(cpu.read_reg16(SP), 0xfffe);
+        assert_eq!(cpu.read_reg16(PC), 0x0100);
+    }
+
+    #[test]
+    fn test_post_boot_state_identifies_model_in_register_a() {
+        assert_eq!(CPU::after_boot(Model::DMG).read_reg8(Reg8::A), 0x01);
+        assert_eq!(CPU::after_boot(Model::MGB).read_reg8(Reg8::A), 0xff);
+        assert_eq!(CPU::after_boot(Model::CGB).read_reg8(Reg8::A), 0x11);
+        assert_eq!(CPU::after_boot(Model::SGB).read_reg8(Reg8::A), 0x01);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_pushes_pc_and_jumps_to_handler() {
+        let mut memory = Memory::with_program(&[]);
+        let mut cpu = CPU::after_boot(Model::DMG);
+        cpu.interrupt_master_enable = true;
+        memory.write(IE, 0b00101);
+        memory.write(IF, 0b00100); // timer
+
+        for _ in 0..5 {
+            cpu.cycle(&mut memory).unwrap();
+        }
+
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0050);
+        assert_eq!(cpu.read_reg16(Reg16::SP), 0xfffc);
+        assert_eq!(memory.read(0xfffd), 0x01);
+        assert_eq!(memory.read(0xfffc), 0x00);
+        assert_eq!(memory.read(IF) & 0x1f, 0);
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    // dispatches the timer interrupt with SP set so that pushing PC writes one of its bytes over IE
+    fn dispatch_pushing_over_ie(sp: u16) -> (CPU, Memory) {
+        let mut memory = Memory::with_program(&[]);
+        let mut cpu = CPU::after_boot(Model::DMG);
+        cpu.interrupt_master_enable = true;
+        cpu.write_reg16(Reg16::SP, sp);
+        memory.write(IE, 0b00100);
+        memory.write(IF, 0b00100); // timer
+
+        for _ in 0..5 {
+            cpu.cycle(&mut memory).unwrap();
+        }
+        return (cpu, memory);
+    }
+
+    #[test]
+    fn test_pushing_pc_high_over_ie_cancels_dispatch() {
+        let (cpu, memory) = dispatch_pushing_over_ie(0x0000); // pushes 0x01
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0000);
+        assert_eq!(memory.peek(IF) & 0x1f, 0b00100);
+    }
+
+    #[test]
+    fn test_pushing_pc_low_over_ie_is_too_late_to_cancel_dispatch() {
+        let (cpu, memory) = dispatch_pushing_over_ie(0x0001); // pushes 0x00
+        assert_eq!(cpu.read_reg16(Reg16::PC), 0x0050);
+        assert_eq!(memory.peek(IE), 0x00);
+        assert_eq!(memory.peek(IF) & 0x1f, 0);
+    }
+
+    #[test]
+    fn test_halt_resumes_when_interrupt_is_requested() {
+        let mut memory = Memory::with_program(&[0x76, 0x3c]); // HALT, INC A
+        let mut cpu = CPU::new();
+        memory.write(IE, 0b00001);
+
+        cpu.cycle(&mut memory).unwrap();
+        cpu.cycle(&mut memory).unwrap();
+        assert_eq!(cpu.state, CPUState::Halted);
+
+        memory.write(IF, 0b00001);
+        cpu.cycle(&mut memory).unwrap();
+        cpu.cycle(&mut memory).unwrap();
+
+        assert_eq!(cpu.read_reg8(Reg8::A), 0x01);
+    }
+
+    #[test]
+    fn test_rejects_state_with_instruction_started_out_of_range() {
+        for (start_cycle, cycle) in [(11, 10), (10, 10 + MAX_INSTR_CYCLES)] {
+            for cpu_state in [
+                CPUState::Excute(Instr::NOP, start_cycle),
+                CPUState::Interrupt(start_cycle),
+            ] {
+                let mut cpu = CPU::new();
+                cpu.state = cpu_state;
+                cpu.cycle = cycle;
+                let mut state = StateWriter::new();
+                cpu.save_state(&mut state);
+                let state = state.finish();
+
+                let result = CPU::new().load_state(&mut StateReader::new(&state));
+                assert!(result.is_err(), "{:?} at {}", cpu_state, cycle);
+            }
+        }
+    }
 }
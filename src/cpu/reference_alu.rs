@@ -0,0 +1,335 @@
+// a second, plainer model of the 8-bit ALU for the tests, written from the SM83 documentation
+// rather than from the CPU's code, which the CPU is checked against over every input and flag
+// combination https://gbdev.io/pandocs/CPU_Instruction_Set.html
+
+const Z: u8 = 0x80;
+const N: u8 = 0x40;
+const H: u8 = 0x20;
+const C: u8 = 0x10;
+
+// the flags register from individual flags
+fn flags(z: bool, n: bool, h: bool, c: bool) -> u8 {
+    return (z as u8 * Z) | (n as u8 * N) | (h as u8 * H) | (c as u8 * C);
+}
+
+// results of an operation, as the new A or operand and the new flags
+type Output = (u8, u8);
+
+pub fn add(a: u8, value: u8, f: u8, with_carry: bool) -> Output {
+    let carry = (with_carry && f & C != 0) as u32;
+    let sum = a as u32 + value as u32 + carry;
+    let half = (a & 0xf) as u32 + (value & 0xf) as u32 + carry;
+    return (
+        sum as u8,
+        flags(sum as u8 == 0, false, half > 0xf, sum > 0xff),
+    );
+}
+
+pub fn sub(a: u8, value: u8, f: u8, with_carry: bool) -> Output {
+    let borrow = (with_carry && f & C != 0) as u32;
+    let taken = value as u32 + borrow;
+    let result = (a as u32).wrapping_sub(taken) as u8;
+    let half_borrow = ((a & 0xf) as u32) < (value & 0xf) as u32 + borrow;
+    return (
+        result,
+        flags(result == 0, true, half_borrow, (a as u32) < taken),
+    );
+}
+
+pub fn and(a: u8, value: u8) -> Output {
+    return (a & value, flags(a & value == 0, false, true, false));
+}
+
+pub fn or(a: u8, value: u8) -> Output {
+    return (a | value, flags(a | value == 0, false, false, false));
+}
+
+pub fn xor(a: u8, value: u8) -> Output {
+    return (a ^ value, flags(a ^ value == 0, false, false, false));
+}
+
+// the flags of subtracting, leaving A alone
+pub fn cp(a: u8, value: u8, f: u8) -> Output {
+    return (a, sub(a, value, f, false).1);
+}
+
+pub fn inc(value: u8, f: u8) -> Output {
+    let result = value.wrapping_add(1);
+    return (
+        result,
+        flags(result == 0, false, value & 0xf == 0xf, f & C != 0),
+    );
+}
+
+pub fn dec(value: u8, f: u8) -> Output {
+    let result = value.wrapping_sub(1);
+    return (
+        result,
+        flags(result == 0, true, value & 0xf == 0, f & C != 0),
+    );
+}
+
+// corrects A after adding or subtracting two binary coded decimal numbers
+pub fn daa(a: u8, f: u8) -> Output {
+    let mut result = a;
+    let mut carry = f & C != 0;
+    if f & N == 0 {
+        if carry || a > 0x99 {
+            result = result.wrapping_add(0x60);
+            carry = true;
+        }
+        if f & H != 0 || a & 0xf > 0x9 {
+            result = result.wrapping_add(0x06);
+        }
+    } else {
+        if carry {
+            result = result.wrapping_sub(0x60);
+        }
+        if f & H != 0 {
+            result = result.wrapping_sub(0x06);
+        }
+    }
+    return (result, flags(result == 0, f & N != 0, false, carry));
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shift {
+    RLC, // rotate left, through bit 0
+    RL,  // rotate left through the carry
+    RRC,
+    RR,
+    SLA, // shift left, filling with 0
+    SRA, // shift right, keeping bit 7
+    SRL, // shift right, filling with 0
+    SWAP,
+}
+
+// the prefixed rotates and shifts, which set Z from the result
+pub fn shift(shift: Shift, value: u8, f: u8) -> Output {
+    let carry_in = f & C != 0;
+    let (result, carry) = match shift {
+        Shift::RLC => (value.rotate_left(1), value & 0x80 != 0),
+        Shift::RL => (value << 1 | carry_in as u8, value & 0x80 != 0),
+        Shift::RRC => (value.rotate_right(1), value & 1 != 0),
+        Shift::RR => (value >> 1 | (carry_in as u8) << 7, value & 1 != 0),
+        Shift::SLA => (value << 1, value & 0x80 != 0),
+        Shift::SRA => ((value as i8 >> 1) as u8, value & 1 != 0),
+        Shift::SRL => (value >> 1, value & 1 != 0),
+        Shift::SWAP => (value.rotate_left(4), false),
+    };
+    return (result, flags(result == 0, false, false, carry));
+}
+
+// RLCA, RLA, RRCA and RRA, which always clear Z
+pub fn shift_a(shift: Shift, a: u8, f: u8) -> Output {
+    let (result, f) = self::shift(shift, a, f);
+    return (result, f & !Z);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instr::operand::Op8;
+    use crate::cpu::{Reg8, CPU};
+    use crate::test_util::TestBus;
+
+    // every value of the flags register, whose lower 4 bits are always 0
+    fn all_flags() -> impl Iterator<Item = u8> {
+        return (0..0x10).map(|f| f << 4);
+    }
+
+    // runs an instruction on A and B with the given flags, returning A, B and the flags after
+    fn execute<F>(
+        cpu: &mut CPU,
+        memory: &mut TestBus,
+        a: u8,
+        b: u8,
+        f: u8,
+        instr: F,
+    ) -> (u8, u8, u8)
+    where
+        F: Fn(&mut CPU, &mut TestBus),
+    {
+        cpu.write_reg8(Reg8::A, a);
+        cpu.write_reg8(Reg8::B, b);
+        cpu.write_reg8(Reg8::F, f);
+        instr(cpu, memory);
+        return (
+            cpu.read_reg8(Reg8::A),
+            cpu.read_reg8(Reg8::B),
+            cpu.read_reg8(Reg8::F),
+        );
+    }
+
+    // checks an instruction on A and B against the model for every A, B and flags
+    fn check_binary<F, M>(name: &str, instr: F, model: M)
+    where
+        F: Fn(&mut CPU, &mut TestBus),
+        M: Fn(u8, u8, u8) -> Output,
+    {
+        let mut cpu = CPU::new();
+        let mut memory = TestBus::new();
+        for a in 0..=0xff {
+            for b in 0..=0xff {
+                for f in all_flags() {
+                    let (result, _, flags) = execute(&mut cpu, &mut memory, a, b, f, &instr);
+                    assert_eq!(
+                        (result, flags),
+                        model(a, b, f),
+                        "{} of {:02x} and {:02x} with flags {:02x}",
+                        name,
+                        a,
+                        b,
+                        f
+                    );
+                }
+            }
+        }
+    }
+
+    // checks an instruction on a single register against the model for every value and flags
+    fn check_unary<F, M>(name: &str, instr: F, model: M, on_a: bool)
+    where
+        F: Fn(&mut CPU, &mut TestBus),
+        M: Fn(u8, u8) -> Output,
+    {
+        let mut cpu = CPU::new();
+        let mut memory = TestBus::new();
+        for value in 0..=0xff {
+            for f in all_flags() {
+                let (a, b, flags) = if on_a {
+                    execute(&mut cpu, &mut memory, value, 0, f, &instr)
+                } else {
+                    execute(&mut cpu, &mut memory, 0, value, f, &instr)
+                };
+                let result = if on_a { a } else { b };
+                assert_eq!(
+                    (result, flags),
+                    model(value, f),
+                    "{} of {:02x} with flags {:02x}",
+                    name,
+                    value,
+                    f
+                );
+            }
+        }
+    }
+
+    const B: Op8 = Op8::Reg(Reg8::B);
+    const A: Op8 = Op8::Reg(Reg8::A);
+
+    type Prefixed = fn(&mut CPU, &mut TestBus, u8, Op8);
+    type OnA = fn(&mut CPU);
+
+    #[test]
+    fn test_add_and_adc() {
+        check_binary(
+            "ADD",
+            |cpu, m| cpu.add(m, 0, A, B),
+            |a, b, f| add(a, b, f, false),
+        );
+        check_binary(
+            "ADC",
+            |cpu, m| cpu.adc(m, 0, A, B),
+            |a, b, f| add(a, b, f, true),
+        );
+    }
+
+    #[test]
+    fn test_sub_sbc_and_cp() {
+        check_binary(
+            "SUB",
+            |cpu, m| cpu.sub(m, 0, B),
+            |a, b, f| sub(a, b, f, false),
+        );
+        check_binary(
+            "SBC",
+            |cpu, m| cpu.sbc(m, 0, B),
+            |a, b, f| sub(a, b, f, true),
+        );
+        check_binary("CP", |cpu, m| cpu.cp(m, 0, B), cp);
+    }
+
+    #[test]
+    fn test_logic() {
+        check_binary("AND", |cpu, m| cpu.and(m, 0, B), |a, b, _| and(a, b));
+        check_binary("OR", |cpu, m| cpu.or(m, 0, B), |a, b, _| or(a, b));
+        check_binary("XOR", |cpu, m| cpu.xor(m, 0, B), |a, b, _| xor(a, b));
+    }
+
+    #[test]
+    fn test_inc_and_dec() {
+        check_unary("INC", |cpu, m| cpu.inc(m, 0, B), inc, false);
+        check_unary("DEC", |cpu, m| cpu.dec(m, 0, B), dec, false);
+    }
+
+    #[test]
+    fn test_daa() {
+        check_unary("DAA", |cpu, _| cpu.daa(), daa, true);
+    }
+
+    #[test]
+    fn test_rotates_and_shifts() {
+        use Shift::*;
+
+        let instrs: [(Shift, Prefixed); 8] = [
+            (RLC, CPU::rlc),
+            (RL, CPU::rl),
+            (RRC, CPU::rrc),
+            (RR, CPU::rr),
+            (SLA, CPU::sla),
+            (SRA, CPU::sra),
+            (SRL, CPU::srl),
+            (SWAP, CPU::swap),
+        ];
+        for &(shift, instr) in instrs.iter() {
+            let name = format!("{:?}", shift);
+            check_unary(
+                &name,
+                |cpu, m| instr(cpu, m, 0, B),
+                |value, f| self::shift(shift, value, f),
+                false,
+            );
+        }
+
+        let instrs: [(Shift, OnA); 4] = [
+            (RLC, CPU::rlca),
+            (RL, CPU::rla),
+            (RRC, CPU::rrca),
+            (RR, CPU::rra),
+        ];
+        for &(shift, instr) in instrs.iter() {
+            let name = format!("{:?}A", shift);
+            check_unary(
+                &name,
+                |cpu, _| instr(cpu),
+                |a, f| shift_a(shift, a, f),
+                true,
+            );
+        }
+    }
+
+    #[test]
+    fn test_daa_makes_sums_and_differences_decimal() {
+        let bcd = |n: u8| ((n / 10) << 4) | (n % 10);
+        for x in 0..100 {
+            for y in 0..100 {
+                let (sum, f) = add(bcd(x), bcd(y), 0, false);
+                assert_eq!(
+                    daa(sum, f),
+                    (
+                        bcd((x + y) % 100),
+                        flags((x + y) % 100 == 0, false, false, x + y >= 100)
+                    )
+                );
+
+                let (difference, f) = sub(bcd(x), bcd(y), 0, false);
+                let expected = (x as i16 - y as i16).rem_euclid(100) as u8;
+                assert_eq!(
+                    daa(difference, f),
+                    (bcd(expected), flags(expected == 0, true, false, x < y))
+                );
+            }
+        }
+    }
+}
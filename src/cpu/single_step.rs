@@ -0,0 +1,269 @@
+// runs single step CPU tests in the JSON format of https://github.com/SingleStepTests/sm83, each
+// giving the state before and after one instruction and the bus access in each machine cycle,
+// starting with the opcode fetch as CPU::cycle does
+
+use crate::cpu::{Bus, CPUState, Reg16, Reg8, CPU};
+use crate::test_util::{Access, TestBus};
+use serde_json::Value;
+
+const REGISTERS: [(&str, Reg8); 8] = [
+    ("a", Reg8::A),
+    ("b", Reg8::B),
+    ("c", Reg8::C),
+    ("d", Reg8::D),
+    ("e", Reg8::E),
+    ("f", Reg8::F),
+    ("h", Reg8::H),
+    ("l", Reg8::L),
+];
+const MAX_CYCLES: usize = 6;
+const IE_ADDRESS: u16 = 0xffff;
+
+// runs the test, failing with everything which differed from it
+pub fn run(test: &Value) -> Result<(), String> {
+    let mut cpu = CPU::new();
+    let mut memory = TestBus::new();
+    load(&mut cpu, &mut memory, &test["initial"])?;
+    memory.take_accesses();
+
+    let mut cycles = vec![];
+    loop {
+        cpu.cycle(&mut memory).map_err(|e| e.to_string())?;
+        cycles.push(memory.take_accesses());
+        let running = matches!(cpu.state, CPUState::Excute(..) | CPUState::FetchPrefixed);
+        if !running || cycles.len() > MAX_CYCLES {
+            break;
+        }
+    }
+
+    let mut errors = compare(&cpu, &memory, &test["final"])?;
+    errors.extend(compare_cycles(&cycles, &test["cycles"])?);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    return Err(errors.join(", "));
+}
+
+fn load(cpu: &mut CPU, memory: &mut TestBus, state: &Value) -> Result<(), String> {
+    for &(name, r) in REGISTERS.iter() {
+        cpu.write_reg8(r, number(&state[name])? as u8);
+    }
+    cpu.write_reg16(Reg16::PC, number(&state["pc"])? as u16);
+    cpu.write_reg16(Reg16::SP, number(&state["sp"])? as u16);
+    cpu.interrupt_master_enable = number(&state["ime"])? != 0;
+    for (address, value) in ram(state)? {
+        memory.write(address, value);
+    }
+    if !state["ie"].is_null() {
+        memory.write(IE_ADDRESS, number(&state["ie"])? as u8);
+    }
+    return Ok(());
+}
+
+// a line for each register and byte of memory which differs from the state
+fn compare(cpu: &CPU, memory: &TestBus, state: &Value) -> Result<Vec<String>, String> {
+    let mut errors = vec![];
+    let mut check = |name: &str, actual: u16, expected: &Value| -> Result<(), String> {
+        let expected = number(expected)? as u16;
+        if actual != expected {
+            errors.push(format!("{} is {:02x} not {:02x}", name, actual, expected));
+        }
+        return Ok(());
+    };
+
+    for &(name, r) in REGISTERS.iter() {
+        check(name, cpu.read_reg8(r) as u16, &state[name])?;
+    }
+    check("pc", cpu.read_reg16(Reg16::PC), &state["pc"])?;
+    check("sp", cpu.read_reg16(Reg16::SP), &state["sp"])?;
+    check("ime", cpu.interrupt_master_enable as u16, &state["ime"])?;
+    for (address, value) in ram(state)? {
+        check(
+            &format!("{:04x}", address),
+            memory.peek(address) as u16,
+            &value.into(),
+        )?;
+    }
+    return Ok(errors);
+}
+
+// a line for each machine cycle whose bus access differs from the test's, which are either null or
+// like [address, value, "r-m"], where r or w at the start says whether it reads or writes
+fn compare_cycles(cycles: &[Vec<Access>], expected: &Value) -> Result<Vec<String>, String> {
+    let expected = expected.as_array().ok_or("cycles is not a list")?;
+    let mut errors = vec![];
+    if cycles.len() != expected.len() {
+        errors.push(format!(
+            "took {} cycles not {}",
+            cycles.len(),
+            expected.len()
+        ));
+    }
+
+    for (i, (accesses, expected)) in cycles.iter().zip(expected.iter()).enumerate() {
+        let expected = match expected {
+            Value::Null => None,
+            Value::Array(cycle) if cycle.len() == 3 => {
+                let address = number(&cycle[0])? as u16;
+                let value = number(&cycle[1]).unwrap_or(0) as u8;
+                match cycle[2].as_str().unwrap_or("").as_bytes() {
+                    [b'r', ..] => Some(Access::Read(address, value)),
+                    [_, b'w', ..] => Some(Access::Write(address, value)),
+                    _ => None,
+                }
+            }
+            _ => return Err(format!("cycle {} is not a bus access", i)),
+        };
+        if accesses.as_slice() != expected.as_slice() {
+            errors.push(format!(
+                "cycle {} accessed {:?} not {:?}",
+                i, accesses, expected
+            ));
+        }
+    }
+    return Ok(errors);
+}
+
+fn ram(state: &Value) -> Result<Vec<(u16, u8)>, String> {
+    let bytes = state["ram"].as_array().ok_or("ram is not a list")?;
+    return bytes
+        .iter()
+        .map(|byte| {
+            return Ok((number(&byte[0])? as u16, number(&byte[1])? as u8));
+        })
+        .collect();
+}
+
+fn number(value: &Value) -> Result<u64, String> {
+    return value
+        .as_u64()
+        .ok_or_else(|| format!("{} is not a number", value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // hardware timings for instructions whose accesses are spread over their cycles
+    const TESTS: &str = r#"[
+        {
+            "name": "36 ld (hl), n",
+            "initial": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 208, "l": 0,
+                "pc": 49152, "sp": 65534, "ime": 0, "ram": [[49152, 54], [49153, 90]]},
+            "final": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 208, "l": 0,
+                "pc": 49154, "sp": 65534, "ime": 0, "ram": [[53248, 90]]},
+            "cycles": [[49152, 54, "r-m"], [49153, 90, "r-m"], [53248, 90, "-wm"]]
+        },
+        {
+            "name": "c5 push bc",
+            "initial": {"a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 49152, "sp": 53248, "ime": 0, "ram": [[49152, 197]]},
+            "final": {"a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 49153, "sp": 53246, "ime": 0, "ram": [[53247, 18], [53246, 52]]},
+            "cycles": [[49152, 197, "r-m"], null, [53247, 18, "-wm"], [53246, 52, "-wm"]]
+        },
+        {
+            "name": "cd call nn",
+            "initial": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 49152, "sp": 53248, "ime": 0,
+                "ram": [[49152, 205], [49153, 52], [49154, 18]]},
+            "final": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 4660, "sp": 53246, "ime": 0, "ram": [[53247, 192], [53246, 3]]},
+            "cycles": [[49152, 205, "r-m"], [49153, 52, "r-m"], [49154, 18, "r-m"],
+                [49154, null, "---"], [53247, 192, "-wm"], [53246, 3, "-wm"]]
+        },
+        {
+            "name": "c9 ret",
+            "initial": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 49152, "sp": 53246, "ime": 0,
+                "ram": [[49152, 201], [53246, 3], [53247, 192]]},
+            "final": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                "pc": 49155, "sp": 53248, "ime": 0, "ram": []},
+            "cycles": [[49152, 201, "r-m"], [53246, 3, "r-m"], [53247, 192, "r-m"], null]
+        },
+        {
+            "name": "20 jr nz, e not taken",
+            "initial": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 128, "h": 0, "l": 0,
+                "pc": 49152, "sp": 65534, "ime": 0, "ram": [[49152, 32], [49153, 254]]},
+            "final": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 128, "h": 0, "l": 0,
+                "pc": 49154, "sp": 65534, "ime": 0, "ram": []},
+            "cycles": [[49152, 32, "r-m"], [49153, 254, "r-m"]]
+        },
+        {
+            "name": "cb 46 bit 0, (hl)",
+            "initial": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 208, "l": 0,
+                "pc": 49152, "sp": 65534, "ime": 0,
+                "ram": [[49152, 203], [49153, 70], [53248, 254]]},
+            "final": {"a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 160, "h": 208, "l": 0,
+                "pc": 49154, "sp": 65534, "ime": 0, "ram": []},
+            "cycles": [[49152, 203, "r-m"], [49153, 70, "r-m"], [53248, 254, "r-m"]]
+        }
+    ]"#;
+
+    fn failures(tests: &Value) -> Vec<String> {
+        return tests
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|test| {
+                let result = run(test);
+                return result.err().map(|e| format!("{}: {}", test["name"], e));
+            })
+            .collect();
+    }
+
+    #[test]
+    fn test_runs_instructions_cycle_by_cycle() {
+        let tests: Value = serde_json::from_str(TESTS).unwrap();
+        assert_eq!(failures(&tests), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_reports_what_differs() {
+        let mut tests: Value = serde_json::from_str(TESTS).unwrap();
+        let test = &mut tests[0];
+        test["final"]["pc"] = 49155.into();
+        test["cycles"][2] = Value::Null;
+
+        assert_eq!(
+            run(test),
+            Err("pc is c002 not c003, \
+                 cycle 2 accessed [Write(53248, 90)] not None"
+                .to_string())
+        );
+    }
+
+    // every file of tests, like 00.json or cb 00.json, in the directory given by SM83_TESTS
+    #[test]
+    fn test_sm83() {
+        let dir = match env::var_os("SM83_TESTS") {
+            Some(dir) => PathBuf::from(dir),
+            None => return eprintln!("Skipping SM83 tests, SM83_TESTS is not set"),
+        };
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json"))
+            .collect();
+        paths.sort();
+
+        let mut failed = 0;
+        for path in paths {
+            let tests: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            let failures = failures(&tests);
+            if let Some(first) = failures.first() {
+                eprintln!(
+                    "{}: {} failed, first {}",
+                    path.display(),
+                    failures.len(),
+                    first
+                );
+            }
+            failed += failures.len();
+        }
+        assert_eq!(failed, 0);
+    }
+}
@@ -0,0 +1,639 @@
+use crate::cpu::instr::instr::Instr;
+use crate::cpu::{CPUState, Reg16, Reg8};
+use crate::disassembler;
+use crate::error::EmuError;
+use crate::gameboy::{GameBoy, STEP_LIMIT};
+use crate::memory::{WatchHit, Watchpoint};
+use std::io;
+use std::io::{BufRead, Write};
+
+const PROMPT: &str = "(gbdb) ";
+const DEFAULT_MEM_LENGTH: usize = 0x40;
+const DEFAULT_DISASM_COUNT: usize = 10;
+const MAX_INSTR_LENGTH: usize = 3;
+const ADDRESS_SPACE: usize = 0x10000; // bytes shown at most, as addresses wrap around after it
+
+const HELP: &str = "\
+step [N]                      run N instructions (default 1)
+next                          run one instruction, stepping over calls
+continue                      run until a breakpoint, watchpoint or lock up
+break ADDR [if REG==VALUE]    stop before the instruction at ADDR, optionally only when a
+                              register has (==) or does not have (!=) the value
+delete ADDR                   remove a breakpoint
+watch ADDR [r|w|rw]           stop after the CPU reads and/or writes ADDR (default w)
+unwatch ADDR                  remove a watchpoint
+regs                          show registers
+mem ADDR [LEN]                show memory
+disasm [ADDR] [COUNT]         disassemble instructions, from PC by default
+quit                          exit
+numbers are decimal, or hexadecimal with a 0x or $ prefix";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    Reg8(Reg8),
+    Reg16(Reg16),
+}
+
+impl Register {
+    fn read(&self, gb: &GameBoy) -> u16 {
+        return match *self {
+            Register::Reg8(r) => gb.cpu.read_reg8(r) as u16,
+            Register::Reg16(r) => gb.cpu.read_reg16(r),
+        };
+    }
+}
+
+// a register compared with a value, like A==0x11
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Condition {
+    register: Register,
+    equal: bool, // == rather than !=
+    value: u16,
+}
+
+impl Condition {
+    fn holds(&self, gb: &GameBoy) -> bool {
+        return (self.register.read(gb) == self.value) == self.equal;
+    }
+}
+
+struct Breakpoint {
+    address: u16,
+    condition: Option<Condition>,
+}
+
+// why running stopped early
+pub(crate) enum Stop {
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Error(EmuError),
+}
+
+// an interactive debugger which stops the CPU at instruction boundaries, between which it is in
+// the Fetch state, and on watched bus accesses
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    last_command: String, // repeated by an empty line
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        return Debugger::new();
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        return Debugger {
+            breakpoints: vec![],
+            last_command: String::new(),
+        };
+    }
+
+    // reads commands until quit or the end of the input
+    pub fn run(
+        &mut self,
+        gb: &mut GameBoy,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        writeln!(output, "{}", self.current_instr(gb))?;
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let command = line.trim();
+            if command == "quit" || command == "q" {
+                break;
+            }
+            let result = self.execute(gb, command);
+            if !result.is_empty() {
+                writeln!(output, "{}", result)?;
+            }
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+        }
+        return Ok(());
+    }
+
+    // runs a single command, returning what to show for it
+    pub fn execute(&mut self, gb: &mut GameBoy, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        return match self.command(gb, &line) {
+            Ok(output) => output,
+            Err(e) => e,
+        };
+    }
+
+    fn command(&mut self, gb: &mut GameBoy, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let args = words.get(1..).unwrap_or(&[]);
+
+        return match words.first().copied().unwrap_or("") {
+            "" => Ok(String::new()),
+            "step" | "s" => {
+                let count = args.first().map_or(Ok(1), |n| parse_number(n))?;
+                for _ in 0..count {
+                    if let Some(stop) = step_instruction(gb)? {
+                        return Ok(self.stopped(gb, stop));
+                    }
+                }
+                Ok(self.current_instr(gb))
+            }
+            "next" | "n" => self.next(gb),
+            "continue" | "c" => self.continue_until(gb, None),
+            "break" | "b" if args.is_empty() => Ok(self.list_breakpoints()),
+            "break" | "b" => {
+                let address = parse_address(args[0])?;
+                let condition = match args.get(1..) {
+                    Some([]) => None,
+                    Some(["if", condition @ ..]) => Some(parse_condition(&condition.concat())?),
+                    _ => return Err(format!("Expected if after {}", args[0])),
+                };
+                self.breakpoints.retain(|b| b.address != address);
+                self.breakpoints.push(Breakpoint { address, condition });
+                Ok(format!("Breakpoint at {:04x}", address))
+            }
+            "delete" | "d" => {
+                let address = parse_address(args.first().ok_or("Expected an address")?)?;
+                let count = self.breakpoints.len();
+                self.breakpoints.retain(|b| b.address != address);
+                if self.breakpoints.len() == count {
+                    return Err(format!("No breakpoint at {:04x}", address));
+                }
+                Ok(String::new())
+            }
+            "watch" | "w" if args.is_empty() => Ok(list_watchpoints(gb)),
+            "watch" | "w" => {
+                let address = parse_address(args[0])?;
+                let (read, write) = match args.get(1).copied().unwrap_or("w") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    access => {
+                        return Err(format!("Unknown access {}, expected r, w or rw", access))
+                    }
+                };
+                gb.memory.add_watchpoint(Watchpoint {
+                    address,
+                    read,
+                    write,
+                });
+                Ok(format!("Watchpoint at {:04x}", address))
+            }
+            "unwatch" => {
+                let address = parse_address(args.first().ok_or("Expected an address")?)?;
+                if !gb.memory.remove_watchpoint(address) {
+                    return Err(format!("No watchpoint at {:04x}", address));
+                }
+                Ok(String::new())
+            }
+            "regs" | "r" => Ok(registers(gb)),
+            "mem" | "m" => {
+                let address = parse_address(args.first().ok_or("Expected an address")?)?;
+                let length = args
+                    .get(1)
+                    .map_or(Ok(DEFAULT_MEM_LENGTH), |n| parse_number(n))?;
+                Ok(dump(gb, address, length))
+            }
+            "disasm" | "x" => {
+                let pc = gb.cpu.read_reg16(Reg16::PC);
+                let address = args.first().map_or(Ok(pc), |a| parse_address(a))?;
+                let count = args
+                    .get(1)
+                    .map_or(Ok(DEFAULT_DISASM_COUNT), |n| parse_number(n))?;
+                Ok(disassemble(gb, address, count))
+            }
+            "help" | "h" => Ok(HELP.to_string()),
+            command => Err(format!("Unknown command {}, try help", command)),
+        };
+    }
+
+    // steps over calls by running until the instruction after them
+    fn next(&mut self, gb: &mut GameBoy) -> Result<String, String> {
+        use crate::cpu::instr::instr::Instr::{CALL, RST};
+
+        let pc = gb.cpu.read_reg16(Reg16::PC);
+        return match decode(gb, pc) {
+            Some(instr @ CALL(_, _)) | Some(instr @ RST(_)) => {
+                self.continue_until(gb, Some(pc.wrapping_add(instr.info().length as u16)))
+            }
+            _ => self.command(gb, "step"),
+        };
+    }
+
+    fn continue_until(&mut self, gb: &mut GameBoy, until: Option<u16>) -> Result<String, String> {
+        loop {
+            if let Some(stop) = step_instruction(gb)? {
+                return Ok(self.stopped(gb, stop));
+            }
+            let pc = gb.cpu.read_reg16(Reg16::PC);
+            if until == Some(pc) {
+                return Ok(self.current_instr(gb));
+            }
+            let hit = self.breakpoints.iter().any(|b| {
+                b.address == pc && b.condition.is_none_or(|condition| condition.holds(gb))
+            });
+            if hit {
+                return Ok(self.stopped(gb, Stop::Breakpoint(pc)));
+            }
+        }
+    }
+
+    fn stopped(&self, gb: &GameBoy, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Breakpoint(address) => format!("Breakpoint at {:04x}", address),
+            Stop::Watchpoint(hit) if hit.write => {
+                format!("Write of {:02x} to {:04x}", hit.value, hit.address)
+            }
+            Stop::Watchpoint(hit) => format!("Read of {:02x} from {:04x}", hit.value, hit.address),
+            Stop::Error(e) => e.to_string(),
+        };
+        return format!("{}\n{}", reason, self.current_instr(gb));
+    }
+
+    // the instruction at PC, or where the CPU is part way through one after a watchpoint
+    fn current_instr(&self, gb: &GameBoy) -> String {
+        let pc = gb.cpu.read_reg16(Reg16::PC);
+        return match gb.cpu.state {
+            CPUState::Fetch => disassemble(gb, pc, 1),
+            CPUState::Halted => format!("Halted at {:04x}", pc),
+            CPUState::Stopped => format!("Stopped at {:04x}", pc),
+            CPUState::LockedUp => format!("Locked up at {:04x}", pc),
+            CPUState::Interrupt(_) => format!("Dispatching an interrupt at {:04x}", pc),
+            CPUState::FetchPrefixed | CPUState::Excute(_, _) => {
+                format!("Part way through an instruction, PC is {:04x}", pc)
+            }
+        };
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let mut lines = vec![];
+        for breakpoint in self.breakpoints.iter() {
+            let mut line = format!("{:04x}", breakpoint.address);
+            if let Some(condition) = breakpoint.condition {
+                let comparison = if condition.equal { "==" } else { "!=" };
+                line += &format!(
+                    " if {}{}{:#x}",
+                    register_name(condition.register),
+                    comparison,
+                    condition.value
+                );
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            return "No breakpoints".to_string();
+        }
+        return lines.join("\n");
+    }
+}
+
+// runs the machine until the CPU starts its next instruction, a watchpoint is hit or the CPU
+// locks up
+pub(crate) fn step_instruction(gb: &mut GameBoy) -> Result<Option<Stop>, String> {
+    let result = gb.step();
+    if let Some(hit) = gb.memory.take_watch_hit() {
+        return Ok(Some(Stop::Watchpoint(hit)));
+    }
+    if let Err(e) = result {
+        return Ok(Some(Stop::Error(e)));
+    }
+    if gb.cpu.state != CPUState::Fetch {
+        return Err(format!(
+            "No instruction ran within {} cycles, PC is {:04x}",
+            STEP_LIMIT,
+            gb.cpu.read_reg16(Reg16::PC)
+        ));
+    }
+    return Ok(None);
+}
+
+// the bytes from an address, read without triggering watchpoints
+fn peek_bytes(gb: &GameBoy, address: u16, length: usize) -> Vec<u8> {
+    return (0..length)
+        .map(|i| gb.memory.peek(address.wrapping_add(i as u16)))
+        .collect();
+}
+
+fn decode(gb: &GameBoy, address: u16) -> Option<Instr> {
+    return disassembler::disassemble(&peek_bytes(gb, address, MAX_INSTR_LENGTH), address)[0].instr;
+}
+
+fn disassemble(gb: &GameBoy, address: u16, count: usize) -> String {
+    let pc = gb.cpu.read_reg16(Reg16::PC);
+    let length = count.saturating_mul(MAX_INSTR_LENGTH).min(ADDRESS_SPACE);
+    let bytes = peek_bytes(gb, address, length);
+    let lines: Vec<String> = disassembler::disassemble(&bytes, address)
+        .iter()
+        .take(count)
+        .map(|line| {
+            let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let marker = if line.address == pc { "=>" } else { "  " };
+            format!(
+                "{} {:04x}: {:<9} {}",
+                marker,
+                line.address,
+                bytes.join(" "),
+                line.text
+            )
+        })
+        .collect();
+    return lines.join("\n");
+}
+
+fn registers(gb: &GameBoy) -> String {
+    let cpu = &gb.cpu;
+    let f = cpu.read_reg8(Reg8::F);
+    let flags: String = ["Z", "N", "H", "C"]
+        .iter()
+        .enumerate()
+        .map(|(i, &flag)| if f & (0x80 >> i) != 0 { flag } else { "-" })
+        .collect();
+    return format!(
+        "A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x} SP={:04x} PC={:04x} \
+         flags={} IME={}",
+        cpu.read_reg8(Reg8::A),
+        f,
+        cpu.read_reg8(Reg8::B),
+        cpu.read_reg8(Reg8::C),
+        cpu.read_reg8(Reg8::D),
+        cpu.read_reg8(Reg8::E),
+        cpu.read_reg8(Reg8::H),
+        cpu.read_reg8(Reg8::L),
+        cpu.read_reg16(Reg16::SP),
+        cpu.read_reg16(Reg16::PC),
+        flags,
+        cpu.interrupt_master_enable as u8
+    );
+}
+
+fn list_watchpoints(gb: &GameBoy) -> String {
+    let watchpoints = gb.memory.watchpoints();
+    if watchpoints.is_empty() {
+        return "No watchpoints".to_string();
+    }
+    let lines: Vec<String> = watchpoints
+        .iter()
+        .map(|w| {
+            let access = match (w.read, w.write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                _ => "w",
+            };
+            format!("{:04x} {}", w.address, access)
+        })
+        .collect();
+    return lines.join("\n");
+}
+
+// 16 bytes per line, read without triggering watchpoints
+fn dump(gb: &GameBoy, address: u16, length: usize) -> String {
+    let length = length.min(ADDRESS_SPACE);
+    let mut lines = vec![];
+    for start in (0..length).step_by(16) {
+        let line_address = address.wrapping_add(start as u16);
+        let bytes: Vec<String> = (start..length.min(start + 16))
+            .map(|i| format!("{:02x}", gb.memory.peek(address.wrapping_add(i as u16))))
+            .collect();
+        lines.push(format!("{:04x}: {}", line_address, bytes.join(" ")));
+    }
+    return lines.join("\n");
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix('$'));
+    let number = match hex {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    return number.map_err(|_| format!("Invalid number {}", text));
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let address = parse_number(text)?;
+    if address > 0xffff {
+        return Err(format!("Address {} is out of range", text));
+    }
+    return Ok(address as u16);
+}
+
+fn parse_register(name: &str) -> Result<Register, String> {
+    return Ok(match name.to_ascii_uppercase().as_str() {
+        "A" => Register::Reg8(Reg8::A),
+        "F" => Register::Reg8(Reg8::F),
+        "B" => Register::Reg8(Reg8::B),
+        "C" => Register::Reg8(Reg8::C),
+        "D" => Register::Reg8(Reg8::D),
+        "E" => Register::Reg8(Reg8::E),
+        "H" => Register::Reg8(Reg8::H),
+        "L" => Register::Reg8(Reg8::L),
+        "AF" => Register::Reg16(Reg16::AF),
+        "BC" => Register::Reg16(Reg16::BC),
+        "DE" => Register::Reg16(Reg16::DE),
+        "HL" => Register::Reg16(Reg16::HL),
+        "SP" => Register::Reg16(Reg16::SP),
+        "PC" => Register::Reg16(Reg16::PC),
+        _ => return Err(format!("Unknown register {}", name)),
+    });
+}
+
+// REG==VALUE or REG!=VALUE, without spaces
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let (register, value, equal) = match (text.split_once("=="), text.split_once("!=")) {
+        (Some((register, value)), _) => (register, value, true),
+        (_, Some((register, value))) => (register, value, false),
+        _ => return Err(format!("Invalid condition {}, expected REG==VALUE", text)),
+    };
+    let register = parse_register(register)?;
+    let value = parse_number(value)?;
+    let limit = match register {
+        Register::Reg8(_) => 0xff,
+        Register::Reg16(_) => 0xffff,
+    };
+    if value > limit {
+        return Err(format!(
+            "Value {:#x} does not fit in {}",
+            value,
+            register_name(register)
+        ));
+    }
+    return Ok(Condition {
+        register,
+        equal,
+        value: value as u16,
+    });
+}
+
+fn register_name(register: Register) -> String {
+    return match register {
+        Register::Reg8(r) => format!("{:?}", r),
+        Register::Reg16(r) => format!("{:?}", r),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{counting_loop, rom_with_program};
+
+    fn pc(gb: &GameBoy) -> u16 {
+        return gb.cpu.read_reg16(Reg16::PC);
+    }
+
+    #[test]
+    fn test_steps_instructions() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut gb, "step");
+        assert_eq!(pc(&gb), 0x102);
+        assert_eq!(
+            debugger.execute(&mut gb, "step 2"),
+            "=> 0106: cd 00 02  call $0200"
+        );
+        debugger.execute(&mut gb, "s");
+        assert_eq!(pc(&gb), 0x200);
+        // an empty line repeats the last command
+        debugger.execute(&mut gb, "");
+        assert_eq!(pc(&gb), 0x201);
+    }
+
+    #[test]
+    fn test_steps_over_calls() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut gb, "step 3");
+
+        debugger.execute(&mut gb, "next");
+
+        assert_eq!(pc(&gb), 0x109);
+        assert_eq!(gb.cpu.read_reg8(Reg8::B), 0x11);
+    }
+
+    #[test]
+    fn test_stops_at_conditional_breakpoints() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut gb, "break 0x106 if A == 0x13");
+
+        let output = debugger.execute(&mut gb, "continue");
+
+        assert_eq!(output, "Breakpoint at 0106\n=> 0106: cd 00 02  call $0200");
+        assert_eq!(gb.cpu.read_reg8(Reg8::A), 0x13);
+        assert_eq!(debugger.execute(&mut gb, "break"), "0106 if A==0x13");
+
+        debugger.execute(&mut gb, "delete 0x106");
+        debugger.execute(&mut gb, "break $200 if B!=0x12");
+        debugger.execute(&mut gb, "c");
+        assert_eq!(pc(&gb), 0x200);
+        assert_eq!(gb.cpu.read_reg8(Reg8::A), 0x14);
+    }
+
+    #[test]
+    fn test_stops_at_watched_accesses() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut gb, "watch 0xc000 w");
+
+        let output = debugger.execute(&mut gb, "continue");
+        assert!(output.starts_with("Write of 11 to c000\n"));
+
+        debugger.execute(&mut gb, "unwatch 0xc000");
+        debugger.execute(&mut gb, "watch 0x200 r");
+        debugger.execute(&mut gb, "continue");
+        assert_eq!(gb.memory.peek(0xc000), 0x11);
+        assert_eq!(pc(&gb), 0x201);
+    }
+
+    #[test]
+    fn test_stops_when_the_cpu_locks_up() {
+        let mut gb = rom_with_program(&[0x3c, 0xdd]); // INC A, illegal
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(&mut gb, "continue"),
+            "Illegal opcode 0xdd at 0101 locked up the CPU\nLocked up at 0102"
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "step"),
+            "The CPU is locked up, PC is 0102\nLocked up at 0102"
+        );
+    }
+
+    #[test]
+    fn test_shows_state() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(&mut gb, "mem 0x100 4"),
+            "0100: 3e 10 3c ea"
+        );
+        assert!(debugger
+            .execute(&mut gb, "regs")
+            .contains("SP=fffe PC=0100 flags=Z-HC"));
+        assert_eq!(
+            debugger.execute(&mut gb, "disasm 0x100 3"),
+            "=> 0100: 3e 10     ld a, $10\n   0102: 3c        inc a\n   \
+             0103: ea 00 c0  ld [$c000], a"
+        );
+    }
+
+    #[test]
+    fn test_shows_no_more_than_the_address_space() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+
+        let memory = debugger.execute(&mut gb, "mem 0xfff0 0xffffffffffffffff");
+        assert_eq!(memory.lines().count(), ADDRESS_SPACE / 16);
+        assert!(memory.ends_with("\nffe0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"));
+        let listing = debugger.execute(&mut gb, "disasm 0 18446744073709551615");
+        assert!(listing.starts_with("   0000: "));
+        assert!(listing.lines().count() <= ADDRESS_SPACE);
+    }
+
+    #[test]
+    fn test_reports_invalid_commands() {
+        let mut gb = counting_loop();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(&mut gb, "break 0x150 if Q==1"),
+            "Unknown register Q"
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "break 0x150 if A==0x100"),
+            "Value 0x100 does not fit in A"
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "mem 0x10000"),
+            "Address 0x10000 is out of range"
+        );
+        assert_eq!(
+            debugger.execute(&mut gb, "jump"),
+            "Unknown command jump, try help"
+        );
+    }
+
+    #[test]
+    fn test_runs_commands_from_input() {
+        let mut gb = counting_loop();
+        let mut output = vec![];
+
+        Debugger::new()
+            .run(&mut gb, "step\nquit\nstep\n".as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "=> 0100: 3e 10     ld a, $10\n(gbdb) => 0102: 3c        inc a\n(gbdb) "
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use crate::cpu::instr::instr::Instr;
+use crate::cpu::instr::operand::{Immediate, Op16};
+use crate::cpu::instr::{try_decode_prefixed, try_decode_unprefixed, PREFIX};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// a decoded instruction in RGBDS syntax, or a byte which does not start one
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))] // only read by the debugger
+    pub(crate) instr: Option<Instr>, // None for illegal opcodes and instructions cut off by the end
+    pub target: Option<u16>, // where a jump or call goes
+    pub text: String,
+}
+
+// decodes consecutive instructions from bytes loaded at the given address, with immediates and
+// jump targets written as hexadecimal values
+pub fn disassemble(bytes: &[u8], base_addr: u16) -> Vec<Line> {
+    return disassemble_with(bytes, base_addr, &hex_address);
+}
+
+// a listing which can be assembled again, with labels at every jump and call target within it
+pub fn listing(bytes: &[u8], base_addr: u16) -> String {
+    let lines = disassemble(bytes, base_addr);
+    let starts: BTreeSet<u16> = lines.iter().map(|line| line.address).collect();
+    let targets: BTreeSet<u16> = lines
+        .iter()
+        .filter_map(|line| line.target)
+        .filter(|target| starts.contains(target))
+        .collect();
+    let name = |address: u16| -> String {
+        if targets.contains(&address) {
+            return label(address);
+        }
+        return hex_address(address);
+    };
+
+    let mut listing = String::new();
+    for line in disassemble_with(bytes, base_addr, &name) {
+        if targets.contains(&line.address) {
+            listing += &format!("{}:\n", label(line.address));
+        }
+        let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        listing += &format!(
+            "    {:<24}; {:04x}: {}\n",
+            line.text,
+            line.address,
+            bytes.join(" ")
+        );
+    }
+    return listing;
+}
+
+fn label(address: u16) -> String {
+    return format!("L{:04x}", address);
+}
+
+fn hex_address(address: u16) -> String {
+    return format!("${:04x}", address);
+}
+
+fn decode(bytes: &[u8]) -> Option<Instr> {
+    return match *bytes.first()? {
+        PREFIX => try_decode_prefixed(*bytes.get(1)?),
+        opcode => try_decode_unprefixed(opcode),
+    };
+}
+
+// addresses are written by the given function, so that they can be replaced by labels
+fn disassemble_with(bytes: &[u8], base_addr: u16, name: &dyn Fn(u16) -> String) -> Vec<Line> {
+    use crate::cpu::instr::instr::Instr::{CALL, JP, JR};
+
+    let mut lines = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = base_addr.wrapping_add(offset as u16);
+        let instr = decode(&bytes[offset..])
+            .filter(|instr| offset + instr.info().length as usize <= bytes.len());
+        let length = instr.map_or(1, |instr| instr.info().length as usize);
+        let next = address.wrapping_add(length as u16);
+
+        // immediates follow the opcode, little endian
+        let immediates = match bytes[offset] {
+            PREFIX => &[][..],
+            _ => &bytes[offset + 1..offset + length],
+        };
+        let value = immediates
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u16);
+        let target = match instr {
+            Some(JR(_, _)) => Some(next.wrapping_add(value as u8 as i8 as u16)),
+            Some(JP(_, Op16::NN)) | Some(CALL(_, _)) => Some(value),
+            _ => None,
+        };
+
+        let text = match instr {
+            Some(instr) => instr.format(&|immediate| match immediate {
+                Immediate::N8 => format!("${:02x}", value),
+                Immediate::N16 => format!("${:04x}", value),
+                Immediate::HighAddress => hex_address(0xff00 | value),
+                Immediate::Address => name(value),
+                Immediate::Offset => format!("{}", value as u8 as i8),
+                Immediate::Target => name(target.unwrap()),
+            }),
+            None => format!("db ${:02x}", bytes[offset]),
+        };
+
+        lines.push(Line {
+            address,
+            bytes: bytes[offset..offset + length].to_vec(),
+            instr,
+            target,
+            text,
+        });
+        offset += length;
+    }
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instr::{decode_prefixed, decode_unprefixed};
+
+    fn text(bytes: &[u8]) -> Vec<String> {
+        return disassemble(bytes, 0x150)
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+    }
+
+    #[test]
+    fn test_formats_instructions_in_rgbds_syntax() {
+        assert_eq!(decode_unprefixed(0x2a).to_string(), "ld a, [hl+]");
+        assert_eq!(decode_unprefixed(0xe2).to_string(), "ldh [c], a");
+        assert_eq!(decode_prefixed(0x5e).to_string(), "bit 3, [hl]");
+        assert_eq!(decode_unprefixed(0xe0).to_string(), "ldh [a8], a");
+        assert_eq!(decode_unprefixed(0x08).to_string(), "ld [a16], sp");
+        assert_eq!(decode_unprefixed(0xf8).to_string(), "ld hl, sp+e8");
+        assert_eq!(decode_unprefixed(0xc2).to_string(), "jp nz, a16");
+        assert_eq!(decode_unprefixed(0xe9).to_string(), "jp hl");
+        assert_eq!(decode_unprefixed(0xd8).to_string(), "ret c");
+        assert_eq!(decode_unprefixed(0x9e).to_string(), "sbc a, [hl]");
+        assert_eq!(decode_unprefixed(0xff).to_string(), "rst $38");
+    }
+
+    #[test]
+    fn test_resolves_immediates_and_targets() {
+        let bytes = [
+            0x3e, 0x10, //       ld a, $10
+            0x01, 0x34, 0x12, // ld bc, $1234
+            0xf0, 0x44, //       ldh a, [$ff44]
+            0xe8, 0xfe, //       add sp, -2
+            0xf8, 0x02, //       ld hl, sp+2
+            0x20, 0xf3, //       jr nz, $0150
+            0xcd, 0x00, 0x40, // call $4000
+            0xcb, 0x7c, //       bit 7, h
+            0x10, 0x00, //       stop
+            0xd3, //             illegal
+            0xc3, 0x50, //       cut off, leaving ld d, b
+        ];
+
+        assert_eq!(
+            text(&bytes),
+            [
+                "ld a, $10",
+                "ld bc, $1234",
+                "ldh a, [$ff44]",
+                "add sp, -2",
+                "ld hl, sp+2",
+                "jr nz, $0150",
+                "call $4000",
+                "bit 7, h",
+                "stop",
+                "db $d3",
+                "db $c3",
+                "ld d, b",
+            ]
+        );
+        let lines = disassemble(&bytes, 0x150);
+        assert_eq!(lines[5].address, 0x15b);
+        assert_eq!(lines[5].target, Some(0x150));
+        assert_eq!(lines[7].bytes, [0xcb, 0x7c]);
+        assert_eq!(lines[8].bytes, [0x10, 0x00]);
+    }
+
+    #[test]
+    fn test_labels_targets_in_listing() {
+        let bytes = [
+            0x3c, //             inc a
+            0xc2, 0x00, 0x01, // jp nz, L0100
+            0x18, 0xfe, //       jr L0104
+        ];
+
+        assert_eq!(
+            listing(&bytes, 0x100),
+            "L0100:\n    inc a                   ; 0100: 3c\n    \
+             jp nz, L0100            ; 0101: c2 00 01\n\
+             L0104:\n    jr L0104                ; 0104: 18 fe\n"
+        );
+    }
+}
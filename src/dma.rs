@@ -0,0 +1,108 @@
+use crate::save_state::{StateReader, StateWriter};
+use alloc::string::String;
+
+const OAM_SIZE: u8 = 0xa0;
+const ECHO_OFFSET: u16 = 0x2000;
+
+// OAM DMA, started by writing the source's upper byte to DMA and copying a byte per machine cycle
+// after a cycle of setup, during which the CPU cannot use OAM
+// https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+#[derive(Clone)]
+pub struct DMA {
+    source: u16,
+    offset: u8,     // of the next byte in OAM, OAM_SIZE when idle
+    starting: bool, // spending the setup cycle before the first byte
+}
+
+impl DMA {
+    pub fn new() -> DMA {
+        return DMA {
+            source: 0,
+            offset: OAM_SIZE,
+            starting: false,
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u8(self.offset);
+        state.bool(self.starting);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.source = state.u16()?;
+        self.offset = state.u8()?.min(OAM_SIZE);
+        self.starting = state.bool()?;
+        return Ok(());
+    }
+
+    // restarts any transfer already in progress
+    pub fn start(&mut self, value: u8) {
+        // sources above work RAM read its echo
+        let source = (value as u16) << 8;
+        self.source = if source >= 0xe000 {
+            source - ECHO_OFFSET
+        } else {
+            source
+        };
+        self.offset = 0;
+        self.starting = true;
+    }
+
+    // whether OAM is being written, which keeps the CPU from reading or writing it
+    pub fn active(&self) -> bool {
+        return self.offset < OAM_SIZE && !self.starting;
+    }
+
+    // whether no transfer is starting or in progress
+    #[cfg(feature = "blocks")]
+    pub fn idle(&self) -> bool {
+        return self.offset == OAM_SIZE && !self.starting;
+    }
+
+    // source address and OAM offset of the byte to copy this cycle, if any
+    pub fn next_byte(&mut self) -> Option<(u16, u16)> {
+        if self.starting {
+            self.starting = false;
+            return None;
+        }
+        if self.offset == OAM_SIZE {
+            return None;
+        }
+
+        let next = (self.source + self.offset as u16, self.offset as u16);
+        self.offset += 1;
+        return Some(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copies_a_byte_per_cycle_after_setup() {
+        let mut dma = DMA::new();
+        dma.start(0xc1);
+
+        assert!(!dma.active());
+        assert_eq!(dma.next_byte(), None);
+        assert!(dma.active());
+        assert_eq!(dma.next_byte(), Some((0xc100, 0)));
+        assert_eq!(dma.next_byte(), Some((0xc101, 1)));
+        for _ in 2..OAM_SIZE {
+            assert!(dma.next_byte().is_some());
+        }
+        assert!(!dma.active());
+        assert_eq!(dma.next_byte(), None);
+    }
+
+    #[test]
+    fn test_sources_past_work_ram_read_its_echo() {
+        let mut dma = DMA::new();
+        dma.start(0xfe);
+        dma.next_byte();
+
+        assert_eq!(dma.next_byte(), Some((0xde00, 0)));
+    }
+}
@@ -0,0 +1,30 @@
+use alloc::string::String;
+use core::error::Error;
+use core::fmt;
+
+// why emulation stopped somewhere a real Game Boy would have carried on or hung, or why the
+// emulator would not take something it was given, which minor versions may add variants to
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum EmuError {
+    IllegalOpcode { pc: u16, opcode: u8 }, // the opcode has no instruction, and locked up the CPU
+    LockedUp { pc: u16 },                  // the CPU is still locked up, PC is past the opcode
+    BadCartridge(String),
+    BadState(String), // a save state, movie or rewind buffer which is corrupt or does not fit
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            EmuError::IllegalOpcode { pc, opcode } => write!(
+                f,
+                "Illegal opcode {:#04x} at {:04x} locked up the CPU",
+                opcode, pc
+            ),
+            EmuError::LockedUp { pc } => write!(f, "The CPU is locked up, PC is {:04x}", pc),
+            EmuError::BadCartridge(reason) | EmuError::BadState(reason) => write!(f, "{}", reason),
+        };
+    }
+}
+
+impl Error for EmuError {}
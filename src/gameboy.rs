@@ -1,17 +1,480 @@
-use crate::cpu::CPU;
+use crate::cartridge::Cartridge;
+#[cfg(feature = "blocks")]
+use crate::cpu::blocks::BlockCache;
+use crate::cpu::{CPUState, Reg16, CPU};
+use crate::error::EmuError;
+use crate::joypad::Button;
+use crate::memory::Memory;
+use crate::model::Model;
+use crate::ppu::{Mode, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::save_state::{StateReader, StateWriter, MAGIC, VERSION};
+use crate::sgb::{BORDER_HEIGHT, BORDER_WIDTH};
+#[cfg(feature = "std")]
+use crate::trace::Tracer;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
-pub const MEMORY_SIZE: usize = 0x10000;
+// machine cycles in a frame at normal speed, which is how long a frame lasts with the LCD off
+pub(crate) const CYCLES_PER_FRAME: usize = 70224 / 4;
+// machine cycles to wait for the next instruction, which never comes if the CPU halts with
+// interrupts disabled
+pub(crate) const STEP_LIMIT: usize = 1 << 20;
+
+// what running the machine for a while ended with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    FrameReady, // the frame buffer holds a complete frame to show
+    CyclesDone, // ran for as long as asked without completing a frame
+}
 
 pub struct GameBoy {
-    pub memory: [u8; MEMORY_SIZE],
-    pub cpu: CPU,
+    pub(crate) memory: Memory,
+    pub(crate) cpu: CPU,
+    #[cfg(feature = "std")]
+    tracer: Option<Tracer>,
+    #[cfg(feature = "blocks")]
+    blocks: Option<BlockCache>, // when running straight line code a whole instruction at a time
 }
 
 impl GameBoy {
-    pub fn new() -> GameBoy {
+    // starts from the state the boot ROM would have left behind
+    pub fn new(model: Model, cartridge: Cartridge) -> GameBoy {
+        let mut memory = Memory::new(model, cartridge);
+        memory.write_post_boot_io(model);
         return GameBoy {
-            memory: [0; MEMORY_SIZE],
+            memory,
+            cpu: CPU::after_boot(model),
+            #[cfg(feature = "std")]
+            tracer: None,
+            #[cfg(feature = "blocks")]
+            blocks: None,
+        };
+    }
+
+    // starts from power-on with the boot ROM mapped over the cartridge
+    pub fn with_boot_rom(model: Model, cartridge: Cartridge, boot_rom: Vec<u8>) -> GameBoy {
+        return GameBoy {
+            memory: Memory::with_boot_rom(model, cartridge, boot_rom),
             cpu: CPU::new(),
+            #[cfg(feature = "std")]
+            tracer: None,
+            #[cfg(feature = "blocks")]
+            blocks: None,
         };
     }
+
+    // a single machine cycle, which the rest of the machine completes even if the CPU locks up
+    pub fn cycle(&mut self) -> Result<(), EmuError> {
+        let mut result = Ok(());
+        // VRAM DMA halts the CPU while it copies
+        if !self.memory.hdma_active() {
+            #[cfg(feature = "std")]
+            if let Some(tracer) = &mut self.tracer {
+                if self.cpu.at_instruction_start(&self.memory) {
+                    tracer.trace(&self.cpu, &self.memory);
+                }
+            }
+            result = self.cpu.cycle(&mut self.memory);
+        }
+        let cpu_halted = matches!(self.cpu.state, CPUState::Halted | CPUState::Stopped);
+        self.memory.cycle(cpu_halted);
+        return result;
+    }
+
+    // runs until the CPU starts its next instruction, stopping early when a watchpoint is hit or
+    // after STEP_LIMIT cycles without the CPU leaving HALT or STOP
+    pub fn step(&mut self) -> Result<(), EmuError> {
+        if self.cpu.state == CPUState::LockedUp {
+            return Err(EmuError::LockedUp {
+                pc: self.cpu.read_reg16(Reg16::PC),
+            });
+        }
+
+        let mut cpu_ran = false;
+        for _ in 0..STEP_LIMIT {
+            // the CPU is paused during general purpose and HBlank VRAM DMA
+            cpu_ran |= !self.memory.hdma_active();
+            self.cycle()?;
+            #[cfg(feature = "std")]
+            if self.memory.watch_hit_pending() {
+                break;
+            }
+            if cpu_ran && self.cpu.state == CPUState::Fetch {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    // runs frames through the block cache from now on, or no longer, which gives the same results
+    // faster where the cache can be used
+    #[cfg(feature = "blocks")]
+    pub fn use_blocks(&mut self, enabled: bool) {
+        self.blocks = enabled.then(BlockCache::new);
+    }
+
+    // logs every instruction from now on
+    #[cfg(feature = "std")]
+    pub fn trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    #[cfg(feature = "std")]
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        return self.tracer.take();
+    }
+
+    // runs until the PPU enters VBlank, giving up after as long as a frame would take as it never
+    // does while the LCD is off
+    pub fn run_until_vblank(&mut self) -> Result<Event, EmuError> {
+        let cycles = if self.memory.double_speed() {
+            CYCLES_PER_FRAME * 2
+        } else {
+            CYCLES_PER_FRAME
+        };
+
+        let mut result = Ok(());
+        let mut ran = 0;
+        while ran < cycles {
+            let (cycles_run, vblank) = self.run_into_vblank(&mut result, cycles - ran);
+            if vblank {
+                return result.map(|_| Event::FrameReady);
+            }
+            ran += cycles_run;
+        }
+        return result.map(|_| Event::CyclesDone);
+    }
+
+    // runs for a frame, which is blank while the LCD is off
+    pub fn run_frame(&mut self) -> Result<Event, EmuError> {
+        return self.run_until_vblank().map(|_| Event::FrameReady);
+    }
+
+    // a cycle, or as many whole instructions of no more than budget cycles as the block cache can
+    // run, which keeps the first error in result so that the machine carries on through a lock up
+    // to the end of the frame, returning the cycles run and whether the PPU entered VBlank
+    fn run_into_vblank(
+        &mut self,
+        result: &mut Result<(), EmuError>,
+        budget: usize,
+    ) -> (usize, bool) {
+        // nothing but the CPU does anything during instructions from the block cache
+        #[cfg(feature = "blocks")]
+        match self.run_blocks(budget) {
+            0 => {}
+            cycles => return (cycles, false),
+        }
+        #[cfg(not(feature = "blocks"))]
+        let _ = budget;
+
+        let was_vblank = self.memory.ppu.mode() == Mode::VBlank;
+        let cycled = self.cycle();
+        if result.is_ok() {
+            *result = cycled;
+        }
+        return (1, !was_vblank && self.memory.ppu.mode() == Mode::VBlank);
+    }
+
+    #[cfg(feature = "blocks")]
+    fn run_blocks(&mut self, budget: usize) -> usize {
+        let blocks = match &mut self.blocks {
+            Some(blocks) => blocks,
+            None => return 0,
+        };
+        #[cfg(feature = "std")]
+        let tracer = &mut self.tracer;
+        let trace = |cpu: &CPU, memory: &Memory| {
+            #[cfg(feature = "std")]
+            if let Some(tracer) = tracer {
+                tracer.trace(cpu, memory);
+            }
+            #[cfg(not(feature = "std"))]
+            let _ = (cpu, memory);
+        };
+        return blocks.run(&mut self.cpu, &mut self.memory, budget as u128, trace) as usize;
+    }
+}
+
+// input, output and save states for frontends
+impl GameBoy {
+    // runs for the given number of machine cycles, to keep pace with something like an audio
+    // device, telling whether a frame completed meanwhile
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<Event, EmuError> {
+        let mut result = Ok(());
+        let mut event = Event::CyclesDone;
+        let mut ran = 0;
+        while ran < cycles {
+            let (cycles_run, vblank) = self.run_into_vblank(&mut result, cycles - ran);
+            if vblank {
+                event = Event::FrameReady;
+            }
+            ran += cycles_run;
+        }
+        return result.map(|_| event);
+    }
+
+    // interleaved left and right samples at apu::SAMPLE_RATE, mixed since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        return self.memory.take_audio_samples();
+    }
+
+    // keeps what the game sends over the link port, which is how many test ROMs print
+    pub fn record_serial_output(&mut self) {
+        self.memory.record_serial_output();
+    }
+
+    pub fn serial_output(&self) -> &[u8] {
+        return self.memory.serial_output();
+    }
+
+    pub fn model(&self) -> Model {
+        return self.memory.model();
+    }
+
+    pub fn cartridge_checksum(&self) -> [u8; 3] {
+        return self.memory.cartridge_checksum();
+    }
+
+    // RGB555 pixels, with red in the lowest bits
+    pub fn frame_buffer(&self) -> &[u16; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        return self.memory.ppu.frame_buffer();
+    }
+
+    // the coloured screen inside its border, only when emulating a Super Game Boy
+    pub fn sgb_frame_buffer(&self) -> Option<&[u16; BORDER_WIDTH * BORDER_HEIGHT]> {
+        return self.memory.sgb.as_ref().map(|sgb| sgb.frame_buffer());
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.memory.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.memory.release(button);
+    }
+
+    // one bit per button held, in Button::bit order
+    pub fn buttons(&self) -> u8 {
+        return self.memory.buttons();
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.memory.set_buttons(buttons);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.bytes(MAGIC);
+        state.u32(VERSION);
+        state.bytes(&self.memory.cartridge_checksum());
+        self.cpu.save_state(&mut state);
+        self.memory.save_state(&mut state);
+        return state.finish();
+    }
+
+    // the machine is left untouched if the state cannot be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        return self.read_state(data).map_err(EmuError::BadState);
+    }
+
+    // loads the state, leaving the machine as it was if the state is rejected
+    fn read_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+
+        let mut magic = [0; 4];
+        state.bytes(&mut magic)?;
+        if magic != *MAGIC {
+            return Err("Not a save state".to_string());
+        }
+        let version = state.u32()?;
+        if version != VERSION {
+            return Err(format!("Unsupported save state version {}", version));
+        }
+        let mut checksum = [0; 3];
+        state.bytes(&mut checksum)?;
+        if checksum != self.memory.cartridge_checksum() {
+            return Err("State is for a different game".to_string());
+        }
+
+        let mut cpu = self.cpu.clone();
+        let mut memory = self.memory.clone();
+        cpu.load_state(&mut state)?;
+        memory.load_state(&mut state)?;
+        state.finish()?;
+
+        self.cpu = cpu;
+        self.memory = memory;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::special_registers::{LCDC, LY};
+    use crate::test_util::rom_with;
+
+    // a game which keeps writing to work RAM, so that its state changes every cycle
+    fn gameboy(model: Model, global_checksum: u8) -> GameBoy {
+        // LD HL, 0xc000; loop: INC A; LD (HL+), A; SWAP A; JP loop
+        let program = [0x21, 0x00, 0xc0, 0x3c, 0x22, 0xcb, 0x37, 0xc3, 0x03, 0x01];
+        return rom_with(model, &[(0x100, &program), (0x14f, &[global_checksum])]);
+    }
+
+    fn run(gb: &mut GameBoy, cycles: usize) {
+        for _ in 0..cycles {
+            gb.cycle().unwrap();
+        }
+    }
+
+    fn locking_up(model: Model) -> GameBoy {
+        return rom_with(model, &[(0x100, &[0x00, 0x3c, 0xd3])]); // NOP, INC A, illegal
+    }
+
+    #[test]
+    fn test_steps_whole_instructions() {
+        let mut gb = gameboy(Model::DMG, 0);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.read_reg16(Reg16::PC), 0x103);
+        assert_eq!(gb.cpu.state, CPUState::Fetch);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.read_reg16(Reg16::PC), 0x104);
+    }
+
+    #[test]
+    fn test_runs_cycles_and_frames() {
+        let mut gb = gameboy(Model::DMG, 0);
+        assert_eq!(gb.run_cycles(10), Ok(Event::CyclesDone));
+        assert_eq!(gb.run_until_vblank(), Ok(Event::FrameReady));
+        assert_eq!(gb.run_cycles(CYCLES_PER_FRAME), Ok(Event::FrameReady));
+
+        // with the LCD off there is never a VBlank, but frames still pass
+        gb.memory.write(LCDC, 0x00);
+        assert_eq!(gb.run_until_vblank(), Ok(Event::CyclesDone));
+        assert_eq!(gb.run_frame(), Ok(Event::FrameReady));
+
+        let samples = gb.take_audio_samples();
+        assert_eq!(samples.len() % 2, 0);
+        assert!(!samples.is_empty());
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up_the_cpu() {
+        let mut gb = locking_up(Model::DMG);
+        gb.step().unwrap();
+        gb.step().unwrap();
+
+        assert_eq!(
+            gb.step(),
+            Err(EmuError::IllegalOpcode {
+                pc: 0x102,
+                opcode: 0xd3
+            })
+        );
+        assert_eq!(gb.cpu.state, CPUState::LockedUp);
+        assert_eq!(gb.step(), Err(EmuError::LockedUp { pc: 0x103 }));
+
+        // the PPU carries on while the CPU does nothing, even with an interrupt to service
+        gb.cpu.interrupt_master_enable = true;
+        let line = gb.memory.peek(LY);
+        run(&mut gb, 1000);
+        assert_ne!(gb.memory.peek(LY), line);
+        assert_eq!(gb.cpu.read_reg16(Reg16::PC), 0x103);
+        assert_eq!(gb.cpu.read_reg8(crate::cpu::Reg8::A), 0x02);
+    }
+
+    #[test]
+    fn test_frame_finishes_after_lock_up() {
+        let mut gb = locking_up(Model::DMG);
+        assert!(matches!(
+            gb.run_frame(),
+            Err(EmuError::IllegalOpcode { .. })
+        ));
+        assert_eq!(gb.memory.ppu.mode(), Mode::VBlank);
+        assert_eq!(gb.run_frame(), Ok(Event::FrameReady));
+    }
+
+    #[test]
+    fn test_locked_up_state_round_trips() {
+        let mut gb = locking_up(Model::DMG);
+        assert!(gb.run_frame().is_err());
+
+        let state = gb.save_state();
+        let mut other = locking_up(Model::DMG);
+        other.load_state(&state).unwrap();
+
+        assert_eq!(other.cpu.state, CPUState::LockedUp);
+    }
+
+    #[test]
+    fn test_loaded_state_continues_identically() {
+        let mut gb = gameboy(Model::CGB, 0);
+        run(&mut gb, 1001); // part way through an instruction
+        assert!(matches!(gb.cpu.state, CPUState::Excute(_, _)));
+
+        let state = gb.save_state();
+        run(&mut gb, 20000);
+        let expected = gb.save_state();
+
+        let mut other = gameboy(Model::CGB, 0);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        run(&mut other, 20000);
+        assert!(other.save_state() == expected);
+    }
+
+    #[test]
+    fn test_sgb_state_round_trips() {
+        let mut gb = gameboy(Model::SGB, 0);
+        run(&mut gb, 100000);
+
+        let state = gb.save_state();
+        let mut other = gameboy(Model::SGB, 0);
+        other.load_state(&state).unwrap();
+
+        assert!(other.save_state() == state);
+    }
+
+    #[test]
+    fn test_rejects_other_versions_and_games() {
+        let gb = gameboy(Model::DMG, 0);
+        let mut state = gb.save_state();
+
+        let mut other_game = gameboy(Model::DMG, 1);
+        assert_eq!(
+            other_game.load_state(&state),
+            Err(EmuError::BadState(
+                "State is for a different game".to_string()
+            ))
+        );
+
+        let mut other_model = gameboy(Model::CGB, 0);
+        assert!(other_model.load_state(&state).is_err());
+
+        state[4] = 4;
+        let mut same = gameboy(Model::DMG, 0);
+        assert_eq!(
+            same.load_state(&state),
+            Err(EmuError::BadState(
+                "Unsupported save state version 4".to_string()
+            ))
+        );
+        assert_eq!(
+            same.load_state(b"not a state"),
+            Err(EmuError::BadState("Not a save state".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_failed_load_leaves_machine_untouched() {
+        let mut gb = gameboy(Model::DMG, 0);
+        let state = gb.save_state();
+        run(&mut gb, 1000);
+        let before = gb.save_state();
+
+        assert!(gb.load_state(&state[..state.len() - 1]).is_err());
+
+        assert!(gb.save_state() == before);
+    }
 }
@@ -0,0 +1,563 @@
+use crate::cpu::{CPUState, Reg16};
+use crate::debugger::{step_instruction, Stop};
+use crate::gameboy::GameBoy;
+use crate::memory::Watchpoint;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// register numbers, in the order of GDB's Z80 target which the SM83's registers are a subset of
+const REGISTERS: [Reg16; 6] = [
+    Reg16::AF,
+    Reg16::BC,
+    Reg16::DE,
+    Reg16::HL,
+    Reg16::SP,
+    Reg16::PC,
+];
+const INTERRUPT: u8 = 0x03; // sent by GDB to stop a running target
+const POLL_INTERVAL: usize = 0x1000; // instructions run between checks for an interrupt
+
+// signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4; // the CPU locked up on an illegal opcode
+const SIGTRAP: u8 = 5;
+
+// a GDB remote serial protocol server for a single connection, stopping the machine on
+// breakpoints, watchpoints and when asked to by the client
+pub struct GdbStub {
+    breakpoints: Vec<u16>,
+}
+
+// what to do after handling a packet
+enum Reply {
+    Packet(String),
+    Close,
+}
+
+impl Default for GdbStub {
+    fn default() -> GdbStub {
+        return GdbStub::new();
+    }
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        return GdbStub {
+            breakpoints: vec![],
+        };
+    }
+
+    // waits for a client and serves it until it detaches, kills the target or disconnects, which
+    // fails with UnexpectedEof when the target is running
+    pub fn serve(&mut self, gb: &mut GameBoy, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream };
+
+        while let Some(packet) = connection.receive()? {
+            match self.handle(gb, &packet, &mut connection)? {
+                Reply::Packet(reply) => connection.send(&reply)?,
+                Reply::Close => break,
+            }
+        }
+        return Ok(());
+    }
+
+    fn handle(
+        &mut self,
+        gb: &mut GameBoy,
+        packet: &str,
+        connection: &mut Connection,
+    ) -> io::Result<Reply> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" if gb.cpu.state == CPUState::LockedUp => stop_reply(SIGILL),
+            "?" => stop_reply(SIGTRAP),
+            "g" => REGISTERS
+                .iter()
+                .map(|&r| hex_u16(gb.cpu.read_reg16(r)))
+                .collect(),
+            "G" => write_registers(gb, args),
+            "p" => match register(args) {
+                Some(r) => hex_u16(gb.cpu.read_reg16(r)),
+                None => error(),
+            },
+            "P" => write_register(gb, args),
+            "m" => read_memory(gb, args),
+            "M" => write_memory(gb, args),
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    gb.cpu.write_reg16(Reg16::PC, address);
+                }
+                return Ok(Reply::Packet(if command == "s" {
+                    self.step(gb)
+                } else {
+                    self.resume(gb, connection)?
+                }));
+            }
+            "Z" | "z" => self.set_breakpoint(gb, args, command == "Z"),
+            "H" => "OK".to_string(), // there is only one thread
+            "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "D" => {
+                connection.send("OK")?;
+                return Ok(Reply::Close);
+            }
+            "k" => return Ok(Reply::Close),
+            // an empty reply tells the client a packet is not supported
+            _ => String::new(),
+        };
+        return Ok(Reply::Packet(reply));
+    }
+
+    fn step(&mut self, gb: &mut GameBoy) -> String {
+        return match step_instruction(gb) {
+            Ok(Some(Stop::Watchpoint(hit))) => watch_reply(gb, hit.address),
+            Ok(Some(Stop::Error(_))) => stop_reply(SIGILL),
+            _ => stop_reply(SIGTRAP),
+        };
+    }
+
+    // runs until a breakpoint, watchpoint or interrupt from the client
+    fn resume(&mut self, gb: &mut GameBoy, connection: &mut Connection) -> io::Result<String> {
+        let mut instructions = 0;
+        loop {
+            // a halted CPU with interrupts disabled never reaches the next instruction, which
+            // only stops it for a check for an interrupt
+            match step_instruction(gb) {
+                Ok(Some(Stop::Watchpoint(hit))) => return Ok(watch_reply(gb, hit.address)),
+                Ok(Some(Stop::Error(_))) => return Ok(stop_reply(SIGILL)),
+                _ => {}
+            }
+            if self.breakpoints.contains(&gb.cpu.read_reg16(Reg16::PC)) {
+                return Ok(stop_reply(SIGTRAP));
+            }
+            instructions += 1;
+            if instructions % POLL_INTERVAL == 0 && connection.interrupted()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    // Z/z TYPE,ADDR,KIND where type 0 is a software breakpoint, 2 a write watchpoint, 3 a read
+    // watchpoint and 4 an access watchpoint, whose kind is the length watched
+    fn set_breakpoint(&mut self, gb: &mut GameBoy, args: &str, insert: bool) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let (kind, address, length) = match fields[..] {
+            [kind, address, length] => match (parse_hex(address), parse_hex(length)) {
+                (Some(address), Some(length)) => (kind, address, length),
+                _ => return error(),
+            },
+            _ => return error(),
+        };
+        let (read, write) = match kind {
+            "0" => {
+                self.breakpoints.retain(|&b| b != address);
+                if insert {
+                    self.breakpoints.push(address);
+                }
+                return "OK".to_string();
+            }
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return String::new(),
+        };
+
+        for i in 0..length {
+            let address = address.wrapping_add(i);
+            let existing = gb
+                .memory
+                .watchpoints()
+                .iter()
+                .find(|w| w.address == address)
+                .copied();
+            let (old_read, old_write) = existing.map_or((false, false), |w| (w.read, w.write));
+            let watchpoint = if insert {
+                Watchpoint {
+                    address,
+                    read: old_read || read,
+                    write: old_write || write,
+                }
+            } else {
+                Watchpoint {
+                    address,
+                    read: old_read && !read,
+                    write: old_write && !write,
+                }
+            };
+            if watchpoint.read || watchpoint.write {
+                gb.memory.add_watchpoint(watchpoint);
+            } else {
+                gb.memory.remove_watchpoint(address);
+            }
+        }
+        return "OK".to_string();
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        return match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        };
+    }
+
+    // the next $DATA#CHECKSUM packet, acknowledging it, or None when the client disconnects
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acknowledgements and interrupts sent while already stopped
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(sum(&data));
+            if !valid {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+        return self.stream.write_all(packet.as_bytes());
+    }
+
+    // whether the client has asked the running target to stop, without waiting, failing with
+    // UnexpectedEof once the client has disconnected so that the target stops running for nobody
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut interrupted = false;
+        loop {
+            match self.read_byte() {
+                Ok(Some(INTERRUPT)) => interrupted = true,
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    self.stream.set_nonblocking(false)?;
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.stream.set_nonblocking(false)?;
+                    return Err(e);
+                }
+            }
+        }
+        self.stream.set_nonblocking(false)?;
+        return Ok(interrupted);
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    return data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte));
+}
+
+fn stop_reply(signal: u8) -> String {
+    return format!("S{:02x}", signal);
+}
+
+fn watch_reply(gb: &GameBoy, address: u16) -> String {
+    let watchpoint = gb
+        .memory
+        .watchpoints()
+        .iter()
+        .find(|w| w.address == address);
+    let kind = match watchpoint.map(|w| (w.read, w.write)) {
+        Some((true, true)) => "awatch",
+        Some((true, false)) => "rwatch",
+        _ => "watch",
+    };
+    return format!("T{:02x}{}:{:x};", SIGTRAP, kind, address);
+}
+
+fn error() -> String {
+    return "E01".to_string();
+}
+
+// registers are sent in target byte order, which is little endian
+fn hex_u16(value: u16) -> String {
+    return format!("{:02x}{:02x}", value & 0xff, value >> 8);
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    return u16::from_str_radix(text, 16).ok();
+}
+
+fn parse_hex_u16_le(text: &str) -> Option<u16> {
+    let bytes = parse_hex_bytes(text)?;
+    return match bytes[..] {
+        [low, high] => Some(u16::from_le_bytes([low, high])),
+        _ => None,
+    };
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+fn register(number: &str) -> Option<Reg16> {
+    return REGISTERS
+        .get(usize::from_str_radix(number, 16).ok()?)
+        .copied();
+}
+
+fn write_registers(gb: &mut GameBoy, data: &str) -> String {
+    if data.len() != REGISTERS.len() * 4 {
+        return error();
+    }
+    let values: Option<Vec<u16>> = (0..REGISTERS.len())
+        .map(|i| parse_hex_u16_le(&data[i * 4..i * 4 + 4]))
+        .collect();
+    return match values {
+        Some(values) => {
+            for (&r, &value) in REGISTERS.iter().zip(values.iter()) {
+                gb.cpu.write_reg16(r, value);
+            }
+            "OK".to_string()
+        }
+        None => error(),
+    };
+}
+
+// P N=VALUE
+fn write_register(gb: &mut GameBoy, args: &str) -> String {
+    let parsed = args
+        .split_once('=')
+        .and_then(|(number, value)| Some((register(number)?, parse_hex_u16_le(value)?)));
+    return match parsed {
+        Some((r, value)) => {
+            gb.cpu.write_reg16(r, value);
+            "OK".to_string()
+        }
+        None => error(),
+    };
+}
+
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (address, length) = args.split_once(',')?;
+    return Some((parse_hex(address)?, parse_hex(length)?));
+}
+
+// m ADDR,LENGTH, read without triggering watchpoints
+fn read_memory(gb: &GameBoy, args: &str) -> String {
+    return match parse_range(args) {
+        Some((address, length)) => (0..length)
+            .map(|i| format!("{:02x}", gb.memory.peek(address.wrapping_add(i))))
+            .collect(),
+        None => error(),
+    };
+}
+
+// M ADDR,LENGTH:DATA, written over the bus so that the cartridge and IO registers see the writes
+fn write_memory(gb: &mut GameBoy, args: &str) -> String {
+    let parsed = args.split_once(':').and_then(|(range, data)| {
+        let (address, length) = parse_range(range)?;
+        let bytes = parse_hex_bytes(data)?;
+        return (bytes.len() == length as usize).then_some((address, bytes));
+    });
+    return match parsed {
+        Some((address, bytes)) => {
+            for (i, &byte) in bytes.iter().enumerate() {
+                gb.memory.write(address.wrapping_add(i as u16), byte);
+            }
+            // writes from the client are not the game's, so do not stop at them
+            gb.memory.take_watch_hit();
+            "OK".to_string()
+        }
+        None => error(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::counting_loop;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    // a scripted client connected to a stub serving from another thread, which returns the
+    // machine and how serving ended once the session ends
+    struct Client {
+        stream: TcpStream,
+        server: JoinHandle<(GameBoy, io::Result<()>)>,
+    }
+
+    impl Client {
+        fn connect() -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            // the machine is too large for the default thread stack in unoptimised builds
+            let server = thread::Builder::new()
+                .stack_size(64 * 1024 * 1024)
+                .spawn(move || {
+                    let mut gb = counting_loop();
+                    let result = GdbStub::new().serve(&mut gb, &listener);
+                    return (gb, result);
+                })
+                .unwrap();
+            return Client {
+                stream: TcpStream::connect(address).unwrap(),
+                server,
+            };
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = vec![];
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                packet.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(
+                std::str::from_utf8(&checksum).unwrap(),
+                format!("{:02x}", sum(&packet))
+            );
+            self.stream.write_all(b"+").unwrap();
+            return String::from_utf8(packet).unwrap();
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            return self.reply();
+        }
+
+        fn kill(mut self) -> GameBoy {
+            self.send("k");
+            let (gb, result) = self.server.join().unwrap();
+            result.unwrap();
+            return gb;
+        }
+    }
+
+    #[test]
+    fn test_reads_and_writes_registers() {
+        let mut client = Client::connect();
+
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(client.request("g"), "b0011300d8004d01feff0001");
+        assert_eq!(client.request("P3=3412"), "OK");
+        assert_eq!(client.request("p3"), "3412");
+        assert_eq!(client.request("G112233445566778899aabbcc"), "OK");
+        assert_eq!(client.request("p9"), "E01");
+
+        let gb = client.kill();
+        assert_eq!(gb.cpu.read_reg16(Reg16::AF), 0x2210);
+        assert_eq!(gb.cpu.read_reg16(Reg16::PC), 0xccbb);
+    }
+
+    #[test]
+    fn test_reads_and_writes_memory() {
+        let mut client = Client::connect();
+
+        assert_eq!(client.request("m100,3"), "3e103c");
+        assert_eq!(client.request("Mc000,2:abcd"), "OK");
+        assert_eq!(client.request("mc000,2"), "abcd");
+        assert_eq!(client.request("Mc000,2:ab"), "E01");
+
+        client.kill();
+    }
+
+    #[test]
+    fn test_stops_at_breakpoints_and_steps() {
+        let mut client = Client::connect();
+
+        assert_eq!(client.request("Z0,106,1"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p5"), "0601");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p5"), "0002");
+
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0")[2..], *"12");
+        assert_eq!(client.request("z0,106,1"), "OK");
+
+        client.kill();
+    }
+
+    #[test]
+    fn test_stops_at_watchpoints() {
+        let mut client = Client::connect();
+
+        assert_eq!(client.request("Z2,c000,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:c000;");
+        assert_eq!(client.request("mc000,1"), "11");
+        assert_eq!(client.request("z2,c000,1"), "OK");
+        assert_eq!(client.request("Z3,200,1"), "OK");
+        assert_eq!(client.request("c"), "T05rwatch:200;");
+
+        let gb = client.kill();
+        assert!(gb.memory.watchpoints().iter().all(|w| !w.write));
+    }
+
+    #[test]
+    fn test_stops_when_interrupted() {
+        let mut client = Client::connect();
+
+        client.send("c");
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.reply(), "S02");
+
+        assert_eq!(client.request("D"), "OK");
+        client.server.join().unwrap().1.unwrap();
+    }
+
+    #[test]
+    fn test_stops_serving_when_the_client_disconnects_while_running() {
+        let mut client = Client::connect();
+
+        client.send("c");
+        let Client { stream, server } = client;
+        drop(stream);
+
+        let (_, result) = server.join().unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::cpu::special_registers::*;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::String;
+
+const BLOCK_SIZE: u8 = 0x10;
+const VRAM_SIZE: u16 = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    Idle,
+    General, // copies everything at once, halting the CPU
+    HBlank,  // copies one block at the start of each HBlank
+}
+
+// CGB VRAM DMA, controlled through HDMA1-HDMA5
+// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+#[derive(Clone)]
+pub struct HDMA {
+    source: u16,
+    destination: u16, // offset into VRAM
+    blocks: u8,       // remaining blocks minus one, as read back from HDMA5
+    transfer: Transfer,
+    block_bytes: u8, // bytes left to copy of the current block, the CPU is halted while non-zero
+}
+
+impl HDMA {
+    pub fn new() -> HDMA {
+        return HDMA {
+            source: 0,
+            destination: 0,
+            blocks: 0x7f,
+            transfer: Transfer::Idle,
+            block_bytes: 0,
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.blocks);
+        state.u8(self.transfer as u8);
+        state.u8(self.block_bytes);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.source = state.u16()?;
+        self.destination = state.u16()? % VRAM_SIZE;
+        self.blocks = state.u8()? & 0x7f;
+        self.transfer = match state.u8()? {
+            0 => Transfer::Idle,
+            1 => Transfer::General,
+            2 => Transfer::HBlank,
+            transfer => return Err(format!("Unknown VRAM DMA transfer {} in state", transfer)),
+        };
+        self.block_bytes = state.u8()?.min(BLOCK_SIZE);
+        return Ok(());
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            // bit 7 is clear while an HBlank transfer is active
+            HDMA5 => ((self.transfer == Transfer::Idle) as u8) << 7 | self.blocks,
+            _ => 0xff, // write only
+        };
+    }
+
+    // in_hblank is whether a block would already be due, so that an HBlank transfer starts
+    // straight away
+    pub fn write(&mut self, address: u16, value: u8, in_hblank: bool) {
+        match address {
+            HDMA1 => self.source = (self.source & 0x00ff) | (value as u16) << 8,
+            HDMA2 => self.source = (self.source & 0xff00) | (value & 0xf0) as u16,
+            HDMA3 => self.destination = (self.destination & 0x00ff) | ((value & 0x1f) as u16) << 8,
+            HDMA4 => self.destination = (self.destination & 0xff00) | (value & 0xf0) as u16,
+            HDMA5 => {
+                if self.transfer == Transfer::HBlank && value & 0x80 == 0 {
+                    // cancelled, the remaining length can still be read back
+                    self.transfer = Transfer::Idle;
+                    return;
+                }
+
+                self.blocks = value & 0x7f;
+                if value & 0x80 == 0 {
+                    self.transfer = Transfer::General;
+                    self.block_bytes = BLOCK_SIZE;
+                } else {
+                    self.transfer = Transfer::HBlank;
+                    if in_hblank {
+                        self.block_bytes = BLOCK_SIZE;
+                    }
+                }
+            }
+            _ => return,
+        }
+    }
+
+    // called when the PPU enters HBlank on a visible line
+    pub fn hblank(&mut self) {
+        if self.transfer == Transfer::HBlank {
+            self.block_bytes = BLOCK_SIZE;
+        }
+    }
+
+    // whether a block is being copied, during which the CPU does not run
+    pub fn active(&self) -> bool {
+        return self.block_bytes > 0;
+    }
+
+    // source address and VRAM offset of the next byte to copy, if a block is in progress
+    pub fn next_byte(&mut self) -> Option<(u16, u16)> {
+        if self.block_bytes == 0 {
+            return None;
+        }
+
+        let next = (self.source, self.destination);
+        self.source = self.source.wrapping_add(1);
+        self.destination += 1;
+        self.block_bytes -= 1;
+
+        if self.block_bytes == 0 {
+            self.finish_block();
+        }
+        return Some(next);
+    }
+
+    fn finish_block(&mut self) {
+        let (blocks, finished) = self.blocks.overflowing_sub(1);
+        self.blocks = blocks & 0x7f;
+        // the transfer also stops when the destination runs past the end of VRAM
+        if finished || self.destination >= VRAM_SIZE {
+            self.transfer = Transfer::Idle;
+            self.destination &= VRAM_SIZE - 1;
+        } else if self.transfer == Transfer::General {
+            self.block_bytes = BLOCK_SIZE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // copies until the CPU would be allowed to run again
+    fn copy_block(hdma: &mut HDMA) -> Vec<(u16, u16)> {
+        let mut bytes = vec![];
+        while let Some(byte) = hdma.next_byte() {
+            bytes.push(byte);
+        }
+        return bytes;
+    }
+
+    fn start(hdma: &mut HDMA, source: u16, destination: u16, hdma5: u8, in_hblank: bool) {
+        hdma.write(HDMA1, (source >> 8) as u8, false);
+        hdma.write(HDMA2, source as u8, false);
+        hdma.write(HDMA3, (destination >> 8) as u8, false);
+        hdma.write(HDMA4, destination as u8, false);
+        hdma.write(HDMA5, hdma5, in_hblank);
+    }
+
+    #[test]
+    fn test_general_transfer_copies_everything() {
+        let mut hdma = HDMA::new();
+
+        start(&mut hdma, 0xc123, 0x8456, 0x02, false);
+        let bytes = copy_block(&mut hdma);
+
+        assert_eq!(bytes.len(), 0x30);
+        assert_eq!(bytes[0], (0xc120, 0x0450));
+        assert_eq!(bytes[0x2f], (0xc14f, 0x047f));
+        assert_eq!(hdma.read(HDMA5), 0xff);
+    }
+
+    #[test]
+    fn test_hblank_transfer_copies_a_block_per_hblank() {
+        let mut hdma = HDMA::new();
+
+        start(&mut hdma, 0xc000, 0x8000, 0x81, false);
+        assert!(!hdma.active());
+        assert_eq!(hdma.read(HDMA5), 0x01);
+
+        hdma.hblank();
+        assert_eq!(copy_block(&mut hdma).len(), 0x10);
+        assert_eq!(hdma.read(HDMA5), 0x00);
+
+        hdma.hblank();
+        assert_eq!(copy_block(&mut hdma)[0], (0xc010, 0x0010));
+        assert_eq!(hdma.read(HDMA5), 0xff);
+
+        hdma.hblank();
+        assert!(!hdma.active());
+    }
+
+    #[test]
+    fn test_hblank_transfer_started_in_hblank_copies_immediately() {
+        let mut hdma = HDMA::new();
+
+        start(&mut hdma, 0xc000, 0x8000, 0x80, true);
+
+        assert!(hdma.active());
+    }
+
+    #[test]
+    fn test_hblank_transfer_can_be_cancelled() {
+        let mut hdma = HDMA::new();
+        start(&mut hdma, 0xc000, 0x8000, 0x83, false);
+        hdma.hblank();
+        copy_block(&mut hdma);
+
+        hdma.write(HDMA5, 0x00, false);
+
+        assert_eq!(hdma.read(HDMA5), 0x82);
+        hdma.hblank();
+        assert!(!hdma.active());
+    }
+
+    #[test]
+    fn test_transfer_stops_at_end_of_vram() {
+        let mut hdma = HDMA::new();
+
+        start(&mut hdma, 0xc000, 0x9ff0, 0x03, false);
+
+        assert_eq!(copy_block(&mut hdma).len(), 0x10);
+        assert_eq!(hdma.read(HDMA5), 0x82);
+    }
+}
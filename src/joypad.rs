@@ -0,0 +1,140 @@
+use crate::cpu::Interrupt;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::string::String;
+
+const SELECT_DIRECTIONS: u8 = 1 << 4; // P14, selects when low
+const SELECT_ACTIONS: u8 = 1 << 5; // P15, selects when low
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    // directions occupy the lower nibble and actions the upper one, in P1 bit order
+    pub fn bit(&self) -> u8 {
+        return *self as u8;
+    }
+}
+
+// the P1 register
+#[derive(Clone)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8, // one bit per button
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        return Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            pressed: 0,
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+        state.u8(self.pressed);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.select = state.u8()? & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.pressed = state.u8()?;
+        return Ok(());
+    }
+
+    // one bit per button, set while it is held
+    pub fn pressed(&self) -> u8 {
+        return self.pressed;
+    }
+
+    // the selection lines as last written
+    pub fn select(&self) -> u8 {
+        return self.select;
+    }
+
+    pub fn read(&self) -> u8 {
+        return 0xc0 | self.select | (!self.selected_buttons() & 0x0f);
+    }
+
+    // buttons P1 reads as pressed with the current selection, in P1 bit order
+    fn selected_buttons(&self) -> u8 {
+        let mut buttons = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            buttons |= self.pressed & 0x0f;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            buttons |= self.pressed >> 4;
+        }
+        return buttons;
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+
+    // returns the interrupts requested, which happens when a selected line goes low
+    pub fn press(&mut self, button: Button) -> u8 {
+        let before = self.selected_buttons();
+        self.pressed |= 1 << button.bit();
+        if self.selected_buttons() & !before != 0 {
+            return 1 << Interrupt::Joypad.bit();
+        }
+        return 0;
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !(1 << button.bit());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_selected_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Down);
+        joypad.press(Button::A);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xe7);
+
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xde);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xff);
+    }
+
+    #[test]
+    fn test_requests_interrupt_only_for_selected_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x10);
+
+        assert_eq!(joypad.press(Button::Up), 0);
+        assert_eq!(joypad.press(Button::Start), 1 << Interrupt::Joypad.bit());
+
+        joypad.release(Button::Start);
+        assert_eq!(joypad.read() & 0x0f, 0x0f);
+    }
+}
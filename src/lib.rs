@@ -0,0 +1,51 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::upper_case_acronyms
+)]
+
+// a Game Boy emulator core which frontends run a frame at a time, with the command line runner's
+// tools in modules of their own, needing only alloc without the std feature
+
+extern crate alloc;
+
+mod apu;
+mod cartridge;
+mod cpu;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod disassembler;
+mod dma;
+mod error;
+mod gameboy;
+#[cfg(feature = "std")]
+pub mod gdb;
+mod hdma;
+mod joypad;
+mod memory;
+mod model;
+pub mod movie;
+mod ppu;
+pub mod rewind;
+mod save_state;
+mod scheduler;
+#[cfg(feature = "std")]
+pub mod screenshot;
+mod serial;
+mod sgb;
+pub mod test_rom;
+#[cfg(test)]
+mod test_util;
+mod timer;
+#[cfg(feature = "std")]
+pub mod trace;
+
+pub use crate::apu::SAMPLE_RATE;
+pub use crate::cartridge::Cartridge;
+pub use crate::error::EmuError;
+pub use crate::gameboy::{Event, GameBoy};
+pub use crate::joypad::Button;
+pub use crate::model::Model;
+pub use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::sgb::{BORDER_HEIGHT, BORDER_WIDTH};
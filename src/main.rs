@@ -1,8 +1,304 @@
-mod cpu;
-mod gameboy;
+#![allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::upper_case_acronyms
+)]
 
-use gameboy::GameBoy;
+use gameboy_emulator::debugger::Debugger;
+use gameboy_emulator::disassembler;
+use gameboy_emulator::gdb::GdbStub;
+use gameboy_emulator::movie::Movie;
+use gameboy_emulator::screenshot;
+use gameboy_emulator::screenshot::{Palette, Trigger};
+use gameboy_emulator::test_rom;
+use gameboy_emulator::test_rom::Verdict;
+use gameboy_emulator::trace::Tracer;
+use gameboy_emulator::{Cartridge, GameBoy, Model};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: gameboy-emulator [debug] [--model dmg|mgb|cgb|sgb] [--boot-rom FILE] \
+                     [--play MOVIE] [--gdb PORT] [--trace FILE | --trace-last N] \
+                     [--break-on-lockup] [--blocks] ROM
+       gameboy-emulator test [--model dmg|mgb|cgb|sgb] [--frames N] \
+                     [--reference PNG --diff PNG] ROM
+       gameboy-emulator disasm [--bank N] ROM";
+const ROM_BANK_SIZE: usize = 0x4000;
+const TEST_FRAMES: usize = 60 * 60 * 2; // longer than any of Blargg's test ROMs take
+
+#[derive(PartialEq)]
+enum Command {
+    Run,
+    Debug,  // runs under the interactive debugger rather than freely
+    Test,   // runs a test ROM until it reports a result, exiting with whether it passed
+    Disasm, // prints a listing of a ROM bank
+}
+
+struct Options {
+    command: Command,
+    model: Option<Model>, // chosen from the cartridge header if not given
+    boot_rom_path: Option<String>,
+    movie_path: Option<String>, // checks the movie plays back without desyncing, then exits
+    gdb_port: Option<u16>,      // serves a GDB client on this local port rather than running freely
+    trace_path: Option<String>, // logs every instruction to the file, or stdout for -
+    trace_last: Option<usize>,  // logs the given number of last instructions to stderr on exit
+    break_on_lockup: bool,      // starts the debugger when the CPU locks up, rather than exiting
+    #[cfg_attr(not(feature = "blocks"), allow(dead_code))]
+    blocks: bool, // runs straight line code through the block cache
+    frames: usize,              // that test waits for a result
+    reference_path: Option<String>, // makes test compare the screen at LD B, B with this image
+    diff_path: Option<String>,  // where test writes the pixels which differ from the reference
+    bank: usize,                // disassembled by disasm
+    rom_path: String,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let (command, args) = match args.first().map(|arg| arg.as_str()) {
+        Some("debug") => (Command::Debug, &args[1..]),
+        Some("test") => (Command::Test, &args[1..]),
+        Some("disasm") => (Command::Disasm, &args[1..]),
+        _ => (Command::Run, args),
+    };
+    let mut model = None;
+    let mut boot_rom_path = None;
+    let mut movie_path = None;
+    let mut gdb_port = None;
+    let mut trace_path = None;
+    let mut trace_last = None;
+    let mut break_on_lockup = false;
+    let mut blocks = false;
+    let mut frames = TEST_FRAMES;
+    let mut reference_path = None;
+    let mut diff_path = None;
+    let mut bank = 0;
+    let mut rom_path = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => model = Some(args.next().ok_or(USAGE)?.parse()?),
+            "--boot-rom" => boot_rom_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--play" => movie_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--gdb" => {
+                let port = args.next().ok_or(USAGE)?;
+                gdb_port = Some(port.parse().map_err(|_| format!("Invalid port {}", port))?);
+            }
+            "--trace" => trace_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--trace-last" => {
+                let number = args.next().ok_or(USAGE)?;
+                trace_last = Some(
+                    number
+                        .parse()
+                        .map_err(|_| format!("Invalid count {}", number))?,
+                );
+            }
+            "--break-on-lockup" => break_on_lockup = true,
+            "--blocks" if cfg!(feature = "blocks") => blocks = true,
+            "--blocks" => return Err("--blocks needs the blocks feature".to_string()),
+            "--frames" => {
+                let number = args.next().ok_or(USAGE)?;
+                frames = number
+                    .parse()
+                    .map_err(|_| format!("Invalid count {}", number))?;
+            }
+            "--reference" => reference_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--diff" => diff_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--bank" => {
+                let number = args.next().ok_or(USAGE)?;
+                bank = number
+                    .parse()
+                    .map_err(|_| format!("Invalid bank {}", number))?;
+            }
+            _ => rom_path = Some(arg.clone()),
+        }
+    }
+
+    return Ok(Options {
+        command,
+        model,
+        boot_rom_path,
+        movie_path,
+        gdb_port,
+        trace_path,
+        trace_last,
+        break_on_lockup,
+        blocks,
+        frames,
+        reference_path,
+        diff_path,
+        bank,
+        rom_path: rom_path.ok_or(USAGE)?,
+    });
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    return fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", path, e);
+        process::exit(1);
+    });
+}
 
 fn main() {
-    let gb = &mut GameBoy::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_options(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    if options.command == Command::Disasm {
+        let rom = read_file(&options.rom_path);
+        let start = options.bank * ROM_BANK_SIZE;
+        if start >= rom.len() {
+            eprintln!("ROM has no bank {}", options.bank);
+            process::exit(1);
+        }
+        // bank 0 is always mapped at the start of memory, and the others after it
+        let base_addr = if options.bank == 0 { 0 } else { ROM_BANK_SIZE };
+        let end = rom.len().min(start + ROM_BANK_SIZE);
+        print!(
+            "{}",
+            disassembler::listing(&rom[start..end], base_addr as u16)
+        );
+        return;
+    }
+
+    let cartridge = Cartridge::new(read_file(&options.rom_path)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let movie = options.movie_path.as_ref().map(|path| {
+        Movie::from_bytes(&read_file(path)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })
+    });
+    let model = options
+        .model
+        .or_else(|| movie.as_ref().map(|movie| movie.model()))
+        .unwrap_or_else(|| Model::for_cartridge(&cartridge));
+
+    let gb = &mut match &options.boot_rom_path {
+        Some(path) => GameBoy::with_boot_rom(model, cartridge, read_file(path)),
+        None => GameBoy::new(model, cartridge),
+    };
+    #[cfg(feature = "blocks")]
+    gb.use_blocks(options.blocks);
+
+    match (&options.trace_path, options.trace_last) {
+        (Some(path), _) if path == "-" => {
+            gb.trace(Tracer::to_writer(Box::new(BufWriter::new(io::stdout()))))
+        }
+        (Some(path), _) => match File::create(path) {
+            Ok(file) => gb.trace(Tracer::to_writer(Box::new(BufWriter::new(file)))),
+            Err(e) => {
+                eprintln!("Could not create {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        (None, Some(instructions)) => gb.trace(Tracer::ring(instructions)),
+        (None, None) => {}
+    }
+
+    // the last instructions are written out even if emulation panics
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(gb, &options, movie)));
+    if let Some(tracer) = gb.take_tracer() {
+        if options.trace_last.is_some() && options.trace_path.is_none() {
+            eprintln!("Last instructions executed:");
+        }
+        if let Err(e) = tracer.finish(&mut io::stderr()) {
+            eprintln!("Could not write trace: {}", e);
+        }
+    }
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+fn run(gb: &mut GameBoy, options: &Options, movie: Option<Movie>) -> Result<(), String> {
+    if let Some(movie) = movie {
+        movie.play(gb).map_err(|e| e.to_string())?;
+        println!("Played back {} frames", movie.frames());
+        return Ok(());
+    }
+
+    if let Some(port) = options.gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        println!(
+            "Waiting for GDB on {}",
+            listener.local_addr().map_err(|e| e.to_string())?
+        );
+        return GdbStub::new()
+            .serve(gb, &listener)
+            .map_err(|e| e.to_string());
+    }
+
+    if options.command == Command::Debug {
+        return debug(gb);
+    }
+    if options.command == Command::Test {
+        return match (&options.reference_path, &options.diff_path) {
+            (Some(reference), Some(diff)) => compare_screen(gb, options.frames, reference, diff),
+            (None, None) => test(gb, options.frames),
+            _ => Err(USAGE.to_string()),
+        };
+    }
+
+    // the PPU would go on showing the last frame after a lock up, but there is no screen to see
+    loop {
+        if let Err(e) = gb.run_frame() {
+            if !options.break_on_lockup {
+                return Err(e.to_string());
+            }
+            println!("{}", e);
+            return debug(gb);
+        }
+    }
+}
+
+fn test(gb: &mut GameBoy, frames: usize) -> Result<(), String> {
+    let report = test_rom::run(gb, frames).map_err(|e| e.to_string())?;
+    print!("{}", report.output);
+    return match report.verdict {
+        Verdict::Passed => Ok(()),
+        Verdict::Failed => Err("Test ROM failed".to_string()),
+        Verdict::TimedOut => Err(format!("No result after {} frames", frames)),
+    };
+}
+
+// runs a test ROM to its LD B, B and compares the screen with a reference image, in the greys of
+// the acid tests' images for DMG games and the colours themselves for CGB ones
+fn compare_screen(
+    gb: &mut GameBoy,
+    frames: usize,
+    reference_path: &str,
+    diff_path: &str,
+) -> Result<(), String> {
+    screenshot::run_until(gb, Trigger::Breakpoint(frames))?;
+    let palette = match gb.model() {
+        Model::CGB => Palette::RGB555,
+        _ => screenshot::DMG_GREYS,
+    };
+    screenshot::compare(gb, Path::new(reference_path), palette, Path::new(diff_path))?;
+    println!("Screen matches {}", reference_path);
+    return Ok(());
+}
+
+fn debug(gb: &mut GameBoy) -> Result<(), String> {
+    let stdin = io::stdin();
+    return Debugger::new()
+        .run(gb, stdin.lock(), io::stdout())
+        .map_err(|e| e.to_string());
 }
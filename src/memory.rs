@@ -0,0 +1,1130 @@
+use crate::apu::APU;
+use crate::cartridge::Cartridge;
+use crate::cpu::special_registers::*;
+use crate::cpu::{Bus, Interrupt};
+use crate::dma::DMA;
+use crate::hdma::HDMA;
+use crate::joypad::{Button, Joypad};
+use crate::model::Model;
+use crate::ppu::{Mode, DOTS_PER_LINE, PPU};
+use crate::save_state::{StateReader, StateWriter};
+use crate::scheduler::{Component, Scheduler};
+use crate::serial::Serial;
+use crate::sgb::SGB;
+use crate::timer::Timer;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::cell::Cell;
+
+pub const MEMORY_SIZE: usize = 0x10000;
+
+const ROM_END: u16 = 0x7fff;
+const VRAM_START: u16 = 0x8000;
+const BOOT_ROM_HEADER_START: usize = 0x100; // the CGB boot ROM skips over the cartridge header
+const BOOT_ROM_HEADER_END: usize = 0x200;
+const WRAM_START: u16 = 0xc000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANK_COUNT: usize = 8; // only banks 0 and 1 exist outside of CGB mode
+const ECHO_OFFSET: u16 = 0x2000;
+const OAM_START: u16 = 0xfe00;
+const WAVE_RAM_END: u16 = 0xff3f;
+// DIV bit whose falling edge clocks the APU's frame sequencer at 512 Hz, at each speed
+const DIV_APU_BIT: u16 = 12;
+const DIV_APU_DOUBLE_SPEED_BIT: u16 = 13;
+#[cfg(feature = "blocks")]
+const CODE_LINE_SIZE: u16 = 0x10; // granularity at which writes to decoded RAM code are noticed
+
+// an address the debugger stops at when the CPU accesses it
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+// the access which triggered a watchpoint
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8, // read or written
+    pub write: bool,
+}
+
+#[derive(Clone)]
+pub struct Memory {
+    data: [u8; MEMORY_SIZE], // IO registers and high RAM not owned by a peripheral
+    boot_rom: Option<Vec<u8>>, // mapped over the start of the cartridge until BOOT is written
+    cartridge: Cartridge,
+    pub ppu: PPU,
+    hdma: HDMA,
+    dma: DMA,
+    timer: Timer,
+    serial: Serial,
+    apu: APU,
+    scheduler: Scheduler, // when the timer, serial port, APU and PPU next need to catch up
+    joypad: Joypad,
+    pub sgb: Option<Box<SGB>>,
+    wram: [[u8; WRAM_BANK_SIZE]; WRAM_BANK_COUNT],
+    wram_bank: usize, // bank mapped at 0xd000-0xdfff
+    model: Model,
+    cgb: bool,      // running on CGB hardware
+    cgb_mode: bool, // CGB features enabled, false for DMG games running on a CGB
+    speed_switch_armed: bool,
+    double_speed: bool,
+    #[cfg(feature = "std")]
+    watchpoints: Vec<Watchpoint>,
+    #[cfg(feature = "std")]
+    watch_hit: Cell<Option<WatchHit>>, // set by reads too, which only borrow memory
+    #[cfg(feature = "blocks")]
+    code_lines: [bool; 0x10000 / CODE_LINE_SIZE as usize], // of RAM holding decoded blocks
+    #[cfg(feature = "blocks")]
+    code_generation: u64, // changed whenever RAM holding decoded blocks may have been written
+}
+
+impl Memory {
+    pub fn new(model: Model, cartridge: Cartridge) -> Memory {
+        let cgb = model == Model::CGB;
+        let cgb_mode = cgb && cartridge.supports_cgb();
+        let sgb = (model == Model::SGB).then(|| Box::new(SGB::new(cartridge.supports_sgb())));
+        let mut memory = Memory {
+            data: [0; MEMORY_SIZE],
+            boot_rom: None,
+            cartridge,
+            ppu: PPU::new(cgb_mode),
+            hdma: HDMA::new(),
+            dma: DMA::new(),
+            timer: Timer::new(),
+            serial: Serial::new(cgb_mode),
+            apu: APU::new(),
+            scheduler: Scheduler::new(),
+            joypad: Joypad::new(),
+            sgb,
+            wram: [[0; WRAM_BANK_SIZE]; WRAM_BANK_COUNT],
+            wram_bank: 1,
+            model,
+            cgb,
+            cgb_mode,
+            speed_switch_armed: false,
+            double_speed: false,
+            #[cfg(feature = "std")]
+            watchpoints: vec![],
+            #[cfg(feature = "std")]
+            watch_hit: Cell::new(None),
+            #[cfg(feature = "blocks")]
+            code_lines: [false; 0x10000 / CODE_LINE_SIZE as usize],
+            #[cfg(feature = "blocks")]
+            code_generation: 0,
+        };
+        memory.schedule_apu();
+        return memory;
+    }
+
+    // the CGB boot ROM starts in CGB mode and decides whether to stay in it through KEY0
+    pub fn with_boot_rom(model: Model, cartridge: Cartridge, boot_rom: Vec<u8>) -> Memory {
+        let mut memory = Memory::new(model, cartridge);
+        memory.boot_rom = Some(boot_rom);
+        memory.set_cgb_mode(memory.cgb);
+        return memory;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.cgb);
+        state.bool(self.sgb.is_some());
+        state.bytes(&self.data);
+        match &self.boot_rom {
+            Some(boot_rom) => {
+                state.bool(true);
+                state.vec(boot_rom);
+            }
+            None => state.bool(false),
+        }
+        self.cartridge.save_state(state);
+        self.ppu.save_state(state);
+        self.hdma.save_state(state);
+        self.dma.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.apu.save_state(state);
+        self.joypad.save_state(state);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+        for bank in self.wram.iter() {
+            state.bytes(bank);
+        }
+        state.usize(self.wram_bank);
+        state.bool(self.cgb_mode);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.double_speed);
+        self.scheduler.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if state.bool()? != self.cgb || state.bool()? != self.sgb.is_some() {
+            return Err("State is for a different model".to_string());
+        }
+        state.bytes(&mut self.data)?;
+        self.boot_rom = match state.bool()? {
+            true => Some(state.vec()?),
+            false => None,
+        };
+        self.cartridge.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.hdma.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(state)?;
+        }
+        for bank in self.wram.iter_mut() {
+            state.bytes(bank)?;
+        }
+        self.wram_bank = state.index(WRAM_BANK_COUNT)?.max(1);
+        self.set_cgb_mode(state.bool()?);
+        self.speed_switch_armed = state.bool()?;
+        self.double_speed = state.bool()?;
+        self.scheduler.load_state(state)?;
+
+        // the PPU is run at each of its mode changes, so it is never more than a line behind
+        let ppu_behind = self
+            .scheduler
+            .pending(Component::PPU)
+            .saturating_mul(self.dots_per_cycle());
+        if self.ppu.dots_until_change().is_some() && ppu_behind > DOTS_PER_LINE as u128 {
+            return Err("PPU is too far behind the machine in state".to_string());
+        }
+        #[cfg(feature = "blocks")]
+        self.forget_code();
+        return Ok(());
+    }
+
+    pub fn model(&self) -> Model {
+        return self.model;
+    }
+
+    pub fn cartridge_checksum(&self) -> [u8; 3] {
+        return self.cartridge.checksum();
+    }
+
+    fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.ppu.set_cgb_mode(cgb_mode);
+        self.serial.set_cgb_mode(cgb_mode);
+    }
+
+    // whether KEY1 has been armed so that the next STOP switches speed
+    pub fn speed_switch_armed(&self) -> bool {
+        return self.cgb_mode && self.speed_switch_armed;
+    }
+
+    pub fn double_speed(&self) -> bool {
+        return self.double_speed;
+    }
+
+    pub fn switch_speed(&mut self) {
+        // the PPU and APU count dots, of which there are half as many per cycle at double speed
+        self.catch_up_ppu(false);
+        self.catch_up_apu();
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        self.schedule_ppu();
+        self.schedule_apu();
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.data[IF as usize] |= self.joypad.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    // one bit per button, in Button::bit order
+    pub fn buttons(&self) -> u8 {
+        return self.joypad.pressed();
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        for button in Button::ALL.iter() {
+            if buttons & (1 << button.bit()) != 0 {
+                self.press(*button);
+            } else {
+                self.release(*button);
+            }
+        }
+    }
+
+    // with both lines deselected, an SGB reports which controller is being read
+    fn read_p1(&self) -> u8 {
+        return match &self.sgb {
+            Some(sgb) if self.joypad.select() == 0x30 => 0xf0 | (0x0f - sgb.player()),
+            Some(sgb) if sgb.player() != 0 => self.joypad.select() | 0xcf,
+            _ => self.joypad.read(),
+        };
+    }
+
+    // whether VRAM DMA is copying, which keeps the CPU from running
+    pub fn hdma_active(&self) -> bool {
+        return self.hdma.active();
+    }
+
+    pub fn record_serial_output(&mut self) {
+        self.serial.record_output();
+    }
+
+    // bytes sent over the link port since record_serial_output
+    pub fn serial_output(&self) -> &[u8] {
+        return self.serial.output();
+    }
+
+    // samples mixed by the APU since the last call, interleaved left and right
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.catch_up_apu();
+        return self.apu.take_samples();
+    }
+
+    // advances the peripherals by one CPU machine cycle
+    pub fn cycle(&mut self, cpu_halted: bool) {
+        self.scheduler.tick();
+        while let Some(component) = self.scheduler.pop_due() {
+            match component {
+                Component::Timer => {
+                    self.catch_up_timer();
+                    self.schedule_timer();
+                }
+                Component::Serial => {
+                    self.catch_up_serial();
+                    self.schedule_serial();
+                }
+                Component::APU => {
+                    self.catch_up_apu();
+                    self.apu.step_sequencer();
+                    self.schedule_apu();
+                }
+                Component::PPU => {
+                    self.catch_up_ppu(cpu_halted);
+                    self.schedule_ppu();
+                }
+            }
+        }
+
+        // OAM DMA runs at CPU speed
+        if let Some((source, offset)) = self.dma.next_byte() {
+            let value = self.peek(source);
+            self.ppu.write(OAM_START + offset, value);
+        }
+
+        // a block always takes 8 microseconds, so half as many bytes are copied per cycle at
+        // double speed, and like OAM DMA the copy is not the CPU's so triggers no watchpoints
+        let bytes = if self.double_speed { 1 } else { 2 };
+        for _ in 0..bytes {
+            if let Some((source, destination)) = self.hdma.next_byte() {
+                let value = self.peek(source);
+                self.ppu.write(VRAM_START + destination, value);
+            }
+        }
+    }
+
+    // the PPU and APU run at the same rate regardless of CPU speed
+    fn dots_per_cycle(&self) -> u128 {
+        return if self.double_speed { 2 } else { 4 };
+    }
+
+    fn div_apu_bit(&self) -> u16 {
+        return if self.double_speed {
+            DIV_APU_DOUBLE_SPEED_BIT
+        } else {
+            DIV_APU_BIT
+        };
+    }
+
+    fn catch_up_timer(&mut self) {
+        let cycles = self.scheduler.catch_up(Component::Timer);
+        self.data[IF as usize] |= self.timer.advance(cycles);
+    }
+
+    fn schedule_timer(&mut self) {
+        let cycles = self.timer.cycles_until_event();
+        self.scheduler.schedule(Component::Timer, cycles);
+    }
+
+    fn catch_up_serial(&mut self) {
+        let cycles = self.scheduler.catch_up(Component::Serial);
+        self.data[IF as usize] |= self.serial.advance(cycles);
+    }
+
+    fn schedule_serial(&mut self) {
+        let cycles = self.serial.cycles_until_event();
+        self.scheduler.schedule(Component::Serial, cycles);
+    }
+
+    fn catch_up_apu(&mut self) {
+        let dots = self.scheduler.catch_up(Component::APU) * self.dots_per_cycle();
+        self.apu.advance(dots.min(u32::MAX as u128) as u32);
+    }
+
+    // the frame sequencer steps as its bit of the timer's counter falls
+    fn schedule_apu(&mut self) {
+        self.catch_up_timer();
+        let cycles = self.timer.cycles_until_fall(self.div_apu_bit());
+        self.scheduler.schedule(Component::APU, Some(cycles));
+    }
+
+    // nothing but the dot changes between the PPU's events, so only they can raise interrupts
+    fn catch_up_ppu(&mut self, cpu_halted: bool) {
+        let dots = self.scheduler.catch_up(Component::PPU) * self.dots_per_cycle();
+        if dots == 0 {
+            return;
+        }
+
+        let was_hblank = self.ppu.mode() == Mode::HBlank;
+        let interrupts = self.ppu.cycle(dots.min(u16::MAX as u128) as u16);
+        self.data[IF as usize] |= interrupts;
+        if interrupts & (1 << Interrupt::VBlank.bit()) != 0 {
+            if let Some(sgb) = &mut self.sgb {
+                sgb.end_frame(self.ppu.shades());
+            }
+        }
+
+        // HBlank DMA is paused while the CPU is halted, and resumes at the next HBlank after it
+        // wakes up
+        if !was_hblank && self.ppu.mode() == Mode::HBlank && !cpu_halted {
+            self.hdma.hblank();
+        }
+    }
+
+    fn schedule_ppu(&mut self) {
+        let dots_per_cycle = self.dots_per_cycle();
+        let cycles = self
+            .ppu
+            .dots_until_change()
+            .map(|dots| (dots as u128).div_ceil(dots_per_cycle));
+        self.scheduler.schedule(Component::PPU, cycles);
+    }
+
+    // IO register state left behind by each model's boot ROM
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+    pub fn write_post_boot_io(&mut self, model: Model) {
+        use Model::*;
+
+        // NR52 first, as the other sound registers ignore writes while it is off
+        let io: [(u16, u8); 37] = [
+            (P1, 0xcf),
+            (SB, 0x00),
+            (SC, if model == CGB { 0x7f } else { 0x7e }),
+            (TIMA, 0x00),
+            (TMA, 0x00),
+            (TAC, 0xf8),
+            (IF, 0xe1),
+            (NR_52, 0xf1),
+            (NR_10, 0x80),
+            (NR_11, 0xbf),
+            (NR_12, 0xf3),
+            (NR_13, 0xff),
+            (NR_14, 0xbf),
+            (NR_21, 0x3f),
+            (NR_22, 0x00),
+            (NR_23, 0xff),
+            (NR_24, 0xbf),
+            (NR_30, 0x7f),
+            (NR_31, 0xff),
+            (NR_32, 0x9f),
+            (NR_33, 0xff),
+            (NR_34, 0xbf),
+            (NR_41, 0xff),
+            (NR_42, 0x00),
+            (NR_43, 0x00),
+            (NR_44, 0xbf),
+            (NR_50, 0x77),
+            (NR_51, 0xf3),
+            (LCDC, 0x91),
+            (STAT, 0x85),
+            (SCY, 0x00),
+            (SCX, 0x00),
+            (LYC, 0x00),
+            (BGP, 0xfc),
+            (WY, 0x00),
+            (WX, 0x00),
+            (IE, 0x00),
+        ];
+
+        for (address, value) in io.iter() {
+            self.write(*address, *value);
+        }
+        // the boot ROM never starts an OAM DMA, which writing DMA would
+        self.data[DMA as usize] = if model == CGB { 0x00 } else { 0xff };
+        // DIV depends on how long the boot ROM ran, and writing it would reset it
+        let div = if model == CGB { 0x26 } else { 0xab };
+        self.catch_up_timer();
+        self.timer.set_counter(div << 8);
+        self.schedule_timer();
+        self.schedule_apu();
+        self.apu.end_boot_sound();
+
+        // the CGB boot ROM initialises every background colour to white
+        if self.cgb_mode {
+            self.write(BCPS, 0x80);
+            for _ in 0..0x40 {
+                self.write(BCPD, 0xff);
+            }
+            self.write(BCPS, 0x00);
+        }
+    }
+
+    // watchpoints are only set and taken by the debugger and GDB stub
+    #[cfg(feature = "std")]
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        return &self.watchpoints;
+    }
+
+    // replaces any watchpoint already at the address
+    #[cfg(feature = "std")]
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.remove_watchpoint(watchpoint.address);
+        self.watchpoints.push(watchpoint);
+    }
+
+    #[cfg(feature = "std")]
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.address != address);
+        return self.watchpoints.len() != count;
+    }
+
+    // whether a watched access is waiting to be taken
+    #[cfg(feature = "std")]
+    pub(crate) fn watch_hit_pending(&self) -> bool {
+        return self.watch_hit.get().is_some();
+    }
+
+    // the first watched access since the last call
+    #[cfg(feature = "std")]
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        return self.watch_hit.take();
+    }
+
+    #[cfg(feature = "std")]
+    fn check_watchpoints(&self, address: u16, value: u8, write: bool) {
+        let watched = self
+            .watchpoints
+            .iter()
+            .any(|w| w.address == address && if write { w.write } else { w.read });
+        if watched && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit {
+                address,
+                value,
+                write,
+            }));
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        // OAM DMA has the bus to OAM
+        if (OAM_START..=0xfe9f).contains(&address) && self.dma.active() {
+            return 0xff;
+        }
+        let value = self.peek(address);
+        #[cfg(feature = "std")]
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, false);
+        }
+        return value;
+    }
+
+    // reads without triggering watchpoints, for the debugger to inspect memory
+    pub fn peek(&self, address: u16) -> u8 {
+        if let Some(boot_rom) = &self.boot_rom {
+            let index = address as usize;
+            let in_header = (BOOT_ROM_HEADER_START..BOOT_ROM_HEADER_END).contains(&index);
+            if index < boot_rom.len() && !in_header {
+                return boot_rom[index];
+            }
+        }
+
+        return match address {
+            0x0000..=ROM_END => self.cartridge.read_rom(address),
+            VRAM_START..=0x9fff => self.ppu.read(address),
+            0xa000..=0xbfff => self.cartridge.read_ram(address),
+            0xc000..=0xcfff => self.wram[0][(address - WRAM_START) as usize],
+            0xd000..=0xdfff => self.wram[self.wram_bank][(address - 0xd000) as usize],
+            0xe000..=0xfdff => self.peek(address - ECHO_OFFSET),
+            0xfe00..=0xfe9f => self.ppu.read(address),
+            0xfea0..=0xfeff => 0xff, // unusable
+            P1 => self.read_p1(),
+            // the timer and serial port are only run as far as needed to interrupt on time
+            SB | SC => {
+                let mut serial = self.serial.clone();
+                serial.advance(self.scheduler.pending(Component::Serial));
+                serial.read(address)
+            }
+            DIV..=TAC => {
+                let mut timer = self.timer.clone();
+                timer.advance(self.scheduler.pending(Component::Timer));
+                timer.read(address)
+            }
+            NR_10..=WAVE_RAM_END => self.apu.read(address),
+            LCDC..=WX | VBK | BCPS..=OCPD if address != DMA => self.ppu.read(address),
+            KEY1 if self.cgb_mode => {
+                0x7e | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+            }
+            SVBK if self.cgb_mode => 0xf8 | self.wram_bank as u8,
+            HDMA1..=HDMA5 if self.cgb_mode => self.hdma.read(address),
+            KEY0 | KEY1 | HDMA1..=HDMA5 | SVBK => 0xff,
+            IF => self.data[IF as usize] | 0xe0, // upper 3 bits are unused and always read high
+            BOOT => 0xff,
+            _ => self.data[address as usize],
+        };
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        #[cfg(feature = "std")]
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, true);
+        }
+        #[cfg(feature = "blocks")]
+        if self.code_lines[(address / CODE_LINE_SIZE) as usize] {
+            self.forget_code();
+        }
+        match address {
+            0x0000..=ROM_END => self.cartridge.write_rom(address, value),
+            VRAM_START..=0x9fff => self.ppu.write(address, value),
+            0xa000..=0xbfff => self.cartridge.write_ram(address, value),
+            0xc000..=0xcfff => self.wram[0][(address - WRAM_START) as usize] = value,
+            0xd000..=0xdfff => self.wram[self.wram_bank][(address - 0xd000) as usize] = value,
+            0xe000..=0xfdff => self.write(address - ECHO_OFFSET, value),
+            // OAM DMA has the bus to OAM
+            0xfe00..=0xfe9f if self.dma.active() => return,
+            0xfe00..=0xfe9f => self.ppu.write(address, value),
+            0xfea0..=0xfeff => return,
+            P1 => {
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                }
+            }
+            SB | SC => {
+                self.catch_up_serial();
+                self.serial.write(address, value);
+                self.schedule_serial();
+            }
+            DIV..=TAC => {
+                self.catch_up_timer();
+                if address == DIV {
+                    // resetting the counter can make the frame sequencer's bit fall
+                    self.catch_up_apu();
+                    if self.timer.counter() & (1 << self.div_apu_bit()) != 0 {
+                        self.apu.step_sequencer();
+                    }
+                }
+                self.timer.write(address, value);
+                self.schedule_timer();
+                if address == DIV {
+                    self.schedule_apu();
+                }
+            }
+            NR_10..=WAVE_RAM_END => {
+                self.catch_up_apu();
+                self.apu.write(address, value);
+            }
+            DMA => {
+                self.data[DMA as usize] = value;
+                self.dma.start(value);
+            }
+            LCDC..=WX | VBK | BCPS..=OCPD => {
+                // the STAT interrupt line is checked again in the next cycle, as it is after
+                // each event
+                self.catch_up_ppu(false);
+                self.ppu.write(address, value);
+                self.scheduler.schedule(Component::PPU, Some(1));
+            }
+            // bit 2 selects DMG compatibility mode
+            KEY0 if self.cgb && self.boot_rom.is_some() => {
+                // the serial port's fast clock is only there in CGB mode
+                self.catch_up_serial();
+                self.set_cgb_mode(value & 0x04 == 0);
+                self.schedule_serial();
+            }
+            KEY0 => return,
+            KEY1 => self.speed_switch_armed = value & 1 == 1,
+            HDMA1..=HDMA5 if self.cgb_mode => {
+                let in_hblank = self.ppu.mode() == Mode::HBlank;
+                self.hdma.write(address, value, in_hblank);
+            }
+            HDMA1..=HDMA5 => return,
+            // bank 0 selects bank 1
+            SVBK if self.cgb_mode => self.wram_bank = ((value & 0b111) as usize).max(1),
+            BOOT => {
+                if value != 0 {
+                    self.boot_rom = None;
+                }
+            }
+            _ => self.data[address as usize] = value,
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read(&self, address: u16) -> u8 {
+        return Memory::read(self, address);
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        Memory::write(self, address, value);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        return Memory::peek(self, address);
+    }
+
+    fn speed_switch_armed(&self) -> bool {
+        return Memory::speed_switch_armed(self);
+    }
+
+    fn switch_speed(&mut self) {
+        Memory::switch_speed(self);
+    }
+}
+
+// what the CPU's block cache needs to know to run instructions whole
+#[cfg(feature = "blocks")]
+impl Memory {
+    // which bank of code is mapped at the address, None where code is never cached
+    pub(crate) fn code_bank(&self, address: u16) -> Option<usize> {
+        if let Some(boot_rom) = &self.boot_rom {
+            let index = address as usize;
+            let in_header = (BOOT_ROM_HEADER_START..BOOT_ROM_HEADER_END).contains(&index);
+            if index < boot_rom.len() && !in_header {
+                return Some(usize::MAX);
+            }
+        }
+        return match address {
+            0x0000..=ROM_END => Some(self.cartridge.rom_bank(address)),
+            0xc000..=0xcfff | 0xff80..=0xfffe => Some(0),
+            0xd000..=0xdfff => Some(self.wram_bank),
+            0xe000..=0xfdff => self.code_bank(address - ECHO_OFFSET),
+            _ => None,
+        };
+    }
+
+    // whether the CPU accessing the address touches nothing the rest of the machine looks at, so
+    // that when in the instruction it happens makes no difference
+    pub(crate) fn plain(&self, address: u16, write: bool) -> bool {
+        return match address {
+            0x0000..=ROM_END => !write,
+            0xc000..=0xfdff | 0xff80..=0xfffe => true,
+            _ => false,
+        };
+    }
+
+    // notes that a decoded block holds the byte, so that writing it forgets every block
+    pub(crate) fn mark_code(&mut self, address: u16) {
+        let address = match address {
+            0xe000..=0xfdff => address - ECHO_OFFSET,
+            _ => address,
+        };
+        if address >= WRAM_START {
+            self.code_lines[(address / CODE_LINE_SIZE) as usize] = true;
+        }
+    }
+
+    pub(crate) fn code_generation(&self) -> u64 {
+        return self.code_generation;
+    }
+
+    fn forget_code(&mut self) {
+        self.code_generation += 1;
+        self.code_lines = [false; 0x10000 / CODE_LINE_SIZE as usize];
+    }
+
+    // cycles in which nothing but the CPU would do anything, so which it can run through at once
+    pub(crate) fn quiet_cycles(&self) -> u128 {
+        if !self.dma.idle() || self.hdma.active() {
+            return 0;
+        }
+        #[cfg(feature = "std")]
+        if !self.watchpoints.is_empty() {
+            return 0;
+        }
+        return self.scheduler.quiet_cycles();
+    }
+
+    // the same as calling cycle as many times, for no more than quiet_cycles
+    pub(crate) fn skip_cycles(&mut self, cycles: u128) {
+        self.scheduler.skip(cycles);
+    }
+}
+
+#[cfg(test)]
+impl Memory {
+    // DMG memory with a ROM only cartridge holding the given program at 0x0000
+    pub(crate) fn with_program(program: &[u8]) -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[..program.len()].copy_from_slice(program);
+        return Memory::new(Model::DMG, Cartridge::new(rom).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(fill: u8, cgb_flag: u8) -> Cartridge {
+        let mut rom = vec![fill; 0x8000];
+        rom[0x143] = cgb_flag;
+        rom[0x147] = 0x00;
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x00;
+        return Cartridge::new(rom).unwrap();
+    }
+
+    #[test]
+    fn test_boot_rom_is_mapped_over_cartridge_until_disabled() {
+        let mut memory = Memory::with_boot_rom(Model::DMG, cartridge(0xc3, 0), vec![0x31; 0x100]);
+
+        assert_eq!(memory.read(0x0000), 0x31);
+        assert_eq!(memory.read(0x00ff), 0x31);
+        assert_eq!(memory.read(0x0100), 0xc3);
+
+        memory.write(BOOT, 0x01);
+
+        assert_eq!(memory.read(0x0000), 0xc3);
+        assert_eq!(memory.read(0x00ff), 0xc3);
+    }
+
+    #[test]
+    fn test_writing_zero_to_boot_does_not_unmap_boot_rom() {
+        let mut memory = Memory::with_boot_rom(Model::DMG, cartridge(0xc3, 0), vec![0x31; 0x100]);
+
+        memory.write(BOOT, 0x00);
+
+        assert_eq!(memory.read(0x0000), 0x31);
+    }
+
+    #[test]
+    fn test_cgb_boot_rom_leaves_cartridge_header_visible() {
+        let memory = Memory::with_boot_rom(Model::CGB, cartridge(0xc3, 0), vec![0x31; 0x900]);
+
+        assert_eq!(memory.read(0x00ff), 0x31);
+        assert_eq!(memory.read(0x0134), 0xc3);
+        assert_eq!(memory.read(0x0200), 0x31);
+        assert_eq!(memory.read(0x08ff), 0x31);
+        assert_eq!(memory.read(0x0900), 0xc3);
+    }
+
+    #[test]
+    fn test_cartridge_rom_is_read_only() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0x12, 0));
+
+        memory.write(0x0042, 0x34);
+
+        assert_eq!(memory.read(0x0042), 0x12);
+    }
+
+    #[test]
+    fn test_post_boot_io_registers() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+
+        memory.write_post_boot_io(Model::DMG);
+
+        assert_eq!(memory.read(LCDC), 0x91);
+        assert_eq!(memory.read(BGP), 0xfc);
+        assert_eq!(memory.read(DIV), 0xab);
+        assert_eq!(memory.read(IF), 0xe1);
+    }
+
+    #[test]
+    fn test_echo_ram_mirrors_work_ram() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+
+        memory.write(0xc123, 0x45);
+        memory.write(0xf000, 0x67);
+
+        assert_eq!(memory.read(0xe123), 0x45);
+        assert_eq!(memory.read(0xd000), 0x67);
+    }
+
+    #[test]
+    fn test_cgb_mode_is_selected_by_cartridge() {
+        let dmg_game = Memory::new(Model::CGB, cartridge(0, 0x00));
+        let cgb_game = Memory::new(Model::CGB, cartridge(0, 0x80));
+
+        assert!(!dmg_game.cgb_mode);
+        assert!(cgb_game.cgb_mode);
+        assert_eq!(dmg_game.read(SVBK), 0xff);
+    }
+
+    #[test]
+    fn test_cgb_boot_rom_selects_dmg_compatibility_mode() {
+        let mut memory = Memory::with_boot_rom(Model::CGB, cartridge(0, 0x00), vec![0; 0x900]);
+        assert!(memory.cgb_mode);
+
+        memory.write(KEY0, 0x04);
+        memory.write(BOOT, 0x01);
+        memory.write(KEY0, 0x00); // only writable while the boot ROM is mapped
+
+        assert!(!memory.cgb_mode);
+    }
+
+    #[test]
+    fn test_wram_banks_in_cgb_mode() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+
+        memory.write(0xd000, 0x01);
+        memory.write(SVBK, 0x03);
+        memory.write(0xd000, 0x03);
+        assert_eq!(memory.read(SVBK), 0xfb);
+        assert_eq!(memory.read(0xd000), 0x03);
+
+        memory.write(SVBK, 0x00); // bank 0 selects bank 1
+        assert_eq!(memory.read(0xd000), 0x01);
+    }
+
+    #[test]
+    fn test_vram_banks_in_cgb_mode() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+
+        memory.write(0x8000, 0x11);
+        memory.write(VBK, 0x01);
+        memory.write(0x8000, 0x22);
+        assert_eq!(memory.read(VBK), 0xff);
+        assert_eq!(memory.read(0x8000), 0x22);
+
+        memory.write(VBK, 0x00);
+        assert_eq!(memory.read(0x8000), 0x11);
+    }
+
+    #[test]
+    fn test_key1_reports_armed_and_current_speed() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+
+        memory.write(KEY1, 0x01);
+        assert_eq!(memory.read(KEY1), 0x7f);
+        assert!(memory.speed_switch_armed());
+
+        memory.switch_speed();
+        assert_eq!(memory.read(KEY1), 0xfe);
+        assert!(memory.double_speed());
+    }
+
+    fn start_vram_dma(memory: &mut Memory, hdma5: u8) {
+        for i in 0..0x20 {
+            memory.write(0xc000 + i, i as u8 + 1);
+        }
+        memory.write(HDMA1, 0xc0);
+        memory.write(HDMA2, 0x00);
+        memory.write(HDMA3, 0x80);
+        memory.write(HDMA4, 0x00);
+        memory.write(HDMA5, hdma5);
+    }
+
+    #[test]
+    fn test_general_dma_takes_8_cycles_per_block() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+        start_vram_dma(&mut memory, 0x01);
+
+        for _ in 0..15 {
+            memory.cycle(false);
+            assert!(memory.hdma_active());
+        }
+        memory.cycle(false);
+
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.read(0x8000), 0x01);
+        assert_eq!(memory.read(0x801f), 0x20);
+        assert_eq!(memory.read(HDMA5), 0xff);
+    }
+
+    #[test]
+    fn test_general_dma_takes_twice_as_many_cycles_at_double_speed() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+        memory.switch_speed();
+        start_vram_dma(&mut memory, 0x00);
+
+        for _ in 0..15 {
+            memory.cycle(false);
+        }
+        assert!(memory.hdma_active());
+        memory.cycle(false);
+
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.read(0x800f), 0x10);
+    }
+
+    #[test]
+    fn test_hblank_dma_waits_for_hblank_and_halted_cpu() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+        memory.write(LCDC, 0x80);
+        start_vram_dma(&mut memory, 0x81);
+        assert!(!memory.hdma_active());
+
+        // the first HBlank happens while the CPU is halted
+        while memory.ppu.mode() != Mode::HBlank {
+            memory.cycle(true);
+        }
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.read(HDMA5), 0x01);
+
+        while memory.ppu.mode() == Mode::HBlank {
+            memory.cycle(false);
+        }
+        while memory.ppu.mode() != Mode::HBlank {
+            memory.cycle(false);
+        }
+        assert!(memory.hdma_active());
+        for _ in 0..8 {
+            memory.cycle(false);
+        }
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.read(HDMA5), 0x00);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_vram_dma_triggers_no_watchpoints() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x80));
+        start_vram_dma(&mut memory, 0x00);
+        memory.add_watchpoint(Watchpoint {
+            address: 0xc005,
+            read: true,
+            write: false,
+        });
+
+        for _ in 0..8 {
+            memory.cycle(false);
+        }
+
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.peek(0x8005), 0x06);
+        assert_eq!(memory.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn test_hdma_registers_are_unavailable_in_dmg_mode() {
+        let mut memory = Memory::new(Model::CGB, cartridge(0, 0x00));
+
+        start_vram_dma(&mut memory, 0x00);
+
+        assert!(!memory.hdma_active());
+        assert_eq!(memory.read(HDMA5), 0xff);
+    }
+
+    #[test]
+    fn test_oam_dma_copies_a_byte_per_cycle_and_blocks_oam() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+        for i in 0..0xa0 {
+            memory.write(0xc100 + i, i as u8 + 1);
+        }
+
+        memory.write(DMA, 0xc1);
+        memory.cycle(false); // setup
+        memory.cycle(false);
+        assert_eq!(memory.read(OAM_START), 0xff);
+        assert_eq!(memory.peek(OAM_START), 0x01);
+        assert_eq!(memory.peek(OAM_START + 1), 0x00);
+        for _ in 1..0xa0 {
+            memory.cycle(false);
+        }
+
+        assert_eq!(memory.read(OAM_START + 0x9f), 0xa0);
+        assert_eq!(memory.read(DMA), 0xc1);
+    }
+
+    #[test]
+    fn test_timer_and_serial_request_interrupts() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+        memory.write(TIMA, 0xff);
+        memory.write(TAC, 0x05);
+        memory.write(SC, 0x81);
+
+        for _ in 0..5 {
+            memory.cycle(false);
+        }
+        assert_eq!(memory.read(IF) & 0x1f, 0x04);
+        for _ in 5..128 * 8 {
+            memory.cycle(false);
+        }
+        assert_eq!(memory.read(IF) & 0x1f, 0x0c);
+    }
+
+    #[test]
+    fn test_registers_read_as_if_peripherals_ran_every_cycle() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+        memory.write(TAC, 0x05); // every 4 cycles
+        memory.write(LCDC, 0x80);
+        memory.write(SB, 0x00);
+        memory.write(SC, 0x81);
+
+        for _ in 0..1000 {
+            memory.cycle(false);
+        }
+
+        assert_eq!(memory.read(DIV), (1000 * 4 / 256) as u8);
+        assert_eq!(memory.read(TIMA), 250);
+        assert_eq!(memory.read(SB), 0x7f); // 7 bits in after 896 cycles
+        assert_eq!(memory.read(LY), 8); // 114 cycles per line
+        assert_eq!(memory.read(STAT) & 0x03, 0); // 352 dots into the line
+        assert_eq!(memory.read(IF) & 0x1f, 0);
+    }
+
+    #[test]
+    fn test_post_boot_sound_registers() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+
+        memory.write_post_boot_io(Model::DMG);
+
+        assert_eq!(memory.read(NR_52), 0xf1);
+        assert_eq!(memory.read(NR_50), 0x77);
+        assert_eq!(memory.read(NR_11), 0xbf);
+    }
+
+    #[test]
+    fn test_joypad_press_requests_interrupt() {
+        let mut memory = Memory::new(Model::DMG, cartridge(0, 0));
+        memory.write(P1, 0x20);
+
+        memory.press(Button::Left);
+
+        assert_eq!(memory.read(P1), 0xed);
+        assert_eq!(memory.read(IF), 0xf0);
+    }
+
+    #[test]
+    fn test_sgb_reports_controller_through_p1() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x146] = 0x03;
+        rom[0x14b] = 0x33;
+        let mut memory = Memory::new(Model::SGB, Cartridge::new(rom).unwrap());
+        memory.press(Button::A);
+
+        memory.write(P1, 0x30);
+        assert_eq!(memory.read(P1), 0xff);
+
+        // MLT_REQ for 2 players
+        let packet = [0x11 << 3 | 1, 0x01];
+        memory.write(P1, 0x00);
+        memory.write(P1, 0x30);
+        for i in 0..128 {
+            let bit = packet.get(i / 8).map_or(0, |byte| (byte >> (i % 8)) & 1);
+            memory.write(P1, if bit == 1 { 0x10 } else { 0x20 });
+            memory.write(P1, 0x30);
+        }
+        memory.write(P1, 0x20);
+        memory.write(P1, 0x30);
+
+        memory.write(P1, 0x10);
+        assert_eq!(memory.read(P1), 0xde);
+        memory.write(P1, 0x30);
+        assert_eq!(memory.read(P1), 0xfe);
+        memory.write(P1, 0x10);
+        assert_eq!(memory.read(P1), 0xdf); // the second controller has nothing pressed
+    }
+}
@@ -0,0 +1,39 @@
+use crate::cartridge::Cartridge;
+use alloc::format;
+use alloc::string::String;
+use core::str::FromStr;
+
+// the hardware revision being emulated, which determines the post-boot state
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Model {
+    DMG, // original Game Boy
+    MGB, // Game Boy Pocket
+    CGB, // Game Boy Color
+    SGB, // Super Game Boy
+}
+
+impl Model {
+    // games declaring CGB support in their header run on a CGB
+    pub fn for_cartridge(cartridge: &Cartridge) -> Model {
+        if cartridge.supports_cgb() {
+            return Model::CGB;
+        }
+        return Model::DMG;
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        use Model::*;
+
+        match s.to_ascii_lowercase().as_str() {
+            "dmg" => Ok(DMG),
+            "mgb" => Ok(MGB),
+            "cgb" => Ok(CGB),
+            "sgb" => Ok(SGB),
+            _ => Err(format!("Unknown model {}", s)),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::model::Model;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u32 = 2;
+const HASH_INTERVAL: usize = 60; // frames between checks that playback matches the recording
+
+// joypad input for each frame from a starting state, emulation is deterministic so playing it
+// back reproduces the recording exactly
+pub struct Movie {
+    checksum: [u8; 3], // of the cartridge it was recorded with
+    model: Model,
+    initial_state: Vec<u8>,
+    inputs: Vec<u8>,  // buttons held during each frame
+    hashes: Vec<u64>, // of the state before every HASH_INTERVAL-th frame
+    end_hash: u64,    // of the state after the last frame
+}
+
+// a movie still being recorded, which only becomes one that can be saved or played once finished
+pub struct Recording {
+    checksum: [u8; 3],
+    model: Model,
+    initial_state: Vec<u8>,
+    inputs: Vec<u8>,
+    hashes: Vec<u64>,
+}
+
+impl Recording {
+    // to be called before running each frame, with its input already applied
+    pub fn record_frame(&mut self, gb: &GameBoy) {
+        if self.inputs.len().is_multiple_of(HASH_INTERVAL) {
+            self.hashes.push(hash(&gb.save_state()));
+        }
+        self.inputs.push(gb.buttons());
+    }
+
+    pub fn frames(&self) -> usize {
+        return self.inputs.len();
+    }
+
+    // to be called after running the last frame, so that playback also checks the frames since
+    // the last periodic hash
+    pub fn finish(self, gb: &GameBoy) -> Movie {
+        return Movie {
+            checksum: self.checksum,
+            model: self.model,
+            initial_state: self.initial_state,
+            inputs: self.inputs,
+            hashes: self.hashes,
+            end_hash: hash(&gb.save_state()),
+        };
+    }
+}
+
+impl Movie {
+    // starts recording from the machine's current state
+    pub fn record(gb: &GameBoy) -> Recording {
+        return Recording {
+            checksum: gb.cartridge_checksum(),
+            model: gb.model(),
+            initial_state: gb.save_state(),
+            inputs: vec![],
+            hashes: vec![],
+        };
+    }
+
+    pub fn model(&self) -> Model {
+        return self.model;
+    }
+
+    pub fn frames(&self) -> usize {
+        return self.inputs.len();
+    }
+
+    // replays every frame, failing at the first one whose state differs from the recording
+    pub fn play(&self, gb: &mut GameBoy) -> Result<(), EmuError> {
+        if gb.cartridge_checksum() != self.checksum {
+            return Err(EmuError::BadState(
+                "Movie was recorded with a different game".to_string(),
+            ));
+        }
+        if gb.model() != self.model {
+            return Err(EmuError::BadState(format!(
+                "Movie was recorded on {:?}",
+                self.model
+            )));
+        }
+
+        gb.load_state(&self.initial_state)?;
+        for (frame, &buttons) in self.inputs.iter().enumerate() {
+            gb.set_buttons(buttons);
+            if frame.is_multiple_of(HASH_INTERVAL)
+                && hash(&gb.save_state()) != self.hashes[frame / HASH_INTERVAL]
+            {
+                return Err(desynced(frame));
+            }
+            gb.run_frame()?;
+        }
+        if hash(&gb.save_state()) != self.end_hash {
+            return Err(desynced(self.inputs.len()));
+        }
+        return Ok(());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::new();
+        movie.bytes(MAGIC);
+        movie.u32(VERSION);
+        movie.bytes(&self.checksum);
+        movie.u8(match self.model {
+            Model::DMG => 0,
+            Model::MGB => 1,
+            Model::CGB => 2,
+            Model::SGB => 3,
+        });
+        movie.vec(&self.initial_state);
+        movie.vec(&self.inputs);
+        for &hash in self.hashes.iter() {
+            movie.u64(hash);
+        }
+        movie.u64(self.end_hash);
+        return movie.finish();
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, EmuError> {
+        return Movie::read(data).map_err(EmuError::BadState);
+    }
+
+    fn read(data: &[u8]) -> Result<Movie, String> {
+        let mut movie = StateReader::new(data);
+
+        let mut magic = [0; 4];
+        movie.bytes(&mut magic)?;
+        if magic != *MAGIC {
+            return Err("Not a movie".to_string());
+        }
+        let version = movie.u32()?;
+        if version != VERSION {
+            return Err(format!("Unsupported movie version {}", version));
+        }
+        let mut checksum = [0; 3];
+        movie.bytes(&mut checksum)?;
+        let model = match movie.u8()? {
+            0 => Model::DMG,
+            1 => Model::MGB,
+            2 => Model::CGB,
+            3 => Model::SGB,
+            model => return Err(format!("Unknown model {} in movie", model)),
+        };
+        let initial_state = movie.vec()?;
+        let inputs = movie.vec()?;
+        let hashes = (0..inputs.len().div_ceil(HASH_INTERVAL))
+            .map(|_| movie.u64())
+            .collect::<Result<Vec<u64>, String>>()?;
+        let end_hash = movie.u64()?;
+        movie.finish()?;
+
+        return Ok(Movie {
+            checksum,
+            model,
+            initial_state,
+            inputs,
+            hashes,
+            end_hash,
+        });
+    }
+}
+
+fn desynced(frame: usize) -> EmuError {
+    return EmuError::BadState(format!("Playback desynced at frame {}", frame));
+}
+
+// 64 bit FNV-1a, which unlike the standard library's hasher is guaranteed to stay the same
+fn hash(data: &[u8]) -> u64 {
+    return data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::Button;
+    use crate::test_util::rom_with;
+
+    // a game whose state depends on the buttons held, through P1 and the joypad interrupt
+    fn gameboy() -> GameBoy {
+        // joypad interrupt handler: LDH A, (LY); ADD A, B; LD B, A; RETI
+        let handler = [0xf0, 0x44, 0x80, 0x47, 0xd9];
+        let program = [
+            0x3e, 0x10, //       LD A, 0x10
+            0xe0, 0x00, //       LDH (P1), A
+            0x3e, 0x10, //       LD A, 0x10
+            0xe0, 0xff, //       LDH (IE), A
+            0xfb, //             EI
+            0xf0, 0x00, //       loop: LDH A, (P1)
+            0x80, //             ADD A, B
+            0x4f, //             LD C, A
+            0x81, //             ADD A, C
+            0xe0, 0x80, //       LDH (0x80), A
+            0x18, 0xf7, //       JR loop
+        ];
+        return rom_with(Model::DMG, &[(0x60, &handler), (0x100, &program)]);
+    }
+
+    fn record(frames: usize) -> (Movie, Vec<u8>) {
+        let mut gb = gameboy();
+        let mut recording = Movie::record(&gb);
+        for frame in 0..frames {
+            if frame % 5 == 0 {
+                gb.press(Button::Start);
+            } else if frame % 5 == 2 {
+                gb.release(Button::Start);
+            }
+            recording.record_frame(&gb);
+            gb.run_frame().unwrap();
+        }
+        assert_eq!(recording.frames(), frames);
+        return (recording.finish(&gb), gb.save_state());
+    }
+
+    #[test]
+    fn test_replay_reproduces_recording() {
+        let (movie, final_state) = record(130);
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+
+        let mut gb = gameboy();
+        assert_eq!(movie.play(&mut gb), Ok(()));
+
+        assert!(gb.save_state() == final_state);
+    }
+
+    #[test]
+    fn test_detects_desync() {
+        let (mut movie, _) = record(130);
+        // an extra press, which runs the interrupt handler one more time
+        movie.inputs[103] |= 1 << Button::Start.bit();
+
+        assert_eq!(
+            movie.play(&mut gameboy()),
+            Err(EmuError::BadState(
+                "Playback desynced at frame 120".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_detects_desync_after_the_last_hash() {
+        let (mut movie, _) = record(130);
+        movie.inputs[128] |= 1 << Button::Start.bit();
+
+        assert_eq!(
+            movie.play(&mut gameboy()),
+            Err(EmuError::BadState(
+                "Playback desynced at frame 130".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_games_and_files() {
+        let (movie, _) = record(1);
+        let mut other = rom_with(Model::DMG, &[(0x14e, &[1])]); // another global checksum
+
+        assert!(movie.play(&mut other).is_err());
+        assert!(Movie::from_bytes(b"GBSS").is_err());
+        let bytes = movie.to_bytes();
+        assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
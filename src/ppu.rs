@@ -0,0 +1,642 @@
+use crate::cpu::special_registers::*;
+use crate::cpu::Interrupt;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const VRAM_START: u16 = 0x8000;
+const OAM_START: u16 = 0xfe00;
+const VRAM_BANK_SIZE: usize = 0x2000;
+const VRAM_BANK_COUNT: usize = 2;
+const OAM_SIZE: usize = 0xa0;
+const PALETTE_RAM_SIZE: usize = 0x40;
+
+pub const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const SPRITE_COUNT: usize = 40;
+const SPRITES_PER_LINE: usize = 10;
+
+// RGB555 colours for the 4 DMG shades, from lightest to darkest
+pub(crate) const DMG_SHADES: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+// LCDC bits
+const LCD_ENABLE: u8 = 1 << 7;
+const WINDOW_TILE_MAP: u8 = 1 << 6;
+const WINDOW_ENABLE: u8 = 1 << 5;
+const TILE_DATA: u8 = 1 << 4;
+const BG_TILE_MAP: u8 = 1 << 3;
+const OBJ_SIZE: u8 = 1 << 2;
+const OBJ_ENABLE: u8 = 1 << 1;
+const BG_ENABLE: u8 = 1 << 0; // BG and window master priority on CGB
+
+// background map and object attribute bits
+const ATTR_PRIORITY: u8 = 1 << 7;
+const ATTR_Y_FLIP: u8 = 1 << 6;
+const ATTR_X_FLIP: u8 = 1 << 5;
+const ATTR_DMG_PALETTE: u8 = 1 << 4;
+const ATTR_BANK: u8 = 1 << 3;
+const ATTR_CGB_PALETTE: u8 = 0b111;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OAMScan,
+    Drawing,
+}
+
+#[derive(Clone)]
+pub struct PPU {
+    cgb_mode: bool,
+    vram: [[u8; VRAM_BANK_SIZE]; VRAM_BANK_COUNT],
+    vram_bank: usize,
+    oam: [u8; OAM_SIZE],
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE], // 8 palettes of 4 little endian RGB555 colours
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    bcps: u8,
+    ocps: u8,
+    lcdc: u8,
+    stat: u8, // interrupt selection bits only
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    dot: u16,        // position within the current line
+    window_line: u8, // line of the window to draw next, only advances when the window is drawn
+    stat_line: bool, // STAT interrupts are requested on the rising edge of this
+    frame_buffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    shades: [u8; SCREEN_WIDTH * SCREEN_HEIGHT], // DMG shade of each pixel, used by the SGB
+}
+
+impl PPU {
+    pub fn new(cgb_mode: bool) -> PPU {
+        return PPU {
+            cgb_mode,
+            vram: [[0; VRAM_BANK_SIZE]; VRAM_BANK_COUNT],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
+            bg_palette_ram: [0xff; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0xff; PALETTE_RAM_SIZE],
+            bcps: 0,
+            ocps: 0,
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0xff,
+            obp1: 0xff,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            frame_buffer: [DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        };
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn frame_buffer(&self) -> &[u16; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        return &self.frame_buffer;
+    }
+
+    pub fn shades(&self) -> &[u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        return &self.shades;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.cgb_mode);
+        for bank in self.vram.iter() {
+            state.bytes(bank);
+        }
+        state.usize(self.vram_bank);
+        state.bytes(&self.oam);
+        state.bytes(&self.bg_palette_ram);
+        state.bytes(&self.obj_palette_ram);
+        for register in [
+            self.bcps, self.ocps, self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+            self.bgp, self.obp0, self.obp1, self.wy, self.wx,
+        ] {
+            state.u8(register);
+        }
+        state.u8(self.mode_bits());
+        state.u16(self.dot);
+        state.u8(self.window_line);
+        state.bool(self.stat_line);
+        state.u16s(&self.frame_buffer);
+        state.bytes(&self.shades);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.cgb_mode = state.bool()?;
+        for bank in self.vram.iter_mut() {
+            state.bytes(bank)?;
+        }
+        self.vram_bank = state.index(VRAM_BANK_COUNT)?;
+        state.bytes(&mut self.oam)?;
+        state.bytes(&mut self.bg_palette_ram)?;
+        state.bytes(&mut self.obj_palette_ram)?;
+        for register in [
+            &mut self.bcps,
+            &mut self.ocps,
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *register = state.u8()?;
+        }
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAMScan,
+            3 => Mode::Drawing,
+            mode => return Err(format!("Unknown PPU mode {} in state", mode)),
+        };
+        self.dot = state.u16()?;
+        self.window_line = state.u8()?;
+        self.stat_line = state.bool()?;
+        state.u16s(&mut self.frame_buffer)?;
+        state.bytes(&mut self.shades)?;
+
+        // the lines past the bottom of the screen are all VBlank
+        if self.ly >= LINES_PER_FRAME || self.dot >= DOTS_PER_LINE {
+            return Err(format!(
+                "Out of range position {}, {} in state",
+                self.ly, self.dot
+            ));
+        }
+        if self.ly as usize >= SCREEN_HEIGHT && self.mode != Mode::VBlank {
+            return Err(format!("Line {} outside VBlank in state", self.ly));
+        }
+        return Ok(());
+    }
+
+    pub fn mode(&self) -> Mode {
+        return self.mode;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            0x8000..=0x9fff => self.vram[self.vram_bank][(address - VRAM_START) as usize],
+            0xfe00..=0xfe9f => self.oam[(address - OAM_START) as usize],
+            LCDC => self.lcdc,
+            STAT => 0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode_bits(),
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            VBK if self.cgb_mode => 0xfe | self.vram_bank as u8,
+            BCPS if self.cgb_mode => 0x40 | self.bcps,
+            BCPD if self.cgb_mode => self.bg_palette_ram[(self.bcps & 0x3f) as usize],
+            OCPS if self.cgb_mode => 0x40 | self.ocps,
+            OCPD if self.cgb_mode => self.obj_palette_ram[(self.ocps & 0x3f) as usize],
+            _ => 0xff,
+        };
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9fff => self.vram[self.vram_bank][(address - VRAM_START) as usize] = value,
+            0xfe00..=0xfe9f => self.oam[(address - OAM_START) as usize] = value,
+            LCDC => self.write_lcdc(value),
+            STAT => self.stat = value & 0x78,
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            LY => return, // read only
+            LYC => self.lyc = value,
+            BGP => self.bgp = value,
+            OBP0 => self.obp0 = value,
+            OBP1 => self.obp1 = value,
+            WY => self.wy = value,
+            WX => self.wx = value,
+            VBK if self.cgb_mode => self.vram_bank = (value & 1) as usize,
+            BCPS if self.cgb_mode => self.bcps = value & 0xbf,
+            BCPD if self.cgb_mode => {
+                write_palette_ram(&mut self.bg_palette_ram, &mut self.bcps, value)
+            }
+            OCPS if self.cgb_mode => self.ocps = value & 0xbf,
+            OCPD if self.cgb_mode => {
+                write_palette_ram(&mut self.obj_palette_ram, &mut self.ocps, value)
+            }
+            _ => return,
+        }
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcdc & LCD_ENABLE != 0;
+        self.lcdc = value;
+        if was_enabled && value & LCD_ENABLE == 0 {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+        } else if !was_enabled && value & LCD_ENABLE != 0 {
+            self.mode = Mode::OAMScan;
+            self.window_line = 0;
+        }
+    }
+
+    fn mode_bits(&self) -> u8 {
+        return match self.mode {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::OAMScan => 2,
+            Mode::Drawing => 3,
+        };
+    }
+
+    // dots until the mode or line next changes, which nothing else does between, None while the
+    // LCD is off
+    pub fn dots_until_change(&self) -> Option<u16> {
+        if self.lcdc & LCD_ENABLE == 0 {
+            return None;
+        }
+        let end = match self.mode {
+            Mode::OAMScan => OAM_SCAN_DOTS,
+            Mode::Drawing => OAM_SCAN_DOTS + DRAWING_DOTS,
+            Mode::HBlank | Mode::VBlank => DOTS_PER_LINE,
+        };
+        return Some(end.saturating_sub(self.dot));
+    }
+
+    // advances by the given number of dots, returning the interrupts requested
+    pub fn cycle(&mut self, dots: u16) -> u8 {
+        if self.lcdc & LCD_ENABLE == 0 {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        self.dot += dots;
+
+        match self.mode {
+            Mode::OAMScan if self.dot >= OAM_SCAN_DOTS => self.mode = Mode::Drawing,
+            Mode::Drawing if self.dot >= OAM_SCAN_DOTS + DRAWING_DOTS => {
+                self.render_line();
+                self.mode = Mode::HBlank;
+            }
+            Mode::HBlank | Mode::VBlank if self.dot >= DOTS_PER_LINE => {
+                self.dot -= DOTS_PER_LINE;
+                self.ly = (self.ly + 1) % LINES_PER_FRAME;
+                if self.ly == SCREEN_HEIGHT as u8 {
+                    self.mode = Mode::VBlank;
+                    self.window_line = 0;
+                    interrupts |= 1 << Interrupt::VBlank.bit();
+                } else if (self.ly as usize) < SCREEN_HEIGHT {
+                    self.mode = Mode::OAMScan;
+                }
+            }
+            _ => {}
+        }
+
+        let stat_line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OAMScan)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank);
+        if stat_line && !self.stat_line {
+            interrupts |= 1 << Interrupt::Stat.bit();
+        }
+        self.stat_line = stat_line;
+
+        return interrupts;
+    }
+
+    fn set_dmg_pixel(&mut self, index: usize, shade: u8) {
+        self.shades[index] = shade;
+        self.frame_buffer[index] = DMG_SHADES[shade as usize];
+    }
+
+    fn render_line(&mut self) {
+        let y = self.ly as usize;
+        // colour index and attributes of the background under each pixel, for object priority
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_attributes = [0u8; SCREEN_WIDTH];
+
+        let bg_visible = self.cgb_mode || self.lcdc & BG_ENABLE != 0;
+        let window_visible =
+            bg_visible && self.lcdc & WINDOW_ENABLE != 0 && self.wy <= self.ly && self.wx <= 166;
+        let window_start = self.wx as i16 - 7;
+
+        for x in 0..SCREEN_WIDTH {
+            if !bg_visible {
+                self.set_dmg_pixel(y * SCREEN_WIDTH + x, 0);
+                continue;
+            }
+
+            let (map, map_x, map_y) = if window_visible && x as i16 >= window_start {
+                let map = if self.lcdc & WINDOW_TILE_MAP != 0 {
+                    0x1c00
+                } else {
+                    0x1800
+                };
+                (map, (x as i16 - window_start) as u8, self.window_line)
+            } else {
+                let map = if self.lcdc & BG_TILE_MAP != 0 {
+                    0x1c00
+                } else {
+                    0x1800
+                };
+                (
+                    map,
+                    self.scx.wrapping_add(x as u8),
+                    self.scy.wrapping_add(self.ly),
+                )
+            };
+
+            let (color, attributes) = self.background_pixel(map, map_x, map_y);
+            bg_colors[x] = color;
+            bg_attributes[x] = attributes;
+            if self.cgb_mode {
+                self.frame_buffer[y * SCREEN_WIDTH + x] =
+                    cgb_color(&self.bg_palette_ram, attributes & ATTR_CGB_PALETTE, color);
+            } else {
+                self.set_dmg_pixel(y * SCREEN_WIDTH + x, dmg_shade(self.bgp, color));
+            }
+        }
+
+        if window_visible && window_start < SCREEN_WIDTH as i16 {
+            self.window_line += 1;
+        }
+
+        if self.lcdc & OBJ_ENABLE != 0 {
+            self.render_sprites(&bg_colors, &bg_attributes);
+        }
+    }
+
+    // colour index and CGB attributes of a pixel within a 256x256 tile map
+    fn background_pixel(&self, map: usize, x: u8, y: u8) -> (u8, u8) {
+        let map_index = map + (y as usize / 8) * 32 + (x as usize / 8);
+        let tile = self.vram[0][map_index];
+        let attributes = if self.cgb_mode {
+            self.vram[1][map_index]
+        } else {
+            0
+        };
+
+        let mut tile_x = x % 8;
+        let mut tile_y = y % 8;
+        if attributes & ATTR_X_FLIP != 0 {
+            tile_x = 7 - tile_x;
+        }
+        if attributes & ATTR_Y_FLIP != 0 {
+            tile_y = 7 - tile_y;
+        }
+
+        // 0x8000 addressing uses unsigned tile numbers, 0x8800 uses signed ones relative to 0x9000
+        let tile_address = if self.lcdc & TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+        let bank = ((attributes & ATTR_BANK) != 0) as usize;
+        return (
+            self.tile_pixel(bank, tile_address, tile_x, tile_y),
+            attributes,
+        );
+    }
+
+    fn tile_pixel(&self, bank: usize, tile_address: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[bank][tile_address + y as usize * 2];
+        let high = self.vram[bank][tile_address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        return ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+    }
+
+    fn render_sprites(&mut self, bg_colors: &[u8; SCREEN_WIDTH], bg_attributes: &[u8]) {
+        let height: i16 = if self.lcdc & OBJ_SIZE != 0 { 16 } else { 8 };
+        let ly = self.ly as i16;
+
+        // the first 10 objects in OAM on this line are drawn
+        let mut sprites: Vec<usize> = (0..SPRITE_COUNT)
+            .filter(|i| {
+                let top = self.oam[i * 4] as i16 - 16;
+                top <= ly && ly < top + height
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+        // on DMG the object with the lower X coordinate wins, the sort keeps OAM order for ties
+        if !self.cgb_mode {
+            sprites.sort_by_key(|i| self.oam[i * 4 + 1]);
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            for &i in sprites.iter() {
+                let left = self.oam[i * 4 + 1] as i16 - 8;
+                let x_offset = x as i16 - left;
+                if !(0..8).contains(&x_offset) {
+                    continue;
+                }
+
+                let attributes = self.oam[i * 4 + 3];
+                let mut tile_x = x_offset as u8;
+                let mut tile_y = (ly - (self.oam[i * 4] as i16 - 16)) as u8;
+                if attributes & ATTR_X_FLIP != 0 {
+                    tile_x = 7 - tile_x;
+                }
+                if attributes & ATTR_Y_FLIP != 0 {
+                    tile_y = height as u8 - 1 - tile_y;
+                }
+
+                let mut tile = self.oam[i * 4 + 2];
+                if height == 16 {
+                    tile &= 0xfe;
+                }
+                let bank = (self.cgb_mode && attributes & ATTR_BANK != 0) as usize;
+                let color = self.tile_pixel(bank, tile as usize * 16, tile_x, tile_y);
+                if color == 0 {
+                    continue; // transparent, so a lower priority object may show through
+                }
+
+                let bg_wins = if self.cgb_mode {
+                    self.lcdc & BG_ENABLE != 0
+                        && bg_colors[x] != 0
+                        && (attributes & ATTR_PRIORITY != 0
+                            || bg_attributes[x] & ATTR_PRIORITY != 0)
+                } else {
+                    attributes & ATTR_PRIORITY != 0 && bg_colors[x] != 0
+                };
+                if !bg_wins {
+                    let index = self.ly as usize * SCREEN_WIDTH + x;
+                    if self.cgb_mode {
+                        self.frame_buffer[index] =
+                            cgb_color(&self.obj_palette_ram, attributes & ATTR_CGB_PALETTE, color);
+                    } else if attributes & ATTR_DMG_PALETTE != 0 {
+                        self.set_dmg_pixel(index, dmg_shade(self.obp1, color));
+                    } else {
+                        self.set_dmg_pixel(index, dmg_shade(self.obp0, color));
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
+
+fn dmg_shade(palette: u8, color: u8) -> u8 {
+    return (palette >> (color * 2)) & 0b11;
+}
+
+fn cgb_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color: u8) -> u16 {
+    let index = (palette * 8 + color * 2) as usize;
+    return u16::from_le_bytes([palette_ram[index], palette_ram[index + 1]]) & 0x7fff;
+}
+
+// writes through a BCPS/OCPS style index register, which can auto-increment after writes
+fn write_palette_ram(palette_ram: &mut [u8; PALETTE_RAM_SIZE], index: &mut u8, value: u8) {
+    palette_ram[(*index & 0x3f) as usize] = value;
+    if *index & 0x80 != 0 {
+        *index = 0x80 | ((*index + 1) & 0x3f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_frame(ppu: &mut PPU) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..(DOTS_PER_LINE as usize * LINES_PER_FRAME as usize / 4) {
+            interrupts |= ppu.cycle(4);
+        }
+        return interrupts;
+    }
+
+    #[test]
+    fn test_palette_data_auto_increments() {
+        let mut ppu = PPU::new(true);
+
+        ppu.write(BCPS, 0x82);
+        ppu.write(BCPD, 0x1f);
+        ppu.write(BCPD, 0x00);
+
+        assert_eq!(ppu.read(BCPS), 0xc4);
+        ppu.write(BCPS, 0x02);
+        assert_eq!(ppu.read(BCPD), 0x1f);
+        ppu.write(BCPS, 0x03);
+        assert_eq!(ppu.read(BCPD), 0x00);
+    }
+
+    #[test]
+    fn test_palette_index_wraps() {
+        let mut ppu = PPU::new(true);
+
+        ppu.write(OCPS, 0xbf);
+        ppu.write(OCPD, 0x12);
+
+        assert_eq!(ppu.read(OCPS), 0xc0);
+    }
+
+    #[test]
+    fn test_cgb_registers_are_hidden_in_dmg_mode() {
+        let mut ppu = PPU::new(false);
+
+        ppu.write(VBK, 0x01);
+        ppu.write(0x8000, 0x42);
+        ppu.set_cgb_mode(true);
+
+        assert_eq!(ppu.read(VBK), 0xfe);
+        assert_eq!(ppu.read(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_requests_vblank_once_per_frame() {
+        let mut ppu = PPU::new(false);
+        ppu.write(LCDC, LCD_ENABLE);
+
+        assert_eq!(run_frame(&mut ppu), 1 << Interrupt::VBlank.bit());
+        assert_eq!(ppu.read(LY), 0);
+    }
+
+    #[test]
+    fn test_renders_dmg_background_through_bgp() {
+        let mut ppu = PPU::new(false);
+        // tile 0 is solid colour 3
+        for i in 0..16 {
+            ppu.write(0x8000 + i, 0xff);
+        }
+        ppu.write(BGP, 0b11_00_00_00);
+        ppu.write(LCDC, LCD_ENABLE | TILE_DATA | BG_ENABLE);
+
+        run_frame(&mut ppu);
+
+        assert!(ppu.frame_buffer().iter().all(|&pixel| pixel == 0x0000));
+    }
+
+    #[test]
+    fn test_renders_cgb_attributes_in_rgb555() {
+        let mut ppu = PPU::new(true);
+        // tile 1 in bank 1 is solid colour 1
+        ppu.write(VBK, 1);
+        for i in 0..8 {
+            ppu.write(0x8010 + i * 2, 0xff);
+        }
+        // the top left map entry uses it with BG palette 2
+        ppu.write(0x9800, 0x0a);
+        ppu.write(VBK, 0);
+        ppu.write(0x9800, 0x01);
+        // palette 2 colour 1 is pure blue
+        ppu.write(BCPS, 0x80 | (2 * 8 + 2));
+        ppu.write(BCPD, 0x00);
+        ppu.write(BCPD, 0x7c);
+        ppu.write(LCDC, LCD_ENABLE | TILE_DATA);
+
+        run_frame(&mut ppu);
+
+        assert_eq!(ppu.frame_buffer()[0], 0x7c00);
+        assert_eq!(ppu.frame_buffer()[7 * SCREEN_WIDTH + 7], 0x7c00);
+        assert_eq!(ppu.frame_buffer()[8], 0x7fff);
+    }
+
+    #[test]
+    fn test_rejects_state_with_position_out_of_range() {
+        let corruptions: [fn(&mut PPU); 3] = [
+            |ppu| ppu.ly = 255,
+            |ppu| ppu.dot = DOTS_PER_LINE,
+            |ppu| {
+                ppu.ly = SCREEN_HEIGHT as u8;
+                ppu.mode = Mode::Drawing;
+            },
+        ];
+        for corrupt in corruptions.iter() {
+            let mut ppu = PPU::new(false);
+            corrupt(&mut ppu);
+            let mut state = StateWriter::new();
+            ppu.save_state(&mut state);
+            let state = state.finish();
+
+            assert!(PPU::new(false)
+                .load_state(&mut StateReader::new(&state))
+                .is_err());
+        }
+    }
+}
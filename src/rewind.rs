@@ -0,0 +1,266 @@
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+// a snapshot every 10 frames fits a minute of history in 64MiB for typical games, older snapshots
+// are dropped beyond that
+pub const DEFAULT_INTERVAL: usize = 10;
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+// snapshots of a running machine, taken every few frames, which can be rewound to any frame
+// since the oldest one by restoring a snapshot and replaying the joypad input recorded after it
+pub struct Rewind {
+    interval: usize, // frames between snapshots
+    capacity: usize, // bytes used by snapshots before the oldest are dropped
+    newest: Vec<u8>, // save state of the most recent snapshot
+    // older snapshots, oldest first, each stored as the difference from the one after it
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+    inputs: VecDeque<u8>, // buttons held in each frame run since the oldest snapshot
+}
+
+impl Rewind {
+    pub fn new(interval: usize, capacity: usize) -> Rewind {
+        return Rewind {
+            interval: interval.max(1),
+            capacity,
+            newest: vec![],
+            deltas: VecDeque::new(),
+            size: 0,
+            inputs: VecDeque::new(),
+        };
+    }
+
+    // frames which can currently be rewound
+    pub fn frames(&self) -> usize {
+        return self.inputs.len();
+    }
+
+    // to be called before running each frame, with its input already applied
+    pub fn record_frame(&mut self, gb: &GameBoy) {
+        // the newest snapshot was taken deltas.len() intervals after the oldest
+        let next_snapshot = (self.deltas.len() + 1) * self.interval;
+        if self.newest.is_empty() || self.inputs.len() == next_snapshot {
+            self.push_snapshot(gb.save_state());
+        }
+        self.inputs.push_back(gb.buttons());
+    }
+
+    fn push_snapshot(&mut self, state: Vec<u8>) {
+        if !self.newest.is_empty() {
+            let delta = delta(&self.newest, &state);
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.size = self.size + state.len() - self.newest.len();
+        self.newest = state;
+
+        while self.size > self.capacity && !self.deltas.is_empty() {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.size -= oldest.len();
+            self.inputs.drain(..self.interval.min(self.inputs.len()));
+        }
+    }
+
+    // goes back the given number of frames, or as far as possible, returning how far it went
+    pub fn rewind(&mut self, gb: &mut GameBoy, frames: usize) -> Result<usize, EmuError> {
+        if self.newest.is_empty() {
+            return Ok(0);
+        }
+
+        let frames = frames.min(self.inputs.len());
+        let target = self.inputs.len() - frames;
+        let snapshot = (target / self.interval).min(self.deltas.len());
+
+        // undo deltas from the newest snapshot back to the chosen one, only dropping them once the
+        // machine has been restored, so that a corrupt one leaves everything as it was
+        let mut state = None;
+        for delta in self.deltas.range(snapshot..).rev() {
+            let next = state.as_ref().unwrap_or(&self.newest);
+            state = Some(undo_delta(delta, next).map_err(EmuError::BadState)?);
+        }
+
+        let buttons = gb.buttons();
+        gb.load_state(state.as_ref().unwrap_or(&self.newest))?;
+        if let Some(state) = state {
+            let undone: usize = self.deltas.drain(snapshot..).map(|delta| delta.len()).sum();
+            self.size = self.size - undone + state.len() - self.newest.len();
+            self.newest = state;
+        }
+        for frame in snapshot * self.interval..target {
+            gb.set_buttons(self.inputs[frame]);
+            gb.run_frame()?;
+        }
+        gb.set_buttons(buttons);
+        self.inputs.truncate(target);
+        return Ok(frames);
+    }
+}
+
+// the bytes of one state which differ from another, as runs of unchanged bytes followed by
+// replacement bytes, so that mostly unchanged states take little space
+fn delta(state: &[u8], next: &[u8]) -> Vec<u8> {
+    let mut delta = (state.len() as u32).to_le_bytes().to_vec();
+    let mut i = 0;
+    while i < state.len() {
+        let unchanged = (i..state.len())
+            .take_while(|&j| next.get(j) == Some(&state[j]))
+            .count();
+        i += unchanged;
+        let changed = (i..state.len())
+            .take_while(|&j| next.get(j) != Some(&state[j]))
+            .count();
+        delta.extend_from_slice(&(unchanged as u32).to_le_bytes());
+        delta.extend_from_slice(&(changed as u32).to_le_bytes());
+        delta.extend_from_slice(&state[i..i + changed]);
+        i += changed;
+    }
+    return delta;
+}
+
+fn undo_delta(delta: &[u8], next: &[u8]) -> Result<Vec<u8>, String> {
+    let corrupt = || "Corrupt rewind snapshot".to_string();
+    let read_u32 = |position: usize| -> Result<usize, String> {
+        let bytes = delta.get(position..position + 4).ok_or_else(corrupt)?;
+        return Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize);
+    };
+
+    let length = read_u32(0)?;
+    let mut state = Vec::with_capacity(length);
+    let mut position = 4;
+    while position < delta.len() {
+        let unchanged = read_u32(position)?;
+        let changed = read_u32(position + 4)?;
+        position += 8;
+        let start = state.len();
+        state.extend_from_slice(next.get(start..start + unchanged).ok_or_else(corrupt)?);
+        state.extend_from_slice(
+            delta
+                .get(position..position + changed)
+                .ok_or_else(corrupt)?,
+        );
+        position += changed;
+    }
+    if state.len() != length {
+        return Err(corrupt());
+    }
+    return Ok(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::Button;
+    use crate::test_util::rom_with_program;
+
+    // a game which draws the buttons held into the background and work RAM every frame
+    fn gameboy() -> GameBoy {
+        let program = [
+            0x21, 0x00, 0xc0, // LD HL, 0xc000
+            0x3e, 0x10, //       loop: LD A, 0x10
+            0xe0, 0x00, //       LDH (P1), A
+            0xf0, 0x00, //       LDH A, (P1)
+            0x22, //             LD (HL+), A
+            0xea, 0x00, 0x80, // LD (0x8000), A
+            0xcb, 0x64, //       BIT 4, H
+            0x28, 0xf2, //       JR Z, loop
+            0x21, 0x00, 0xc0, // LD HL, 0xc000
+            0x18, 0xed, //       JR loop
+        ];
+        return rom_with_program(&program);
+    }
+
+    // runs frames with changing input, returning the state before each one
+    fn run(gb: &mut GameBoy, rewind: &mut Rewind, frames: usize) -> Vec<Vec<u8>> {
+        let mut states = vec![];
+        for frame in 0..frames {
+            if frame % 7 == 0 {
+                gb.press(Button::A);
+            } else if frame % 7 == 3 {
+                gb.release(Button::A);
+            }
+            states.push(gb.save_state());
+            rewind.record_frame(gb);
+            gb.run_frame().unwrap();
+        }
+        return states;
+    }
+
+    #[test]
+    fn test_delta_round_trips() {
+        let state = vec![1, 2, 3, 4, 5, 6];
+        let next = vec![1, 9, 9, 4, 5, 6, 7];
+
+        assert_eq!(undo_delta(&delta(&state, &next), &next), Ok(state.clone()));
+        assert_eq!(undo_delta(&delta(&next, &state), &state), Ok(next));
+    }
+
+    #[test]
+    fn test_rewinds_to_the_exact_frame() {
+        let mut gb = gameboy();
+        let mut rewind = Rewind::new(4, DEFAULT_CAPACITY);
+        let states = run(&mut gb, &mut rewind, 30);
+
+        assert_eq!(rewind.rewind(&mut gb, 7), Ok(7));
+        assert!(gb.save_state() == states[23]);
+
+        assert_eq!(rewind.rewind(&mut gb, 8), Ok(8));
+        assert!(gb.save_state() == states[15]);
+        assert_eq!(rewind.frames(), 15);
+    }
+
+    #[test]
+    fn test_recording_continues_after_rewinding() {
+        let mut gb = gameboy();
+        let mut rewind = Rewind::new(4, DEFAULT_CAPACITY);
+        run(&mut gb, &mut rewind, 10);
+        rewind.rewind(&mut gb, 5).unwrap();
+
+        let states = run(&mut gb, &mut rewind, 10);
+
+        rewind.rewind(&mut gb, 3).unwrap();
+        assert!(gb.save_state() == states[7]);
+    }
+
+    #[test]
+    fn test_keeps_everything_when_a_snapshot_is_corrupt() {
+        let mut gb = gameboy();
+        let mut rewind = Rewind::new(4, DEFAULT_CAPACITY);
+        let states = run(&mut gb, &mut rewind, 20);
+        rewind.deltas[0].truncate(6);
+        let size = rewind.size;
+        let state = gb.save_state();
+
+        assert_eq!(
+            rewind.rewind(&mut gb, 20),
+            Err(EmuError::BadState("Corrupt rewind snapshot".to_string()))
+        );
+        assert!(gb.save_state() == state);
+        assert_eq!(
+            (rewind.frames(), rewind.deltas.len(), rewind.size),
+            (20, 4, size)
+        );
+
+        assert_eq!(rewind.rewind(&mut gb, 10), Ok(10));
+        assert!(gb.save_state() == states[10]);
+    }
+
+    #[test]
+    fn test_memory_stays_bounded() {
+        let mut gb = gameboy();
+        let state_size = gb.save_state().len();
+        let mut rewind = Rewind::new(2, state_size + 100);
+        let states = run(&mut gb, &mut rewind, 40);
+
+        assert!(rewind.size <= state_size + 100);
+        assert!(rewind.frames() < 40);
+
+        let frames = rewind.frames();
+        assert_eq!(rewind.rewind(&mut gb, 100), Ok(frames));
+        assert!(gb.save_state() == states[40 - frames]);
+    }
+}
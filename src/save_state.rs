@@ -0,0 +1,196 @@
+// binary encoding of machine state, each component writes its fields in a fixed order and reads
+// them back in the same order
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+pub const MAGIC: &[u8; 4] = b"GBSS";
+pub const VERSION: u32 = 3;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        return StateWriter { data: vec![] };
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        return self.data;
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, value: u128) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u32(value as u32);
+    }
+
+    // fixed size data, whose length the reader already knows
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn u16s(&mut self, values: &[u16]) {
+        for &value in values {
+            self.u16(value);
+        }
+    }
+
+    // variable size data, preceded by its length
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        return StateReader { data };
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if !self.data.is_empty() {
+            return Err(format!(
+                "{} unexpected bytes at end of state",
+                self.data.len()
+            ));
+        }
+        return Ok(());
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < length {
+            return Err("State ends unexpectedly".to_string());
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        return Ok(taken);
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        return match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(format!("Invalid boolean {:#04x} in state", value)),
+        };
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        return Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()));
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    pub fn u128(&mut self) -> Result<u128, String> {
+        return Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()));
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        return Ok(self.u32()? as usize);
+    }
+
+    // an index which must be below the given limit
+    pub fn index(&mut self, limit: usize) -> Result<usize, String> {
+        let index = self.usize()?;
+        if index >= limit {
+            return Err(format!("Out of range index {} in state", index));
+        }
+        return Ok(index);
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), String> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        return Ok(());
+    }
+
+    pub fn u16s(&mut self, values: &mut [u16]) -> Result<(), String> {
+        for value in values.iter_mut() {
+            *value = self.u16()?;
+        }
+        return Ok(());
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, String> {
+        let length = self.usize()?;
+        return Ok(self.take(length)?.to_vec());
+    }
+
+    // a variable size field whose length must not change, like cartridge RAM
+    pub fn vec_into(&mut self, bytes: &mut [u8]) -> Result<(), String> {
+        if self.usize()? != bytes.len() {
+            return Err("State does not match this machine".to_string());
+        }
+        return self.bytes(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips_values() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u128(1 << 100);
+        writer.vec(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u128(), Ok(1 << 100));
+        assert_eq!(reader.vec(), Ok(vec![1, 2, 3]));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_truncated_data() {
+        let mut reader = StateReader::new(&[0x12]);
+
+        assert!(reader.u16().is_err());
+    }
+}
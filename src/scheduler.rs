@@ -0,0 +1,207 @@
+use crate::save_state::{StateReader, StateWriter};
+use alloc::collections::BinaryHeap;
+use alloc::format;
+use alloc::string::String;
+use core::cmp::Reverse;
+
+// the peripherals which are run lazily, only when something changes or is looked at
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Component {
+    Timer,  // next TIMA overflow or reload
+    Serial, // end of the transfer
+    APU,    // next frame sequencer step
+    PPU,    // next mode or line change
+}
+
+const COMPONENT_COUNT: usize = 4;
+const COMPONENTS: [Component; COMPONENT_COUNT] = [
+    Component::Timer,
+    Component::Serial,
+    Component::APU,
+    Component::PPU,
+];
+
+// when each component next needs to run, in machine cycles since power on, so that cycles where
+// nothing happens cost nothing, counted here as the CPU's own counter stops during VRAM DMA
+#[derive(Clone)]
+pub struct Scheduler {
+    now: u128,
+    updated: [u128; COMPONENT_COUNT], // how far each component has been run
+    due: [Option<u128>; COMPONENT_COUNT],
+    events: BinaryHeap<Reverse<(u128, Component)>>, // may hold events since rescheduled
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        return Scheduler {
+            now: 0,
+            updated: [0; COMPONENT_COUNT],
+            due: [None; COMPONENT_COUNT],
+            events: BinaryHeap::new(),
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u128(self.now);
+        for (&updated, due) in self.updated.iter().zip(self.due.iter()) {
+            state.u128(updated);
+            match due {
+                Some(due) => {
+                    state.bool(true);
+                    state.u128(*due);
+                }
+                None => state.bool(false),
+            }
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        let now = state.u128()?;
+        let mut scheduler = Scheduler::new();
+        scheduler.now = now;
+        for &component in COMPONENTS.iter() {
+            let updated = state.u128()?;
+            if updated > now {
+                return Err(format!("{:?} is ahead of the machine in state", component));
+            }
+            scheduler.updated[component as usize] = updated;
+            if state.bool()? {
+                scheduler.schedule_at(component, state.u128()?);
+            }
+        }
+        *self = scheduler;
+        return Ok(());
+    }
+
+    pub fn tick(&mut self) {
+        self.now += 1;
+    }
+
+    // cycles the component is behind by
+    pub fn pending(&self, component: Component) -> u128 {
+        return self.now - self.updated[component as usize];
+    }
+
+    // cycles the component is behind by, which it is then taken to have caught up on
+    pub fn catch_up(&mut self, component: Component) -> u128 {
+        let pending = self.pending(component);
+        self.updated[component as usize] = self.now;
+        return pending;
+    }
+
+    // replaces any event already scheduled for the component, None cancelling it
+    pub fn schedule(&mut self, component: Component, cycles: Option<u128>) {
+        match cycles {
+            Some(cycles) => self.schedule_at(component, self.now + cycles.max(1)),
+            None => self.due[component as usize] = None,
+        }
+    }
+
+    fn schedule_at(&mut self, component: Component, at: u128) {
+        self.due[component as usize] = Some(at);
+        self.events.push(Reverse((at, component)));
+    }
+
+    // cycles from now in which no event is due, or fewer when an event has been rescheduled
+    #[cfg(feature = "blocks")]
+    pub fn quiet_cycles(&self) -> u128 {
+        return match self.events.peek() {
+            Some(&Reverse((at, _))) => (at - self.now).saturating_sub(1),
+            None => u128::MAX,
+        };
+    }
+
+    // moves on by cycles in which no event is due
+    #[cfg(feature = "blocks")]
+    pub fn skip(&mut self, cycles: u128) {
+        debug_assert!(cycles <= self.quiet_cycles());
+        self.now += cycles;
+    }
+
+    // the next component whose event is due by now, in order of time and then of Component
+    pub fn pop_due(&mut self) -> Option<Component> {
+        while let Some(&Reverse((at, component))) = self.events.peek() {
+            if at > self.now {
+                return None;
+            }
+            self.events.pop();
+            if self.due[component as usize] == Some(at) {
+                self.due[component as usize] = None;
+                return Some(component);
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(scheduler: &mut Scheduler, cycles: usize) -> Vec<(u128, Component)> {
+        let mut fired = vec![];
+        for _ in 0..cycles {
+            scheduler.tick();
+            while let Some(component) = scheduler.pop_due() {
+                fired.push((scheduler.now, component));
+            }
+        }
+        return fired;
+    }
+
+    #[test]
+    fn test_fires_events_in_order_of_time() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Component::PPU, Some(3));
+        scheduler.schedule(Component::Timer, Some(5));
+        scheduler.schedule(Component::APU, Some(3));
+
+        assert_eq!(
+            run(&mut scheduler, 10),
+            [
+                (3, Component::APU),
+                (3, Component::PPU),
+                (5, Component::Timer)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rescheduling_replaces_the_event() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Component::Serial, Some(2));
+        scheduler.schedule(Component::Serial, Some(4));
+        scheduler.schedule(Component::PPU, Some(1));
+        scheduler.schedule(Component::PPU, None);
+
+        assert_eq!(run(&mut scheduler, 10), [(4, Component::Serial)]);
+    }
+
+    #[test]
+    fn test_tracks_how_far_behind_components_are() {
+        let mut scheduler = Scheduler::new();
+        run(&mut scheduler, 7);
+        assert_eq!(scheduler.catch_up(Component::Timer), 7);
+        run(&mut scheduler, 2);
+
+        assert_eq!(scheduler.pending(Component::Timer), 2);
+        assert_eq!(scheduler.pending(Component::APU), 9);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut scheduler = Scheduler::new();
+        run(&mut scheduler, 5);
+        scheduler.catch_up(Component::APU);
+        scheduler.schedule(Component::Timer, Some(3));
+        let mut state = StateWriter::new();
+        scheduler.save_state(&mut state);
+        let state = state.finish();
+
+        let mut other = Scheduler::new();
+        other.load_state(&mut StateReader::new(&state)).unwrap();
+
+        assert_eq!(other.pending(Component::APU), 0);
+        assert_eq!(run(&mut other, 5), [(8, Component::Timer)]);
+    }
+}
@@ -0,0 +1,225 @@
+// screenshot regression tests, comparing the frame buffer pixel for pixel with a reference image,
+// which the test command runs with --reference
+
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+use crate::ppu::{DMG_SHADES, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::test_rom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+type RGB = [u8; 3];
+
+const MISMATCH: RGB = [0xff, 0x00, 0x00];
+
+// how frame buffer colours map to the colours of a reference image
+#[derive(Clone, Copy)]
+pub enum Palette {
+    Shades([RGB; 4]), // the colour of each DMG shade, lightest first
+    RGB555,           // each 5 bit channel stretched to 8 bits, as for CGB games
+}
+
+// the greys dmg-acid2's reference image is drawn in
+pub const DMG_GREYS: Palette = Palette::Shades([[0xff; 3], [0xaa; 3], [0x55; 3], [0x00; 3]]);
+
+impl Palette {
+    fn rgb(&self, pixel: u16) -> RGB {
+        if let Palette::Shades(colors) = self {
+            if let Some(shade) = DMG_SHADES.iter().position(|&s| s == pixel) {
+                return colors[shade];
+            }
+        }
+        let channel = |shift: u16| {
+            let value = (pixel >> shift & 0x1f) as u8;
+            return value << 3 | value >> 2;
+        };
+        return [channel(0), channel(5), channel(10)];
+    }
+}
+
+// when the screen is ready to compare
+#[derive(Clone, Copy)]
+pub enum Trigger {
+    Breakpoint(usize), // the CPU is about to run LD B, B, within the given number of frames
+    Frames(usize),
+}
+
+pub fn run_until(gb: &mut GameBoy, trigger: Trigger) -> Result<(), String> {
+    match trigger {
+        Trigger::Frames(frames) => {
+            for _ in 0..frames {
+                gb.run_frame().map_err(|e| e.to_string())?;
+            }
+        }
+        Trigger::Breakpoint(frames) => {
+            let end = gb.cpu.cycles() + (frames * CYCLES_PER_FRAME) as u128;
+            while !test_rom::at_breakpoint(gb) {
+                if gb.cpu.cycles() >= end {
+                    return Err(format!("No LD B, B after {} frames", frames));
+                }
+                gb.step().map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+// fails with the number of pixels which differ from the reference image, after writing an image
+// of them in red over a faded copy of the reference to diff_path
+pub fn compare(
+    gb: &GameBoy,
+    reference_path: &Path,
+    palette: Palette,
+    diff_path: &Path,
+) -> Result<(), String> {
+    let reference = read_png(reference_path)?;
+    let screen: Vec<RGB> = gb.frame_buffer().iter().map(|&p| palette.rgb(p)).collect();
+    let count = screen
+        .iter()
+        .zip(reference.iter())
+        .filter(|(a, b)| a != b)
+        .count();
+    if count == 0 {
+        return Ok(());
+    }
+
+    let diff: Vec<RGB> = screen
+        .iter()
+        .zip(reference.iter())
+        .map(|(actual, expected)| {
+            if actual != expected {
+                return MISMATCH;
+            }
+            return expected.map(|channel| 0xc0 + channel / 4);
+        })
+        .collect();
+    write_png(diff_path, &diff)?;
+    return Err(format!(
+        "{} pixels differ from {}, see {}",
+        count,
+        reference_path.display(),
+        diff_path.display()
+    ));
+}
+
+// a screen sized image, in any of the colour types PNG has
+pub fn read_png(path: &Path) -> Result<Vec<RGB>, String> {
+    let error = |e: &dyn ToString| format!("Could not read {}: {}", path.display(), e.to_string());
+    let file = File::open(path).map_err(|e| error(&e))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| error(&e))?;
+    let mut data = vec![
+        0;
+        reader
+            .output_buffer_size()
+            .ok_or_else(|| error(&"too large"))?
+    ];
+    let info = reader.next_frame(&mut data).map_err(|e| error(&e))?;
+    if (info.width as usize, info.height as usize) != (SCREEN_WIDTH, SCREEN_HEIGHT) {
+        return Err(error(&format!(
+            "{}x{} is not the size of the screen",
+            info.width, info.height
+        )));
+    }
+
+    let samples = info.color_type.samples();
+    return Ok(data[..info.buffer_size()]
+        .chunks(samples)
+        .map(|pixel| match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [pixel[0]; 3],
+            _ => [pixel[0], pixel[1], pixel[2]],
+        })
+        .collect());
+}
+
+pub fn write_png(path: &Path, pixels: &[RGB]) -> Result<(), String> {
+    let error = |e: &dyn ToString| format!("Could not write {}: {}", path.display(), e.to_string());
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| error(&e))?;
+    return writer
+        .write_image_data(&pixels.concat())
+        .map_err(|e| error(&e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom_with_program;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // a file in a directory of its own for this test run
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("gameboy-emulator-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        return dir.join(name);
+    }
+
+    // sets the background palette so that the blank background is black, then loops
+    fn gameboy(breakpoint: bool) -> GameBoy {
+        let mut program = vec![
+            0x3e, 0x1b, // LD A, 0x1b
+            0xe0, 0x47, // LDH (BGP), A
+        ];
+        if breakpoint {
+            program.push(0x40); // LD B, B
+        }
+        program.extend_from_slice(&[0x18, 0xfe]); // JR -2
+        return rom_with_program(&program);
+    }
+
+    #[test]
+    fn test_matches_the_reference() {
+        let mut gb = gameboy(false);
+        run_until(&mut gb, Trigger::Frames(3)).unwrap();
+        let reference = temp_path("black.png");
+        write_png(&reference, &[[0x00; 3]; SCREEN_WIDTH * SCREEN_HEIGHT]).unwrap();
+
+        let diff = temp_path("black-diff.png");
+        assert_eq!(compare(&gb, &reference, DMG_GREYS, &diff), Ok(()));
+        assert!(!diff.exists());
+    }
+
+    #[test]
+    fn test_writes_the_differences() {
+        let mut gb = gameboy(false);
+        run_until(&mut gb, Trigger::Frames(3)).unwrap();
+        let mut pixels = vec![[0x00; 3]; SCREEN_WIDTH * SCREEN_HEIGHT];
+        pixels[SCREEN_WIDTH + 2] = [0xaa; 3];
+        let reference = temp_path("dot.png");
+        write_png(&reference, &pixels).unwrap();
+
+        let diff_path = temp_path("dot-diff.png");
+        let error = compare(&gb, &reference, DMG_GREYS, &diff_path).unwrap_err();
+        assert!(error.starts_with("1 pixels differ"));
+        let diff = read_png(&diff_path).unwrap();
+        assert_eq!(diff[SCREEN_WIDTH + 2], MISMATCH);
+        assert_eq!(diff[0], [0xc0; 3]);
+    }
+
+    #[test]
+    fn test_maps_colours_through_the_palette() {
+        assert_eq!(DMG_GREYS.rgb(DMG_SHADES[1]), [0xaa; 3]);
+        assert_eq!(Palette::RGB555.rgb(DMG_SHADES[1]), [0xad; 3]);
+        assert_eq!(Palette::RGB555.rgb(0x7c1f), [0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_runs_to_the_breakpoint() {
+        let mut gb = gameboy(true);
+        run_until(&mut gb, Trigger::Breakpoint(1)).unwrap();
+        assert!(test_rom::at_breakpoint(&gb));
+
+        let mut gb = gameboy(false);
+        assert!(run_until(&mut gb, Trigger::Breakpoint(1)).is_err());
+    }
+}
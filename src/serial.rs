@@ -0,0 +1,201 @@
+use crate::cpu::special_registers::*;
+use crate::cpu::Interrupt;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const BITS_PER_TRANSFER: u8 = 8;
+const CYCLES_PER_BIT: u8 = 128; // 8192 Hz
+const FAST_CYCLES_PER_BIT: u8 = 4; // 262144 Hz, selected by SC bit 1 on CGB
+
+// the link port through SB and SC, with no other Game Boy plugged in, so every bit received is 1
+// and transfers waiting on an external clock never finish
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+#[derive(Clone)]
+pub struct Serial {
+    data: u8,    // SB, shifted out from the top as bits are shifted in at the bottom
+    control: u8, // SC
+    cgb_mode: bool,
+    bits: u8,              // left to shift in the current transfer
+    clock: u8,             // cycles until the next bit
+    sent: Option<Vec<u8>>, // bytes sent so far, when recording them for test ROMs that print
+}
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Serial {
+        return Serial {
+            data: 0,
+            control: 0,
+            cgb_mode,
+            bits: 0,
+            clock: 0,
+            sent: None,
+        };
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    // starts keeping every byte sent from now on
+    pub fn record_output(&mut self) {
+        self.sent.get_or_insert_with(Vec::new);
+    }
+
+    pub fn output(&self) -> &[u8] {
+        return self.sent.as_deref().unwrap_or(&[]);
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+        state.u8(self.bits);
+        state.u8(self.clock);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.data = state.u8()?;
+        self.control = state.u8()? & 0x83;
+        self.bits = state.u8()?.min(BITS_PER_TRANSFER);
+        self.clock = state.u8()?;
+        return Ok(());
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            SB => self.data,
+            SC if self.cgb_mode => 0x7c | self.control,
+            SC => 0x7e | self.control,
+            _ => 0xff,
+        };
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB => self.data = value,
+            SC => {
+                self.control = value & 0x83;
+                // only a transfer on the internal clock makes progress
+                if self.control & 0x81 == 0x81 {
+                    if let Some(sent) = &mut self.sent {
+                        sent.push(self.data);
+                    }
+                    self.bits = BITS_PER_TRANSFER;
+                    self.clock = self.cycles_per_bit();
+                } else {
+                    self.bits = 0;
+                }
+            }
+            _ => return,
+        }
+    }
+
+    // advances by some CPU machine cycles, returning the interrupt requested if a transfer ends
+    pub fn advance(&mut self, cycles: u128) -> u8 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let clock = self.clock as u128;
+        if cycles < clock {
+            self.clock -= cycles as u8;
+            return 0;
+        }
+
+        let period = self.cycles_per_bit() as u128;
+        let shifted = (1 + (cycles - clock) / period).min(self.bits as u128) as u8;
+        self.data = if shifted >= BITS_PER_TRANSFER {
+            0xff
+        } else {
+            self.data << shifted | ((1 << shifted) - 1)
+        };
+        self.bits -= shifted;
+        if self.bits > 0 {
+            self.clock = (period - (cycles - clock) % period) as u8;
+            return 0;
+        }
+        self.clock = period as u8;
+        self.control &= 0x7f;
+        return 1 << Interrupt::Serial.bit();
+    }
+
+    // cycles until the transfer in progress ends, if there is one
+    pub fn cycles_until_event(&self) -> Option<u128> {
+        if self.bits == 0 {
+            return None;
+        }
+        let period = self.cycles_per_bit() as u128;
+        return Some(self.clock as u128 + (self.bits as u128 - 1) * period);
+    }
+
+    fn cycles_per_bit(&self) -> u8 {
+        if self.cgb_mode && self.control & 0x02 != 0 {
+            return FAST_CYCLES_PER_BIT;
+        }
+        return CYCLES_PER_BIT;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(serial: &mut Serial, cycles: usize) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            interrupts |= serial.advance(1);
+        }
+        return interrupts;
+    }
+
+    #[test]
+    fn test_transfer_on_internal_clock_receives_ones() {
+        let mut serial = Serial::new(false);
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x81);
+
+        assert_eq!(run(&mut serial, 128 * 8 - 1), 0);
+        assert_eq!(serial.read(SC), 0xff);
+        assert_eq!(run(&mut serial, 1), 1 << Interrupt::Serial.bit());
+        assert_eq!(serial.read(SB), 0xff);
+        assert_eq!(serial.read(SC), 0x7f);
+    }
+
+    #[test]
+    fn test_transfer_on_external_clock_waits_forever() {
+        let mut serial = Serial::new(false);
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x80);
+
+        assert_eq!(run(&mut serial, 10000), 0);
+        assert_eq!(serial.read(SB), 0x42);
+        assert_eq!(serial.read(SC), 0xfe);
+    }
+
+    #[test]
+    fn test_fast_clock_only_on_cgb() {
+        let mut serial = Serial::new(true);
+        serial.write(SC, 0x83);
+        assert_eq!(run(&mut serial, 4 * 8), 1 << Interrupt::Serial.bit());
+
+        let mut serial = Serial::new(false);
+        serial.write(SC, 0x83);
+        assert_eq!(run(&mut serial, 4 * 8), 0);
+        assert_eq!(serial.read(SC), 0xff);
+    }
+
+    #[test]
+    fn test_advancing_at_once_matches_single_cycles() {
+        let mut stepped = Serial::new(false);
+        stepped.write(SB, 0x42);
+        stepped.write(SC, 0x81);
+        let mut advanced = stepped.clone();
+
+        for &cycles in [1, 200, 127, 300, 400].iter() {
+            let interrupts = run(&mut stepped, cycles);
+            assert_eq!(advanced.advance(cycles as u128), interrupts);
+            assert_eq!(advanced.read(SB), stepped.read(SB));
+            assert_eq!(advanced.read(SC), stepped.read(SC));
+            assert_eq!(advanced.cycles_until_event(), stepped.cycles_until_event());
+        }
+    }
+}
@@ -0,0 +1,765 @@
+use crate::ppu::{DMG_SHADES, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::save_state::{StateReader, StateWriter};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+
+// position of the game screen within the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+const TRANSFER_SIZE: usize = 0x1000; // bytes sent by a VRAM transfer, read from the screen
+const TILES_PER_TRANSFER: usize = TRANSFER_SIZE / 16;
+
+// the screen is coloured in blocks of 8x8 pixels
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const ATTR_FILE_SIZE: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+const ATTR_FILE_COUNT: usize = 45;
+
+const SYSTEM_PALETTE_COUNT: usize = 512;
+const BORDER_TILE_SIZE: usize = 32; // 8x8 pixels at 4 bits each
+const BORDER_TILE_COUNT: usize = 256;
+const BORDER_MAP_WIDTH: usize = BORDER_WIDTH / 8;
+const BORDER_MAP_HEIGHT: usize = BORDER_HEIGHT / 8;
+const BORDER_PALETTE_OFFSET: usize = 0x800; // within PCT_TRN data
+const BORDER_FIRST_PALETTE: usize = 4;
+
+// command codes, from the upper 5 bits of the first byte of a packet
+// https://gbdev.io/pandocs/SGB_Command_Summary.html
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0a;
+const PAL_TRN: u8 = 0x0b;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mask {
+    None,
+    Freeze, // keep showing the last frame
+    Black,
+    Color0, // fill with colour 0 of palette 0
+}
+
+// what the next frame shown on screen will be read as
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    Palettes,
+    BorderTiles(usize), // first tile to replace
+    Border,
+    Attributes,
+}
+
+// the Super Game Boy, which receives command packets through P1 and colours the screen
+#[derive(Clone)]
+pub struct SGB {
+    commands_enabled: bool, // only for cartridges declaring SGB support
+    p1_lines: u8,
+    packet: [u8; PACKET_SIZE],
+    packet_bit: Option<usize>, // bit being received, None until a reset pulse is sent
+    command: Vec<u8>,          // packets of a command sent over several of them
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT], // palette of each 8x8 block of the screen
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>, // little endian entries with tile, palette and flip bits
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+    transfer: Option<Transfer>,
+    player_count: u8,
+    player: u8, // controller P1 currently reads from
+    screen: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame_buffer: [u16; BORDER_WIDTH * BORDER_HEIGHT],
+}
+
+impl SGB {
+    pub fn new(commands_enabled: bool) -> SGB {
+        let grey = [DMG_SHADES[0], DMG_SHADES[1], DMG_SHADES[2], DMG_SHADES[3]];
+        return SGB {
+            commands_enabled,
+            p1_lines: 0x30,
+            packet: [0; PACKET_SIZE],
+            packet_bit: None,
+            command: vec![],
+            palettes: [grey; 4],
+            system_palettes: vec![grey; SYSTEM_PALETTE_COUNT],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            attribute_files: vec![0; ATTR_FILE_COUNT * ATTR_FILE_SIZE],
+            border_tiles: vec![0; BORDER_TILE_COUNT * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT * 2],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            transfer: None,
+            player_count: 1,
+            player: 0,
+            screen: [DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_buffer: [DMG_SHADES[0]; BORDER_WIDTH * BORDER_HEIGHT],
+        };
+    }
+
+    // RGB555 pixels of the coloured screen inside its border
+    pub fn frame_buffer(&self) -> &[u16; BORDER_WIDTH * BORDER_HEIGHT] {
+        return &self.frame_buffer;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.commands_enabled);
+        state.u8(self.p1_lines);
+        state.bytes(&self.packet);
+        // a bit index past the end of a packet marks that none is being received
+        state.usize(self.packet_bit.unwrap_or(PACKET_BITS + 1));
+        state.vec(&self.command);
+        for palette in self.palettes.iter().chain(self.system_palettes.iter()) {
+            state.u16s(palette);
+        }
+        state.bytes(&self.attributes);
+        state.bytes(&self.attribute_files);
+        state.bytes(&self.border_tiles);
+        state.bytes(&self.border_map);
+        for palette in self.border_palettes.iter() {
+            state.u16s(palette);
+        }
+        state.u8(self.mask as u8);
+        state.u8(match self.transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::BorderTiles(0)) => 2,
+            Some(Transfer::BorderTiles(_)) => 3,
+            Some(Transfer::Border) => 4,
+            Some(Transfer::Attributes) => 5,
+        });
+        state.u8(self.player_count);
+        state.u8(self.player);
+        state.u16s(&self.screen);
+        state.u16s(&self.frame_buffer);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.commands_enabled = state.bool()?;
+        self.p1_lines = state.u8()? & 0x30;
+        state.bytes(&mut self.packet)?;
+        let packet_bit = state.index(PACKET_BITS + 2)?;
+        self.packet_bit = (packet_bit <= PACKET_BITS).then_some(packet_bit);
+        self.command = state.vec()?;
+        if !self.command.len().is_multiple_of(PACKET_SIZE) || self.command.len() >= 7 * PACKET_SIZE
+        {
+            return Err("Invalid SGB command in state".to_string());
+        }
+        for palette in self
+            .palettes
+            .iter_mut()
+            .chain(self.system_palettes.iter_mut())
+        {
+            state.u16s(palette)?;
+        }
+        state.bytes(&mut self.attributes)?;
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0b11;
+        }
+        state.bytes(&mut self.attribute_files)?;
+        state.bytes(&mut self.border_tiles)?;
+        state.bytes(&mut self.border_map)?;
+        for palette in self.border_palettes.iter_mut() {
+            state.u16s(palette)?;
+        }
+        self.mask = match state.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            mask => return Err(format!("Unknown SGB mask {} in state", mask)),
+        };
+        self.transfer = match state.u8()? {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::BorderTiles(0)),
+            3 => Some(Transfer::BorderTiles(TILES_PER_TRANSFER / 2)),
+            4 => Some(Transfer::Border),
+            5 => Some(Transfer::Attributes),
+            transfer => return Err(format!("Unknown SGB transfer {} in state", transfer)),
+        };
+        self.player_count = match state.u8()? {
+            count @ (1 | 2 | 4) => count,
+            count => return Err(format!("Invalid SGB player count {} in state", count)),
+        };
+        self.player = state.u8()? % self.player_count;
+        state.u16s(&mut self.screen)?;
+        state.u16s(&mut self.frame_buffer)?;
+        return Ok(());
+    }
+
+    pub fn player(&self) -> u8 {
+        return self.player;
+    }
+
+    // packets are sent one bit at a time by pulsing P14 low for a 0 and P15 low for a 1, starting
+    // with a reset pulse of both and ending with a 0 stop bit
+    // https://gbdev.io/pandocs/SGB_Command_Packet.html
+    pub fn write_p1(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = self.p1_lines;
+        self.p1_lines = lines;
+        if lines == previous {
+            return;
+        }
+
+        match lines {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.packet_bit = Some(0);
+            }
+            0x30 => {
+                // the next controller is selected when P15 goes high
+                if previous & 0x20 == 0 {
+                    self.player = (self.player + 1) % self.player_count;
+                }
+            }
+            _ if previous == 0x30 => {
+                let bit = (lines == 0x10) as u8;
+                match self.packet_bit {
+                    Some(PACKET_BITS) => {
+                        self.packet_bit = None;
+                        if bit == 0 {
+                            self.receive_packet();
+                        }
+                    }
+                    Some(index) => {
+                        self.packet[index / 8] |= bit << (index % 8);
+                        self.packet_bit = Some(index + 1);
+                    }
+                    None => return,
+                }
+            }
+            _ => self.packet_bit = None,
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        if !self.commands_enabled {
+            return;
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        let packet_count = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() >= packet_count * PACKET_SIZE {
+            let command = core::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        match command[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, command),
+            PAL23 => self.set_palettes(2, 3, command),
+            PAL03 => self.set_palettes(0, 3, command),
+            PAL12 => self.set_palettes(1, 2, command),
+            ATTR_BLK => self.attr_blk(command),
+            ATTR_LIN => self.attr_lin(command),
+            ATTR_DIV => self.attr_div(command),
+            ATTR_CHR => self.attr_chr(command),
+            PAL_SET => self.pal_set(command),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.player_count = match command[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => {
+                let first_tile = (command[1] & 1) as usize * TILES_PER_TRANSFER / 2;
+                self.transfer = Some(Transfer::BorderTiles(first_tile));
+            }
+            PCT_TRN => self.transfer = Some(Transfer::Border),
+            ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+            MASK_EN => {
+                self.mask = match command[1] & 0b11 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                }
+            }
+            _ => return, // sound, SNES program and other unsupported commands
+        }
+    }
+
+    // colour 0 is shared by all palettes
+    fn set_palettes(&mut self, first: usize, second: usize, command: &[u8]) {
+        let color = |index: usize| {
+            u16::from_le_bytes([command[1 + index * 2], command[2 + index * 2]]) & 0x7fff
+        };
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, command: &[u8]) {
+        let count = (command[1] & 0x1f) as usize;
+        for set in command[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0b111;
+            let inside_palette = set[1] & 0b11;
+            let line_palette = (set[1] >> 2) & 0b11;
+            let outside_palette = (set[1] >> 4) & 0b11;
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            // when only the inside or outside is changed, the surrounding line goes with it
+            let line_palette = match control {
+                0b001 => Some(inside_palette),
+                0b100 => Some(outside_palette),
+                _ if control & 0b010 != 0 => Some(line_palette),
+                _ => None,
+            };
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let inside = x1 < x && x < x2 && y1 < y && y < y2;
+                    let palette = if inside {
+                        (control & 0b001 != 0).then_some(inside_palette)
+                    } else if within {
+                        line_palette
+                    } else {
+                        (control & 0b100 != 0).then_some(outside_palette)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, command: &[u8]) {
+        let count = command[1] as usize;
+        for &line in command[2..].iter().take(count) {
+            let index = (line & 0x1f) as usize;
+            let palette = (line >> 5) & 0b11;
+            if line & 0x80 != 0 {
+                if index < ATTR_HEIGHT {
+                    self.attributes[index * ATTR_WIDTH..(index + 1) * ATTR_WIDTH].fill(palette);
+                }
+            } else if index < ATTR_WIDTH {
+                for y in 0..ATTR_HEIGHT {
+                    self.attributes[y * ATTR_WIDTH + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, command: &[u8]) {
+        let after_palette = command[1] & 0b11;
+        let before_palette = (command[1] >> 2) & 0b11;
+        let line_palette = (command[1] >> 4) & 0b11;
+        let horizontal = command[1] & 0x40 != 0;
+        let division = command[2] as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_WIDTH + x] = match position.cmp(&division) {
+                    core::cmp::Ordering::Less => before_palette,
+                    core::cmp::Ordering::Equal => line_palette,
+                    core::cmp::Ordering::Greater => after_palette,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, command: &[u8]) {
+        let mut x = command[1] as usize;
+        let mut y = command[2] as usize;
+        let count = u16::from_le_bytes([command[3], command[4]]) as usize;
+        let vertical = command[5] & 1 != 0;
+
+        for i in 0..count.min(ATTR_WIDTH * ATTR_HEIGHT) {
+            let Some(&byte) = command.get(6 + i / 4) else {
+                break;
+            };
+            if x < ATTR_WIDTH && y < ATTR_HEIGHT {
+                self.attributes[y * ATTR_WIDTH + x] = (byte >> (6 - (i % 4) * 2)) & 0b11;
+            }
+
+            // writing continues on the next row or column once one is filled
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // copies four palettes out of the ones sent by PAL_TRN, optionally applying an ATTR_TRN file
+    fn pal_set(&mut self, command: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let index = u16::from_le_bytes([command[1 + i * 2], command[2 + i * 2]]) as usize;
+            *palette = self.system_palettes[index % SYSTEM_PALETTE_COUNT];
+        }
+
+        let flags = command[9];
+        let file = (flags & 0x3f) as usize;
+        if flags & 0x80 != 0 && file < ATTR_FILE_COUNT {
+            let data = &self.attribute_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+            for (i, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (data[i / 4] >> (6 - (i % 4) * 2)) & 0b11;
+            }
+        }
+        if flags & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    // called at the end of each frame with the DMG shades the PPU produced
+    pub fn end_frame(&mut self, shades: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT]) {
+        if let Some(transfer) = self.transfer.take() {
+            self.receive_transfer(transfer, &screen_data(shades));
+        }
+
+        match self.mask {
+            Mask::None => {
+                for (i, pixel) in self.screen.iter_mut().enumerate() {
+                    let attribute = (i / SCREEN_WIDTH / 8) * ATTR_WIDTH + (i % SCREEN_WIDTH) / 8;
+                    let palette = self.attributes[attribute] as usize;
+                    *pixel = self.palettes[palette][shades[i] as usize];
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => self.screen.fill(0x0000),
+            Mask::Color0 => self.screen.fill(self.palettes[0][0]),
+        }
+
+        self.render_frame();
+    }
+
+    fn receive_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks_exact(8)) {
+                    for (color, bytes) in palette.iter_mut().zip(colors.chunks_exact(2)) {
+                        *color = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7fff;
+                    }
+                }
+            }
+            Transfer::BorderTiles(first_tile) => {
+                let start = first_tile * BORDER_TILE_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Border => {
+                let map_size = self.border_map.len();
+                self.border_map.copy_from_slice(&data[..map_size]);
+                let palette_data = &data[BORDER_PALETTE_OFFSET..];
+                for (palette, colors) in self
+                    .border_palettes
+                    .iter_mut()
+                    .zip(palette_data.chunks_exact(32))
+                {
+                    for (color, bytes) in palette.iter_mut().zip(colors.chunks_exact(2)) {
+                        *color = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7fff;
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    // the border is drawn over the backdrop colour, with the screen on top of both
+    fn render_frame(&mut self) {
+        self.frame_buffer.fill(self.palettes[0][0]);
+
+        for map_y in 0..BORDER_MAP_HEIGHT {
+            for map_x in 0..BORDER_MAP_WIDTH {
+                let index = (map_y * BORDER_MAP_WIDTH + map_x) * 2;
+                let entry =
+                    u16::from_le_bytes([self.border_map[index], self.border_map[index + 1]]);
+                let tile = (entry & 0xff) as usize;
+                let palette = ((entry >> 10) & 0b111) as usize;
+                let x_flip = entry & 0x4000 != 0;
+                let y_flip = entry & 0x8000 != 0;
+                if !(BORDER_FIRST_PALETTE..BORDER_FIRST_PALETTE + 4).contains(&palette) {
+                    continue;
+                }
+
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let tile_x = if x_flip { 7 - x } else { x };
+                        let tile_y = if y_flip { 7 - y } else { y };
+                        let color = self.border_pixel(tile, tile_x, tile_y);
+                        if color != 0 {
+                            let pixel = (map_y * 8 + y) * BORDER_WIDTH + map_x * 8 + x;
+                            self.frame_buffer[pixel] =
+                                self.border_palettes[palette - BORDER_FIRST_PALETTE][color];
+                        }
+                    }
+                }
+            }
+        }
+
+        for y in 0..SCREEN_HEIGHT {
+            let start = (SCREEN_Y + y) * BORDER_WIDTH + SCREEN_X;
+            self.frame_buffer[start..start + SCREEN_WIDTH]
+                .copy_from_slice(&self.screen[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH]);
+        }
+    }
+
+    // SNES tiles store bit planes 0 and 1 interleaved by row, followed by planes 2 and 3
+    fn border_pixel(&self, tile: usize, x: usize, y: usize) -> usize {
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE..(tile + 1) * BORDER_TILE_SIZE];
+        let bit = 7 - x;
+        let planes = [
+            data[y * 2],
+            data[y * 2 + 1],
+            data[16 + y * 2],
+            data[17 + y * 2],
+        ];
+        return planes
+            .iter()
+            .enumerate()
+            .map(|(plane, byte)| (((byte >> bit) & 1) as usize) << plane)
+            .sum();
+    }
+}
+
+// VRAM transfers are read back from the screen as 2 bits per pixel tiles, going across each row
+// of tiles in turn
+fn screen_data(shades: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(TRANSFER_SIZE);
+    for tile in 0..TILES_PER_TRANSFER {
+        let tile_x = (tile % ATTR_WIDTH) * 8;
+        let tile_y = (tile / ATTR_WIDTH) * 8;
+        for y in 0..8 {
+            let row = &shades[(tile_y + y) * SCREEN_WIDTH + tile_x..][..8];
+            let plane = |bit: u8| {
+                row.iter()
+                    .fold(0, |byte, shade| byte << 1 | (shade >> bit) & 1)
+            };
+            data.push(plane(0));
+            data.push(plane(1));
+        }
+    }
+    return data;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut SGB, packet: &[u8]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for i in 0..PACKET_BITS {
+            let bit = packet.get(i / 8).map_or(0, |byte| (byte >> (i % 8)) & 1);
+            sgb.write_p1(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    fn send_command(sgb: &mut SGB, command: u8, data: &[u8]) {
+        let packet_count = (data.len() + 1).div_ceil(PACKET_SIZE).max(1);
+        let mut bytes = vec![command << 3 | packet_count as u8];
+        bytes.extend_from_slice(data);
+        bytes.resize(packet_count * PACKET_SIZE, 0);
+        for packet in bytes.chunks(PACKET_SIZE) {
+            send_packet(sgb, packet);
+        }
+    }
+
+    fn screen_pixel(sgb: &SGB, x: usize, y: usize) -> u16 {
+        return sgb.frame_buffer()[(SCREEN_Y + y) * BORDER_WIDTH + SCREEN_X + x];
+    }
+
+    #[test]
+    fn test_pal01_colours_the_screen() {
+        let mut sgb = SGB::new(true);
+        let colors: [u16; 7] = [0x7fff, 0x001f, 0x03e0, 0x7c00, 0x1111, 0x2222, 0x3333];
+        let data: Vec<u8> = colors
+            .iter()
+            .flat_map(|color| color.to_le_bytes())
+            .collect();
+
+        send_command(&mut sgb, PAL01, &data);
+        let mut shades = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[1] = 1;
+        sgb.end_frame(&shades);
+
+        assert_eq!(screen_pixel(&sgb, 0, 0), 0x7fff);
+        assert_eq!(screen_pixel(&sgb, 1, 0), 0x001f);
+        // colour 0 is also the backdrop outside the screen
+        assert_eq!(sgb.frame_buffer()[0], 0x7fff);
+    }
+
+    #[test]
+    fn test_ignores_commands_from_unsupported_games() {
+        let mut sgb = SGB::new(false);
+
+        send_command(&mut sgb, MASK_EN, &[2]);
+
+        assert_eq!(sgb.mask, Mask::None);
+    }
+
+    #[test]
+    fn test_aborts_packet_without_stop_bit() {
+        let mut sgb = SGB::new(true);
+
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        let packet = [MASK_EN << 3 | 1, 2];
+        for i in 0..PACKET_BITS {
+            let bit = (packet.get(i / 8).unwrap_or(&0) >> (i % 8)) & 1;
+            sgb.write_p1(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x10);
+
+        assert_eq!(sgb.mask, Mask::None);
+    }
+
+    #[test]
+    fn test_attr_blk_sets_inside_line_and_outside() {
+        let mut sgb = SGB::new(true);
+
+        // inside palette 1, line palette 2, outside palette 3
+        send_command(&mut sgb, ATTR_BLK, &[1, 0b111, 0b11_10_01, 2, 2, 5, 5]);
+
+        assert_eq!(sgb.attributes[3 * ATTR_WIDTH + 3], 1);
+        assert_eq!(sgb.attributes[2 * ATTR_WIDTH + 4], 2);
+        assert_eq!(sgb.attributes[5 * ATTR_WIDTH + 5], 2);
+        assert_eq!(sgb.attributes[0], 3);
+    }
+
+    #[test]
+    fn test_attr_blk_extends_only_inside_to_line() {
+        let mut sgb = SGB::new(true);
+
+        send_command(&mut sgb, ATTR_BLK, &[1, 0b001, 0b00_00_01, 2, 2, 5, 5]);
+
+        assert_eq!(sgb.attributes[2 * ATTR_WIDTH + 2], 1);
+        assert_eq!(sgb.attributes[0], 0);
+    }
+
+    #[test]
+    fn test_attr_lin_and_div() {
+        let mut sgb = SGB::new(true);
+
+        send_command(&mut sgb, ATTR_DIV, &[0b0001_1011, 10]);
+        assert_eq!(sgb.attributes[9], 2);
+        assert_eq!(sgb.attributes[10], 1);
+        assert_eq!(sgb.attributes[11], 3);
+
+        send_command(&mut sgb, ATTR_LIN, &[2, 0x80 | 3 << 5 | 4, 7]);
+        assert_eq!(sgb.attributes[4 * ATTR_WIDTH + 19], 3);
+        assert_eq!(sgb.attributes[17 * ATTR_WIDTH + 7], 0);
+    }
+
+    #[test]
+    fn test_attr_chr_wraps_to_next_row() {
+        let mut sgb = SGB::new(true);
+
+        send_command(&mut sgb, ATTR_CHR, &[19, 0, 2, 0, 0, 0b1011_0000]);
+
+        assert_eq!(sgb.attributes[19], 2);
+        assert_eq!(sgb.attributes[ATTR_WIDTH], 3);
+    }
+
+    #[test]
+    fn test_pal_trn_and_pal_set() {
+        let mut sgb = SGB::new(true);
+        send_command(&mut sgb, PAL_TRN, &[]);
+
+        // the data is read from the next frame, where only the last pixel in the first row of each
+        // tile is set, so every 16 bytes start with 0x01 0x00
+        let mut shades = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for x in (7..SCREEN_WIDTH).step_by(8) {
+            shades[x] = 1;
+        }
+        sgb.end_frame(&shades);
+        assert_eq!(sgb.system_palettes[0][0], 0x0001);
+        assert_eq!(sgb.system_palettes[2][0], 0x0001);
+
+        send_command(&mut sgb, PAL_SET, &[2, 0, 0, 0, 0, 0, 0, 0, 0x40]);
+        assert_eq!(sgb.palettes[0][0], 0x0001);
+    }
+
+    #[test]
+    fn test_mask_en_blanks_the_screen() {
+        let mut sgb = SGB::new(true);
+
+        send_command(&mut sgb, MASK_EN, &[2]);
+        sgb.end_frame(&[0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+
+        assert_eq!(screen_pixel(&sgb, 0, 0), 0x0000);
+    }
+
+    #[test]
+    fn test_mlt_req_cycles_through_controllers() {
+        let mut sgb = SGB::new(true);
+
+        send_command(&mut sgb, MLT_REQ, &[1]);
+        assert_eq!(sgb.player(), 0);
+
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.player(), 1);
+
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.player(), 0);
+    }
+
+    #[test]
+    fn test_renders_border_behind_screen() {
+        let mut sgb = SGB::new(true);
+        sgb.border_tiles[BORDER_TILE_SIZE..BORDER_TILE_SIZE * 2].fill(0xff); // tile 1 is colour 15
+        sgb.border_map[0] = 1;
+        sgb.border_map[1] = 4 << 2; // palette 4
+        sgb.border_map[(SCREEN_Y / 8 * BORDER_MAP_WIDTH + SCREEN_X / 8) * 2] = 1;
+        sgb.border_map[(SCREEN_Y / 8 * BORDER_MAP_WIDTH + SCREEN_X / 8) * 2 + 1] = 4 << 2;
+        sgb.border_palettes[0][15] = 0x1234;
+
+        sgb.end_frame(&[0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+
+        assert_eq!(sgb.frame_buffer()[0], 0x1234);
+        assert_eq!(sgb.frame_buffer()[8], DMG_SHADES[0]);
+        assert_eq!(screen_pixel(&sgb, 0, 0), DMG_SHADES[0]);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::cpu::instr::instr::Instr;
+use crate::cpu::instr::operand::Op8;
+use crate::cpu::instr::try_decode_unprefixed;
+use crate::cpu::{Reg16, Reg8};
+use crate::error::EmuError;
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// test ROMs in Blargg's style that write to cartridge RAM put a status byte, then the signature,
+// then the text they print
+const STATUS: u16 = 0xa000;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const TEXT_START: u16 = 0xa004;
+const TEXT_END: u16 = 0xbfff;
+const RUNNING: u8 = 0x80;
+
+// Mooneye's test ROMs run LD B, B with these in B, C, D, E, H and L when they pass, and with 0x42
+// in all of them when they fail
+const BREAKPOINT: Instr = Instr::LD(Op8::Reg(Reg8::B), Op8::Reg(Reg8::B));
+const RESULT_REGISTERS: [Reg8; 6] = [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L];
+const PASSED_REGISTERS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILED_REGISTERS: [u8; 6] = [0x42; 6];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Passed,
+    Failed,
+    TimedOut, // the ROM had not reported a result by the frame limit
+}
+
+pub struct Report {
+    pub verdict: Verdict,
+    pub output: String, // what the ROM printed, which names the sub-tests and how they went
+}
+
+// runs a test ROM until it reports its result, either by printing Passed or Failed over the link
+// port, through the signature in cartridge RAM or with registers set at LD B, B, giving up after
+// the given number of frames
+pub fn run(gb: &mut GameBoy, frames: usize) -> Result<Report, EmuError> {
+    gb.record_serial_output();
+    let start = gb.cpu.cycles();
+    let end = start + (frames * CYCLES_PER_FRAME) as u128;
+    let mut next_frame = start + CYCLES_PER_FRAME as u128;
+    while gb.cpu.cycles() < end {
+        gb.step()?;
+        if let Some(report) = breakpoint_result(gb) {
+            return Ok(report);
+        }
+        if gb.cpu.cycles() >= next_frame {
+            next_frame += CYCLES_PER_FRAME as u128;
+            if let Some(report) = result(gb) {
+                return Ok(report);
+            }
+        }
+    }
+    return Ok(Report {
+        verdict: Verdict::TimedOut,
+        output: printed(gb),
+    });
+}
+
+fn result(gb: &GameBoy) -> Option<Report> {
+    if has_signature(gb) {
+        let status = gb.memory.peek(STATUS);
+        if status == RUNNING {
+            return None;
+        }
+        let verdict = if status == 0 {
+            Verdict::Passed
+        } else {
+            Verdict::Failed
+        };
+        return Some(Report {
+            verdict,
+            output: printed(gb),
+        });
+    }
+
+    // the result is only final at the end of its line, which goes on to say what failed
+    let output = printed(gb);
+    for &(word, verdict) in [("Failed", Verdict::Failed), ("Passed", Verdict::Passed)].iter() {
+        if let Some(at) = output.find(word) {
+            if output[at..].contains('\n') {
+                return Some(Report { verdict, output });
+            }
+        }
+    }
+    return None;
+}
+
+// whether the CPU is about to run LD B, B, which test ROMs use as a breakpoint
+pub fn at_breakpoint(gb: &GameBoy) -> bool {
+    let pc = gb.cpu.read_reg16(Reg16::PC);
+    return try_decode_unprefixed(gb.memory.peek(pc)) == Some(BREAKPOINT);
+}
+
+// the result of a Mooneye test ROM at its breakpoint, which other ROMs may run for other reasons
+fn breakpoint_result(gb: &GameBoy) -> Option<Report> {
+    if !at_breakpoint(gb) {
+        return None;
+    }
+    let registers: Vec<u8> = RESULT_REGISTERS
+        .iter()
+        .map(|&r| gb.cpu.read_reg8(r))
+        .collect();
+    let verdict = if registers == PASSED_REGISTERS {
+        Verdict::Passed
+    } else if registers == FAILED_REGISTERS {
+        Verdict::Failed
+    } else {
+        return None;
+    };
+    let output = RESULT_REGISTERS
+        .iter()
+        .zip(registers.iter())
+        .map(|(r, value)| format!("{}={:02x}", r, value))
+        .collect::<Vec<String>>()
+        .join(" ");
+    return Some(Report {
+        verdict,
+        output: output + "\n",
+    });
+}
+
+fn has_signature(gb: &GameBoy) -> bool {
+    return (0..SIGNATURE.len()).all(|i| gb.memory.peek(STATUS + 1 + i as u16) == SIGNATURE[i]);
+}
+
+// the text in cartridge RAM if the ROM keeps it there, or else what it sent over the link port
+fn printed(gb: &GameBoy) -> String {
+    let bytes: Vec<u8> = if has_signature(gb) {
+        (TEXT_START..=TEXT_END)
+            .map(|address| gb.memory.peek(address))
+            .take_while(|&byte| byte != 0)
+            .collect()
+    } else {
+        gb.serial_output().to_vec()
+    };
+    return String::from_utf8_lossy(&bytes).into_owned();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::test_util::{rom_with, rom_with_program};
+
+    // prints the string at 0x200 over the link port, then copies the 256 bytes at 0x300 to
+    // cartridge RAM and stops
+    fn gameboy(text: &str, cartridge_ram: &[u8]) -> GameBoy {
+        let program = [
+            0x21, 0x00, 0x02, // LD HL, 0x0200
+            0x2a, //             print: LD A, (HL+)
+            0xb7, //             OR A
+            0x28, 0x0d, //       JR Z, done
+            0xe0, 0x01, //       LDH (SB), A
+            0x3e, 0x81, //       LD A, 0x81
+            0xe0, 0x02, //       LDH (SC), A
+            0xf0, 0x02, //       wait: LDH A, (SC)
+            0x87, //             ADD A, A
+            0x38, 0xfb, //       JR C, wait
+            0x18, 0xef, //       JR print
+            0x3e, 0x0a, //       done: LD A, 0x0a
+            0xea, 0x00, 0x00, // LD (0x0000), A, enabling cartridge RAM
+            0x21, 0x00, 0x03, // LD HL, 0x0300
+            0x11, 0x00, 0xa0, // LD DE, 0xa000
+            0x2a, //             copy: LD A, (HL+)
+            0x12, //             LD (DE), A
+            0x13, //             INC DE
+            0x7a, //             LD A, D
+            0xfe, 0xa0, //       CP 0xa0
+            0x28, 0xf8, //       JR Z, copy
+            0x18, 0xfe, //       stop: JR stop
+        ];
+        return rom_with(
+            Model::DMG,
+            &[
+                (0x100, &[0xc3, 0x50, 0x01]), // JP 0x0150
+                (0x147, &[0x03]),             // MBC1 with RAM
+                (0x149, &[0x02]),             // 8 KiB
+                (0x150, &program),
+                (0x200, text.as_bytes()),
+                (0x300, cartridge_ram),
+            ],
+        );
+    }
+
+    // loads the registers with the given values and runs LD B, B
+    fn mooneye(registers: [u8; 6]) -> GameBoy {
+        let mut program = vec![];
+        // LD B, n; LD C, n; LD D, n; LD E, n; LD H, n; LD L, n
+        for (&opcode, &value) in [0x06, 0x0e, 0x16, 0x1e, 0x26, 0x2e]
+            .iter()
+            .zip(registers.iter())
+        {
+            program.extend_from_slice(&[opcode, value]);
+        }
+        program.extend_from_slice(&[0x40, 0x18, 0xfe]); // LD B, B; JR -2
+        return rom_with_program(&program);
+    }
+
+    #[test]
+    fn test_reads_the_result_printed_over_the_link_port() {
+        let mut gb = gameboy("01-special\n\n\nPassed\n", &[]);
+        let report = run(&mut gb, 60).unwrap();
+
+        assert_eq!(report.verdict, Verdict::Passed);
+        assert_eq!(report.output, "01-special\n\n\nPassed\n");
+    }
+
+    #[test]
+    fn test_waits_for_the_end_of_the_failure() {
+        let mut gb = gameboy("cpu_instrs\n\n01:ok  02:04\n\nFailed 1 tests\n", &[]);
+        let report = run(&mut gb, 60).unwrap();
+
+        assert_eq!(report.verdict, Verdict::Failed);
+        assert!(report.output.ends_with("Failed 1 tests\n"));
+    }
+
+    #[test]
+    fn test_reads_the_result_from_cartridge_ram() {
+        let mut ram = vec![0x03, 0xde, 0xb0, 0x61];
+        ram.extend_from_slice(b"Failed #3\n\0");
+        let mut gb = gameboy("", &ram);
+        let report = run(&mut gb, 60).unwrap();
+
+        assert_eq!(report.verdict, Verdict::Failed);
+        assert_eq!(report.output, "Failed #3\n");
+    }
+
+    #[test]
+    fn test_gives_up_on_roms_which_never_finish() {
+        let mut ram = vec![RUNNING, 0xde, 0xb0, 0x61];
+        ram.extend_from_slice(b"Running\0");
+        let mut gb = gameboy("", &ram);
+        let report = run(&mut gb, 60).unwrap();
+
+        assert_eq!(report.verdict, Verdict::TimedOut);
+        assert_eq!(report.output, "Running");
+    }
+
+    #[test]
+    fn test_reads_the_result_from_registers_at_ld_b_b() {
+        let report = run(&mut mooneye([3, 5, 8, 13, 21, 34]), 60).unwrap();
+        assert_eq!(report.verdict, Verdict::Passed);
+        assert_eq!(report.output, "b=03 c=05 d=08 e=0d h=15 l=22\n");
+
+        let report = run(&mut mooneye([0x42; 6]), 60).unwrap();
+        assert_eq!(report.verdict, Verdict::Failed);
+    }
+
+    #[test]
+    fn test_ignores_ld_b_b_with_other_registers() {
+        let report = run(&mut mooneye([1, 2, 3, 4, 5, 6]), 60).unwrap();
+        assert_eq!(report.verdict, Verdict::TimedOut);
+    }
+}
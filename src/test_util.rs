@@ -0,0 +1,124 @@
+// games and helpers shared by the tests of several modules
+
+// some are only used by the tests of the debugger, GDB stub and tracing, which need std
+#![cfg_attr(not(feature = "std"), allow(dead_code, unused_imports))]
+
+use crate::cartridge::Cartridge;
+use crate::cpu::Bus;
+use crate::gameboy::GameBoy;
+use crate::model::Model;
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+const ENTRY_POINT: usize = 0x100;
+const MIN_ROM_SIZE: usize = 0x8000;
+const ROM_SIZE: usize = 0x148;
+
+// a DMG running the program from the cartridge's entry point
+pub fn rom_with_program(program: &[u8]) -> GameBoy {
+    return rom_with(Model::DMG, &[(ENTRY_POINT, program)]);
+}
+
+// a cartridge which is zero apart from the given bytes at each address, as large as its header
+// says, so that header fields can be given too
+pub fn rom_with(model: Model, contents: &[(usize, &[u8])]) -> GameBoy {
+    let mut rom = vec![0; MIN_ROM_SIZE];
+    for &(address, bytes) in contents {
+        let end = address + bytes.len();
+        rom.resize(rom.len().max(end), 0);
+        rom[address..end].copy_from_slice(bytes);
+    }
+    rom.resize(rom.len().max(MIN_ROM_SIZE << rom[ROM_SIZE]), 0);
+    return GameBoy::new(model, Cartridge::new(rom).unwrap());
+}
+
+// a loop which counts in A, stores it and calls a subroutine, for stepping and breaking on
+pub fn counting_loop() -> GameBoy {
+    let program = [
+        0x3e, 0x10, //       LD A, 0x10
+        0x3c, //             loop: INC A
+        0xea, 0x00, 0xc0, // LD (0xc000), A
+        0xcd, 0x00, 0x02, // CALL 0x0200
+        0x18, 0xf7, //       JR loop
+    ];
+    let subroutine = [0x47, 0xc9]; // LD B, A; RET
+    return rom_with(Model::DMG, &[(ENTRY_POINT, &program), (0x200, &subroutine)]);
+}
+
+// a writer whose output the test can still read after handing it to a tracer
+#[derive(Clone)]
+pub struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl SharedWriter {
+    pub fn new() -> SharedWriter {
+        return SharedWriter(Arc::new(Mutex::new(vec![])));
+    }
+
+    pub fn text(&self) -> String {
+        return String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        return self.0.lock().unwrap().write(data);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// an access the CPU made over the bus, with the value read or written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// nothing but 64 KiB of RAM, logging the CPU's accesses, to run the CPU on its own
+pub struct TestBus {
+    ram: Vec<u8>,
+    accesses: RefCell<Vec<Access>>, // logged by reads too, which only borrow the bus
+}
+
+impl TestBus {
+    pub fn new() -> TestBus {
+        return TestBus {
+            ram: vec![0; 0x10000],
+            accesses: RefCell::new(vec![]),
+        };
+    }
+
+    // the accesses made since the last call
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        return self.accesses.take();
+    }
+}
+
+impl Bus for TestBus {
+    fn read(&self, address: u16) -> u8 {
+        let value = self.ram[address as usize];
+        self.accesses
+            .borrow_mut()
+            .push(Access::Read(address, value));
+        return value;
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.ram[address as usize] = value;
+        self.accesses.get_mut().push(Access::Write(address, value));
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        return self.ram[address as usize];
+    }
+
+    fn speed_switch_armed(&self) -> bool {
+        return false;
+    }
+
+    fn switch_speed(&mut self) {}
+}
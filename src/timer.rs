@@ -0,0 +1,268 @@
+use crate::cpu::special_registers::*;
+use crate::cpu::Interrupt;
+use crate::save_state::{StateReader, StateWriter};
+use alloc::string::String;
+
+// bit of the internal counter whose falling edge increments TIMA, for each TAC clock select
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+
+// DIV, TIMA, TMA and TAC, all driven by one counter of clock cycles which DIV is the upper byte of
+// https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+#[derive(Clone)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflowed: bool, // TIMA overflowed last cycle, and is reloaded from TMA in this one
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        return Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflowed: false,
+        };
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.bool(self.overflowed);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()? & 0x07;
+        self.overflowed = state.bool()?;
+        return Ok(());
+    }
+
+    // the whole counter, whose bits also clock the APU's frame sequencer
+    pub fn counter(&self) -> u16 {
+        return self.counter;
+    }
+
+    // where the boot ROM leaves the counter, which depends on how long it ran
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        return match address {
+            DIV => (self.counter >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => 0xf8 | self.tac,
+            _ => 0xff,
+        };
+    }
+
+    // resetting the counter or changing TAC can make the selected bit fall, incrementing TIMA
+    pub fn write(&mut self, address: u16, value: u8) {
+        let before = self.signal();
+        match address {
+            DIV => self.counter = 0,
+            TIMA => {
+                // a write in the cycle after an overflow cancels the reload
+                self.tima = value;
+                self.overflowed = false;
+            }
+            TMA => self.tma = value,
+            TAC => self.tac = value & 0x07,
+            _ => return,
+        }
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+
+    // advances by some CPU machine cycles at once, returning the interrupt requested, exactly as
+    // if it had been run a cycle at a time
+    pub fn advance(&mut self, cycles: u128) -> u8 {
+        let mut interrupts = 0;
+        let mut left = cycles;
+        while left > 0 {
+            if self.overflowed {
+                self.overflowed = false;
+                self.tima = self.tma;
+                interrupts |= 1 << Interrupt::Timer.bit();
+                self.count(1);
+                left -= 1;
+                continue;
+            }
+            match self.cycles_until_overflow() {
+                Some(until) if until <= left => {
+                    self.count(until);
+                    left -= until;
+                }
+                _ => {
+                    self.count(left);
+                    left = 0;
+                }
+            }
+        }
+        return interrupts;
+    }
+
+    // cycles until the timer next needs to run to interrupt on time, if it ever does
+    pub fn cycles_until_event(&self) -> Option<u128> {
+        if self.overflowed {
+            return Some(1);
+        }
+        return self.cycles_until_overflow();
+    }
+
+    // cycles until the given counter bit next falls
+    pub fn cycles_until_fall(&self, bit: u16) -> u128 {
+        let period = 1u32 << (bit + 1);
+        let counter = self.counter as u32;
+        let next = (counter / period + 1) * period;
+        return ((next - counter) as u128).div_ceil(4);
+    }
+
+    // cycles until the increment which overflows TIMA, while enabled
+    fn cycles_until_overflow(&self) -> Option<u128> {
+        if self.tac & 0x04 == 0 {
+            return None;
+        }
+        let bit = TAC_BITS[(self.tac & 0x03) as usize];
+        let period = (1u128 << (bit + 1)) / 4;
+        let increments = 0x100 - self.tima as u128;
+        return Some(self.cycles_until_fall(bit) + (increments - 1) * period);
+    }
+
+    // moves the counter on by the cycles, incrementing TIMA on each falling edge they pass
+    fn count(&mut self, cycles: u128) {
+        let mut increments = 0;
+        if self.tac & 0x04 != 0 {
+            let bit = TAC_BITS[(self.tac & 0x03) as usize];
+            let until = self.cycles_until_fall(bit);
+            if cycles >= until {
+                increments = 1 + (cycles - until) / ((1u128 << (bit + 1)) / 4);
+            }
+        }
+        // the counter wraps every 0x4000 cycles
+        let steps = (cycles % 0x4000) as u16;
+        self.counter = self.counter.wrapping_add(steps.wrapping_mul(4));
+        // callers stop at the increment which overflows
+        let tima = self.tima as u128 + increments;
+        self.tima = tima as u8;
+        self.overflowed = self.overflowed || tima == 0x100;
+    }
+
+    // the selected counter bit while the timer is enabled
+    fn signal(&self) -> bool {
+        let bit = TAC_BITS[(self.tac & 0x03) as usize];
+        return self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0;
+    }
+
+    // TIMA reads 0 for a cycle after overflowing, before it is reloaded
+    fn increment(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflowed = overflowed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(timer: &mut Timer, cycles: usize) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            interrupts |= timer.advance(1);
+        }
+        return interrupts;
+    }
+
+    #[test]
+    fn test_div_counts_up_every_64_cycles() {
+        let mut timer = Timer::new();
+        run(&mut timer, 63);
+        assert_eq!(timer.read(DIV), 0);
+        run(&mut timer, 1);
+        assert_eq!(timer.read(DIV), 1);
+
+        timer.write(DIV, 0x55);
+        assert_eq!(timer.read(DIV), 0);
+    }
+
+    #[test]
+    fn test_tima_counts_at_the_selected_rate() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0x05); // enabled, every 4 cycles
+        run(&mut timer, 40);
+        assert_eq!(timer.read(TIMA), 10);
+
+        timer.write(TAC, 0x00);
+        run(&mut timer, 40);
+        assert_eq!(timer.read(TIMA), 10);
+        assert_eq!(timer.read(TAC), 0xf8);
+    }
+
+    #[test]
+    fn test_overflow_reloads_tma_a_cycle_later_and_interrupts() {
+        let mut timer = Timer::new();
+        timer.write(TMA, 0xf0);
+        timer.write(TIMA, 0xff);
+        timer.write(TAC, 0x05);
+
+        assert_eq!(run(&mut timer, 4), 0);
+        assert_eq!(timer.read(TIMA), 0x00);
+        assert_eq!(run(&mut timer, 1), 1 << Interrupt::Timer.bit());
+        assert_eq!(timer.read(TIMA), 0xf0);
+    }
+
+    #[test]
+    fn test_writing_tima_after_overflow_cancels_the_reload() {
+        let mut timer = Timer::new();
+        timer.write(TMA, 0xf0);
+        timer.write(TIMA, 0xff);
+        timer.write(TAC, 0x05);
+        run(&mut timer, 4);
+
+        timer.write(TIMA, 0x12);
+
+        assert_eq!(run(&mut timer, 1), 0);
+        assert_eq!(timer.read(TIMA), 0x12);
+    }
+
+    #[test]
+    fn test_resetting_div_on_a_falling_edge_increments_tima() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0x05);
+        run(&mut timer, 2); // the selected bit 3 is now set
+
+        timer.write(DIV, 0);
+
+        assert_eq!(timer.read(TIMA), 1);
+    }
+
+    #[test]
+    fn test_advancing_at_once_matches_single_cycles() {
+        for &tac in [0x04, 0x05, 0x06, 0x07, 0x01].iter() {
+            let mut stepped = Timer::new();
+            stepped.write(TMA, 0xfd);
+            stepped.write(TAC, tac);
+            stepped.set_counter(0xabcc);
+            let mut advanced = stepped.clone();
+
+            for &cycles in [1, 3, 70, 1000, 5, 100000].iter() {
+                let interrupts = run(&mut stepped, cycles);
+                assert_eq!(advanced.advance(cycles as u128), interrupts);
+                assert_eq!(advanced.counter(), stepped.counter());
+                assert_eq!(advanced.read(TIMA), stepped.read(TIMA));
+                assert_eq!(advanced.cycles_until_event(), stepped.cycles_until_event());
+            }
+        }
+    }
+}
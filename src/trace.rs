@@ -0,0 +1,201 @@
+use crate::cpu::{Reg16, Reg8, CPU};
+use crate::memory::Memory;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+
+const REGISTERS: [Reg8; 8] = [
+    Reg8::A,
+    Reg8::F,
+    Reg8::B,
+    Reg8::C,
+    Reg8::D,
+    Reg8::E,
+    Reg8::H,
+    Reg8::L,
+];
+const PC_MEMORY_LENGTH: u16 = 4;
+
+// the machine as an instruction starts, kept compact so that many fit in the ring buffer
+#[derive(Clone, Copy)]
+struct Entry {
+    registers: [u8; REGISTERS.len()],
+    sp: u16,
+    pc: u16,
+    pc_memory: [u8; PC_MEMORY_LENGTH as usize],
+}
+
+impl Entry {
+    // the Gameboy Doctor format, which BGB can also log
+    fn write(&self, output: &mut dyn Write) -> io::Result<()> {
+        let [a, f, b, c, d, e, h, l] = self.registers;
+        let [m0, m1, m2, m3] = self.pc_memory;
+        return writeln!(
+            output,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+             PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            a, f, b, c, d, e, h, l, self.sp, self.pc, m0, m1, m2, m3
+        );
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    Ring(VecDeque<Entry>, usize), // the most recent instructions, up to a capacity
+}
+
+// logs one line per executed instruction, to compare the CPU against a known good log
+pub struct Tracer {
+    output: Output,
+    error: Option<io::Error>, // the first failed write, after which nothing more is written
+}
+
+impl Tracer {
+    pub fn to_writer(writer: Box<dyn Write + Send>) -> Tracer {
+        return Tracer {
+            output: Output::Writer(writer),
+            error: None,
+        };
+    }
+
+    // keeps only the last instructions, to be written out when emulation ends
+    pub fn ring(capacity: usize) -> Tracer {
+        return Tracer {
+            output: Output::Ring(VecDeque::with_capacity(capacity), capacity),
+            error: None,
+        };
+    }
+
+    // to be called as each instruction starts
+    pub(crate) fn trace(&mut self, cpu: &CPU, memory: &Memory) {
+        let pc = cpu.read_reg16(Reg16::PC);
+        let mut entry = Entry {
+            registers: [0; REGISTERS.len()],
+            sp: cpu.read_reg16(Reg16::SP),
+            pc,
+            pc_memory: [0; PC_MEMORY_LENGTH as usize],
+        };
+        for (value, &r) in entry.registers.iter_mut().zip(REGISTERS.iter()) {
+            *value = cpu.read_reg8(r);
+        }
+        for (i, value) in entry.pc_memory.iter_mut().enumerate() {
+            *value = memory.peek(pc.wrapping_add(i as u16));
+        }
+
+        match &mut self.output {
+            Output::Writer(writer) => {
+                if self.error.is_none() {
+                    if let Err(e) = entry.write(writer) {
+                        self.error = Some(e);
+                    }
+                }
+            }
+            Output::Ring(entries, capacity) => {
+                if entries.len() == *capacity {
+                    entries.pop_front();
+                }
+                if *capacity > 0 {
+                    entries.push_back(entry);
+                }
+            }
+        }
+    }
+
+    // flushes the log, or writes the ring buffer's instructions oldest first to the given output
+    pub fn finish(self, ring_output: &mut dyn Write) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        return match self.output {
+            Output::Writer(mut writer) => writer.flush(),
+            Output::Ring(entries, _) => {
+                for entry in entries.iter() {
+                    entry.write(ring_output)?;
+                }
+                ring_output.flush()
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::model::Model;
+    use crate::test_util::{rom_with, SharedWriter};
+
+    fn gameboy() -> GameBoy {
+        let program = [
+            0x00, //             NOP
+            0x3e, 0x01, //       LD A, 0x01
+            0xe0, 0xff, //       LDH (IE), A
+            0xfb, //             EI
+            0x18, 0xfe, //       loop: JR loop
+        ];
+        let handler = [0xd9]; // VBlank handler: RETI
+        return rom_with(Model::DMG, &[(0x40, &handler), (0x100, &program)]);
+    }
+
+    fn lines(output: &SharedWriter) -> Vec<String> {
+        return output.text().lines().map(|line| line.to_string()).collect();
+    }
+
+    #[test]
+    fn test_logs_each_instruction_in_gameboy_doctor_format() {
+        let mut gb = gameboy();
+        let output = SharedWriter::new();
+        gb.trace(Tracer::to_writer(Box::new(output.clone())));
+
+        for _ in 0..10 {
+            gb.cycle().unwrap();
+        }
+
+        assert_eq!(
+            lines(&output)[..3],
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,3E,01,E0",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:3E,01,E0,FF",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:E0,FF,FB,18",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skips_interrupt_dispatch() {
+        let mut gb = gameboy();
+        let output = SharedWriter::new();
+        gb.trace(Tracer::to_writer(Box::new(output.clone())));
+
+        gb.run_frame().unwrap();
+        gb.run_frame().unwrap();
+
+        let lines = lines(&output);
+        let handler = lines
+            .iter()
+            .position(|line| line.contains("PC:0040"))
+            .unwrap();
+        assert!(lines[handler - 1].contains("PC:0106"));
+        assert!(lines[handler + 1].contains("PC:0106"));
+        assert!(gb.take_tracer().unwrap().finish(&mut io::sink()).is_ok());
+    }
+
+    #[test]
+    fn test_ring_keeps_the_last_instructions() {
+        let mut gb = gameboy();
+        gb.trace(Tracer::ring(2));
+        for _ in 0..10 {
+            gb.cycle().unwrap();
+        }
+
+        let mut dump = vec![];
+        gb.take_tracer().unwrap().finish(&mut dump).unwrap();
+
+        let dump = String::from_utf8(dump).unwrap();
+        let pcs: Vec<&str> = dump
+            .lines()
+            .map(|line| line.split(' ').nth(9).unwrap())
+            .collect();
+        assert_eq!(pcs, ["PC:0105", "PC:0106"]);
+    }
+}
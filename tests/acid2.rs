@@ -0,0 +1,57 @@
+#![allow(clippy::needless_return)]
+
+// runs Matt Currie's acid tests of the PPU, https://github.com/mattcurrie/dmg-acid2 and
+// https://github.com/mattcurrie/cgb-acid2, through the emulator's test command, with each ROM and
+// its reference image named like dmg-acid2.gb and dmg-acid2.png in the directory given by
+// ACID2_TEST_ROMS
+//
+// ROMs which are not there are skipped, so these pass without them.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// runs the ROM to its LD B, B and compares the screen with the reference image, returning the
+// error the emulator exited with if they differ
+fn run(name: &str, model: &str) -> Result<(), String> {
+    let dir = match env::var_os("ACID2_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("Skipping {}, ACID2_TEST_ROMS is not set", name);
+            return Ok(());
+        }
+    };
+    let rom = dir.join(format!("{}.gb", name));
+    if !rom.exists() {
+        eprintln!("Skipping {}, {} not found", name, rom.display());
+        return Ok(());
+    }
+
+    // differing pixels are drawn in red in an image in a directory of its own for this test run
+    let diff_dir = env::temp_dir().join(format!("gameboy-emulator-{}", std::process::id()));
+    fs::create_dir_all(&diff_dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_gameboy-emulator"))
+        .args(["test", "--model", model, "--frames", "600", "--reference"])
+        .arg(dir.join(format!("{}.png", name)))
+        .arg("--diff")
+        .arg(diff_dir.join(format!("{}-diff.png", name)))
+        .arg(&rom)
+        .output()
+        .expect("could not run the emulator");
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    return Ok(());
+}
+
+#[test]
+fn test_dmg_acid2() {
+    assert_eq!(run("dmg-acid2", "dmg"), Ok(()));
+}
+
+#[test]
+fn test_cgb_acid2() {
+    assert_eq!(run("cgb-acid2", "cgb"), Ok(()));
+}
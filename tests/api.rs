@@ -0,0 +1,322 @@
+#![allow(clippy::needless_return)]
+
+// the library's public API as frontends and tools use it, each function coerced to a function
+// pointer of its expected type so that a breaking change fails to compile here
+
+use gameboy_emulator::disassembler::{self, Line};
+use gameboy_emulator::movie::{Movie, Recording};
+use gameboy_emulator::rewind::{self, Rewind};
+use gameboy_emulator::test_rom::{self, Report, Verdict};
+use gameboy_emulator::{
+    Button, Cartridge, EmuError, Event, GameBoy, Model, BORDER_HEIGHT, BORDER_WIDTH, SAMPLE_RATE,
+    SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use std::error::Error;
+#[cfg(feature = "std")]
+use {
+    gameboy_emulator::debugger::Debugger,
+    gameboy_emulator::gdb::GdbStub,
+    gameboy_emulator::screenshot::{self, Palette, Trigger},
+    gameboy_emulator::trace::Tracer,
+    std::io,
+    std::io::Write,
+    std::net::TcpListener,
+    std::path::Path,
+};
+
+type FrameBuffer = [u16; SCREEN_WIDTH * SCREEN_HEIGHT];
+type BorderFrameBuffer = [u16; BORDER_WIDTH * BORDER_HEIGHT];
+
+// a ROM which waits in a loop for the screen to be drawn
+fn cartridge() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]); // JR -2
+    return Cartridge::new(rom).unwrap();
+}
+
+#[test]
+fn test_signatures() {
+    let _: fn(Model, Cartridge) -> GameBoy = GameBoy::new;
+    let _: fn(Model, Cartridge, Vec<u8>) -> GameBoy = GameBoy::with_boot_rom;
+    let _: fn(&mut GameBoy) -> Result<(), EmuError> = GameBoy::cycle;
+    let _: fn(&mut GameBoy) -> Result<(), EmuError> = GameBoy::step;
+    let _: fn(&mut GameBoy) -> Result<Event, EmuError> = GameBoy::run_frame;
+    let _: fn(&mut GameBoy) -> Result<Event, EmuError> = GameBoy::run_until_vblank;
+    let _: fn(&mut GameBoy, usize) -> Result<Event, EmuError> = GameBoy::run_cycles;
+    let _: fn(&GameBoy) -> &FrameBuffer = GameBoy::frame_buffer;
+    let _: fn(&GameBoy) -> Option<&BorderFrameBuffer> = GameBoy::sgb_frame_buffer;
+    let _: fn(&mut GameBoy) -> Vec<i16> = GameBoy::take_audio_samples;
+    let _: fn(&mut GameBoy, Button) = GameBoy::press;
+    let _: fn(&mut GameBoy, Button) = GameBoy::release;
+    let _: fn(&GameBoy) -> u8 = GameBoy::buttons;
+    let _: fn(&mut GameBoy, u8) = GameBoy::set_buttons;
+    let _: fn(&GameBoy) -> Vec<u8> = GameBoy::save_state;
+    let _: fn(&mut GameBoy, &[u8]) -> Result<(), EmuError> = GameBoy::load_state;
+    let _: fn(&GameBoy) -> Model = GameBoy::model;
+    let _: fn(&GameBoy) -> [u8; 3] = GameBoy::cartridge_checksum;
+    let _: fn(&mut GameBoy) = GameBoy::record_serial_output;
+    let _: fn(&GameBoy) -> &[u8] = GameBoy::serial_output;
+
+    let _: fn(Vec<u8>) -> Result<Cartridge, EmuError> = Cartridge::new;
+    let _: fn(&Cartridge) -> bool = Cartridge::supports_cgb;
+    let _: fn(&Cartridge) -> bool = Cartridge::supports_sgb;
+    let _: fn(&Cartridge) -> [u8; 3] = Cartridge::checksum;
+    let _: fn(&Cartridge) -> Model = Model::for_cartridge;
+    let _: fn(&Button) -> u8 = Button::bit;
+    let _: [Button; 8] = Button::ALL;
+
+    let _: (usize, usize, usize, usize, u32) = (
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
+        BORDER_WIDTH,
+        BORDER_HEIGHT,
+        SAMPLE_RATE,
+    );
+}
+
+#[test]
+fn test_module_signatures() {
+    let _: fn(&GameBoy) -> Recording = Movie::record;
+    let _: fn(&mut Recording, &GameBoy) = Recording::record_frame;
+    let _: fn(&Recording) -> usize = Recording::frames;
+    let _: fn(Recording, &GameBoy) -> Movie = Recording::finish;
+    let _: fn(&Movie) -> Model = Movie::model;
+    let _: fn(&Movie) -> usize = Movie::frames;
+    let _: fn(&Movie, &mut GameBoy) -> Result<(), EmuError> = Movie::play;
+    let _: fn(&Movie) -> Vec<u8> = Movie::to_bytes;
+    let _: fn(&[u8]) -> Result<Movie, EmuError> = Movie::from_bytes;
+
+    let _: fn(usize, usize) -> Rewind = Rewind::new;
+    let _: fn(&Rewind) -> usize = Rewind::frames;
+    let _: fn(&mut Rewind, &GameBoy) = Rewind::record_frame;
+    let _: fn(&mut Rewind, &mut GameBoy, usize) -> Result<usize, EmuError> = Rewind::rewind;
+    let _: (usize, usize) = (rewind::DEFAULT_INTERVAL, rewind::DEFAULT_CAPACITY);
+
+    let _: fn(&[u8], u16) -> Vec<Line> = disassembler::disassemble;
+    let _: fn(&[u8], u16) -> String = disassembler::listing;
+    let _ = |line: Line| -> (u16, Vec<u8>, Option<u16>, String) {
+        return (line.address, line.bytes, line.target, line.text);
+    };
+
+    let _: fn(&mut GameBoy, usize) -> Result<Report, EmuError> = test_rom::run;
+    let _: fn(&GameBoy) -> bool = test_rom::at_breakpoint;
+    let _ = |report: Report| -> (Verdict, String) {
+        return (report.verdict, report.output);
+    };
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_std_module_signatures() {
+    let _: fn(&mut GameBoy, Tracer) = GameBoy::trace;
+    let _: fn(&mut GameBoy) -> Option<Tracer> = GameBoy::take_tracer;
+    let _: fn(Box<dyn Write + Send>) -> Tracer = Tracer::to_writer;
+    let _: fn(usize) -> Tracer = Tracer::ring;
+    let _: fn(Tracer, &mut dyn Write) -> io::Result<()> = Tracer::finish;
+
+    let _: fn() -> Debugger = Debugger::new;
+    let _: fn(&mut Debugger, &mut GameBoy, &str) -> String = Debugger::execute;
+    let _ = |debugger: &mut Debugger, gb: &mut GameBoy| -> io::Result<()> {
+        return debugger.run(gb, &b""[..], io::sink());
+    };
+
+    let _: fn() -> GdbStub = GdbStub::new;
+    let _: fn(&mut GdbStub, &mut GameBoy, &TcpListener) -> io::Result<()> = GdbStub::serve;
+
+    let _: fn(&mut GameBoy, Trigger) -> Result<(), String> = screenshot::run_until;
+    let _: fn(&GameBoy, &Path, Palette, &Path) -> Result<(), String> = screenshot::compare;
+    let _: fn(&Path) -> Result<Vec<[u8; 3]>, String> = screenshot::read_png;
+    let _: fn(&Path, &[[u8; 3]]) -> Result<(), String> = screenshot::write_png;
+    let _: Palette = screenshot::DMG_GREYS;
+}
+
+// matching without a wildcard stops compiling if a variant is added or removed
+#[test]
+fn test_enums() {
+    for model in [Model::DMG, Model::MGB, Model::CGB, Model::SGB] {
+        match model {
+            Model::DMG | Model::MGB | Model::CGB | Model::SGB => {}
+        }
+    }
+    for button in Button::ALL {
+        match button {
+            Button::Right | Button::Left | Button::Up | Button::Down => {}
+            Button::A | Button::B | Button::Select | Button::Start => {}
+        }
+    }
+    for event in [Event::FrameReady, Event::CyclesDone] {
+        match event {
+            Event::FrameReady | Event::CyclesDone => {}
+        }
+    }
+    for verdict in [Verdict::Passed, Verdict::Failed, Verdict::TimedOut] {
+        match verdict {
+            Verdict::Passed | Verdict::Failed | Verdict::TimedOut => {}
+        }
+    }
+    // EmuError is non_exhaustive, so only its variants are pinned
+    let _ = EmuError::IllegalOpcode { pc: 0, opcode: 0 };
+    let _ = EmuError::LockedUp { pc: 0 };
+    let _ = EmuError::BadCartridge(String::new());
+    let error = EmuError::BadState(String::new());
+    let _: &dyn Error = &error;
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_std_enums() {
+    for palette in [screenshot::DMG_GREYS, Palette::RGB555] {
+        match palette {
+            Palette::Shades(_) | Palette::RGB555 => {}
+        }
+    }
+    for trigger in [Trigger::Breakpoint(0), Trigger::Frames(0)] {
+        match trigger {
+            Trigger::Breakpoint(_) | Trigger::Frames(_) => {}
+        }
+    }
+}
+
+#[test]
+fn test_runs_frames() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    assert_eq!(gb.model(), Model::DMG);
+    assert_eq!(gb.run_frame(), Ok(Event::FrameReady));
+    assert_eq!(gb.frame_buffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+    assert!(gb.sgb_frame_buffer().is_none());
+
+    // a frame of stereo samples
+    gb.take_audio_samples();
+    gb.run_frame().unwrap();
+    let samples = gb.take_audio_samples().len() as u32;
+    let expected = SAMPLE_RATE * 2 / 60;
+    assert!(samples.abs_diff(expected) < expected / 10, "{}", samples);
+}
+
+#[test]
+fn test_buttons() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    gb.press(Button::Start);
+    gb.press(Button::Left);
+    assert_eq!(
+        gb.buttons(),
+        1 << Button::Start.bit() | 1 << Button::Left.bit()
+    );
+    gb.release(Button::Left);
+    assert_eq!(gb.buttons(), 1 << Button::Start.bit());
+    gb.set_buttons(0);
+    assert_eq!(gb.buttons(), 0);
+}
+
+#[test]
+fn test_save_states() {
+    let mut gb = GameBoy::new(Model::CGB, cartridge());
+    gb.run_frame().unwrap();
+    let state = gb.save_state();
+    let frame = *gb.frame_buffer();
+
+    gb.run_frame().unwrap();
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.save_state(), state);
+    assert_eq!(*gb.frame_buffer(), frame);
+    assert!(matches!(
+        gb.load_state(&state[..4]),
+        Err(EmuError::BadState(_))
+    ));
+}
+
+#[test]
+fn test_rejects_bad_cartridges() {
+    assert!(matches!(
+        Cartridge::new(vec![0; 0x100]),
+        Err(EmuError::BadCartridge(_))
+    ));
+}
+
+#[test]
+fn test_movies() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    let mut recording = Movie::record(&gb);
+    for buttons in [0, 1 << Button::A.bit()] {
+        gb.set_buttons(buttons);
+        recording.record_frame(&gb);
+        gb.run_frame().unwrap();
+    }
+    let movie = recording.finish(&gb);
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    assert_eq!((movie.model(), movie.frames()), (Model::DMG, 2));
+    let mut player = GameBoy::new(Model::DMG, cartridge());
+    movie.play(&mut player).unwrap();
+    assert_eq!(player.save_state(), gb.save_state());
+    assert!(matches!(
+        Movie::from_bytes(b"GBMV"),
+        Err(EmuError::BadState(_))
+    ));
+}
+
+#[test]
+fn test_rewinds() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    let mut rewind = Rewind::new(2, rewind::DEFAULT_CAPACITY);
+    let state = gb.save_state();
+    for _ in 0..4 {
+        rewind.record_frame(&gb);
+        gb.run_frame().unwrap();
+    }
+
+    assert_eq!(rewind.frames(), 4);
+    assert_eq!(rewind.rewind(&mut gb, 4), Ok(4));
+    assert_eq!(gb.save_state(), state);
+}
+
+#[test]
+fn test_disassembles() {
+    let lines = disassembler::disassemble(&[0x18, 0xfe, 0xd3], 0x100);
+    assert_eq!(lines[0].text, "jr $0100");
+    assert_eq!(lines[0].target, Some(0x100));
+    assert_eq!(
+        (lines[1].address, &lines[1].bytes[..]),
+        (0x102, &[0xd3][..])
+    );
+    assert_eq!(
+        disassembler::listing(&[0x18, 0xfe], 0x100),
+        "L0100:\n    jr L0100                ; 0100: 18 fe\n"
+    );
+}
+
+#[test]
+fn test_runs_test_roms() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    let report = test_rom::run(&mut gb, 2).unwrap();
+    assert_eq!(report.verdict, Verdict::TimedOut);
+    assert!(report.output.is_empty());
+    assert!(!test_rom::at_breakpoint(&gb));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_debugs() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    let mut debugger = Debugger::new();
+    assert!(!debugger.execute(&mut gb, "step").is_empty());
+
+    let mut output = vec![];
+    debugger.run(&mut gb, &b"quit\n"[..], &mut output).unwrap();
+    assert!(!output.is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_traces() {
+    let mut gb = GameBoy::new(Model::DMG, cartridge());
+    gb.trace(Tracer::ring(2));
+    for _ in 0..3 {
+        gb.step().unwrap();
+    }
+
+    let mut output = vec![];
+    gb.take_tracer().unwrap().finish(&mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap().lines().count(), 2);
+    assert!(gb.take_tracer().is_none());
+}
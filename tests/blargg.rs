@@ -0,0 +1,85 @@
+#![allow(clippy::needless_return)]
+
+// runs Blargg's test ROMs through the emulator's test command, from a copy of
+// https://github.com/retrio/gb-test-roms in the directory given by GB_TEST_ROMS
+//
+// ROMs which are not there are skipped, so these pass without them.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+// each made of numbered sub-tests
+const CPU_INSTRS: &[&str] = &["cpu_instrs/cpu_instrs.gb"];
+const INSTR_TIMING: &[&str] = &["instr_timing/instr_timing.gb"];
+const MEM_TIMING: &[&str] = &["mem_timing/mem_timing.gb", "mem_timing-2/mem_timing.gb"];
+
+// runs every ROM that is present, printing how each of its sub-tests went, and returns the ROMs
+// which failed
+fn run(roms: &[&str]) -> Vec<String> {
+    let dir = match env::var_os("GB_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("Skipping test ROMs, GB_TEST_ROMS is not set");
+            return vec![];
+        }
+    };
+
+    let mut failed = vec![];
+    for &rom in roms {
+        let path = dir.join(rom);
+        if !path.exists() {
+            eprintln!("{}: skipped, not found", rom);
+            continue;
+        }
+        let output = Command::new(env!("CARGO_BIN_EXE_gameboy-emulator"))
+            .arg("test")
+            .arg(&path)
+            .output()
+            .expect("could not run the emulator");
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for (test, result) in sub_tests(&stdout) {
+            eprintln!("{} {}: {}", rom, test, result);
+        }
+        if output.status.success() {
+            eprintln!("{}: passed", rom);
+        } else {
+            eprintln!(
+                "{}: {}",
+                rom,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            eprintln!("{}", stdout.trim());
+            failed.push(rom.to_string());
+        }
+    }
+    return failed;
+}
+
+// the "01:ok  02:04" results which ROMs made of several tests print, a number being the failure
+fn sub_tests(output: &str) -> Vec<(&str, &str)> {
+    return output
+        .split_whitespace()
+        .filter_map(|word| {
+            let (test, result) = word.split_at(word.find(':')?);
+            let is_number = !test.is_empty() && test.bytes().all(|b| b.is_ascii_digit());
+            return is_number.then(|| (test, &result[1..]));
+        })
+        .collect();
+}
+
+#[test]
+fn test_cpu_instrs() {
+    assert_eq!(run(CPU_INSTRS), Vec::<String>::new());
+}
+
+#[test]
+fn test_instr_timing() {
+    assert_eq!(run(INSTR_TIMING), Vec::<String>::new());
+}
+
+#[test]
+fn test_mem_timing() {
+    assert_eq!(run(MEM_TIMING), Vec::<String>::new());
+}
@@ -0,0 +1,85 @@
+#![allow(clippy::needless_return)]
+
+// runs every ROM from a build of Mooneye's test suite, https://github.com/Gekkio/mooneye-test-suite,
+// in the directory given by MOONEYE_TEST_ROMS and prints a table of which passed to compare between
+// releases, only failing if the emulator could not be run as many of them still fail
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const TIMEOUT_FRAMES: usize = 60 * 10; // far longer than any of the tests take
+const SKIPPED_DIRS: &[&str] = &["manual-only", "utils"]; // results checked by eye, or not tests
+
+// the ROMs in the directory and those under it, in order of name
+fn roms(dir: &Path) -> Vec<PathBuf> {
+    let mut found = vec![];
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("could not read the test ROM directory")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap().to_string_lossy();
+        if path.is_dir() && !SKIPPED_DIRS.contains(&name.as_ref()) {
+            found.extend(roms(&path));
+        } else if name.ends_with(".gb") {
+            found.push(path);
+        }
+    }
+    return found;
+}
+
+// the model a ROM is meant for, from the suffix naming the hardware it passes on, like -dmgABC,
+// -GS for DMG and SGB or -C for CGB
+fn model(rom: &Path) -> Option<&'static str> {
+    let stem = rom.file_stem()?.to_str()?;
+    let suffix = &stem[stem.rfind('-')? + 1..];
+    return if suffix.starts_with("dmg") || suffix.contains('G') {
+        Some("dmg")
+    } else if suffix.starts_with("mgb") {
+        Some("mgb")
+    } else if suffix.starts_with("sgb") || suffix == "S" {
+        Some("sgb")
+    } else if suffix.starts_with("cgb") || suffix == "C" {
+        Some("cgb")
+    } else {
+        None
+    };
+}
+
+#[test]
+fn test_mooneye() {
+    let dir = match env::var_os("MOONEYE_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("Skipping Mooneye's test ROMs, MOONEYE_TEST_ROMS is not set");
+            return;
+        }
+    };
+
+    let roms = roms(&dir);
+    let mut passed = 0;
+    for rom in roms.iter() {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gameboy-emulator"));
+        command.arg("test");
+        if let Some(model) = model(rom) {
+            command.args(["--model", model]);
+        }
+        let output = command
+            .args(["--frames", &TIMEOUT_FRAMES.to_string()])
+            .arg(rom)
+            .output()
+            .expect("could not run the emulator");
+
+        let result = if output.status.success() {
+            passed += 1;
+            "pass"
+        } else {
+            "FAIL"
+        };
+        println!("{}  {}", result, rom.strip_prefix(&dir).unwrap().display());
+    }
+    println!("{} of {} passed", passed, roms.len());
+}